@@ -1,132 +1,473 @@
-use crate::vector_db::QdrantClient;
+use crate::blob_store::BlobStore;
+use crate::blobs;
+use crate::events::{Numbered, QueryEvent, QueryEvents};
+use crate::file_worker;
+use crate::jobs;
+use crate::http_server;
+use crate::keyword::KeywordIndex;
 use crate::storage;
+use crate::vector_store::{VectorFilter, VectorStore};
+use crate::worker;
 use anyhow::Result;
 use bytes::Buf;
 use futures_util::TryStreamExt;
 use serde::Deserialize;
 use sqlx::{MySqlPool, Row};
-use warp::{multipart::FormData, Filter, Rejection, Reply};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use warp::{filters::BoxedFilter, multipart::FormData, Filter, Rejection, Reply};
 
 #[derive(Debug, Deserialize)]
 struct DeleteQuery {
     id: String,
 }
 
-pub fn routes(pool: MySqlPool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+#[derive(Debug, Deserialize)]
+struct UploadQuery {
+    /// What to do with a file whose content is already stored: `analyze` it
+    /// again (default), `skip` it, or `link` it to the existing analysis
+    duplicates: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JobsQuery {
+    status: Option<String>,
+    job_type: Option<String>,
+    subject_id: Option<String>,
+    limit: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PurgeJobsQuery {
+    /// `Completed` or `DeadLettered`
+    status: String,
+    older_than_hours: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentQuery {
+    /// "1"/"true": `Content-Disposition: attachment` instead of inline
+    download: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamQuery {
+    id: String,
+    /// "1"/"true": cancel the query if the client disconnects before it finishes
+    cancel_on_disconnect: Option<String>,
+}
+
+/// `GET /api/v1/queries/{id}/stream` options; the id is in the path.
+#[derive(Debug, Deserialize)]
+struct StreamOptions {
+    cancel_on_disconnect: Option<String>,
+}
+
+/// Body of `PATCH /api/v1/files/{id}`; absent fields are left unchanged.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FilePatch {
+    filename: Option<String>,
+    tags: Option<Vec<String>>,
+}
+
+/// `LEGACY_API_ROUTES`: also serve the unversioned routes under /api, such as
+/// `GET /api/files/delete?id=` (default false). Each use is logged as deprecated.
+fn legacy_routes_enabled() -> bool {
+    parse_legacy_routes(std::env::var("LEGACY_API_ROUTES").ok().as_deref())
+}
+
+fn parse_legacy_routes(value: Option<&str>) -> bool {
+    value.is_some_and(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+}
+
+pub fn routes(
+    pool: MySqlPool,
+    store: Arc<dyn VectorStore>,
+    keywords: Arc<KeywordIndex>,
+    events: Arc<QueryEvents>,
+    blob_store: Arc<dyn BlobStore>,
+) -> BoxedFilter<(warp::reply::Response,)> {
     let pool_filter = warp::any().map(move || pool.clone());
+    let store_filter = warp::any().map(move || store.clone());
+    let keywords_filter = warp::any().map(move || keywords.clone());
+    let events_filter = warp::any().map(move || events.clone());
+    let blob_store_filter = warp::any().map(move || blob_store.clone());
 
     // Import demo files from demo-data directory
     let import_demo = warp::path!("files" / "import-demo")
         .and(warp::post())
         .and(
             warp::query::<std::collections::HashMap<String, String>>()
-                .or(warp::any().map(|| std::collections::HashMap::new()))
+                .or(warp::any().map(std::collections::HashMap::new))
                 .unify()
         )
         .and(pool_filter.clone())
+        .and(store_filter.clone())
+        .and(keywords_filter.clone())
+        .and(blob_store_filter.clone())
         .and_then(handle_import_demo);
 
-    // Upload file
+    // Upload file (unversioned route; /api/v1 has `files_create` below)
     let upload = warp::path("files")
         .and(warp::post())
-        .and(warp::multipart::form().max_length(50_000_000)) // 50MB per part default; storage is filesystem-backed
+        .and(warp::query::<UploadQuery>())
+        // No limit on the whole form (it would also refuse chunked bodies); each
+        // file is held to MAX_UPLOAD_BYTES while it streams to disk
+        .and(warp::multipart::form().max_length(None))
         .and(pool_filter.clone())
+        .and(blob_store_filter.clone())
         .and_then(handle_upload);
 
-    // Delete file
+    // Stored document (inline or as a download) and the text of one page
+    let content = warp::path!("files" / String / "content")
+        .and(warp::get())
+        .and(warp::query::<ContentQuery>())
+        .and(warp::header::headers_cloned())
+        .and(pool_filter.clone())
+        .and(blob_store_filter.clone())
+        .and_then(handle_file_content);
+    let page_text = warp::path!("files" / String / "pages" / u32 / "text")
+        .and(warp::get())
+        .and(pool_filter.clone())
+        .and_then(handle_page_text);
+
+    // Analyze a file again, or every file whose analysis gave up
+    let reanalyze = warp::path!("files" / String / "reanalyze")
+        .and(warp::post())
+        .and(pool_filter.clone())
+        .and_then(handle_reanalyze);
+    let retry_failed = warp::path!("files" / "retry-failed")
+        .and(warp::post())
+        .and(pool_filter.clone())
+        .and_then(handle_retry_failed);
+
+    // Job queue admin: list, retry, purge finished jobs
+    let jobs_list = warp::path!("jobs")
+        .and(warp::get())
+        .and(warp::query::<JobsQuery>())
+        .and(pool_filter.clone())
+        .and_then(handle_list_jobs);
+    let jobs_retry = warp::path!("jobs" / String / "retry")
+        .and(warp::post())
+        .and(pool_filter.clone())
+        .and_then(handle_retry_job);
+    let jobs_purge = warp::path!("jobs")
+        .and(warp::delete())
+        .and(warp::query::<PurgeJobsQuery>())
+        .and(pool_filter.clone())
+        .and_then(handle_purge_jobs);
+
+    // Conversational sessions: create, list, get with turns, delete
+    let session_create = warp::path!("sessions")
+        .and(warp::post())
+        .and(warp::body::bytes())
+        .and(pool_filter.clone())
+        .and_then(handle_create_session);
+    let session_list = warp::path!("sessions")
+        .and(warp::get())
+        .and(pool_filter.clone())
+        .and_then(handle_list_sessions);
+    let session_get = warp::path!("sessions" / String)
+        .and(warp::get())
+        .and(pool_filter.clone())
+        .and_then(handle_get_session);
+    let session_delete = warp::path!("sessions" / String)
+        .and(warp::delete())
+        .and(pool_filter.clone())
+        .and_then(handle_delete_session);
+
+    // Vector index stats
+    let index_stats = warp::path!("index" / "stats")
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and(keywords_filter.clone())
+        .and_then(handle_index_stats);
+
+    // Routes that are the same in both API versions
+    let shared = content
+        .or(page_text)
+        .or(reanalyze)
+        .or(retry_failed)
+        .or(import_demo)
+        .or(session_create)
+        .or(session_list)
+        .or(session_get)
+        .or(session_delete)
+        .or(jobs_list)
+        .or(jobs_retry)
+        .or(jobs_purge)
+        .or(index_stats);
+
+    // /api/v1: files and queries as resources, changed only by POST, PATCH and DELETE
+    let files_list = warp::path!("files")
+        .and(warp::get())
+        .and(pool_filter.clone())
+        .and_then(handle_list);
+    let files_create = warp::path!("files")
+        .and(warp::post())
+        .and(warp::query::<UploadQuery>())
+        .and(warp::multipart::form().max_length(None))
+        .and(pool_filter.clone())
+        .and(blob_store_filter.clone())
+        .and_then(handle_upload);
+    let file_get = warp::path!("files" / String)
+        .and(warp::get())
+        .and(pool_filter.clone())
+        .and_then(handle_get_file);
+    let file_update = warp::path!("files" / String)
+        .and(warp::patch())
+        .and(warp::body::json::<FilePatch>())
+        .and(pool_filter.clone())
+        .and(store_filter.clone())
+        .and(keywords_filter.clone())
+        .and_then(handle_update_file);
+    let file_delete = warp::path!("files" / String)
+        .and(warp::delete())
+        .and(pool_filter.clone())
+        .and(store_filter.clone())
+        .and(keywords_filter.clone())
+        .and(blob_store_filter.clone())
+        .and_then(handle_delete_file);
+    let queries_create = warp::path!("queries")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(pool_filter.clone())
+        .and_then(handle_create_query);
+    let query_get = warp::path!("queries" / String)
+        .and(warp::get())
+        .and(pool_filter.clone())
+        .and_then(handle_get_query);
+    let query_stream = warp::path!("queries" / String / "stream")
+        .and(warp::get())
+        .and(warp::query::<StreamOptions>())
+        .map(|id, o: StreamOptions| StreamQuery { id, cancel_on_disconnect: o.cancel_on_disconnect })
+        .and(warp::sse::last_event_id::<usize>())
+        .and(pool_filter.clone())
+        .and(events_filter.clone())
+        .and_then(handle_query_stream);
+    let query_cancel = warp::path!("queries" / String / "cancel")
+        .and(warp::post())
+        .and(pool_filter.clone())
+        .and(events_filter.clone())
+        .and_then(handle_cancel);
+
+    let v1 = shared
+        .clone()
+        .or(files_list)
+        .or(files_create)
+        .or(file_get)
+        .or(file_update)
+        .or(file_delete)
+        .or(queries_create)
+        .or(query_get)
+        .or(query_stream)
+        .or(query_cancel)
+        .map(Reply::into_response)
+        .boxed();
+
+    // Unversioned routes from before /api/v1, including the GETs that delete
+    // and cancel; kept for existing clients while LEGACY_API_ROUTES is on
     let delete = warp::path!("files" / "delete")
         .and(warp::get())
         .and(warp::query::<DeleteQuery>())
         .and(pool_filter.clone())
+        .and(store_filter.clone())
+        .and(keywords_filter.clone())
+        .and(blob_store_filter.clone())
         .and_then(handle_delete);
-
-    // List files
     let list = warp::path!("files" / "list")
         .and(warp::get())
         .and(pool_filter.clone())
         .and_then(handle_list);
-
-    // Create query
     let create_q = warp::path!("query" / "create")
         .and(warp::post())
         .and(warp::body::json())
         .and(pool_filter.clone())
         .and_then(handle_create_query);
-
-    // Query status
     let status = warp::path!("query" / "status")
         .and(warp::get())
         .and(warp::query::<DeleteQuery>())
         .and(pool_filter.clone())
         .and_then(handle_query_status);
-
-    // Query result
     let result = warp::path!("query" / "result")
         .and(warp::get())
         .and(warp::query::<DeleteQuery>())
         .and(pool_filter.clone())
         .and_then(handle_query_result);
-
-    // Cancel
+    // Stream progress and answer tokens (Server-Sent Events)
+    let stream = warp::path!("query" / "stream")
+        .and(warp::get())
+        .and(warp::query::<StreamQuery>())
+        .and(warp::sse::last_event_id::<usize>())
+        .and(pool_filter.clone())
+        .and(events_filter.clone())
+        .and_then(handle_query_stream);
     let cancel = warp::path!("query" / "cancel")
         .and(warp::get())
         .and(warp::query::<DeleteQuery>())
         .and(pool_filter.clone())
+        .and(events_filter.clone())
         .and_then(handle_cancel_query);
 
-    let api = upload.or(import_demo).or(delete).or(list).or(create_q).or(status).or(result).or(cancel);
-    warp::path("api").and(api)
+    let legacy_enabled = legacy_routes_enabled();
+    if legacy_enabled {
+        tracing::info!("Serving deprecated unversioned /api routes (LEGACY_API_ROUTES)");
+    }
+    let legacy = warp::any()
+        .and_then(move || async move {
+            if legacy_enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+        .and(warp::path::full())
+        .and(
+            shared
+                .or(upload)
+                .or(delete)
+                .or(list)
+                .or(create_q)
+                .or(status)
+                .or(result)
+                .or(stream)
+                .or(cancel),
+        )
+        .map(|path: warp::path::FullPath, reply| {
+            tracing::warn!("Deprecated route {} called; use the /api/v1 equivalent", path.as_str());
+            warp::reply::with_header(reply, "deprecation", "true").into_response()
+        })
+        .boxed();
+
+    warp::path("api")
+        .and(warp::path("v1").and(v1).or(legacy).unify())
+        .recover(handle_rejection)
+        .unify()
+        .boxed()
 }
 
-async fn handle_upload(mut form: FormData, pool: MySqlPool) -> Result<impl Reply, Rejection> {
-    let mut created_files = Vec::new();
-    while let Some(field) = form.try_next().await.map_err(|_| warp::reject())? {
-    let _name = field.name().to_string();
-        let filename = field
-            .filename()
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| format!("upload-{}", uuid::Uuid::new_v4()));
-
-        // Read stream of Buf into a Vec<u8>
-        let data = field
-            .stream()
-            .map_ok(|mut buf| {
-                let mut v = Vec::new();
-                while buf.has_remaining() {
-                    let chunk = buf.chunk();
-                    v.extend_from_slice(chunk);
-                    let n = chunk.len();
-                    buf.advance(n);
+/// A request the client got wrong; answered with 400 and `{"error": ...}`.
+#[derive(Debug)]
+struct BadRequest(String);
+
+impl warp::reject::Reject for BadRequest {}
+
+fn bad_request(message: impl Into<String>) -> Rejection {
+    warp::reject::custom(BadRequest(message.into()))
+}
+
+/// Reply to `BadRequest` rejections with a JSON body, like `bad_filename`;
+/// anything else keeps warp's own handling.
+async fn handle_rejection(err: Rejection) -> Result<warp::reply::Response, Rejection> {
+    match err.find::<BadRequest>() {
+        Some(BadRequest(message)) => {
+            let body = serde_json::json!({ "error": message });
+            Ok(warp::reply::with_status(warp::reply::json(&body), warp::http::StatusCode::BAD_REQUEST).into_response())
+        }
+        None => Err(err),
+    }
+}
+
+async fn handle_upload(query: UploadQuery, mut form: FormData, pool: MySqlPool, blob_store: Arc<dyn BlobStore>) -> Result<warp::reply::Response, Rejection> {
+    let duplicates = query.duplicates.as_deref().unwrap_or("analyze");
+    if !matches!(duplicates, "analyze" | "skip" | "link") {
+        return Err(bad_request(format!("duplicates must be analyze, skip or link, not '{duplicates}'")));
+    }
+    // Files are recorded after the whole form is read so that a `tags` field
+    // applies to every file regardless of its position in the form
+    let limit = storage::max_upload_bytes();
+    let mut staged: Vec<(String, storage::StagedFile, String)> = Vec::new();
+    let mut tags: Vec<String> = Vec::new();
+    while let Some(field) = form.try_next().await.map_err(|e| bad_request(format!("invalid multipart form: {e}")))? {
+        if field.name() == "tags" {
+            let Some(data) = read_small_field(field).await? else {
+                return Ok(too_large("tags", TAGS_FIELD_LIMIT));
+            };
+            tags.extend(parse_tags(&String::from_utf8_lossy(&data)));
+            continue;
+        }
+
+        // Checked before any bytes are read; the name is only kept as metadata
+        let filename = match field.filename() {
+            Some(raw) => match storage::display_name(raw) {
+                Ok(name) => name,
+                Err(e) => return Ok(bad_filename(raw, &e)),
+            },
+            None => format!("upload-{}", uuid::Uuid::new_v4()),
+        };
+        let content_type = field.content_type().map(|s| s.to_string());
+
+        // Stream the part to a temp file; it is removed if we bail out or the client goes away
+        let mut upload = storage::Upload::create(Some(limit)).await.map_err(|e| {
+            tracing::error!("Failed to start upload: {}", e);
+            warp::reject()
+        })?;
+        let stream = field.stream();
+        futures_util::pin_mut!(stream);
+        while let Some(mut buf) = stream.try_next().await.map_err(|e| bad_request(format!("invalid multipart form: {e}")))? {
+            while buf.has_remaining() {
+                let chunk = buf.chunk();
+                let n = chunk.len();
+                if let Err(e) = upload.write(chunk).await {
+                    if e.is::<storage::TooLarge>() {
+                        return Ok(too_large(&filename, limit));
+                    }
+                    tracing::error!("Upload write error: {}", e);
+                    return Err(warp::reject());
                 }
-                v
-            })
-            .try_fold(Vec::new(), |mut acc, chunk_vec| async move {
-                acc.extend_from_slice(&chunk_vec);
-                Ok(acc)
-            })
-            .await
-            .map_err(|_| warp::reject())?;
-
-        // Save file
-        let path = storage::save_file(&filename, &data).map_err(|_| warp::reject())?;
-
-        // Insert file record with pending_analysis = true, description = NULL
-        let id = uuid::Uuid::new_v4().to_string();
-        sqlx::query("INSERT INTO files (id, filename, path, description, pending_analysis, analysis_status) VALUES (?, ?, ?, ?, ?, 'Queued')")
-            .bind(&id)
-            .bind(&filename)
-            .bind(path.to_str().unwrap())
-            .bind(Option::<String>::None)
-            .bind(true)
-            .execute(&pool)
-            .await
-            .map_err(|e| {
-                tracing::error!("DB insert error: {}", e);
+                buf.advance(n);
+            }
+        }
+        let file = upload.finish().await.map_err(|e| {
+            tracing::error!("Failed to store upload {}: {}", filename, e);
+            warp::reject()
+        })?;
+        let mime_type = content_type
+            .filter(|c| !c.is_empty() && c != "application/octet-stream")
+            .map(|c| c.split(';').next().unwrap_or("").trim().to_lowercase())
+            .unwrap_or_else(|| storage::guess_mime(&filename).to_string());
+        staged.push((filename, file, mime_type));
+    }
+
+    let mut created_files = Vec::new();
+    let mut skipped_files = Vec::new();
+    for (filename, file, mime_type) in staged {
+        let duplicate_of = if duplicates == "analyze" {
+            None
+        } else {
+            let mut conn = pool.acquire().await.map_err(|_| warp::reject())?;
+            blobs::find_duplicate(&mut conn, &file.sha256).await.map_err(|e| {
+                tracing::error!("Duplicate lookup failed for {}: {}", filename, e);
                 warp::reject()
-            })?;
+            })?
+        };
+        if let (Some((existing_id, existing_name)), "skip") = (&duplicate_of, duplicates) {
+            skipped_files.push(serde_json::json!({
+                "filename": filename,
+                "duplicate_of": existing_id,
+                "duplicate_filename": existing_name,
+                "content_hash": file.sha256
+            }));
+            continue;
+        }
+        // A linked file copies the analysis of its duplicate instead of being analyzed again
+        let copy_from = duplicate_of.as_ref().map(|(existing_id, _)| existing_id.as_str());
+        let id = record_file(&pool, blob_store.as_ref(), &file, &filename, &mime_type, &tags, copy_from).await.map_err(|e| {
+            tracing::error!("Failed to record upload {}: {}", filename, e);
+            warp::reject()
+        })?;
         created_files.push(serde_json::json!({
             "id": id,
             "filename": filename,
+            "mime_type": mime_type,
+            "tags": tags,
+            "size_bytes": file.size,
+            "content_hash": file.sha256,
+            "duplicate_of": copy_from,
             "pending_analysis": true,
             "analysis_status": "Queued"
         }));
@@ -134,14 +475,139 @@ async fn handle_upload(mut form: FormData, pool: MySqlPool) -> Result<impl Reply
 
     Ok(warp::reply::json(&serde_json::json!({
         "uploaded": created_files.len(),
-        "files": created_files
-    })))
+        "files": created_files,
+        "skipped": skipped_files
+    }))
+    .into_response())
+}
+
+/// Insert a `files` row for a staged file, taking a reference to its blob, and
+/// queue its analysis (a copy of `copy_from`'s when given), all in one
+/// transaction. Returns the new file id.
+async fn record_file(
+    pool: &MySqlPool,
+    blob_store: &dyn BlobStore,
+    file: &storage::StagedFile,
+    filename: &str,
+    mime_type: &str,
+    tags: &[String],
+    copy_from: Option<&str>,
+) -> Result<String> {
+    let id = uuid::Uuid::new_v4().to_string();
+    let mut tx = pool.begin().await?;
+    let key = blobs::add_ref(&mut tx, blob_store, file).await?;
+    // Insert file record with pending_analysis = true, description = NULL
+    sqlx::query("INSERT INTO files (id, filename, path, description, pending_analysis, analysis_status, mime_type, tags, size_bytes, content_hash) VALUES (?, ?, ?, ?, ?, 'Queued', ?, ?, ?, ?)")
+        .bind(&id)
+        .bind(filename)
+        .bind(&key)
+        .bind(Option::<String>::None)
+        .bind(true)
+        .bind(mime_type)
+        .bind(serde_json::json!(tags))
+        .bind(file.size)
+        .bind(&file.sha256)
+        .execute(&mut *tx)
+        .await?;
+    let payload = match copy_from {
+        Some(source) => serde_json::json!({ "copy_from": source }),
+        None => serde_json::json!({}),
+    };
+    jobs::enqueue(&mut *tx, file_worker::ANALYZE_FILE, Some(&id), payload, 0).await?;
+    tx.commit().await?;
+    Ok(id)
+}
+
+/// Delete a `files` row and drop its reference to the stored bytes, removing
+/// them once the delete committed if nothing else uses them. Returns false
+/// when there was no such file.
+async fn delete_file_record(pool: &MySqlPool, blob_store: &dyn BlobStore, file_id: &str) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let Some(row) = sqlx::query("SELECT path, content_hash FROM files WHERE id = ? FOR UPDATE")
+        .bind(file_id)
+        .fetch_optional(&mut *tx)
+        .await?
+    else {
+        return Ok(false);
+    };
+    let path: String = row.get("path");
+    let content_hash: Option<String> = row.get("content_hash");
+    sqlx::query("DELETE FROM files WHERE id = ?").bind(file_id).execute(&mut *tx).await?;
+    let unreferenced = blobs::release(&mut tx, content_hash.as_deref(), &path).await?;
+    tx.commit().await?;
+    if let Some(unreferenced) = unreferenced {
+        // The record is gone either way; bytes that stay behind only take space
+        if let Err(e) = blobs::remove(pool, blob_store, unreferenced).await {
+            tracing::warn!("Failed to remove the stored bytes of deleted file {}: {}", file_id, e);
+        }
+    }
+    Ok(true)
 }
 
-async fn handle_import_demo(params: std::collections::HashMap<String, String>, pool: MySqlPool) -> Result<impl Reply, Rejection> {
+/// Upper bound on a non-file form field such as `tags`.
+const TAGS_FIELD_LIMIT: u64 = 64 * 1024;
+
+/// A small form field read into memory; `None` when it exceeds `TAGS_FIELD_LIMIT`.
+async fn read_small_field(field: warp::multipart::Part) -> Result<Option<Vec<u8>>, Rejection> {
+    let stream = field.stream();
+    futures_util::pin_mut!(stream);
+    let mut data = Vec::new();
+    while let Some(mut buf) = stream.try_next().await.map_err(|e| bad_request(format!("invalid multipart form: {e}")))? {
+        while buf.has_remaining() {
+            let chunk = buf.chunk();
+            if data.len() + chunk.len() > TAGS_FIELD_LIMIT as usize {
+                return Ok(None);
+            }
+            data.extend_from_slice(chunk);
+            let n = chunk.len();
+            buf.advance(n);
+        }
+    }
+    Ok(Some(data))
+}
+
+fn too_large(what: &str, limit: u64) -> warp::reply::Response {
+    let body = serde_json::json!({
+        "error": format!("{what} exceeds the upload limit of {limit} bytes"),
+        "max_bytes": limit
+    });
+    warp::reply::with_status(warp::reply::json(&body), warp::http::StatusCode::PAYLOAD_TOO_LARGE).into_response()
+}
+
+fn bad_filename(raw: &str, err: &storage::BadFilename) -> warp::reply::Response {
+    let body = serde_json::json!({
+        "error": err.to_string(),
+        "filename": raw
+    });
+    warp::reply::with_status(warp::reply::json(&body), warp::http::StatusCode::BAD_REQUEST).into_response()
+}
+
+/// Comma-separated tags, trimmed, lowercased and deduplicated.
+fn parse_tags(raw: &str) -> Vec<String> {
+    normalize_tags(raw.split(','))
+}
+
+fn normalize_tags<'a>(raw: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for t in raw.map(|t| t.trim().to_lowercase()).filter(|t| !t.is_empty()) {
+        if !tags.contains(&t) {
+            tags.push(t);
+        }
+    }
+    tags
+}
+
+async fn handle_import_demo(
+    params: std::collections::HashMap<String, String>,
+    pool: MySqlPool,
+    store: Arc<dyn VectorStore>,
+    keywords: Arc<KeywordIndex>,
+    blob_store: Arc<dyn BlobStore>,
+) -> Result<impl Reply, Rejection> {
     use std::fs;
     use std::path::PathBuf;
     let force = params.get("force").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false);
+    let demo_tags = params.get("tags").map(|t| parse_tags(t)).unwrap_or_else(|| vec!["demo".to_string()]);
     let demo_dir_setting = std::env::var("DEMO_DATA_DIR").unwrap_or_else(|_| "demo-data".to_string());
     let base = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
 
@@ -197,42 +663,55 @@ async fn handle_import_demo(params: std::collections::HashMap<String, String>, p
         let entry = entry.map_err(|_| warp::reject())?;
         let path = entry.path();
         if path.extension().and_then(|e| e.to_str()).map(|e| e.eq_ignore_ascii_case("pdf")).unwrap_or(false) {
-            let filename = path.file_name().and_then(|n| n.to_str()).unwrap_or("unknown.pdf").to_string();
+            let raw_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("unknown.pdf");
+            let filename = match storage::display_name(raw_name) {
+                Ok(name) => name,
+                Err(e) => {
+                    tracing::warn!("Skipping demo file {}: {}", path.display(), e);
+                    skipped += 1;
+                    continue;
+                }
+            };
 
             // check if exists
-            if !force {
-                if let Some(_) = sqlx::query("SELECT id FROM files WHERE filename = ?")
+            if !force
+                && sqlx::query("SELECT id FROM files WHERE filename = ?")
                     .bind(&filename)
                     .fetch_optional(&pool)
                     .await
-                    .map_err(|_| warp::reject())? {
-                    skipped += 1;
-                    continue;
-                }
+                    .map_err(|_| warp::reject())?
+                    .is_some()
+            {
+                skipped += 1;
+                continue;
             }
 
-            // read and save to storage
-            let data = fs::read(&path).map_err(|_| warp::reject())?;
-            let stored_path = storage::save_file(&filename, &data).map_err(|_| warp::reject())?;
+            // stream into storage
+            let file = storage::import_file(&path).await.map_err(|e| {
+                tracing::error!("Failed to import {}: {}", path.display(), e);
+                warp::reject()
+            })?;
 
-            // insert or upsert db record
-            let id = uuid::Uuid::new_v4().to_string();
+            // replace earlier imports of the same file
             if force {
-                let _ = sqlx::query("DELETE FROM files WHERE filename = ?")
+                let existing = sqlx::query("SELECT id FROM files WHERE filename = ?")
                     .bind(&filename)
-                    .execute(&pool)
-                    .await;
+                    .fetch_all(&pool)
+                    .await
+                    .map_err(|_| warp::reject())?;
+                for row in existing {
+                    let old_id: String = row.get("id");
+                    // The row goes first: an analysis still running sees that and purges what it writes later
+                    if let Err(e) = delete_file_record(&pool, blob_store.as_ref(), &old_id).await {
+                        tracing::error!("Failed to delete file {}: {}", old_id, e);
+                    }
+                    file_worker::purge_file_index(&pool, store.as_ref(), &keywords, &old_id).await;
+                }
             }
-            sqlx::query("INSERT INTO files (id, filename, path, description, pending_analysis, analysis_status) VALUES (?, ?, ?, ?, ?, 'Queued')")
-                .bind(&id)
-                .bind(&filename)
-                .bind(stored_path.to_str().unwrap())
-                .bind(Option::<String>::None)
-                .bind(true)
-                .execute(&pool)
+            record_file(&pool, blob_store.as_ref(), &file, &filename, storage::guess_mime(&filename), &demo_tags, None)
                 .await
                 .map_err(|e| {
-                    tracing::error!("DB insert error: {}", e);
+                    tracing::error!("Failed to record import {}: {}", filename, e);
                     warp::reject()
                 })?;
             imported += 1;
@@ -241,72 +720,676 @@ async fn handle_import_demo(params: std::collections::HashMap<String, String>, p
     Ok(warp::reply::json(&serde_json::json!({ "imported": imported, "skipped": skipped })))
 }
 
-async fn handle_delete(q: DeleteQuery, pool: MySqlPool) -> Result<impl Reply, Rejection> {
-    if let Some(row) = sqlx::query("SELECT path FROM files WHERE id = ?")
-        .bind(&q.id)
-        .fetch_optional(&pool)
+async fn handle_delete(
+    q: DeleteQuery,
+    pool: MySqlPool,
+    store: Arc<dyn VectorStore>,
+    keywords: Arc<KeywordIndex>,
+    blob_store: Arc<dyn BlobStore>,
+) -> Result<impl Reply, Rejection> {
+    let deleted = delete_file(&q.id, &pool, &store, &keywords, blob_store.as_ref()).await?;
+    Ok(warp::reply::json(&serde_json::json!({"deleted": deleted})))
+}
+
+async fn handle_delete_file(
+    id: String,
+    pool: MySqlPool,
+    store: Arc<dyn VectorStore>,
+    keywords: Arc<KeywordIndex>,
+    blob_store: Arc<dyn BlobStore>,
+) -> Result<impl Reply, Rejection> {
+    if !delete_file(&id, &pool, &store, &keywords, blob_store.as_ref()).await? {
+        return Err(warp::reject::not_found());
+    }
+    Ok(warp::reply::json(&serde_json::json!({"deleted": true})))
+}
+
+/// Remove a file from the indexes and the database. False when there was no such file.
+async fn delete_file(
+    id: &str,
+    pool: &MySqlPool,
+    store: &Arc<dyn VectorStore>,
+    keywords: &KeywordIndex,
+    blob_store: &dyn BlobStore,
+) -> Result<bool, Rejection> {
+    let exists = sqlx::query("SELECT id FROM files WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
         .await
         .map_err(|_| warp::reject())?
-    {
-        let path: String = row.get("path");
-        let _ = storage::delete_file(std::path::Path::new(&path));
-        // Remove from Qdrant
-        let qdrant_url = std::env::var("QDRANT_URL").unwrap_or_else(|_| "http://qdrant:6333".to_string());
-        let qdrant = QdrantClient::new(&qdrant_url);
-        let _ = qdrant.delete_point(&q.id).await;
-        let _ = sqlx::query("DELETE FROM files WHERE id = ?").bind(&q.id).execute(&pool).await;
-        return Ok(warp::reply::json(&serde_json::json!({"deleted": true})));
+        .is_some();
+    if !exists {
+        return Ok(false);
     }
-    Ok(warp::reply::json(&serde_json::json!({"deleted": false})))
+    // The stored bytes go with the last file that references them. The row
+    // goes before its index, so an analysis still running either finds it
+    // gone before writing or purges what it wrote itself (see `FileWorker::finish`)
+    let deleted = delete_file_record(pool, blob_store, id).await.map_err(|e| {
+        tracing::error!("Failed to delete file {}: {}", id, e);
+        warp::reject()
+    })?;
+    file_worker::purge_file_index(pool, store.as_ref(), keywords, id).await;
+    Ok(deleted)
+}
+
+/// Columns read for a file's JSON representation.
+const FILE_COLUMNS: &str = "id, filename, description, pending_analysis, analysis_status, analysis_error, analysis_attempts, mime_type, tags, size_bytes, content_hash, created_at";
+
+fn file_json(r: &sqlx::mysql::MySqlRow) -> serde_json::Value {
+    let id: String = r.get("id");
+    let filename: String = r.get("filename");
+    let description: Option<String> = r.get("description");
+    let pending: bool = r.get("pending_analysis");
+    let status: Option<String> = r.try_get("analysis_status").ok();
+    let analysis_error: Option<String> = r.get("analysis_error");
+    let analysis_attempts: i32 = r.get("analysis_attempts");
+    let mime_type: Option<String> = r.get("mime_type");
+    let tags: Option<serde_json::Value> = r.get("tags");
+    let size_bytes: Option<i64> = r.get("size_bytes");
+    let content_hash: Option<String> = r.get("content_hash");
+    let created_at: Option<chrono::NaiveDateTime> = r.get("created_at");
+    serde_json::json!({
+        "id": id,
+        "filename": filename,
+        "description": description,
+        "pending_analysis": pending,
+        "analysis_status": status,
+        "analysis_error": analysis_error,
+        "analysis_attempts": analysis_attempts,
+        "mime_type": mime_type,
+        "tags": tags.unwrap_or_else(|| serde_json::json!([])),
+        "size_bytes": size_bytes,
+        "content_hash": content_hash,
+        "created_at": created_at.map(|t| t.and_utc().to_rfc3339())
+    })
+}
+
+async fn fetch_file(pool: &MySqlPool, id: &str) -> Result<serde_json::Value, Rejection> {
+    let row = sqlx::query(&format!("SELECT {FILE_COLUMNS} FROM files WHERE id = ?"))
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("DB file lookup error: {}", e);
+            warp::reject()
+        })?
+        .ok_or_else(warp::reject::not_found)?;
+    Ok(file_json(&row))
 }
 
 async fn handle_list(pool: MySqlPool) -> Result<impl Reply, Rejection> {
-    let rows = sqlx::query("SELECT id, filename, path, description, pending_analysis, analysis_status FROM files ORDER BY created_at DESC LIMIT 500")
+    let rows = sqlx::query(&format!("SELECT {FILE_COLUMNS} FROM files ORDER BY created_at DESC LIMIT 500"))
         .fetch_all(&pool)
         .await
         .map_err(|e| {
             tracing::error!("DB list error: {}", e);
             warp::reject()
         })?;
+    let files: Vec<serde_json::Value> = rows.iter().map(file_json).collect();
+    Ok(warp::reply::json(&serde_json::json!({"files": files})))
+}
 
-    let files: Vec<serde_json::Value> = rows
-        .into_iter()
-        .map(|r| {
-            let id: String = r.get("id");
-            let filename: String = r.get("filename");
-            let path: String = r.get("path");
-            let description: Option<String> = r.get("description");
-            let pending: bool = r.get("pending_analysis");
-            let status: Option<String> = r.try_get("analysis_status").ok();
-            serde_json::json!({
-                "id": id,
-                "filename": filename,
-                "path": path,
-                "description": description,
-                "pending_analysis": pending,
-                "analysis_status": status
-            })
-        })
+async fn handle_get_file(id: String, pool: MySqlPool) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&fetch_file(&pool, &id).await?))
+}
+
+async fn handle_update_file(
+    id: String,
+    patch: FilePatch,
+    pool: MySqlPool,
+    store: Arc<dyn VectorStore>,
+    keywords: Arc<KeywordIndex>,
+) -> Result<warp::reply::Response, Rejection> {
+    let filename = match patch.filename.as_deref().map(storage::display_name) {
+        Some(Err(e)) => return Ok(bad_filename(patch.filename.as_deref().unwrap_or_default(), &e)),
+        Some(Ok(name)) => Some(name),
+        None => None,
+    };
+    let tags = patch.tags.map(|t| normalize_tags(t.iter().map(String::as_str)));
+    let updated = sqlx::query("UPDATE files SET filename = COALESCE(?, filename), tags = COALESCE(?, tags) WHERE id = ?")
+        .bind(&filename)
+        .bind(tags.as_ref().map(|t| serde_json::json!(t)))
+        .bind(&id)
+        .execute(&pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("DB update file error: {}", e);
+            warp::reject()
+        })?;
+    if updated.rows_affected() == 0 {
+        return Err(warp::reject::not_found());
+    }
+    // Tags are copied onto every indexed point for filtering; names are read at query time
+    if let Some(tags) = tags {
+        if let Err(e) = retag_file_index(&store, &keywords, &id, serde_json::json!(tags)).await {
+            tracing::error!("Failed to update index tags of file {}: {}", id, e);
+            return Err(warp::reject());
+        }
+    }
+    Ok(warp::reply::json(&fetch_file(&pool, &id).await?).into_response())
+}
+
+/// Replace the `tags` payload of a file's vector points and keyword entries.
+async fn retag_file_index(store: &Arc<dyn VectorStore>, keywords: &KeywordIndex, file_id: &str, tags: serde_json::Value) -> Result<()> {
+    let filter = VectorFilter::file(file_id);
+    let mut offset = None;
+    loop {
+        let (mut points, next) = store.scroll(&filter, offset, file_worker::UPSERT_BATCH).await?;
+        for p in &mut points {
+            p.payload["tags"] = tags.clone();
+        }
+        if !points.is_empty() {
+            store.upsert(points).await?;
+        }
+        match next {
+            Some(n) => offset = Some(n),
+            None => break,
+        }
+    }
+    keywords.set_file_tags(file_id, tags).await
+}
+
+async fn handle_file_content(
+    id: String,
+    q: ContentQuery,
+    headers: warp::http::HeaderMap,
+    pool: MySqlPool,
+    blob_store: Arc<dyn BlobStore>,
+) -> Result<warp::reply::Response, Rejection> {
+    use warp::http::{header, HeaderValue, StatusCode};
+    let row = sqlx::query("SELECT filename, path, content_hash, mime_type, size_bytes FROM files WHERE id = ?")
+        .bind(&id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("DB file lookup error: {}", e);
+            warp::reject()
+        })?
+        .ok_or_else(warp::reject::not_found)?;
+    let filename: String = row.get("filename");
+    let content_hash: Option<String> = row.get("content_hash");
+    let key = storage::object_key(content_hash.as_deref(), row.get("path")).map_err(|e| {
+        tracing::warn!("Cannot serve file {}: {}", id, e);
+        warp::reject::not_found()
+    })?;
+    let size = match row.get::<Option<i64>, _>("size_bytes") {
+        Some(n) => n.max(0) as u64,
+        None => {
+            let stat = blob_store.stat(&key).await.map_err(|e| {
+                tracing::error!("Blob stat failed for {}: {}", key, e);
+                warp::reject()
+            })?;
+            stat.ok_or_else(warp::reject::not_found)?.size
+        }
+    };
+    let mime_type: Option<String> = row.get("mime_type");
+    let mime_type = mime_type.unwrap_or_else(|| storage::guess_mime(&filename).to_string());
+    // The content hash identifies the bytes exactly, so it is a strong validator
+    let etag = content_hash.map(|h| format!("\"{h}\""));
+    let header_str = |name: header::HeaderName| headers.get(name).and_then(|v| v.to_str().ok());
+
+    let mut resp = warp::reply::Response::default();
+    let out = resp.headers_mut();
+    if let Some(tag) = etag.as_deref().and_then(|t| HeaderValue::from_str(t).ok()) {
+        out.insert(header::ETAG, tag);
+    }
+    out.insert(header::CACHE_CONTROL, HeaderValue::from_static("private, no-cache"));
+    if let (Some(tag), Some(wanted)) = (&etag, header_str(header::IF_NONE_MATCH)) {
+        if etag_matches(wanted, tag) {
+            *resp.status_mut() = StatusCode::NOT_MODIFIED;
+            return Ok(resp);
+        }
+    }
+
+    // A range only applies to the version named by If-Range, if one is given
+    let if_range_ok = header_str(header::IF_RANGE).map(|v| etag.as_deref() == Some(v.trim())).unwrap_or(true);
+    let range = match header_str(header::RANGE).filter(|_| if_range_ok) {
+        Some(spec) => parse_range(spec, size),
+        None => ByteRange::Full,
+    };
+    let (start, end) = match range {
+        ByteRange::Full => (0, size),
+        ByteRange::Partial(start, end) => (start, end + 1),
+        ByteRange::Unsatisfiable => {
+            *resp.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            resp.headers_mut().insert(header::CONTENT_RANGE, HeaderValue::from_str(&format!("bytes */{size}")).unwrap());
+            return Ok(resp);
+        }
+    };
+    // Sent as it is read (see `http_server`), so a large file never sits in memory
+    let stream = if let ByteRange::Full = range {
+        blob_store.get(&key).await
+    } else {
+        blob_store.get_range(&key, start, end - start).await
+    }
+    .map_err(|e| {
+        tracing::error!("Failed to read {} for file {}: {}", key, id, e);
+        warp::reject()
+    })?;
+    http_server::stream_body(&mut resp, stream);
+
+    let download = q.download.map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false);
+    let out = resp.headers_mut();
+    out.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    out.insert(header::CONTENT_TYPE, HeaderValue::from_str(&mime_type).unwrap_or(HeaderValue::from_static("application/octet-stream")));
+    out.insert(header::CONTENT_DISPOSITION, content_disposition(&filename, &mime_type, download));
+    // Never let a browser run uploaded content as something else
+    out.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    out.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start));
+    if let ByteRange::Partial(..) = range {
+        *resp.status_mut() = StatusCode::PARTIAL_CONTENT;
+        resp.headers_mut().insert(header::CONTENT_RANGE, HeaderValue::from_str(&format!("bytes {start}-{}/{size}", end - 1)).unwrap());
+    }
+    Ok(resp)
+}
+
+/// What a `Range` header asks of a blob.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    /// First and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parse a single `bytes=` range (`a-b`, `a-` or the suffix `-n`) against a
+/// blob of `size` bytes. Other units, malformed headers and multiple ranges
+/// are ignored, which the HTTP spec allows, and get the whole blob.
+fn parse_range(spec: &str, size: u64) -> ByteRange {
+    let Some(spec) = spec.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    let Some((first, last)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return ByteRange::Full;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    let (start, end) = if first.is_empty() {
+        match last.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(n) => (size.saturating_sub(n), size.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        }
+    } else {
+        let Ok(start) = first.parse::<u64>() else {
+            return ByteRange::Full;
+        };
+        let end = match last.parse::<u64>() {
+            _ if last.is_empty() => size.saturating_sub(1),
+            Ok(end) if end >= start => end.min(size.saturating_sub(1)),
+            _ => return ByteRange::Full,
+        };
+        (start, end)
+    };
+    if start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
+
+/// Whether an `If-None-Match` list names `etag` (weak comparison, or `*`).
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header.split(',').map(str::trim).any(|t| t == "*" || t.trim_start_matches("W/") == etag)
+}
+
+/// `inline` for types browsers can show safely, `attachment` otherwise (and
+/// on request), with an ASCII fallback name and the UTF-8 name per RFC 6266.
+fn content_disposition(filename: &str, mime_type: &str, download: bool) -> warp::http::HeaderValue {
+    const INLINE_TYPES: &[&str] = &["application/pdf", "text/plain", "text/markdown", "text/csv", "application/json", "image/png", "image/jpeg"];
+    let kind = if !download && INLINE_TYPES.contains(&mime_type) { "inline" } else { "attachment" };
+    let fallback: String = filename
+        .chars()
+        .map(|c| if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' { c } else { '_' })
         .collect();
+    let mut encoded = String::new();
+    for b in filename.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    warp::http::HeaderValue::from_str(&format!("{kind}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}"))
+        .unwrap_or_else(|_| warp::http::HeaderValue::from_static("attachment"))
+}
 
-    Ok(warp::reply::json(&serde_json::json!({"files": files})))
+async fn handle_page_text(id: String, page: u32, pool: MySqlPool) -> Result<impl Reply, Rejection> {
+    let row = sqlx::query(
+        "SELECT p.text, (SELECT COUNT(*) FROM file_pages c WHERE c.file_id = p.file_id) AS page_count \
+         FROM file_pages p WHERE p.file_id = ? AND p.page_number = ?",
+    )
+    .bind(&id)
+    .bind(page)
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("DB page lookup error: {}", e);
+        warp::reject()
+    })?
+    .ok_or_else(warp::reject::not_found)?;
+    let text: String = row.get("text");
+    let page_count: i64 = row.get("page_count");
+    Ok(warp::reply::json(&serde_json::json!({
+        "file_id": id,
+        "page": page,
+        "page_count": page_count,
+        "text": text
+    })))
+}
+
+async fn handle_reanalyze(id: String, pool: MySqlPool) -> Result<impl Reply, Rejection> {
+    let queued = file_worker::queue_reanalysis(&pool, &id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to queue reanalysis of {}: {}", id, e);
+            warp::reject()
+        })?
+        .ok_or_else(warp::reject::not_found)?;
+    Ok(warp::reply::json(&serde_json::json!({"queued": queued})))
+}
+
+async fn handle_retry_failed(pool: MySqlPool) -> Result<impl Reply, Rejection> {
+    let rows = sqlx::query("SELECT id FROM files WHERE analysis_status IN ('Failed', 'DeadLettered')")
+        .fetch_all(&pool)
+        .await
+        .map_err(|_| warp::reject())?;
+    let mut queued = 0;
+    for row in rows {
+        let id: String = row.get("id");
+        match file_worker::queue_reanalysis(&pool, &id).await {
+            Ok(Some(true)) => queued += 1,
+            Ok(_) => {}
+            Err(e) => tracing::error!("Failed to queue reanalysis of {}: {}", id, e),
+        }
+    }
+    Ok(warp::reply::json(&serde_json::json!({"queued": queued})))
+}
+
+async fn handle_index_stats(store: Arc<dyn VectorStore>, keywords: Arc<KeywordIndex>) -> Result<impl Reply, Rejection> {
+    let points = store.count(&VectorFilter::default()).await.map_err(|e| {
+        tracing::error!("Vector count error: {}", e);
+        warp::reject()
+    })?;
+    Ok(warp::reply::json(&serde_json::json!({
+        "backend": store.name(),
+        "points": points,
+        "keyword_chunks": keywords.len()
+    })))
 }
 
 async fn handle_create_query(body: serde_json::Value, pool: MySqlPool) -> Result<impl Reply, Rejection> {
     // Insert query as queued, worker will pick it up
     let id = uuid::Uuid::new_v4().to_string();
+    let session_id = match body.get("session_id") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(v) => Some(v.as_str().ok_or_else(|| bad_request("session_id must be a string"))?.to_string()),
+    };
+    worker::validate_options(&body).map_err(|e| bad_request(e.to_string()))?;
+    // The row and its job are created together, so a query is never left
+    // queued without a job to answer it
+    let mut tx = pool.begin().await.map_err(|_| warp::reject())?;
+    if let Some(sid) = &session_id {
+        // A follow-up names its session; the first question also titles it
+        let question = body.get("q").and_then(|v| v.as_str()).unwrap_or("");
+        let updated = sqlx::query("UPDATE sessions SET title = COALESCE(title, ?), updated_at = NOW() WHERE id = ?")
+            .bind(question)
+            .bind(sid)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| {
+                tracing::error!("DB update session error: {}", e);
+                warp::reject()
+            })?;
+        if updated.rows_affected() == 0 {
+            return Err(warp::reject::not_found());
+        }
+    }
     let payload = body;
-    sqlx::query("INSERT INTO queries (id, status, payload) VALUES (?, 'Queued', ?)")
+    sqlx::query("INSERT INTO queries (id, status, payload, session_id) VALUES (?, 'Queued', ?, ?)")
         .bind(&id)
         .bind(payload)
-        .execute(&pool)
+        .bind(&session_id)
+        .execute(&mut *tx)
         .await
         .map_err(|e| {
             tracing::error!("DB insert query error: {}", e);
             warp::reject()
         })?;
+    jobs::enqueue(&mut *tx, worker::ANSWER_QUERY, Some(&id), serde_json::json!({}), worker::QUERY_PRIORITY)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to enqueue query {}: {}", id, e);
+            warp::reject()
+        })?;
+    tx.commit().await.map_err(|e| {
+        tracing::error!("DB commit query {} error: {}", id, e);
+        warp::reject()
+    })?;
+
+    Ok(warp::reply::json(&serde_json::json!({"id": id, "session_id": session_id})))
+}
+
+async fn handle_create_session(body: bytes::Bytes, pool: MySqlPool) -> Result<impl Reply, Rejection> {
+    // The body is optional: `{"title": "..."}` or nothing
+    let title = if body.is_empty() {
+        None
+    } else {
+        let v: serde_json::Value = serde_json::from_slice(&body).map_err(|e| bad_request(format!("body is not JSON: {e}")))?;
+        v.get("title").and_then(|t| t.as_str()).map(|t| t.trim().to_string()).filter(|t| !t.is_empty())
+    };
+    let id = uuid::Uuid::new_v4().to_string();
+    sqlx::query("INSERT INTO sessions (id, title) VALUES (?, ?)")
+        .bind(&id)
+        .bind(&title)
+        .execute(&pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("DB insert session error: {}", e);
+            warp::reject()
+        })?;
+    Ok(warp::reply::json(&serde_json::json!({"id": id, "title": title})))
+}
+
+async fn handle_list_sessions(pool: MySqlPool) -> Result<impl Reply, Rejection> {
+    let rows = sqlx::query(
+        "SELECT s.id, s.title, s.created_at, s.updated_at, COUNT(q.id) AS turns \
+         FROM sessions s LEFT JOIN queries q ON q.session_id = s.id \
+         GROUP BY s.id, s.title, s.created_at, s.updated_at \
+         ORDER BY s.updated_at DESC",
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("DB list sessions error: {}", e);
+        warp::reject()
+    })?;
+    let sessions: Vec<serde_json::Value> = rows
+        .into_iter()
+        .map(|r| {
+            let created_at: Option<chrono::NaiveDateTime> = r.get("created_at");
+            let updated_at: Option<chrono::NaiveDateTime> = r.get("updated_at");
+            let turns: i64 = r.get("turns");
+            serde_json::json!({
+                "id": r.get::<String, _>("id"),
+                "title": r.get::<Option<String>, _>("title"),
+                "turns": turns,
+                "created_at": created_at.map(|t| t.and_utc().to_rfc3339()),
+                "updated_at": updated_at.map(|t| t.and_utc().to_rfc3339()),
+            })
+        })
+        .collect();
+    Ok(warp::reply::json(&serde_json::json!({"sessions": sessions})))
+}
+
+async fn handle_get_session(id: String, pool: MySqlPool) -> Result<impl Reply, Rejection> {
+    let session = sqlx::query("SELECT title, created_at, updated_at FROM sessions WHERE id = ?")
+        .bind(&id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|_| warp::reject())?
+        .ok_or_else(warp::reject::not_found)?;
+    let rows = sqlx::query("SELECT id, status, payload, result, created_at FROM queries WHERE session_id = ? ORDER BY created_at, id")
+        .bind(&id)
+        .fetch_all(&pool)
+        .await
+        .map_err(|_| warp::reject())?;
+    let turns: Vec<serde_json::Value> = rows
+        .into_iter()
+        .map(|r| {
+            let payload: Option<serde_json::Value> = r.get("payload");
+            let result: Option<serde_json::Value> = r.get("result");
+            let created_at: Option<chrono::NaiveDateTime> = r.get("created_at");
+            let field = |k: &str| result.as_ref().and_then(|v| v.get(k)).cloned().unwrap_or(serde_json::Value::Null);
+            serde_json::json!({
+                "query_id": r.get::<String, _>("id"),
+                "q": payload.as_ref().and_then(|p| p.get("q")).cloned(),
+                "status": r.get::<String, _>("status"),
+                "final_answer": field("final_answer"),
+                "standalone_question": field("standalone_question"),
+                "citations": field("citations"),
+                "created_at": created_at.map(|t| t.and_utc().to_rfc3339()),
+            })
+        })
+        .collect();
+    let created_at: Option<chrono::NaiveDateTime> = session.get("created_at");
+    let updated_at: Option<chrono::NaiveDateTime> = session.get("updated_at");
+    Ok(warp::reply::json(&serde_json::json!({
+        "id": id,
+        "title": session.get::<Option<String>, _>("title"),
+        "created_at": created_at.map(|t| t.and_utc().to_rfc3339()),
+        "updated_at": updated_at.map(|t| t.and_utc().to_rfc3339()),
+        "turns": turns,
+    })))
+}
 
-    Ok(warp::reply::json(&serde_json::json!({"id": id})))
+async fn handle_delete_session(id: String, pool: MySqlPool) -> Result<impl Reply, Rejection> {
+    // Queries go with their session, and so do the jobs still waiting to
+    // answer them; a running one finishes but its row is gone
+    let mut tx = pool.begin().await.map_err(|_| warp::reject())?;
+    sqlx::query(
+        "DELETE FROM jobs WHERE job_type = ? AND status = 'Queued' AND subject_id IN (SELECT id FROM queries WHERE session_id = ?)",
+    )
+    .bind(worker::ANSWER_QUERY)
+    .bind(&id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| warp::reject())?;
+    sqlx::query("DELETE FROM queries WHERE session_id = ?")
+        .bind(&id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| warp::reject())?;
+    let deleted = sqlx::query("DELETE FROM sessions WHERE id = ?")
+        .bind(&id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| warp::reject())?;
+    if deleted.rows_affected() == 0 {
+        return Err(warp::reject::not_found());
+    }
+    tx.commit().await.map_err(|_| warp::reject())?;
+    Ok(warp::reply::json(&serde_json::json!({"deleted": true})))
+}
+
+async fn handle_list_jobs(q: JobsQuery, pool: MySqlPool) -> Result<impl Reply, Rejection> {
+    let mut sql = String::from(
+        "SELECT id, job_type, subject_id, payload, status, priority, attempts, max_attempts, next_run_at, last_error, claimed_by, lease_expires_at, created_at, updated_at FROM jobs WHERE 1=1",
+    );
+    let mut binds: Vec<&str> = Vec::new();
+    for (column, value) in [("status", &q.status), ("job_type", &q.job_type), ("subject_id", &q.subject_id)] {
+        if let Some(v) = value {
+            sql.push_str(&format!(" AND {column} = ?"));
+            binds.push(v);
+        }
+    }
+    sql.push_str(" ORDER BY created_at DESC LIMIT ?");
+    let mut query = sqlx::query(&sql);
+    for b in binds {
+        query = query.bind(b);
+    }
+    let rows = query
+        .bind(q.limit.unwrap_or(100).clamp(1, 1000))
+        .fetch_all(&pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("DB list jobs error: {}", e);
+            warp::reject()
+        })?;
+    let time = |r: &sqlx::mysql::MySqlRow, k: &str| r.get::<Option<chrono::NaiveDateTime>, _>(k).map(|t| t.and_utc().to_rfc3339());
+    let jobs: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|r| {
+            serde_json::json!({
+                "id": r.get::<String, _>("id"),
+                "job_type": r.get::<String, _>("job_type"),
+                "subject_id": r.get::<Option<String>, _>("subject_id"),
+                "payload": r.get::<Option<serde_json::Value>, _>("payload"),
+                "status": r.get::<String, _>("status"),
+                "priority": r.get::<i32, _>("priority"),
+                "attempts": r.get::<i32, _>("attempts"),
+                "max_attempts": r.get::<i32, _>("max_attempts"),
+                "next_run_at": time(r, "next_run_at"),
+                "last_error": r.get::<Option<String>, _>("last_error"),
+                "claimed_by": r.get::<Option<String>, _>("claimed_by"),
+                "lease_expires_at": time(r, "lease_expires_at"),
+                "created_at": time(r, "created_at"),
+                "updated_at": time(r, "updated_at"),
+            })
+        })
+        .collect();
+
+    // Totals per type and status, regardless of the filters
+    let count_rows = sqlx::query("SELECT job_type, status, COUNT(*) AS n FROM jobs GROUP BY job_type, status")
+        .fetch_all(&pool)
+        .await
+        .map_err(|_| warp::reject())?;
+    let mut counts = serde_json::Map::new();
+    for r in count_rows {
+        let per_type = counts
+            .entry(r.get::<String, _>("job_type"))
+            .or_insert_with(|| serde_json::json!({}));
+        per_type[r.get::<String, _>("status")] = serde_json::json!(r.get::<i64, _>("n"));
+    }
+    Ok(warp::reply::json(&serde_json::json!({"jobs": jobs, "counts": counts})))
+}
+
+async fn handle_retry_job(id: String, pool: MySqlPool) -> Result<impl Reply, Rejection> {
+    // A running job is left alone; anything else runs again now with a fresh attempt budget
+    let updated = sqlx::query(
+        "UPDATE jobs SET status = 'Queued', attempts = 0, next_run_at = NOW(), claimed_by = NULL, lease_expires_at = NULL WHERE id = ? AND status <> 'InProgress'",
+    )
+    .bind(&id)
+    .execute(&pool)
+    .await
+    .map_err(|_| warp::reject())?;
+    if updated.rows_affected() == 1 {
+        return Ok(warp::reply::json(&serde_json::json!({"retried": true})));
+    }
+    let exists = sqlx::query("SELECT 1 FROM jobs WHERE id = ?")
+        .bind(&id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|_| warp::reject())?
+        .is_some();
+    if !exists {
+        return Err(warp::reject::not_found());
+    }
+    Ok(warp::reply::json(&serde_json::json!({"retried": false, "status": "InProgress"})))
+}
+
+async fn handle_purge_jobs(q: PurgeJobsQuery, pool: MySqlPool) -> Result<impl Reply, Rejection> {
+    // Only finished jobs can go; queued and running ones still have work to do
+    if q.status != "Completed" && q.status != "DeadLettered" {
+        return Err(bad_request(format!("status must be Completed or DeadLettered, not '{}'", q.status)));
+    }
+    let purged = sqlx::query("DELETE FROM jobs WHERE status = ? AND updated_at <= NOW() - INTERVAL ? HOUR")
+        .bind(&q.status)
+        .bind(q.older_than_hours.unwrap_or(0))
+        .execute(&pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("DB purge jobs error: {}", e);
+            warp::reject()
+        })?;
+    Ok(warp::reply::json(&serde_json::json!({"purged": purged.rows_affected()})))
 }
 
 async fn handle_query_status(q: DeleteQuery, pool: MySqlPool) -> Result<impl Reply, Rejection> {
@@ -335,12 +1418,314 @@ async fn handle_query_result(q: DeleteQuery, pool: MySqlPool) -> Result<impl Rep
     Ok(warp::reply::json(&serde_json::json!({"result": null})))
 }
 
-async fn handle_cancel_query(q: DeleteQuery, pool: MySqlPool) -> Result<impl Reply, Rejection> {
+async fn handle_cancel_query(q: DeleteQuery, pool: MySqlPool, events: Arc<QueryEvents>) -> Result<impl Reply, Rejection> {
     // Mark as cancelled; worker must check status before heavy steps
     sqlx::query("UPDATE queries SET status = 'Cancelled' WHERE id = ?")
         .bind(&q.id)
         .execute(&pool)
         .await
         .map_err(|_| warp::reject())?;
+    events.finish(&q.id, "Cancelled");
     Ok(warp::reply::json(&serde_json::json!({"cancelled": true})))
 }
+
+async fn handle_get_query(id: String, pool: MySqlPool) -> Result<impl Reply, Rejection> {
+    let row = sqlx::query("SELECT status, session_id, result, created_at FROM queries WHERE id = ?")
+        .bind(&id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|_| warp::reject())?
+        .ok_or_else(warp::reject::not_found)?;
+    let created_at: Option<chrono::NaiveDateTime> = row.get("created_at");
+    Ok(warp::reply::json(&serde_json::json!({
+        "id": id,
+        "status": row.get::<String, _>("status"),
+        "session_id": row.get::<Option<String>, _>("session_id"),
+        "result": row.get::<Option<serde_json::Value>, _>("result"),
+        "created_at": created_at.map(|t| t.and_utc().to_rfc3339()),
+    })))
+}
+
+async fn handle_cancel(id: String, pool: MySqlPool, events: Arc<QueryEvents>) -> Result<impl Reply, Rejection> {
+    // Unlike the legacy route, a finished query keeps its status and result
+    let updated = sqlx::query("UPDATE queries SET status = 'Cancelled' WHERE id = ? AND status IN ('Queued', 'InProgress')")
+        .bind(&id)
+        .execute(&pool)
+        .await
+        .map_err(|_| warp::reject())?;
+    if updated.rows_affected() == 1 {
+        events.finish(&id, "Cancelled");
+        return Ok(warp::reply::json(&serde_json::json!({"cancelled": true})));
+    }
+    let row = sqlx::query("SELECT status FROM queries WHERE id = ?")
+        .bind(&id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|_| warp::reject())?
+        .ok_or_else(warp::reject::not_found)?;
+    Ok(warp::reply::json(&serde_json::json!({"cancelled": false, "status": row.get::<String, _>("status")})))
+}
+
+async fn handle_query_stream(
+    q: StreamQuery,
+    last_event_id: Option<usize>,
+    pool: MySqlPool,
+    events: Arc<QueryEvents>,
+) -> Result<impl Reply, Rejection> {
+    // Subscribe before reading the status so nothing published in between is lost
+    let (history, rx) = events.subscribe(&q.id);
+    let row = sqlx::query("SELECT status, result FROM queries WHERE id = ?")
+        .bind(&q.id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|_| warp::reject());
+    let row = match row {
+        Ok(Some(row)) => row,
+        other => {
+            drop(rx);
+            events.release(&q.id);
+            other?;
+            return Err(warp::reject::not_found());
+        }
+    };
+    let status: String = row.get("status");
+
+    let skip = last_event_id.map(|id| id + 1).unwrap_or(0);
+    let cancel = q.cancel_on_disconnect.as_deref().map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false);
+    let mut stream = EventStream {
+        id: q.id.clone(),
+        pending: VecDeque::new(),
+        rx: None,
+        done: false,
+        next_seq: skip,
+        events: events.clone(),
+        pool,
+        cancel_on_disconnect: false,
+    };
+    if is_terminal(&status) && !history.iter().any(|(_, e)| e.is_done()) {
+        // The worker finished before this subscription, or ran on another
+        // replica: replay the stored result after whatever the client has seen
+        drop(rx);
+        events.release(&q.id);
+        stream.pending = numbered(stored_result_events(&status, row.get("result")), skip);
+    } else {
+        stream.pending = history.into_iter().filter(|(seq, _)| *seq >= skip).collect();
+        stream.rx = Some(rx);
+        stream.cancel_on_disconnect = cancel;
+    }
+
+    let events = futures_util::stream::unfold(stream, |mut stream| async move {
+        let (seq, event) = stream.next().await?;
+        let sse = warp::sse::Event::default().id(seq.to_string()).event(event.name());
+        let sse = sse.json_data(&event).unwrap_or_else(|_| warp::sse::Event::default().event("error"));
+        Some((Ok::<_, Infallible>(sse), stream))
+    });
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
+fn is_terminal(status: &str) -> bool {
+    matches!(status, "Completed" | "Failed" | "Cancelled")
+}
+
+/// Number events consecutively from `first`.
+fn numbered(events: Vec<QueryEvent>, first: usize) -> VecDeque<Numbered> {
+    events.into_iter().enumerate().map(|(i, e)| (first + i, e)).collect()
+}
+
+/// How long a stream waits for a live event before checking the query row;
+/// covers queries answered by a worker on another replica.
+fn stream_poll_interval() -> std::time::Duration {
+    let secs = std::env::var("QUERY_STREAM_POLL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(2)
+        .max(1);
+    std::time::Duration::from_secs(secs)
+}
+
+/// Events equivalent to a finished query's stored row.
+fn stored_result_events(status: &str, result: Option<serde_json::Value>) -> Vec<QueryEvent> {
+    let mut out = Vec::new();
+    if let Some(result) = result {
+        if let Some(message) = result.get("error").and_then(|v| v.as_str()) {
+            out.push(QueryEvent::Error { message: message.to_string() });
+        } else {
+            let files = result.get("related_files").and_then(|v| v.as_array()).cloned().unwrap_or_default();
+            out.push(QueryEvent::Files { files });
+            out.push(QueryEvent::Result { result });
+        }
+    }
+    out.push(QueryEvent::Done { status: status.to_string() });
+    out
+}
+
+/// One subscriber's view of a query: buffered events first, then live ones,
+/// until the `done` event.
+struct EventStream {
+    id: String,
+    pending: VecDeque<Numbered>,
+    rx: Option<broadcast::Receiver<Numbered>>,
+    done: bool,
+    /// Number for events made up from the stored row
+    next_seq: usize,
+    events: Arc<QueryEvents>,
+    pool: MySqlPool,
+    /// Cancel the query if the client goes away early
+    cancel_on_disconnect: bool,
+}
+
+impl EventStream {
+    async fn next(&mut self) -> Option<Numbered> {
+        if self.done {
+            return None;
+        }
+        let next = loop {
+            if let Some(e) = self.pending.pop_front() {
+                break Some(e);
+            }
+            let Some(rx) = self.rx.as_mut() else { break None };
+            match tokio::time::timeout(stream_poll_interval(), rx.recv()).await {
+                Ok(Ok(e)) => break Some(e),
+                // A slow client missed some tokens; the result event still has the full answer
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                Ok(Err(broadcast::error::RecvError::Closed)) => break None,
+                Err(_) => self.poll_row().await,
+            }
+        };
+        match &next {
+            Some((seq, e)) => {
+                self.next_seq = seq + 1;
+                self.done = e.is_done();
+            }
+            None => self.done = true,
+        }
+        next
+    }
+
+    /// Check the query row while no live events arrive. A query another
+    /// replica finished never publishes here, so switch to its stored result.
+    async fn poll_row(&mut self) {
+        let (id, pool) = (self.id.clone(), self.pool.clone());
+        // Spawned because the SSE body must be Sync and sqlx's query future is not
+        let row = tokio::spawn(async move {
+            let row = sqlx::query("SELECT status, result FROM queries WHERE id = ?").bind(&id).fetch_optional(&pool).await?;
+            Ok::<_, sqlx::Error>(row.map(|r| (r.get::<String, _>("status"), r.get::<Option<serde_json::Value>, _>("result"))))
+        })
+        .await;
+        match row {
+            Ok(Ok(Some((status, result)))) => {
+                if is_terminal(&status) {
+                    self.rx = None;
+                    self.pending = numbered(stored_result_events(&status, result), self.next_seq);
+                }
+            }
+            // Deleted while streaming
+            Ok(Ok(None)) => self.rx = None,
+            Ok(Err(e)) => tracing::warn!("Polling query {} for its stream failed: {}", self.id, e),
+            Err(e) => tracing::warn!("Polling query {} for its stream failed: {}", self.id, e),
+        }
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.rx.take();
+        self.events.release(&self.id);
+        if self.done {
+            return;
+        }
+        if self.cancel_on_disconnect {
+            let pool = self.pool.clone();
+            let id = self.id.clone();
+            tracing::info!("Stream for query {} closed early; cancelling it", id);
+            tokio::spawn(async move {
+                let _ = sqlx::query("UPDATE queries SET status = 'Cancelled' WHERE id = ? AND status IN ('Queued', 'InProgress')")
+                    .bind(&id)
+                    .execute(&pool)
+                    .await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_results_replay_as_events() {
+        let result = serde_json::json!({ "related_files": [{ "id": "f1" }], "final_answer": "Electrolysis." });
+        let events = numbered(stored_result_events("Completed", Some(result)), 7);
+        let names: Vec<(usize, &str)> = events.iter().map(|(seq, e)| (*seq, e.name())).collect();
+        assert_eq!(names, vec![(7, "files"), (8, "result"), (9, "done")]);
+
+        let failed = stored_result_events("Failed", Some(serde_json::json!({ "error": "quota exhausted" })));
+        assert_eq!(failed.iter().map(|e| e.name()).collect::<Vec<_>>(), vec!["error", "done"]);
+        let cancelled = stored_result_events("Cancelled", None);
+        assert!(cancelled.len() == 1 && cancelled[0].is_done());
+        assert!(is_terminal("Cancelled") && !is_terminal("InProgress"));
+    }
+
+    #[tokio::test]
+    async fn bad_requests_become_json_400s() {
+        let resp = handle_rejection(bad_request("status must be Completed or DeadLettered, not 'Queued'")).await.unwrap();
+        assert_eq!(resp.status(), warp::http::StatusCode::BAD_REQUEST);
+        assert_eq!(resp.headers()[warp::http::header::CONTENT_TYPE], "application/json");
+        // Everything else is left to warp
+        assert!(handle_rejection(warp::reject::not_found()).await.unwrap_err().is_not_found());
+    }
+
+    #[test]
+    fn legacy_routes_are_opt_in() {
+        assert!(!parse_legacy_routes(None));
+        assert!(parse_legacy_routes(Some("true")));
+        assert!(parse_legacy_routes(Some("TRUE")));
+        assert!(parse_legacy_routes(Some("1")));
+        assert!(!parse_legacy_routes(Some("0")));
+        assert!(!parse_legacy_routes(Some("")));
+    }
+
+    #[test]
+    fn ranges() {
+        use ByteRange::*;
+        assert_eq!(parse_range("bytes=0-99", 1000), Partial(0, 99));
+        // Open-ended, and an end past the blob, run to the last byte
+        assert_eq!(parse_range("bytes=900-", 1000), Partial(900, 999));
+        assert_eq!(parse_range("bytes=900-5000", 1000), Partial(900, 999));
+        // Suffix: the last n bytes, or all of them when n is larger
+        assert_eq!(parse_range("bytes=-100", 1000), Partial(900, 999));
+        assert_eq!(parse_range("bytes=-5000", 1000), Partial(0, 999));
+        // 416
+        assert_eq!(parse_range("bytes=1000-", 1000), Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), Unsatisfiable);
+        // Ignored: the whole blob is sent
+        assert_eq!(parse_range("bytes=0-9,20-29", 1000), Full);
+        assert_eq!(parse_range("bytes=50-10", 1000), Full);
+        assert_eq!(parse_range("bytes=x-10", 1000), Full);
+        assert_eq!(parse_range("items=0-9", 1000), Full);
+    }
+
+    #[test]
+    fn etags() {
+        let tag = "\"abc\"";
+        assert!(etag_matches("\"abc\"", tag));
+        assert!(etag_matches("\"xyz\", W/\"abc\"", tag));
+        assert!(etag_matches("*", tag));
+        assert!(!etag_matches("\"abcd\"", tag));
+        assert!(!etag_matches("abc", tag));
+    }
+
+    #[test]
+    fn content_dispositions() {
+        let header = |name: &str, mime: &str, download: bool| content_disposition(name, mime, download).to_str().unwrap().to_string();
+        assert_eq!(header("manual.pdf", "application/pdf", false), "inline; filename=\"manual.pdf\"; filename*=UTF-8''manual.pdf");
+        assert!(header("manual.pdf", "application/pdf", true).starts_with("attachment;"));
+        // Types a browser could run are never shown inline
+        assert!(header("page.html", "text/html", false).starts_with("attachment;"));
+        assert_eq!(
+            header("Über \"plan\".pdf", "application/pdf", false),
+            "inline; filename=\"_ber _plan_.pdf\"; filename*=UTF-8''%C3%9Cber%20%22plan%22.pdf"
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use crate::s3_store::S3Store;
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{Stream, TryStreamExt};
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::info;
+
+/// Blob contents as a stream of chunks.
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+/// Metadata of a stored blob.
+#[derive(Debug, Clone)]
+pub struct BlobStat {
+    pub size: u64,
+}
+
+/// Where stored file contents live, addressed by relative keys such as
+/// `blobs/ab/cd/<sha256>`. Implemented by a local directory and by any
+/// S3-compatible object store, so engine replicas can share their files.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Short identifier used in logs.
+    fn name(&self) -> &str;
+
+    /// Store `size` bytes from `data` under `key`, replacing any blob there.
+    /// Readers never see a partly written blob.
+    async fn put(&self, key: &str, data: ByteStream, size: u64) -> Result<()>;
+
+    /// Stream the blob at `key`; an error if there is none.
+    async fn get(&self, key: &str) -> Result<ByteStream>;
+
+    /// Stream `len` bytes of the blob at `key` from `offset` on, for HTTP
+    /// range requests. The range must lie within the blob.
+    async fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<ByteStream>;
+
+    /// Remove the blob at `key`. Removing a missing blob is not an error.
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.stat(key).await?.is_some())
+    }
+
+    /// `None` when there is no blob at `key`.
+    async fn stat(&self, key: &str) -> Result<Option<BlobStat>>;
+}
+
+/// Read a whole blob into memory.
+pub async fn read_all(store: &dyn BlobStore, key: &str) -> Result<Vec<u8>> {
+    collect(store.get(key).await?).await
+}
+
+/// Gather a blob stream into memory.
+pub async fn collect(mut stream: ByteStream) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    while let Some(chunk) = stream.try_next().await? {
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// Error unless `key` stays inside the store: `/`-separated plain segments,
+/// so no `..`, `.`, empty segments or leading `/`. Every store checks its keys
+/// with this, whatever its own path handling would make of them.
+pub fn check_key(key: &str) -> Result<()> {
+    if key.is_empty() || key.split('/').any(|seg| seg.is_empty() || seg == "." || seg == "..") {
+        bail!("invalid blob key '{key}'");
+    }
+    Ok(())
+}
+
+/// Build the store selected by BLOB_BACKEND: `fs` (default, under
+/// ASTRA_STORAGE) or `s3` (an S3-compatible bucket such as MinIO, see `S3Store`).
+pub fn blob_store_from_env() -> Result<Arc<dyn BlobStore>> {
+    let backend = std::env::var("BLOB_BACKEND").unwrap_or_else(|_| "fs".to_string()).to_lowercase();
+    let store: Arc<dyn BlobStore> = match backend.as_str() {
+        "fs" => Arc::new(FsStore::new(crate::storage::storage_dir())),
+        "s3" => Arc::new(S3Store::from_env()?),
+        other => return Err(anyhow!("unknown BLOB_BACKEND '{other}'")),
+    };
+    info!("Using {} blob store", store.name());
+    Ok(store)
+}
+
+/// Blobs as files under a local directory, one file per key.
+pub struct FsStore {
+    root: PathBuf,
+}
+
+impl FsStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// The file for `key`, which must stay inside the root (see `check_key`;
+    /// prefixes such as `C:` are refused too).
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        check_key(key)?;
+        let rel = Path::new(key);
+        if !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+            bail!("invalid blob key '{key}'");
+        }
+        Ok(self.root.join(rel))
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsStore {
+    fn name(&self) -> &str {
+        "fs"
+    }
+
+    async fn put(&self, key: &str, mut data: ByteStream, _size: u64) -> Result<()> {
+        let path = self.path(key)?;
+        let dir = path.parent().ok_or_else(|| anyhow!("invalid blob key '{key}'"))?;
+        tokio::fs::create_dir_all(dir).await?;
+        // A symlinked directory could still lead out of the root
+        if !tokio::fs::canonicalize(dir).await?.starts_with(tokio::fs::canonicalize(&self.root).await?) {
+            bail!("blob key '{key}' resolves outside the storage root");
+        }
+        // Write next to the target and rename, so the blob appears complete or not at all
+        let tmp = dir.join(format!(".{}.part", uuid::Uuid::new_v4()));
+        let written = async {
+            let mut file = tokio::fs::File::create(&tmp).await?;
+            while let Some(chunk) = data.try_next().await? {
+                file.write_all(&chunk).await?;
+            }
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp, &path).await?;
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        if written.is_err() {
+            let _ = tokio::fs::remove_file(&tmp).await;
+        }
+        written
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream> {
+        let file = tokio::fs::File::open(self.path(key)?).await?;
+        Ok(Box::pin(tokio_util::io::ReaderStream::new(file).map_err(anyhow::Error::from)))
+    }
+
+    async fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<ByteStream> {
+        let mut file = tokio::fs::File::open(self.path(key)?).await?;
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        Ok(Box::pin(tokio_util::io::ReaderStream::new(file.take(len)).map_err(anyhow::Error::from)))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<BlobStat>> {
+        match tokio::fs::metadata(self.path(key)?).await {
+            Ok(meta) => Ok(Some(BlobStat { size: meta.len() })),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("blob-store-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn bytes(data: &'static [u8]) -> ByteStream {
+        Box::pin(futures_util::stream::iter([Ok(Bytes::from_static(data))]))
+    }
+
+    #[test]
+    fn keys_stay_inside_the_root() {
+        let store = FsStore::new(PathBuf::from("/srv/storage"));
+        assert_eq!(store.path("blobs/ab/cd/abcd").unwrap(), PathBuf::from("/srv/storage/blobs/ab/cd/abcd"));
+        for key in ["", "../etc/passwd", "blobs/../../x", "/etc/passwd", "./blobs/x", "blobs/./x", "blobs//x", "blobs/x/"] {
+            assert!(store.path(key).is_err(), "{key:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn put_get_and_ranges() {
+        let root = temp_root("rw");
+        let store = FsStore::new(root.clone());
+        store.put("blobs/ab/cd/x", bytes(b"0123456789"), 10).await.unwrap();
+        assert_eq!(read_all(&store, "blobs/ab/cd/x").await.unwrap(), b"0123456789");
+        assert_eq!(collect(store.get_range("blobs/ab/cd/x", 3, 4).await.unwrap()).await.unwrap(), b"3456");
+        assert_eq!(store.stat("blobs/ab/cd/x").await.unwrap().map(|s| s.size), Some(10));
+        // Only the blob is left behind, no temp file
+        assert_eq!(std::fs::read_dir(root.join("blobs/ab/cd")).unwrap().count(), 1);
+
+        store.delete("blobs/ab/cd/x").await.unwrap();
+        store.delete("blobs/ab/cd/x").await.unwrap();
+        assert!(!store.exists("blobs/ab/cd/x").await.unwrap());
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks_out_of_the_root_are_refused() {
+        let root = temp_root("link");
+        let outside = temp_root("outside");
+        std::os::unix::fs::symlink(&outside, root.join("escape")).unwrap();
+        let store = FsStore::new(root.clone());
+        assert!(store.put("escape/x", bytes(b"data"), 4).await.is_err());
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+        let _ = std::fs::remove_dir_all(root);
+        let _ = std::fs::remove_dir_all(outside);
+    }
+}
@@ -0,0 +1,154 @@
+//! Stored file contents are content-addressed: one blob per SHA-256, shared by
+//! every `files` row with that `content_hash`. `blobs.ref_count` counts those
+//! rows and is changed in the same transaction that inserts or deletes a row.
+//! The bytes are only removed after the transaction dropping the last
+//! reference commits, so a rolled back delete never loses them.
+
+use crate::blob_store::BlobStore;
+use crate::storage::{self, StagedFile};
+use anyhow::Result;
+use sqlx::{MySqlConnection, MySqlPool, Row};
+use tracing::{info, warn};
+
+/// Take a reference to the blob holding `staged`, uploading the bytes unless
+/// the store has them already (a blob of another size is replaced), and return its key. Call in the transaction
+/// inserting the `files` row; the blob row stays locked until it commits, so a
+/// concurrent `release` cannot remove the bytes under us.
+pub async fn add_ref(conn: &mut MySqlConnection, store: &dyn BlobStore, staged: &StagedFile) -> Result<String> {
+    sqlx::query(
+        "INSERT INTO blobs (hash, size_bytes, ref_count) VALUES (?, ?, 1) \
+         ON DUPLICATE KEY UPDATE ref_count = ref_count + 1",
+    )
+    .bind(&staged.sha256)
+    .bind(staged.size)
+    .execute(&mut *conn)
+    .await?;
+    let key = storage::blob_key(&staged.sha256)?;
+    if store.stat(&key).await?.map(|b| b.size) != Some(staged.size) {
+        store.put(&key, staged.stream().await?, staged.size).await?;
+    }
+    Ok(key)
+}
+
+/// Stored bytes no record points at any more, to `remove` once the
+/// transaction that dropped the last reference has committed.
+#[derive(Debug, PartialEq)]
+pub enum Unreferenced {
+    /// A content-addressed blob, by hash
+    Blob(String),
+    /// A file stored before content addressing, by its key in the store
+    Legacy(String),
+}
+
+/// Drop the reference of a `files` row deleted in this transaction and return
+/// the bytes left without one. Files stored before content addressing have no
+/// blob row; their file goes once no other row points at its path.
+pub async fn release(conn: &mut MySqlConnection, content_hash: Option<&str>, path: &str) -> Result<Option<Unreferenced>> {
+    if let Some(hash) = content_hash {
+        let counted = sqlx::query("UPDATE blobs SET ref_count = ref_count - 1 WHERE hash = ?")
+            .bind(hash)
+            .execute(&mut *conn)
+            .await?;
+        if counted.rows_affected() > 0 {
+            let remaining: i64 = sqlx::query("SELECT ref_count FROM blobs WHERE hash = ?")
+                .bind(hash)
+                .fetch_one(&mut *conn)
+                .await?
+                .get("ref_count");
+            if remaining > 0 {
+                return Ok(None);
+            }
+            sqlx::query("DELETE FROM blobs WHERE hash = ?").bind(hash).execute(&mut *conn).await?;
+            return Ok(Some(Unreferenced::Blob(hash.to_string())));
+        }
+    }
+    let shared = sqlx::query("SELECT 1 FROM files WHERE path = ? LIMIT 1")
+        .bind(path)
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_some();
+    if shared {
+        return Ok(None);
+    }
+    match storage::legacy_key(path) {
+        Some(key) => Ok(Some(Unreferenced::Legacy(key))),
+        // Not ours to reach any more; the record goes, the file stays
+        None => {
+            warn!("Left {} in place: it is outside the storage directory", path);
+            Ok(None)
+        }
+    }
+}
+
+/// Delete bytes `release` returned, after its transaction committed. A blob
+/// is only deleted while no `blobs` row exists for its hash; the locking read
+/// holds off an upload taking a new reference until the bytes are gone, and
+/// the upload then stores them again.
+pub async fn remove(pool: &MySqlPool, store: &dyn BlobStore, unreferenced: Unreferenced) -> Result<()> {
+    match unreferenced {
+        Unreferenced::Blob(hash) => {
+            let mut tx = pool.begin().await?;
+            let referenced = sqlx::query("SELECT 1 FROM blobs WHERE hash = ? FOR UPDATE")
+                .bind(&hash)
+                .fetch_optional(&mut *tx)
+                .await?
+                .is_some();
+            if !referenced {
+                store.delete(&storage::blob_key(&hash)?).await?;
+                info!("Removed unreferenced blob {}", hash);
+            }
+            tx.commit().await?;
+        }
+        Unreferenced::Legacy(key) => store.delete(&key).await?,
+    }
+    Ok(())
+}
+
+/// The record to treat an upload with this hash as a duplicate of: a file
+/// whose analysis completed if there is one, else the oldest.
+pub async fn find_duplicate(conn: &mut MySqlConnection, content_hash: &str) -> Result<Option<(String, String)>> {
+    let row = sqlx::query(
+        "SELECT id, filename FROM files WHERE content_hash = ? \
+         ORDER BY analysis_status = 'Completed' DESC, created_at LIMIT 1",
+    )
+    .bind(content_hash)
+    .fetch_optional(&mut *conn)
+    .await?;
+    Ok(row.map(|r| (r.get("id"), r.get("filename"))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::FsStore;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn bytes_go_only_after_the_last_reference_commits() {
+        let Some(pool) = crate::db::test_pool().await else { return };
+        let root = std::env::temp_dir().join(format!("blobs-release-{}", std::process::id()));
+        let store = FsStore::new(root.clone());
+        let hash = format!("{:064x}", std::process::id());
+        let key = storage::blob_key(&hash).unwrap();
+        store.put(&key, Box::pin(futures_util::stream::iter([Ok(Bytes::from_static(b"data"))])), 4).await.unwrap();
+        sqlx::query("INSERT INTO blobs (hash, size_bytes, ref_count) VALUES (?, 4, 1)").bind(&hash).execute(&pool).await.unwrap();
+
+        // A rolled back release keeps the blob row and the bytes
+        let mut tx = pool.begin().await.unwrap();
+        let unreferenced = release(&mut tx, Some(&hash), &key).await.unwrap();
+        assert_eq!(unreferenced, Some(Unreferenced::Blob(hash.clone())));
+        tx.rollback().await.unwrap();
+        assert!(store.exists(&key).await.unwrap());
+
+        // Referenced again before `remove` ran: the bytes stay
+        remove(&pool, &store, Unreferenced::Blob(hash.clone())).await.unwrap();
+        assert!(store.exists(&key).await.unwrap());
+
+        let mut tx = pool.begin().await.unwrap();
+        let unreferenced = release(&mut tx, Some(&hash), &key).await.unwrap().unwrap();
+        tx.commit().await.unwrap();
+        remove(&pool, &store, unreferenced).await.unwrap();
+        assert!(!store.exists(&key).await.unwrap());
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::extract::PageText;
+
+/// A passage of a document that is embedded and indexed on its own.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub index: u32,
+    pub page: u32,
+    pub heading: Option<String>,
+    pub text: String,
+}
+
+/// Chunk sizing in characters. Configured with CHUNK_SIZE / CHUNK_OVERLAP.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkConfig {
+    pub size: usize,
+    pub overlap: usize,
+}
+
+impl ChunkConfig {
+    pub fn from_env() -> Self {
+        let size = std::env::var("CHUNK_SIZE")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(1200)
+            .max(200);
+        let overlap = std::env::var("CHUNK_OVERLAP")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(200)
+            .min(size / 2);
+        Self { size, overlap }
+    }
+}
+
+/// Split extracted pages into overlapping passages.
+///
+/// Chunks never span a page boundary, so every chunk can be cited by a single
+/// page number. Within a page, a detected heading starts a new chunk (once the
+/// current one has some content) and is remembered as the chunk's heading until
+/// the next heading, including across pages.
+pub fn chunk_pages(pages: &[PageText], cfg: ChunkConfig) -> Vec<Chunk> {
+    let mut out = Vec::new();
+    let mut heading: Option<String> = None;
+    for page in pages {
+        let mut buf = String::new();
+        let mut buf_heading = heading.clone();
+        for line in page.text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if is_heading(line) {
+                if buf.chars().count() >= cfg.size / 4 {
+                    push_chunk(&mut out, page.page, &buf_heading, &buf);
+                    buf.clear();
+                }
+                heading = Some(line.to_string());
+                if buf.is_empty() {
+                    buf_heading = heading.clone();
+                }
+            }
+            // Long lines are cut short enough to follow the overlap of the previous chunk
+            for piece in split_long(line, cfg.size.saturating_sub(cfg.overlap).max(1)) {
+                if !buf.is_empty() && buf.chars().count() + piece.chars().count() + 1 > cfg.size {
+                    push_chunk(&mut out, page.page, &buf_heading, &buf);
+                    // Only as much overlap as still leaves room for the piece
+                    let room = cfg.size.saturating_sub(piece.chars().count() + 1);
+                    buf = overlap_tail(&buf, cfg.overlap.min(room));
+                    buf_heading = heading.clone();
+                }
+                if !buf.is_empty() {
+                    buf.push(' ');
+                }
+                buf.push_str(&piece);
+            }
+        }
+        if !buf.trim().is_empty() {
+            push_chunk(&mut out, page.page, &buf_heading, &buf);
+        }
+    }
+    out
+}
+
+fn push_chunk(out: &mut Vec<Chunk>, page: u32, heading: &Option<String>, text: &str) {
+    out.push(Chunk {
+        index: out.len() as u32,
+        page,
+        heading: heading.clone(),
+        text: text.trim().to_string(),
+    });
+}
+
+/// Heuristic for section titles in extracted PDF text: short lines without
+/// terminal punctuation that are either numbered ("3.2 Oxygen Generation") or
+/// written in capitals.
+fn is_heading(line: &str) -> bool {
+    let len = line.chars().count();
+    if !(3..=80).contains(&len) || line.ends_with(['.', ',', ';', ':']) {
+        return false;
+    }
+    let letters: Vec<char> = line.chars().filter(|c| c.is_alphabetic()).collect();
+    if letters.len() < 3 {
+        return false;
+    }
+    let numbered = line
+        .split_whitespace()
+        .next()
+        .map(|w| {
+            let w = w.trim_end_matches('.');
+            !w.is_empty() && w.split('.').all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
+        })
+        .unwrap_or(false);
+    let upper = letters.iter().all(|c| c.is_uppercase());
+    (numbered && line.split_whitespace().count() <= 10) || upper
+}
+
+/// Break a single line that is longer than `max` characters at word boundaries.
+fn split_long(line: &str, max: usize) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut cur = String::new();
+    for word in line.split_whitespace() {
+        let word_len = word.chars().count();
+        if !cur.is_empty() && cur.chars().count() + 1 + word_len > max {
+            out.push(std::mem::take(&mut cur));
+        }
+        if word_len > max {
+            // No sensible boundary (e.g. a long table row without spaces)
+            let chars: Vec<char> = word.chars().collect();
+            out.extend(chars.chunks(max).map(|c| c.iter().collect::<String>()));
+            continue;
+        }
+        if !cur.is_empty() {
+            cur.push(' ');
+        }
+        cur.push_str(word);
+    }
+    if !cur.is_empty() {
+        out.push(cur);
+    }
+    out
+}
+
+/// Last `overlap` characters of `text`, starting on a word boundary.
+fn overlap_tail(text: &str, overlap: usize) -> String {
+    if overlap == 0 {
+        return String::new();
+    }
+    let total = text.chars().count();
+    if total <= overlap {
+        return text.to_string();
+    }
+    let tail: String = text.chars().skip(total - overlap).collect();
+    match tail.find(' ') {
+        Some(pos) => tail[pos + 1..].to_string(),
+        None => tail,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(page: u32, text: &str) -> PageText {
+        PageText { page, text: text.to_string() }
+    }
+
+    const CFG: ChunkConfig = ChunkConfig { size: 200, overlap: 40 };
+
+    #[test]
+    fn headings() {
+        assert!(is_heading("3.2 Oxygen Generation"));
+        assert!(is_heading("ELECTRICAL POWER SYSTEM"));
+        assert!(!is_heading("The bus runs at 160 volts."));
+        assert!(!is_heading("3.2"));
+        assert!(!is_heading("Summary:"));
+    }
+
+    #[test]
+    fn chunks_stay_within_size_and_pages() {
+        let long = "word ".repeat(200);
+        let chunks = chunk_pages(&[page(1, &long), page(2, "short second page")], CFG);
+        assert!(chunks.len() > 2);
+        assert!(chunks.iter().all(|c| c.text.chars().count() <= CFG.size));
+        assert_eq!(chunks.last().unwrap().page, 2);
+        assert_eq!(chunks.last().unwrap().text, "short second page");
+        assert!(chunks.iter().enumerate().all(|(i, c)| c.index == i as u32));
+    }
+
+    #[test]
+    fn consecutive_chunks_overlap() {
+        let text: String = (0..120).map(|i| format!("w{i} ")).collect();
+        let chunks = chunk_pages(&[page(1, &text)], CFG);
+        let first_tail: Vec<&str> = chunks[0].text.split(' ').rev().take(3).collect();
+        assert!(first_tail.iter().all(|w| chunks[1].text.split(' ').any(|x| x == *w)));
+    }
+
+    #[test]
+    fn headings_start_chunks_and_carry_across_pages() {
+        let body = "The oxygen generation assembly electrolyzes water into oxygen and hydrogen for the crew cabin.";
+        let text = format!("INTRODUCTION\n{body}\n3.2 Oxygen Generation\n{body}");
+        let chunks = chunk_pages(&[page(1, &text), page(2, body)], CFG);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].heading.as_deref(), Some("INTRODUCTION"));
+        assert!(chunks[1].text.starts_with("3.2 Oxygen Generation"));
+        assert_eq!(chunks[1].heading.as_deref(), Some("3.2 Oxygen Generation"));
+        assert_eq!(chunks[2].page, 2);
+        assert_eq!(chunks[2].heading.as_deref(), Some("3.2 Oxygen Generation"));
+    }
+
+    #[test]
+    fn long_words_are_split() {
+        let pieces = split_long(&"x".repeat(450), 200);
+        assert_eq!(pieces.iter().map(|p| p.len()).collect::<Vec<_>>(), vec![200, 200, 50]);
+    }
+
+    #[test]
+    fn overlap_tail_starts_on_a_word() {
+        assert_eq!(overlap_tail("alpha beta gamma delta", 12), "gamma delta");
+        assert_eq!(overlap_tail("alpha beta gamma delta", 8), "delta");
+        assert_eq!(overlap_tail("short", 12), "short");
+        assert_eq!(overlap_tail("anything", 0), "");
+    }
+}
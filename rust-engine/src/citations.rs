@@ -0,0 +1,302 @@
+use serde::Serialize;
+
+/// A retrieved chunk shown to the model under a stable label such as `S3`.
+#[derive(Debug, Clone)]
+pub struct Passage {
+    pub label: String,
+    pub chunk_id: String,
+    pub file_id: String,
+    pub filename: String,
+    pub page: u32,
+    pub text: String,
+}
+
+/// A validated reference from the final answer to a retrieved passage.
+#[derive(Debug, Clone, Serialize)]
+pub struct Citation {
+    pub source: String,
+    pub file_id: String,
+    pub filename: String,
+    pub page: u32,
+    pub chunk_id: String,
+    pub snippet: String,
+    /// True when the snippet is the model's quote and it was found verbatim
+    /// (ignoring case and whitespace) in the passage.
+    pub quote_verified: bool,
+}
+
+/// Line the model must put before its citation list.
+const CITATIONS_MARKER: &str = "CITATIONS:";
+const SNIPPET_CHARS: usize = 240;
+
+/// Render passages for a prompt, one labelled block per passage.
+pub fn format_passages(passages: &[Passage]) -> String {
+    passages
+        .iter()
+        .map(|p| format!("[{}] {} (file {}, page {}):\n{}", p.label, p.filename, p.file_id, p.page, p.text))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Instructions appended to the final answer prompt describing the citation format.
+pub fn citation_instructions() -> String {
+    format!(
+        "Cite sources inline with their labels, e.g. [S1]. After the answer, write a line '{CITATIONS_MARKER}' \
+        followed by a JSON array of objects {{\"source\": \"S1\", \"quote\": \"exact short quote from that passage\"}}, \
+        one per passage you relied on. Only cite labels listed above."
+    )
+}
+
+/// Filters a streamed answer so clients only see the answer text: output stops
+/// at the citation list, and a tail that might be the start of the marker is
+/// held back until the next piece shows it is not.
+#[derive(Default)]
+pub struct AnswerStream {
+    pending: String,
+    done: bool,
+}
+
+impl AnswerStream {
+    /// Feed the next piece of model output; returns the text safe to show.
+    pub fn push(&mut self, piece: &str) -> String {
+        if self.done {
+            return String::new();
+        }
+        self.pending.push_str(piece);
+        if let Some(pos) = self.pending.find(CITATIONS_MARKER) {
+            self.done = true;
+            let out = self.pending[..pos].to_string();
+            self.pending.clear();
+            return out;
+        }
+        // Keep the longest suffix that is a prefix of the marker
+        let keep = (1..CITATIONS_MARKER.len())
+            .rev()
+            .find(|&n| self.pending.ends_with(&CITATIONS_MARKER[..n]))
+            .unwrap_or(0);
+        let split = self.pending.len() - keep;
+        let out = self.pending[..split].to_string();
+        self.pending.drain(..split);
+        out
+    }
+
+    /// Text still held back once the model has finished.
+    pub fn finish(&mut self) -> String {
+        self.done = true;
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Split the model output into answer text and validated citations.
+///
+/// Citations whose label was never retrieved are dropped, as are inline
+/// markers that point at them. Passages referenced only inline get a snippet
+/// taken from the passage itself.
+pub fn parse_answer(raw: &str, passages: &[Passage]) -> (String, Vec<Citation>) {
+    let (answer, listed) = match raw.rfind(CITATIONS_MARKER) {
+        Some(pos) => (raw[..pos].trim_end().to_string(), parse_citation_list(&raw[pos + CITATIONS_MARKER.len()..])),
+        None => (raw.trim_end().to_string(), Vec::new()),
+    };
+
+    let mut citations: Vec<Citation> = Vec::new();
+    for (source, quote) in listed {
+        let Some(p) = passages.iter().find(|p| p.label == source) else {
+            continue;
+        };
+        if citations.iter().any(|c| c.source == source) {
+            continue;
+        }
+        let verified = !quote.is_empty() && contains_normalized(&p.text, &quote);
+        let snippet = if verified { quote } else { snippet_of(&p.text) };
+        citations.push(to_citation(p, snippet, verified));
+    }
+
+    let answer = strip_unknown_markers(&answer, passages);
+    for label in inline_labels(&answer) {
+        if citations.iter().any(|c| c.source == label) {
+            continue;
+        }
+        if let Some(p) = passages.iter().find(|p| p.label == label) {
+            citations.push(to_citation(p, snippet_of(&p.text), false));
+        }
+    }
+    (answer, citations)
+}
+
+fn to_citation(p: &Passage, snippet: String, quote_verified: bool) -> Citation {
+    Citation {
+        source: p.label.clone(),
+        file_id: p.file_id.clone(),
+        filename: p.filename.clone(),
+        page: p.page,
+        chunk_id: p.chunk_id.clone(),
+        snippet,
+        quote_verified,
+    }
+}
+
+/// Parse the JSON array after the marker, tolerating a surrounding code fence.
+fn parse_citation_list(s: &str) -> Vec<(String, String)> {
+    let (Some(start), Some(end)) = (s.find('['), s.rfind(']')) else {
+        return Vec::new();
+    };
+    if end < start {
+        return Vec::new();
+    }
+    let items: Vec<serde_json::Value> = serde_json::from_str(&s[start..=end]).unwrap_or_default();
+    items
+        .into_iter()
+        .filter_map(|v| {
+            let source = v.get("source")?.as_str()?.trim().trim_matches(['[', ']']).to_string();
+            let quote = v.get("quote").and_then(|q| q.as_str()).unwrap_or("").trim().to_string();
+            Some((source, quote))
+        })
+        .collect()
+}
+
+/// Labels of inline `[S<n>]` markers in order of first appearance.
+fn inline_labels(text: &str) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("[S") {
+        let after = &rest[start + 1..];
+        let Some(end) = after.find(']') else { break };
+        let label = &after[..end];
+        if label.len() > 1 && label[1..].chars().all(|c| c.is_ascii_digit()) && !out.iter().any(|l| l == label) {
+            out.push(label.to_string());
+        }
+        rest = &after[end..];
+    }
+    out
+}
+
+/// Remove inline markers that reference labels which were not retrieved.
+fn strip_unknown_markers(text: &str, passages: &[Passage]) -> String {
+    let mut out = text.to_string();
+    for label in inline_labels(text) {
+        if !passages.iter().any(|p| p.label == label) {
+            out = out.replace(&format!(" [{label}]"), "").replace(&format!("[{label}]"), "");
+        }
+    }
+    out
+}
+
+fn contains_normalized(haystack: &str, needle: &str) -> bool {
+    let norm = |s: &str| s.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    norm(haystack).contains(&norm(needle))
+}
+
+fn snippet_of(text: &str) -> String {
+    if text.chars().count() <= SNIPPET_CHARS {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(SNIPPET_CHARS).collect();
+    match cut.rfind(' ') {
+        Some(pos) => format!("{}…", &cut[..pos]),
+        None => format!("{cut}…"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passage(label: &str, page: u32, text: &str) -> Passage {
+        Passage {
+            label: label.to_string(),
+            chunk_id: format!("chunk-{label}"),
+            file_id: "file-1".to_string(),
+            filename: "eclss.pdf".to_string(),
+            page,
+            text: text.to_string(),
+        }
+    }
+
+    fn passages() -> Vec<Passage> {
+        vec![
+            passage("S1", 3, "The Oxygen Generation Assembly   produces oxygen by electrolysis of water."),
+            passage("S2", 7, "The main bus operates at 160 V DC."),
+        ]
+    }
+
+    #[test]
+    fn listed_citations_are_validated() {
+        let raw = "Oxygen comes from electrolysis [S1].\nCITATIONS:\n```json\n[{\"source\": \"S1\", \"quote\": \"oxygen generation assembly produces OXYGEN\"}]\n```";
+        let (answer, citations) = parse_answer(raw, &passages());
+        assert_eq!(answer, "Oxygen comes from electrolysis [S1].");
+        assert_eq!(citations.len(), 1);
+        let c = &citations[0];
+        assert_eq!((c.source.as_str(), c.page, c.chunk_id.as_str()), ("S1", 3, "chunk-S1"));
+        assert!(c.quote_verified);
+        assert_eq!(c.snippet, "oxygen generation assembly produces OXYGEN");
+    }
+
+    #[test]
+    fn invented_quotes_fall_back_to_the_passage() {
+        let raw = "Answer [S2].\nCITATIONS: [{\"source\": \"[S2]\", \"quote\": \"the bus is 120 V\"}]";
+        let (_, citations) = parse_answer(raw, &passages());
+        assert!(!citations[0].quote_verified);
+        assert_eq!(citations[0].snippet, "The main bus operates at 160 V DC.");
+    }
+
+    #[test]
+    fn unknown_labels_are_dropped_from_list_and_text() {
+        let raw = "Claim [S9] and fact [S2].\nCITATIONS: [{\"source\": \"S9\", \"quote\": \"x\"}, {\"source\": \"S2\", \"quote\": \"\"}, {\"source\": \"S2\", \"quote\": \"dup\"}]";
+        let (answer, citations) = parse_answer(raw, &passages());
+        assert_eq!(answer, "Claim and fact [S2].");
+        assert_eq!(citations.iter().map(|c| c.source.as_str()).collect::<Vec<_>>(), vec!["S2"]);
+    }
+
+    #[test]
+    fn inline_only_markers_get_citations() {
+        let (answer, citations) = parse_answer("Both [S2] and [S1], again [S2].", &passages());
+        assert_eq!(answer, "Both [S2] and [S1], again [S2].");
+        assert_eq!(citations.iter().map(|c| c.source.as_str()).collect::<Vec<_>>(), vec!["S2", "S1"]);
+        assert!(citations.iter().all(|c| !c.quote_verified));
+    }
+
+    #[test]
+    fn malformed_list_is_ignored() {
+        let (answer, citations) = parse_answer("Text.\nCITATIONS: not json ]", &passages());
+        assert_eq!(answer, "Text.");
+        assert!(citations.is_empty());
+    }
+
+    #[test]
+    fn inline_labels_need_digits() {
+        assert_eq!(inline_labels("[S1] [Sx] [S] [S12] [S1]"), vec!["S1", "S12"]);
+    }
+
+    #[test]
+    fn long_snippets_are_cut_at_a_word() {
+        let snippet = snippet_of(&"word ".repeat(100));
+        assert!(snippet.ends_with("word…"));
+        assert!(snippet.chars().count() <= SNIPPET_CHARS + 1);
+    }
+
+    /// Everything an answer stream shows for the given pieces.
+    fn shown(pieces: &[&str]) -> String {
+        let mut stream = AnswerStream::default();
+        let mut out: String = pieces.iter().map(|p| stream.push(p)).collect();
+        out.push_str(&stream.finish());
+        out
+    }
+
+    #[test]
+    fn answer_stream_stops_at_the_citation_list() {
+        let pieces = ["Oxygen comes from ", "electrolysis [S1].\nCITA", "TIONS:\n[{\"source\": \"S1\"}]"];
+        assert_eq!(shown(&pieces), "Oxygen comes from electrolysis [S1].\n");
+    }
+
+    #[test]
+    fn answer_stream_holds_back_only_a_possible_marker() {
+        let mut stream = AnswerStream::default();
+        assert_eq!(stream.push("The bus runs at 160 V. CIT"), "The bus runs at 160 V. ");
+        // Not the marker after all
+        assert_eq!(stream.push("Y power"), "CITY power");
+        assert_eq!(stream.push(" grid C"), " grid ");
+        assert_eq!(stream.finish(), "C");
+        assert_eq!(stream.push("more"), "");
+        assert_eq!(shown(&["no citations at all"]), "no citations at all");
+    }
+}
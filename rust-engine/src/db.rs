@@ -15,12 +15,28 @@ pub async fn init_db(database_url: &str) -> Result<MySqlPool, sqlx::Error> {
             description TEXT,
             created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
             pending_analysis BOOLEAN DEFAULT TRUE,
-            analysis_status VARCHAR(32) DEFAULT 'Queued'
+            analysis_status VARCHAR(32) DEFAULT 'Queued',
+            mime_type VARCHAR(127),
+            tags JSON,
+            analysis_error TEXT,
+            analysis_started_at DATETIME,
+            analysis_attempts INT NOT NULL DEFAULT 0,
+            size_bytes BIGINT,
+            content_hash CHAR(64),
+            INDEX idx_files_content_hash (content_hash)
         )
         "#,
     )
     .execute(&pool)
     .await?;
+    add_column_if_missing(&pool, "files", "mime_type", "VARCHAR(127)").await?;
+    add_column_if_missing(&pool, "files", "tags", "JSON").await?;
+    add_column_if_missing(&pool, "files", "analysis_error", "TEXT").await?;
+    add_column_if_missing(&pool, "files", "analysis_started_at", "DATETIME").await?;
+    add_column_if_missing(&pool, "files", "analysis_attempts", "INT NOT NULL DEFAULT 0").await?;
+    add_column_if_missing(&pool, "files", "size_bytes", "BIGINT").await?;
+    add_column_if_missing(&pool, "files", "content_hash", "CHAR(64)").await?;
+    add_index_if_missing(&pool, "files", "idx_files_content_hash", "content_hash").await?;
 
     sqlx::query(
         r#"
@@ -30,6 +46,22 @@ pub async fn init_db(database_url: &str) -> Result<MySqlPool, sqlx::Error> {
             payload JSON,
             result JSON,
             created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            updated_at DATETIME DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+            session_id VARCHAR(36),
+            INDEX idx_queries_session (session_id, created_at)
+        )
+        "#,
+    )
+    .execute(&pool)
+    .await?;
+    add_column_if_missing(&pool, "queries", "session_id", "VARCHAR(36)").await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS sessions (
+            id VARCHAR(36) PRIMARY KEY,
+            title TEXT,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
             updated_at DATETIME DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP
         )
         "#,
@@ -37,6 +69,156 @@ pub async fn init_db(database_url: &str) -> Result<MySqlPool, sqlx::Error> {
     .execute(&pool)
     .await?;
 
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS jobs (
+            id VARCHAR(36) PRIMARY KEY,
+            job_type VARCHAR(64) NOT NULL,
+            subject_id VARCHAR(36),
+            payload JSON,
+            status VARCHAR(32) NOT NULL DEFAULT 'Queued',
+            priority INT NOT NULL DEFAULT 0,
+            attempts INT NOT NULL DEFAULT 0,
+            max_attempts INT NOT NULL DEFAULT 5,
+            next_run_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            last_error TEXT,
+            claimed_by VARCHAR(128),
+            lease_expires_at DATETIME,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            updated_at DATETIME DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+            INDEX idx_jobs_claim (job_type, status, priority, next_run_at),
+            INDEX idx_jobs_subject (subject_id)
+        )
+        "#,
+    )
+    .execute(&pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS blobs (
+            hash CHAR(64) PRIMARY KEY,
+            size_bytes BIGINT NOT NULL,
+            ref_count INT NOT NULL DEFAULT 0,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        )
+        "#,
+    )
+    .execute(&pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS file_pages (
+            file_id VARCHAR(36) NOT NULL,
+            page_number INT NOT NULL,
+            text MEDIUMTEXT NOT NULL,
+            PRIMARY KEY (file_id, page_number)
+        )
+        "#,
+    )
+    .execute(&pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS chunks (
+            id VARCHAR(36) PRIMARY KEY,
+            file_id VARCHAR(36) NOT NULL,
+            chunk_index INT NOT NULL,
+            page_number INT NOT NULL,
+            heading TEXT,
+            text MEDIUMTEXT NOT NULL,
+            INDEX idx_chunks_file (file_id, chunk_index)
+        )
+        "#,
+    )
+    .execute(&pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS vectors (
+            id VARCHAR(36) PRIMARY KEY,
+            file_id VARCHAR(36) NOT NULL,
+            payload JSON,
+            embedding LONGBLOB NOT NULL,
+            INDEX idx_vectors_file (file_id)
+        )
+        "#,
+    )
+    .execute(&pool)
+    .await?;
+
+    // Feed of keyword index changes, replayed by the other replicas
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS keyword_changes (
+            seq BIGINT AUTO_INCREMENT PRIMARY KEY,
+            file_id VARCHAR(36) NOT NULL,
+            changed_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )
+        "#,
+    )
+    .execute(&pool)
+    .await?;
+
+    // Vector store deletes that failed while Qdrant was unavailable, replayed
+    // before its next write
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS vector_deletes (
+            file_id VARCHAR(36) PRIMARY KEY,
+            queued_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )
+        "#,
+    )
+    .execute(&pool)
+    .await?;
+
     info!("Database initialized");
     Ok(pool)
 }
+
+/// Add a column to a table created by an older version. MySQL has no
+/// `ADD COLUMN IF NOT EXISTS`, so check the information schema first.
+async fn add_column_if_missing(pool: &MySqlPool, table: &str, column: &str, definition: &str) -> Result<(), sqlx::Error> {
+    let exists = sqlx::query(
+        "SELECT 1 FROM information_schema.COLUMNS WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ? AND COLUMN_NAME = ?",
+    )
+    .bind(table)
+    .bind(column)
+    .fetch_optional(pool)
+    .await?
+    .is_some();
+    if !exists {
+        sqlx::query(&format!("ALTER TABLE {table} ADD COLUMN {column} {definition}")).execute(pool).await?;
+        info!("Added column {}.{}", table, column);
+    }
+    Ok(())
+}
+
+/// Add an index to a table created by an older version.
+async fn add_index_if_missing(pool: &MySqlPool, table: &str, index: &str, columns: &str) -> Result<(), sqlx::Error> {
+    let exists = sqlx::query(
+        "SELECT 1 FROM information_schema.STATISTICS WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ? AND INDEX_NAME = ?",
+    )
+    .bind(table)
+    .bind(index)
+    .fetch_optional(pool)
+    .await?
+    .is_some();
+    if !exists {
+        sqlx::query(&format!("ALTER TABLE {table} ADD INDEX {index} ({columns})")).execute(pool).await?;
+        info!("Added index {}.{}", table, index);
+    }
+    Ok(())
+}
+
+/// Pool for tests that need MySQL, from `TEST_DATABASE_URL` with the schema
+/// created. None when it is unset; those tests then pass without running.
+#[cfg(test)]
+pub async fn test_pool() -> Option<MySqlPool> {
+    let url = std::env::var("TEST_DATABASE_URL").ok()?;
+    Some(init_db(&url).await.expect("TEST_DATABASE_URL is not usable"))
+}
@@ -0,0 +1,983 @@
+use crate::hnsw::{dot, Hnsw, VectorSource};
+use crate::embedding::l2_normalize;
+use crate::vector_store::{ScoredPoint, VectorFilter, VectorPoint, VectorStore};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Roll over to a new segment file once the active one reaches this size.
+const SEGMENT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+/// Compact when at least this many points are tombstoned...
+const COMPACT_MIN_DELETED: usize = 1_000;
+/// ...and they make up at least this fraction of all nodes.
+const COMPACT_DELETED_RATIO: f64 = 0.25;
+/// Compact when writes have spread over more segments than this.
+const COMPACT_MAX_SEGMENTS: usize = 16;
+/// Persist the graph after this many nodes were added since the last snapshot.
+const CHECKPOINT_EVERY: usize = 5_000;
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+const HNSW_M: usize = 16;
+const HNSW_EF_CONSTRUCTION: usize = 100;
+const HNSW_EF_SEARCH: usize = 128;
+
+const RECORD_UPSERT: u8 = 1;
+const RECORD_DELETE: u8 = 2;
+
+/// On-disk vector store for deployments without Qdrant.
+///
+/// Layout under `dir`:
+/// - `seg-NNNNNN.log`: append-only segments of checksummed upsert/tombstone
+///   records; every write is fsynced before the call returns and a torn
+///   record at the end of a segment is truncated on open.
+/// - `graph-NNNNNN.hnsw`: snapshot of the HNSW graph for the first N nodes, so
+///   startup only inserts nodes written after the last checkpoint.
+/// - `MANIFEST`: the live segments and graph snapshot, replaced atomically.
+///
+/// Deletes and overwrites leave tombstoned nodes in the graph until a
+/// background compaction rewrites live points into a fresh segment.
+pub struct EmbeddedStore {
+    dir: PathBuf,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    dir: PathBuf,
+    state: RwLock<Option<State>>,
+    maintenance_started: AtomicBool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    generation: u64,
+    dim: usize,
+    segments: Vec<String>,
+    graph: Option<String>,
+}
+
+/// Unit-length vectors stored contiguously, indexed by node number.
+struct Vectors {
+    dim: usize,
+    data: Vec<f32>,
+}
+
+impl VectorSource for Vectors {
+    fn vector(&self, node: u32) -> &[f32] {
+        let start = node as usize * self.dim;
+        &self.data[start..start + self.dim]
+    }
+}
+
+struct State {
+    manifest: Manifest,
+    active: File,
+    active_len: u64,
+    next_segment: u64,
+    vectors: Vectors,
+    ids: Vec<String>,
+    payloads: Vec<serde_json::Value>,
+    deleted: Vec<bool>,
+    deleted_count: usize,
+    /// Current node of every live id
+    live: HashMap<String, u32>,
+    /// All nodes (live or not) written for a file, for file filters and deletes
+    by_file: HashMap<String, Vec<u32>>,
+    graph: Hnsw,
+    checkpoint_nodes: usize,
+}
+
+/// A decoded segment record.
+enum Record {
+    Upsert(VectorPoint),
+    Delete(String),
+}
+
+impl EmbeddedStore {
+    pub fn new(dir: PathBuf) -> Self {
+        let shared = Arc::new(Shared { dir: dir.clone(), state: RwLock::new(None), maintenance_started: AtomicBool::new(false) });
+        Self { dir, shared }
+    }
+
+    /// Run blocking work against the shared state off the async runtime.
+    async fn blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Shared) -> Result<T> + Send + 'static,
+    {
+        let shared = self.shared.clone();
+        tokio::task::spawn_blocking(move || f(&shared))
+            .await
+            .map_err(|e| anyhow!("embedded store task failed: {e}"))?
+    }
+}
+
+impl Shared {
+    fn open(&self, dim: usize) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let manifest_path = self.dir.join("MANIFEST");
+        let mut manifest: Manifest = if manifest_path.exists() {
+            serde_json::from_slice(&fs::read(&manifest_path)?)?
+        } else {
+            Manifest { generation: 1, dim, segments: Vec::new(), graph: None }
+        };
+
+        let mut vectors = Vectors { dim, data: Vec::new() };
+        let mut state_ids: Vec<String> = Vec::new();
+        let mut payloads = Vec::new();
+        let mut deleted = Vec::new();
+        let mut live: HashMap<String, u32> = HashMap::new();
+        let mut by_file: HashMap<String, Vec<u32>> = HashMap::new();
+        let mut records = 0usize;
+        for name in &manifest.segments {
+            for record in read_segment(&self.dir.join(name))? {
+                records += 1;
+                match record {
+                    Record::Upsert(p) => {
+                        if p.vector.len() != dim {
+                            return Err(anyhow!(
+                                "embedded vector store holds {}-dim vectors but the embedder produces {dim}; remove {} and reindex",
+                                p.vector.len(),
+                                self.dir.display()
+                            ));
+                        }
+                        let node = state_ids.len() as u32;
+                        if let Some(old) = live.insert(p.id.clone(), node) {
+                            deleted[old as usize] = true;
+                        }
+                        by_file.entry(file_of(&p.id, &p.payload)).or_default().push(node);
+                        vectors.data.extend_from_slice(&p.vector);
+                        state_ids.push(p.id);
+                        payloads.push(p.payload);
+                        deleted.push(false);
+                    }
+                    Record::Delete(id) => {
+                        if let Some(old) = live.remove(&id) {
+                            deleted[old as usize] = true;
+                        }
+                    }
+                }
+            }
+        }
+        manifest.dim = dim;
+
+        // Load the last graph snapshot and insert whatever was written after it
+        let mut graph = Hnsw::new(HNSW_M, HNSW_EF_CONSTRUCTION);
+        if let Some(name) = &manifest.graph {
+            match fs::read(self.dir.join(name)).map_err(anyhow::Error::from).and_then(|b| Hnsw::from_bytes(&b)) {
+                Ok(g) if g.len() <= state_ids.len() => graph = g,
+                Ok(_) => warn!("Ignoring graph snapshot {} that is ahead of the segments", name),
+                Err(e) => warn!("Ignoring unreadable graph snapshot {}: {}", name, e),
+            }
+        }
+        let checkpoint_nodes = graph.len();
+        for node in graph.len()..state_ids.len() {
+            graph.insert(node as u32, &vectors);
+        }
+
+        // Writes go to the last segment, or a fresh one
+        let next_segment = manifest
+            .segments
+            .iter()
+            .filter_map(|s| s.trim_start_matches("seg-").trim_end_matches(".log").parse::<u64>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let mut state_next_segment = next_segment;
+        if manifest.segments.is_empty() {
+            manifest.segments.push(segment_name(state_next_segment));
+            state_next_segment += 1;
+            write_manifest(&self.dir, &manifest)?;
+        }
+        let active_path = self.dir.join(manifest.segments.last().unwrap());
+        let active = OpenOptions::new().create(true).append(true).open(&active_path)?;
+        let active_len = active.metadata()?.len();
+
+        let deleted_count = deleted.iter().filter(|d| **d).count();
+        info!(
+            "Embedded vector store opened at {} ({} live points, {} records, {} nodes from snapshot)",
+            self.dir.display(),
+            live.len(),
+            records,
+            checkpoint_nodes
+        );
+        let state = State {
+            manifest,
+            active,
+            active_len,
+            next_segment: state_next_segment,
+            vectors,
+            ids: state_ids,
+            payloads,
+            deleted,
+            deleted_count,
+            live,
+            by_file,
+            graph,
+            checkpoint_nodes,
+        };
+        *self.state.write().unwrap() = Some(state);
+        Ok(())
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&State) -> T) -> Result<T> {
+        let guard = self.state.read().unwrap();
+        let state = guard.as_ref().ok_or_else(|| anyhow!("embedded vector store is not open"))?;
+        Ok(f(state))
+    }
+
+    fn with_state_mut<T>(&self, f: impl FnOnce(&mut State) -> Result<T>) -> Result<T> {
+        let mut guard = self.state.write().unwrap();
+        let state = guard.as_mut().ok_or_else(|| anyhow!("embedded vector store is not open"))?;
+        f(state)
+    }
+
+    /// Periodic housekeeping: compact when tombstones pile up, otherwise
+    /// checkpoint the graph if enough nodes were added.
+    fn maintain(&self) -> Result<()> {
+        let (needs_compaction, needs_checkpoint) = self.with_state(|state| {
+            let total = state.ids.len();
+            let needs_compaction = (state.deleted_count >= COMPACT_MIN_DELETED
+                && state.deleted_count as f64 >= total as f64 * COMPACT_DELETED_RATIO)
+                || state.manifest.segments.len() > COMPACT_MAX_SEGMENTS;
+            (needs_compaction, total - state.checkpoint_nodes >= CHECKPOINT_EVERY)
+        })?;
+        if needs_compaction {
+            self.compact()
+        } else if needs_checkpoint {
+            self.with_state_mut(|state| state.checkpoint(&self.dir))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Compact in three steps so writers are only held up while the state is
+    /// copied and swapped: snapshot the live points under the lock, write the
+    /// new segment and graph without it, then take it again to carry over
+    /// whatever was written in the meantime and switch the manifest.
+    fn compact(&self) -> Result<()> {
+        let started = std::time::Instant::now();
+        let compaction = self.with_state_mut(|state| state.begin_compaction(&self.dir))?;
+        let (seg_name, graph_name) = (compaction.segment.clone(), compaction.graph_name());
+        let result = compaction.write(&self.dir).and_then(|(c, graph)| self.with_state_mut(|state| state.finish_compaction(&self.dir, c, graph)));
+        match result {
+            Ok(removed) => {
+                info!("Compacted embedded vector store: dropped {} tombstoned points in {:?}", removed, started.elapsed());
+                Ok(())
+            }
+            Err(e) => {
+                let _ = fs::remove_file(self.dir.join(seg_name));
+                let _ = fs::remove_file(self.dir.join(graph_name));
+                Err(e)
+            }
+        }
+    }
+}
+
+impl State {
+    fn upsert(&mut self, dir: &Path, points: Vec<VectorPoint>) -> Result<()> {
+        let dim = self.vectors.dim;
+        let mut points = points;
+        for p in points.iter_mut() {
+            if p.vector.len() != dim {
+                return Err(anyhow!("vector for {} has dimension {}, expected {}", p.id, p.vector.len(), dim));
+            }
+            // Stored unit-length so the graph can rank by dot product
+            l2_normalize(&mut p.vector);
+        }
+        let mut buf = Vec::new();
+        for p in &points {
+            encode_upsert(&mut buf, p)?;
+        }
+        self.append(dir, &buf)?;
+        for p in points {
+            let node = self.ids.len() as u32;
+            if let Some(old) = self.live.insert(p.id.clone(), node) {
+                self.mark_deleted(old);
+            }
+            self.by_file.entry(file_of(&p.id, &p.payload)).or_default().push(node);
+            self.vectors.data.extend_from_slice(&p.vector);
+            self.ids.push(p.id);
+            self.payloads.push(p.payload);
+            self.deleted.push(false);
+            self.graph.insert(node, &self.vectors);
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, dir: &Path, filter: &VectorFilter) -> Result<()> {
+        let nodes = self.matching_nodes(filter);
+        if nodes.is_empty() {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        for &n in &nodes {
+            encode_delete(&mut buf, &self.ids[n as usize]);
+        }
+        self.append(dir, &buf)?;
+        for n in nodes {
+            self.live.remove(&self.ids[n as usize]);
+            self.mark_deleted(n);
+        }
+        Ok(())
+    }
+
+    fn mark_deleted(&mut self, node: u32) {
+        if !self.deleted[node as usize] {
+            self.deleted[node as usize] = true;
+            self.deleted_count += 1;
+        }
+    }
+
+    /// Append encoded records to the active segment and fsync, rolling over to
+    /// a new segment when it grows past the size limit.
+    fn append(&mut self, dir: &Path, buf: &[u8]) -> Result<()> {
+        if let Err(e) = self.active.write_all(buf).and_then(|_| self.active.sync_data()) {
+            // Cut off whatever part of the batch made it to disk, so the next
+            // append doesn't land behind a torn record and get lost on open
+            if let Err(trunc) = self.active.set_len(self.active_len) {
+                warn!("Could not roll back a failed append ({}); starting a new segment", trunc);
+                if let Err(roll) = self.roll_over(dir) {
+                    error!("Could not start a new segment after a failed append: {}", roll);
+                }
+            }
+            return Err(e.into());
+        }
+        self.active_len += buf.len() as u64;
+        if self.active_len >= SEGMENT_MAX_BYTES {
+            self.roll_over(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Start writing to a new, empty segment.
+    fn roll_over(&mut self, dir: &Path) -> Result<()> {
+        let name = segment_name(self.next_segment);
+        self.next_segment += 1;
+        let file = OpenOptions::new().create(true).append(true).open(dir.join(&name))?;
+        self.manifest.segments.push(name);
+        write_manifest(dir, &self.manifest)?;
+        self.active = file;
+        self.active_len = 0;
+        Ok(())
+    }
+
+    /// Live nodes matching a filter. File filters use the per-file index;
+    /// anything else scans payloads.
+    fn matching_nodes(&self, filter: &VectorFilter) -> Vec<u32> {
+        let is_live = |n: &u32| !self.deleted[*n as usize];
+        match &filter.file_ids {
+            Some(ids) => ids
+                .iter()
+                .filter_map(|f| self.by_file.get(f))
+                .flatten()
+                .copied()
+                .filter(is_live)
+                .filter(|n| filter.matches(&self.payloads[*n as usize]))
+                .collect(),
+            None => (0..self.ids.len() as u32)
+                .filter(is_live)
+                .filter(|n| filter.matches(&self.payloads[*n as usize]))
+                .collect(),
+        }
+    }
+
+    fn hit(&self, node: u32, score: f32) -> ScoredPoint {
+        ScoredPoint { id: self.ids[node as usize].clone(), score, payload: self.payloads[node as usize].clone() }
+    }
+
+    fn exact_search(&self, q: &[f32], k: usize, nodes: &[u32]) -> Vec<ScoredPoint> {
+        let mut scored: Vec<(u32, f32)> = nodes.iter().map(|&n| (n, dot(q, self.vectors.vector(n)))).collect();
+        scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        scored.into_iter().take(k).map(|(n, s)| self.hit(n, s)).collect()
+    }
+
+    fn search(&self, query: &[f32], k: usize, filter: &VectorFilter) -> Vec<ScoredPoint> {
+        if self.live.is_empty() || k == 0 || query.len() != self.vectors.dim {
+            return Vec::new();
+        }
+        let mut q = query.to_vec();
+        l2_normalize(&mut q);
+
+        // A file filter narrows the candidates to a few chunks: scan them exactly
+        if filter.file_ids.is_some() {
+            return self.exact_search(&q, k, &self.matching_nodes(filter));
+        }
+
+        // Widen the beam to make room for tombstones and filtered-out nodes
+        let widen = if filter.is_empty() { 1 } else { 4 };
+        let ef = (HNSW_EF_SEARCH.max(k * 2) * widen) + self.deleted_count.min(k * 4);
+        let hits: Vec<ScoredPoint> = self
+            .graph
+            .search(&q, ef, &self.vectors)
+            .into_iter()
+            .filter(|(n, _)| !self.deleted[*n as usize] && filter.matches(&self.payloads[*n as usize]))
+            .take(k)
+            .map(|(n, s)| self.hit(n, s))
+            .collect();
+        if hits.len() >= k.min(self.live.len()) {
+            return hits;
+        }
+        // The graph walk found too few matches (selective filter); fall back to a scan
+        self.exact_search(&q, k, &self.matching_nodes(filter))
+    }
+
+    /// Write the current graph to a snapshot and point the manifest at it.
+    fn checkpoint(&mut self, dir: &Path) -> Result<()> {
+        let name = format!("graph-{:06}.hnsw", self.manifest.generation);
+        write_atomic(dir, &name, &self.graph.to_bytes())?;
+        let previous = self.manifest.graph.replace(name.clone());
+        write_manifest(dir, &self.manifest)?;
+        if let Some(old) = previous.filter(|o| *o != name) {
+            let _ = fs::remove_file(dir.join(old));
+        }
+        self.checkpoint_nodes = self.graph.len();
+        Ok(())
+    }
+
+    /// First step of a compaction: move writes to a fresh segment and copy out
+    /// the live points of everything before it.
+    fn begin_compaction(&mut self, dir: &Path) -> Result<Compaction> {
+        let segment = segment_name(self.next_segment);
+        self.next_segment += 1;
+        self.roll_over(dir)?;
+        let replaced = self.manifest.segments[..self.manifest.segments.len() - 1].to_vec();
+
+        let mut remap: Vec<Option<u32>> = Vec::with_capacity(self.ids.len());
+        let mut vectors = Vectors { dim: self.vectors.dim, data: Vec::with_capacity(self.live.len() * self.vectors.dim) };
+        let mut ids = Vec::with_capacity(self.live.len());
+        let mut payloads = Vec::with_capacity(self.live.len());
+        for node in 0..self.ids.len() as u32 {
+            if self.deleted[node as usize] {
+                remap.push(None);
+                continue;
+            }
+            remap.push(Some(ids.len() as u32));
+            vectors.data.extend_from_slice(self.vectors.vector(node));
+            ids.push(self.ids[node as usize].clone());
+            payloads.push(self.payloads[node as usize].clone());
+        }
+        Ok(Compaction {
+            generation: self.manifest.generation + 1,
+            segment,
+            replaced,
+            old_graph: self.graph.clone(),
+            remap,
+            vectors,
+            ids,
+            payloads,
+        })
+    }
+
+    /// Last step of a compaction: append the points written since it began,
+    /// carry over tombstones set in the meantime and switch the manifest.
+    /// Returns the number of points dropped.
+    fn finish_compaction(&mut self, dir: &Path, c: Compaction, mut graph: Hnsw) -> Result<usize> {
+        if self.manifest.generation + 1 != c.generation || !self.manifest.segments.starts_with(&c.replaced) {
+            return Err(anyhow!("embedded vector store changed generation during compaction"));
+        }
+        let graph_name = c.graph_name();
+        let Compaction { segment, replaced, remap, mut vectors, mut ids, mut payloads, .. } = c;
+        let removed = remap.iter().filter(|r| r.is_none()).count();
+        let snapshot_nodes = remap.len();
+
+        let mut deleted = vec![false; ids.len()];
+        for (old, new) in remap.iter().enumerate() {
+            if let Some(new) = new {
+                deleted[*new as usize] = self.deleted[old];
+            }
+        }
+        let checkpoint_nodes = graph.len();
+        for old in snapshot_nodes..self.ids.len() {
+            let node = ids.len() as u32;
+            vectors.data.extend_from_slice(self.vectors.vector(old as u32));
+            ids.push(self.ids[old].clone());
+            payloads.push(self.payloads[old].clone());
+            deleted.push(self.deleted[old]);
+            graph.insert(node, &vectors);
+        }
+
+        let mut segments = vec![segment];
+        segments.extend_from_slice(&self.manifest.segments[replaced.len()..]);
+        let manifest = Manifest { generation: self.manifest.generation + 1, dim: self.vectors.dim, segments, graph: Some(graph_name) };
+        write_manifest(dir, &manifest)?;
+        for s in replaced {
+            let _ = fs::remove_file(dir.join(s));
+        }
+        if let Some(g) = self.manifest.graph.take() {
+            let _ = fs::remove_file(dir.join(g));
+        }
+
+        let mut live: HashMap<String, u32> = HashMap::new();
+        let mut by_file: HashMap<String, Vec<u32>> = HashMap::new();
+        for (node, id) in ids.iter().enumerate() {
+            by_file.entry(file_of(id, &payloads[node])).or_default().push(node as u32);
+            if !deleted[node] {
+                live.insert(id.clone(), node as u32);
+            }
+        }
+        self.manifest = manifest;
+        self.deleted_count = deleted.iter().filter(|d| **d).count();
+        self.deleted = deleted;
+        self.checkpoint_nodes = checkpoint_nodes;
+        self.vectors = vectors;
+        self.ids = ids;
+        self.payloads = payloads;
+        self.live = live;
+        self.by_file = by_file;
+        self.graph = graph;
+        Ok(removed)
+    }
+}
+
+/// Live points copied out of the state by `begin_compaction`.
+struct Compaction {
+    generation: u64,
+    /// Name of the segment the live points are rewritten into
+    segment: String,
+    /// Segments the rewritten one replaces
+    replaced: Vec<String>,
+    old_graph: Hnsw,
+    /// New node number of every node in the snapshot, `None` if tombstoned
+    remap: Vec<Option<u32>>,
+    vectors: Vectors,
+    ids: Vec<String>,
+    payloads: Vec<serde_json::Value>,
+}
+
+impl Compaction {
+    fn graph_name(&self) -> String {
+        format!("graph-{:06}.hnsw", self.generation)
+    }
+
+    /// Write the live points to the new segment and the compacted graph to
+    /// its snapshot file. Runs without the state lock.
+    fn write(mut self, dir: &Path) -> Result<(Self, Hnsw)> {
+        let mut w = BufWriter::new(File::create(dir.join(&self.segment))?);
+        let mut buf = Vec::new();
+        for (node, (id, payload)) in self.ids.iter().zip(&self.payloads).enumerate() {
+            let point = VectorPoint { id: id.clone(), vector: self.vectors.vector(node as u32).to_vec(), payload: payload.clone() };
+            buf.clear();
+            encode_upsert(&mut buf, &point)?;
+            w.write_all(&buf)?;
+        }
+        w.flush()?;
+        w.get_ref().sync_all()?;
+        let graph = self.old_graph.compact(&self.remap, &self.vectors);
+        self.old_graph = Hnsw::new(HNSW_M, HNSW_EF_CONSTRUCTION);
+        write_atomic(dir, &self.graph_name(), &graph.to_bytes())?;
+        Ok((self, graph))
+    }
+}
+
+#[async_trait]
+impl VectorStore for EmbeddedStore {
+    fn name(&self) -> &str {
+        "embedded"
+    }
+
+    async fn ensure_ready(&self, dim: usize) -> Result<()> {
+        self.blocking(move |s| s.open(dim)).await?;
+        if !self.shared.maintenance_started.swap(true, Ordering::SeqCst) {
+            let shared = self.shared.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(MAINTENANCE_INTERVAL).await;
+                    let s = shared.clone();
+                    match tokio::task::spawn_blocking(move || s.maintain()).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => error!("Embedded vector store maintenance failed: {}", e),
+                        Err(e) => error!("Embedded vector store maintenance panicked: {}", e),
+                    }
+                }
+            });
+        }
+        info!("Embedded vector store ready at {}", self.dir.display());
+        Ok(())
+    }
+
+    async fn upsert(&self, points: Vec<VectorPoint>) -> Result<()> {
+        self.blocking(move |s| s.with_state_mut(|st| st.upsert(&s.dir, points))).await
+    }
+
+    async fn delete(&self, filter: &VectorFilter) -> Result<()> {
+        let filter = filter.clone();
+        self.blocking(move |s| s.with_state_mut(|st| st.delete(&s.dir, &filter))).await
+    }
+
+    async fn search(&self, vector: &[f32], k: usize, filter: &VectorFilter) -> Result<Vec<ScoredPoint>> {
+        let (vector, filter) = (vector.to_vec(), filter.clone());
+        self.blocking(move |s| s.with_state(|st| st.search(&vector, k, &filter))).await
+    }
+
+    async fn count(&self, filter: &VectorFilter) -> Result<usize> {
+        let filter = filter.clone();
+        self.blocking(move |s| {
+            s.with_state(|st| if filter.is_empty() { st.live.len() } else { st.matching_nodes(&filter).len() })
+        })
+        .await
+    }
+
+    async fn scroll(&self, filter: &VectorFilter, offset: Option<String>, limit: usize) -> Result<(Vec<VectorPoint>, Option<String>)> {
+        let filter = filter.clone();
+        self.blocking(move |s| {
+            s.with_state(|st| {
+                let mut nodes: Vec<u32> = st
+                    .matching_nodes(&filter)
+                    .into_iter()
+                    .filter(|n| offset.as_ref().map(|o| &st.ids[*n as usize] > o).unwrap_or(true))
+                    .collect();
+                nodes.sort_by(|a, b| st.ids[*a as usize].cmp(&st.ids[*b as usize]));
+                let page: Vec<VectorPoint> = nodes
+                    .iter()
+                    .take(limit)
+                    .map(|&n| VectorPoint {
+                        id: st.ids[n as usize].clone(),
+                        vector: st.vectors.vector(n).to_vec(),
+                        payload: st.payloads[n as usize].clone(),
+                    })
+                    .collect();
+                let next = if nodes.len() > limit { page.last().map(|p| p.id.clone()) } else { None };
+                (page, next)
+            })
+        })
+        .await
+    }
+}
+
+fn file_of(id: &str, payload: &serde_json::Value) -> String {
+    payload.get("file_id").and_then(|v| v.as_str()).unwrap_or(id).to_string()
+}
+
+fn segment_name(n: u64) -> String {
+    format!("seg-{n:06}.log")
+}
+
+/// Record framing: `[u32 body_len][u32 crc32(body)][body]`, little-endian.
+fn frame(buf: &mut Vec<u8>, body: &[u8]) {
+    buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(body).to_le_bytes());
+    buf.extend_from_slice(body);
+}
+
+fn encode_upsert(buf: &mut Vec<u8>, p: &VectorPoint) -> Result<()> {
+    let payload = serde_json::to_vec(&p.payload)?;
+    let mut body = Vec::with_capacity(1 + 2 + p.id.len() + 4 + payload.len() + 4 + p.vector.len() * 4);
+    body.push(RECORD_UPSERT);
+    body.extend_from_slice(&(p.id.len() as u16).to_le_bytes());
+    body.extend_from_slice(p.id.as_bytes());
+    body.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    body.extend_from_slice(&payload);
+    body.extend_from_slice(&(p.vector.len() as u32).to_le_bytes());
+    for x in &p.vector {
+        body.extend_from_slice(&x.to_le_bytes());
+    }
+    frame(buf, &body);
+    Ok(())
+}
+
+fn encode_delete(buf: &mut Vec<u8>, id: &str) {
+    let mut body = Vec::with_capacity(3 + id.len());
+    body.push(RECORD_DELETE);
+    body.extend_from_slice(&(id.len() as u16).to_le_bytes());
+    body.extend_from_slice(id.as_bytes());
+    frame(buf, &body);
+}
+
+fn decode_record(body: &[u8]) -> Result<Record> {
+    let bad = || anyhow!("malformed record");
+    let take = |pos: &mut usize, n: usize| -> Result<&[u8]> {
+        let out = body.get(*pos..*pos + n).ok_or_else(bad)?;
+        *pos += n;
+        Ok(out)
+    };
+    let mut pos = 0;
+    let kind = take(&mut pos, 1)?[0];
+    let id_len = u16::from_le_bytes(take(&mut pos, 2)?.try_into()?) as usize;
+    let id = String::from_utf8(take(&mut pos, id_len)?.to_vec())?;
+    match kind {
+        RECORD_UPSERT => {
+            let payload_len = u32::from_le_bytes(take(&mut pos, 4)?.try_into()?) as usize;
+            let payload = serde_json::from_slice(take(&mut pos, payload_len)?)?;
+            let dim = u32::from_le_bytes(take(&mut pos, 4)?.try_into()?) as usize;
+            let vector = take(&mut pos, dim * 4)?
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect();
+            Ok(Record::Upsert(VectorPoint { id, vector, payload }))
+        }
+        RECORD_DELETE => Ok(Record::Delete(id)),
+        _ => Err(bad()),
+    }
+}
+
+/// Read all intact records of a segment. A torn or corrupt tail (crash during
+/// a write) is truncated so later appends start on a clean record boundary.
+fn read_segment(path: &Path) -> Result<Vec<Record>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    let mut records = Vec::new();
+    let mut pos = 0usize;
+    while pos < bytes.len() {
+        let Some(header) = bytes.get(pos..pos + 8) else { break };
+        let len = u32::from_le_bytes(header[..4].try_into()?) as usize;
+        let crc = u32::from_le_bytes(header[4..].try_into()?);
+        let Some(body) = bytes.get(pos + 8..pos + 8 + len) else { break };
+        if crc32fast::hash(body) != crc {
+            break;
+        }
+        match decode_record(body) {
+            Ok(r) => records.push(r),
+            Err(_) => break,
+        }
+        pos += 8 + len;
+    }
+    if pos < bytes.len() {
+        warn!("Truncating {} bytes of incomplete records from {}", bytes.len() - pos, path.display());
+        OpenOptions::new().write(true).open(path)?.set_len(pos as u64)?;
+    }
+    Ok(records)
+}
+
+/// Write a file via a temporary name, fsync it, rename over the target and
+/// fsync the directory so the rename itself is durable.
+fn write_atomic(dir: &Path, name: &str, bytes: &[u8]) -> Result<()> {
+    let tmp = dir.join(format!("{name}.tmp"));
+    {
+        let mut f = File::create(&tmp)?;
+        f.write_all(bytes)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp, dir.join(name))?;
+    if let Ok(d) = File::open(dir) {
+        let _ = d.sync_all();
+    }
+    Ok(())
+}
+
+fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<()> {
+    write_atomic(dir, "MANIFEST", &serde_json::to_vec_pretty(manifest)?)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const DIM: usize = 8;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("astra-embedded-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Open the store the way `ensure_ready` does, minus the maintenance task.
+    fn open(dir: &Path, dim: usize) -> Shared {
+        let shared = Shared { dir: dir.to_path_buf(), state: RwLock::new(None), maintenance_started: AtomicBool::new(false) };
+        shared.open(dim).unwrap();
+        shared
+    }
+
+    /// Distinct vectors: point `i` leans towards axis `i % DIM`, by more for larger `i`.
+    fn point(file: &str, i: usize) -> VectorPoint {
+        let mut vector = vec![0.1; DIM];
+        vector[i % DIM] += 1.0 + (i / DIM) as f32;
+        VectorPoint { id: format!("{file}-{i}"), vector, payload: json!({ "file_id": file }) }
+    }
+
+    fn upsert(s: &Shared, points: Vec<VectorPoint>) -> Result<()> {
+        s.with_state_mut(|st| st.upsert(&s.dir, points))
+    }
+
+    fn delete_file(s: &Shared, file: &str) {
+        s.with_state_mut(|st| st.delete(&s.dir, &VectorFilter::file(file))).unwrap();
+    }
+
+    fn live_ids(s: &Shared) -> Vec<String> {
+        let mut ids: Vec<String> = s.with_state(|st| st.live.keys().cloned().collect()).unwrap();
+        ids.sort();
+        ids
+    }
+
+    fn top_hit(s: &Shared, vector: &[f32]) -> String {
+        s.with_state(|st| st.search(vector, 1, &VectorFilter::default())).unwrap()[0].id.clone()
+    }
+
+    fn segment_files(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap().filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with("seg-")).count()
+    }
+
+    #[test]
+    fn writes_and_tombstones_survive_reopen() {
+        let dir = temp_dir("reopen");
+        let s = open(&dir, DIM);
+        upsert(&s, (0..5).map(|i| point("a", i)).chain((0..5).map(|i| point("b", i))).collect()).unwrap();
+        let mut moved = point("a", 0);
+        moved.vector = point("x", 7).vector;
+        upsert(&s, vec![moved.clone()]).unwrap();
+        delete_file(&s, "b");
+        drop(s);
+
+        let s = open(&dir, DIM);
+        assert_eq!(live_ids(&s), vec!["a-0", "a-1", "a-2", "a-3", "a-4"]);
+        assert_eq!(s.with_state(|st| st.deleted_count).unwrap(), 6);
+        assert_eq!(top_hit(&s, &moved.vector), "a-0");
+        assert!(Shared { dir: dir.clone(), state: RwLock::new(None), maintenance_started: AtomicBool::new(false) }.open(DIM + 1).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn torn_tail_is_truncated_on_reopen() {
+        let dir = temp_dir("torn");
+        let s = open(&dir, DIM);
+        upsert(&s, (0..3).map(|i| point("a", i)).collect()).unwrap();
+        let segment = s.with_state(|st| dir.join(st.manifest.segments.last().unwrap())).unwrap();
+        drop(s);
+
+        // A crash halfway through the next record: a header and part of the body
+        let mut torn = Vec::new();
+        encode_upsert(&mut torn, &point("a", 3)).unwrap();
+        let intact_len = fs::metadata(&segment).unwrap().len();
+        OpenOptions::new().append(true).open(&segment).unwrap().write_all(&torn[..torn.len() / 2]).unwrap();
+
+        let s = open(&dir, DIM);
+        assert_eq!(fs::metadata(&segment).unwrap().len(), intact_len);
+        assert_eq!(live_ids(&s), vec!["a-0", "a-1", "a-2"]);
+        upsert(&s, vec![point("a", 4)]).unwrap();
+        drop(s);
+
+        let s = open(&dir, DIM);
+        assert_eq!(live_ids(&s), vec!["a-0", "a-1", "a-2", "a-4"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_append_leaves_no_trace() {
+        let dir = temp_dir("failed-append");
+        let s = open(&dir, DIM);
+        upsert(&s, vec![point("a", 0)]).unwrap();
+        // A read-only handle fails the write and the rollback, forcing a new segment
+        s.with_state_mut(|st| {
+            st.active = File::open(dir.join(st.manifest.segments.last().unwrap()))?;
+            Ok(())
+        })
+        .unwrap();
+        assert!(upsert(&s, vec![point("a", 1)]).is_err());
+        assert_eq!(live_ids(&s), vec!["a-0"]);
+        upsert(&s, vec![point("a", 2)]).unwrap();
+        assert_eq!(s.with_state(|st| st.manifest.segments.len()).unwrap(), 2);
+        drop(s);
+
+        let s = open(&dir, DIM);
+        assert_eq!(live_ids(&s), vec!["a-0", "a-2"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn compaction_drops_tombstones_and_keeps_concurrent_writes() {
+        let dir = temp_dir("compact");
+        let s = open(&dir, DIM);
+        upsert(&s, (0..20).map(|i| point("a", i)).collect()).unwrap();
+        upsert(&s, (0..20).map(|i| point("b", i)).collect()).unwrap();
+        delete_file(&s, "b");
+        s.with_state_mut(|st| st.checkpoint(&s.dir)).unwrap();
+
+        let compaction = s.with_state_mut(|st| st.begin_compaction(&s.dir)).unwrap();
+        // Written while the compaction runs outside the lock
+        upsert(&s, (0..5).map(|i| point("c", i)).collect()).unwrap();
+        let mut moved = point("a", 1);
+        moved.vector = point("x", 3).vector;
+        upsert(&s, vec![moved.clone()]).unwrap();
+        let (compaction, graph) = compaction.write(&s.dir).unwrap();
+        let removed = s.with_state_mut(|st| st.finish_compaction(&s.dir, compaction, graph)).unwrap();
+        assert_eq!(removed, 20);
+
+        let expected: Vec<String> = {
+            let mut ids: Vec<String> = (0..20).map(|i| format!("a-{i}")).chain((0..5).map(|i| format!("c-{i}"))).collect();
+            ids.sort();
+            ids
+        };
+        let check = |s: &Shared| {
+            assert_eq!(live_ids(s), expected);
+            s.with_state(|st| {
+                assert_eq!(st.ids.len(), 26);
+                assert_eq!(st.deleted_count, 1);
+                assert_eq!(st.graph.len(), 26);
+                assert_eq!(st.manifest.segments.len(), 2);
+            })
+            .unwrap();
+            assert_eq!(top_hit(s, &moved.vector), "a-1");
+            assert_eq!(top_hit(s, &point("c", 4).vector), "c-4");
+            assert!(s.with_state(|st| st.search(&point("b", 3).vector, 40, &VectorFilter::default())).unwrap().iter().all(|h| !h.id.starts_with("b-")));
+        };
+        check(&s);
+        assert_eq!(segment_files(&dir), 2);
+        drop(s);
+
+        let s = open(&dir, DIM);
+        check(&s);
+        assert_eq!(s.with_state(|st| st.checkpoint_nodes).unwrap(), 20);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Backs the sub-100ms query target for a few hundred thousand vectors.
+    /// Run with `cargo test --release -- --ignored search_latency_at_scale --nocapture`.
+    #[test]
+    #[ignore]
+    fn search_latency_at_scale() {
+        const POINTS: usize = 300_000;
+        const BENCH_DIM: usize = 384;
+        let dir = temp_dir("latency");
+        let s = open(&dir, BENCH_DIM);
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        let mut random_vector = move || -> Vec<f32> {
+            (0..BENCH_DIM)
+                .map(|_| {
+                    x ^= x << 13;
+                    x ^= x >> 7;
+                    x ^= x << 17;
+                    (x >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+                })
+                .collect()
+        };
+        let started = std::time::Instant::now();
+        for batch in 0..POINTS / 1000 {
+            let points = (0..1000)
+                .map(|i| VectorPoint {
+                    id: format!("p-{batch}-{i}"),
+                    vector: random_vector(),
+                    payload: json!({ "file_id": format!("f-{batch}"), "type": "chunk" }),
+                })
+                .collect();
+            upsert(&s, points).unwrap();
+        }
+        println!("Indexed {POINTS} vectors in {:?}", started.elapsed());
+
+        let mut times: Vec<std::time::Duration> = (0..200)
+            .map(|_| {
+                let q = random_vector();
+                let t = std::time::Instant::now();
+                let hits = s.with_state(|st| st.search(&q, 10, &VectorFilter::default())).unwrap();
+                assert_eq!(hits.len(), 10);
+                t.elapsed()
+            })
+            .collect();
+        times.sort();
+        let p50 = times[times.len() / 2];
+        let p99 = times[times.len() * 99 / 100];
+        println!("Search over {POINTS} vectors: p50 {p50:?}, p99 {p99:?}");
+        assert!(p99 < std::time::Duration::from_millis(100), "p99 search latency {p99:?}");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
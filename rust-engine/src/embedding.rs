@@ -0,0 +1,640 @@
+use crate::outbound::{self, estimate_tokens, HttpFailure, Outbound};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{MySqlPool, Row};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Turns text into fixed-size vectors. Shared by the file and query workers so
+/// that documents and queries are always embedded the same way.
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    /// Short identifier used in logs.
+    fn name(&self) -> &str;
+
+    /// Length of every vector returned by this embedder.
+    fn dim(&self) -> usize;
+
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>>;
+
+    async fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        self.embed_batch(&[text.to_string()])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow!("{} returned no embedding", self.name()))
+    }
+
+    /// Embed a search query. The same as `embed` unless the embedder weights
+    /// queries differently from the documents they are matched against.
+    async fn embed_query(&self, text: &str) -> Result<Vec<f32>> {
+        self.embed(text).await
+    }
+}
+
+/// Build the embedder selected by EMBEDDER (`gemini`, `openai`, `lexical` or
+/// `deterministic`). Defaults to Gemini when GEMINI_API_KEY is set and to the
+/// offline lexical embedder otherwise.
+pub async fn embedder_from_env(pool: &MySqlPool) -> Result<Arc<dyn Embedder>> {
+    let has_gemini_key = std::env::var("GEMINI_API_KEY").map(|k| !k.is_empty()).unwrap_or(false);
+    let kind = std::env::var("EMBEDDER")
+        .unwrap_or_else(|_| if has_gemini_key { "gemini" } else { "lexical" }.to_string())
+        .to_lowercase();
+    let dim_override = std::env::var("EMBEDDING_DIM").ok().and_then(|v| v.parse::<usize>().ok());
+
+    let embedder: Arc<dyn Embedder> = match kind.as_str() {
+        "gemini" => {
+            let api_key = std::env::var("GEMINI_API_KEY")
+                .ok()
+                .filter(|k| !k.is_empty())
+                .ok_or_else(|| anyhow!("EMBEDDER=gemini requires GEMINI_API_KEY"))?;
+            let model = std::env::var("GEMINI_EMBED_MODEL").unwrap_or_else(|_| "text-embedding-004".to_string());
+            Arc::new(GeminiEmbedder::new(api_key, model, dim_override.unwrap_or(768)))
+        }
+        "openai" => {
+            let base = std::env::var("OPENAI_EMBED_URL").unwrap_or_else(|_| "http://localhost:11434/v1".to_string());
+            let model = std::env::var("OPENAI_EMBED_MODEL").unwrap_or_else(|_| "nomic-embed-text".to_string());
+            let api_key = std::env::var("OPENAI_API_KEY").ok().filter(|k| !k.is_empty());
+            let mut e = OpenAiEmbedder::new(&base, model, api_key, dim_override.unwrap_or(0));
+            if e.dim == 0 {
+                // Ask the server once instead of requiring EMBEDDING_DIM for every model
+                e.dim = e.embed("dimension probe").await?.len();
+            }
+            Arc::new(e)
+        }
+        "lexical" => {
+            let e = LexicalEmbedder::new(dim_override.unwrap_or(LexicalEmbedder::DEFAULT_DIM));
+            match std::env::var("LEXICAL_IDF_PATH") {
+                Ok(path) => Arc::new(e.with_idf_file(&path)?),
+                Err(_) => {
+                    let e = Arc::new(e);
+                    spawn_idf_refresh(e.clone(), pool.clone());
+                    e
+                }
+            }
+        }
+        "deterministic" => Arc::new(DeterministicEmbedder::new(dim_override.unwrap_or(DeterministicEmbedder::DEFAULT_DIM))),
+        other => return Err(anyhow!("unknown EMBEDDER '{other}'")),
+    };
+    info!("Using {} embedder (dim {})", embedder.name(), embedder.dim());
+    Ok(embedder)
+}
+
+/// Gemini `batchEmbedContents` API.
+pub struct GeminiEmbedder {
+    client: Client,
+    api_key: String,
+    model: String,
+    dim: usize,
+    outbound: Arc<Outbound>,
+}
+
+impl GeminiEmbedder {
+    /// Maximum requests per batchEmbedContents call.
+    const BATCH: usize = 100;
+
+    pub fn new(api_key: String, model: String, dim: usize) -> Self {
+        // Shares limits and the breaker with Gemini text generation (same key)
+        Self { client: Client::new(), api_key, model, dim, outbound: outbound::dependency("gemini", true) }
+    }
+}
+
+#[async_trait]
+impl Embedder for GeminiEmbedder {
+    fn name(&self) -> &str {
+        "gemini"
+    }
+
+    fn dim(&self) -> usize {
+        self.dim
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        #[derive(Deserialize)]
+        struct Embedding { values: Vec<f32> }
+        #[derive(Deserialize)]
+        struct Response { embeddings: Vec<Embedding> }
+
+        let url = format!(
+            "https://generativelanguage.googleapis.com/v1beta/models/{}:batchEmbedContents?key={}",
+            self.model, self.api_key
+        );
+        let mut out = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(Self::BATCH) {
+            let requests: Vec<serde_json::Value> = batch
+                .iter()
+                .map(|t| json!({
+                    "model": format!("models/{}", self.model),
+                    "content": {"parts": [{"text": t}]},
+                    "outputDimensionality": self.dim,
+                }))
+                .collect();
+            let body = json!({ "requests": requests });
+            let tokens = batch.iter().map(|t| estimate_tokens(t)).sum();
+            let data: Response = self
+                .outbound
+                .call(tokens, || async {
+                    let resp = self.client.post(&url).json(&body).send().await?;
+                    let status = resp.status();
+                    if !status.is_success() {
+                        let headers = resp.headers().clone();
+                        let t = resp.text().await.unwrap_or_default();
+                        return Err(HttpFailure::from_response(status, &headers, &t));
+                    }
+                    Ok(resp.json::<Response>().await?)
+                })
+                .await
+                .map_err(|e| failure("gemini embedding failed", e))?;
+            out.extend(data.embeddings.into_iter().map(|e| e.values));
+        }
+        check_dims(self.name(), self.dim, texts.len(), &out)?;
+        Ok(out)
+    }
+}
+
+/// Any server implementing the OpenAI `/embeddings` endpoint (OpenAI, Ollama,
+/// llama.cpp, vLLM, text-embeddings-inference, ...).
+pub struct OpenAiEmbedder {
+    client: Client,
+    base: String,
+    model: String,
+    api_key: Option<String>,
+    dim: usize,
+    outbound: Arc<Outbound>,
+}
+
+impl OpenAiEmbedder {
+    const BATCH: usize = 64;
+
+    pub fn new(base: &str, model: String, api_key: Option<String>, dim: usize) -> Self {
+        Self {
+            client: Client::new(),
+            base: base.trim_end_matches('/').to_string(),
+            model,
+            api_key,
+            dim,
+            outbound: outbound::dependency("openai_embed", true),
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for OpenAiEmbedder {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    fn dim(&self) -> usize {
+        self.dim
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        #[derive(Deserialize)]
+        struct Item { index: usize, embedding: Vec<f32> }
+        #[derive(Deserialize)]
+        struct Response { data: Vec<Item> }
+
+        let url = format!("{}/embeddings", self.base);
+        let mut out = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(Self::BATCH) {
+            let body = json!({ "model": self.model, "input": batch });
+            let tokens = batch.iter().map(|t| estimate_tokens(t)).sum();
+            let mut data: Response = self
+                .outbound
+                .call(tokens, || async {
+                    let mut req = self.client.post(&url).json(&body);
+                    if let Some(key) = &self.api_key {
+                        req = req.bearer_auth(key);
+                    }
+                    let resp = req.send().await?;
+                    let status = resp.status();
+                    if !status.is_success() {
+                        let headers = resp.headers().clone();
+                        let t = resp.text().await.unwrap_or_default();
+                        return Err(HttpFailure::from_response(status, &headers, &t));
+                    }
+                    Ok(resp.json::<Response>().await?)
+                })
+                .await
+                .map_err(|e| failure("embedding request failed", e))?;
+            data.data.sort_by_key(|i| i.index);
+            out.extend(data.data.into_iter().map(|i| i.embedding));
+        }
+        // dim is 0 only while probing the server
+        if self.dim != 0 {
+            check_dims(self.name(), self.dim, texts.len(), &out)?;
+        }
+        Ok(out)
+    }
+}
+
+/// Offline embedder for tests and demos without any API. Identical text always
+/// maps to the identical unit vector; it carries no semantic similarity.
+pub struct DeterministicEmbedder {
+    dim: usize,
+}
+
+impl DeterministicEmbedder {
+    pub const DEFAULT_DIM: usize = 64;
+
+    pub fn new(dim: usize) -> Self {
+        Self { dim: dim.max(1) }
+    }
+
+    fn vector_for(&self, text: &str) -> Vec<f32> {
+        // FNV-1a seed, then xorshift to fill the vector
+        let mut state = fnv1a(text.as_bytes());
+        let mut v = Vec::with_capacity(self.dim);
+        for _ in 0..self.dim {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            v.push((state >> 40) as f32 / (1u64 << 23) as f32 - 1.0);
+        }
+        l2_normalize(&mut v);
+        v
+    }
+}
+
+#[async_trait]
+impl Embedder for DeterministicEmbedder {
+    fn name(&self) -> &str {
+        "deterministic"
+    }
+
+    fn dim(&self) -> usize {
+        self.dim
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        Ok(texts.iter().map(|t| self.vector_for(t)).collect())
+    }
+}
+
+/// Offline embedder based on feature hashing, for air-gapped deployments.
+///
+/// Text is lowercased, split into word tokens, stripped of stopwords and
+/// lightly stemmed; unigrams and adjacent-token bigrams are hashed into `dim`
+/// signed buckets. Each feature is weighted by sublinear term frequency
+/// (`1 + ln tf`), then the vector is L2-normalized. Queries also weight their
+/// features by IDF, so cosine similarity behaves like a TF-IDF overlap score.
+/// IDF weights are fitted on the indexed chunks (see `fit_from_chunks`) and
+/// only used on the query side: stored vectors never depend on them, so a
+/// refit needs no re-embedding. A fixed JSON table (`{"token": idf}`, see
+/// LEXICAL_IDF_PATH) never changes and weights both sides.
+pub struct LexicalEmbedder {
+    dim: usize,
+    fixed_idf: HashMap<String, f32>,
+    corpus_idf: RwLock<Idf>,
+}
+
+/// Document frequencies of unigrams over a corpus. Bigrams are weighted by
+/// the mean of their two words, which keeps the table small.
+#[derive(Default)]
+struct Idf {
+    docs: usize,
+    df: HashMap<String, u32>,
+}
+
+impl Idf {
+    fn add(&mut self, text: &str) {
+        self.docs += 1;
+        for t in lexical_tokens(text).into_iter().collect::<HashSet<_>>() {
+            *self.df.entry(t).or_default() += 1;
+        }
+    }
+
+    /// Smoothed `ln((N + 1) / (df + 1)) + 1`; 1.0 for every feature before
+    /// anything was indexed.
+    fn weight(&self, feature: &str) -> f32 {
+        if self.docs == 0 {
+            return 1.0;
+        }
+        let unigram = |t: &str| ((self.docs as f32 + 1.0) / (*self.df.get(t).unwrap_or(&0) as f32 + 1.0)).ln() + 1.0;
+        match feature.split_once(' ') {
+            Some((a, b)) => (unigram(a) + unigram(b)) / 2.0,
+            None => unigram(feature),
+        }
+    }
+}
+
+/// `LEXICAL_IDF_REFRESH_SECS`: how often the lexical embedder refits its IDF
+/// weights on the stored chunks (default 3600).
+fn idf_refresh_interval() -> Duration {
+    let secs = std::env::var("LEXICAL_IDF_REFRESH_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(3600u64);
+    Duration::from_secs(secs.max(60))
+}
+
+/// Fit the IDF weights now and then periodically, so they follow the corpus
+/// as files are added and removed (on every replica alike, since they come
+/// from MySQL). Only queries use them, so stored vectors stay valid.
+fn spawn_idf_refresh(embedder: Arc<LexicalEmbedder>, pool: MySqlPool) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(idf_refresh_interval());
+        loop {
+            tick.tick().await;
+            match embedder.fit_from_chunks(&pool).await {
+                Ok(n) => info!("Fitted lexical IDF weights on {} chunks", n),
+                Err(e) => warn!("Failed to fit lexical IDF weights: {}", e),
+            }
+        }
+    });
+}
+
+impl LexicalEmbedder {
+    pub const DEFAULT_DIM: usize = 1024;
+    /// Bigrams carry more specific meaning ("oxygen gener") than either word alone.
+    const BIGRAM_WEIGHT: f32 = 1.5;
+
+    /// Rows read per query while fitting on the stored chunks.
+    const FIT_PAGE: i64 = 1000;
+
+    pub fn new(dim: usize) -> Self {
+        Self { dim: dim.max(16), fixed_idf: HashMap::new(), corpus_idf: RwLock::new(Idf::default()) }
+    }
+
+    /// Load fixed IDF weights keyed by (stemmed) unigram or "a b" bigram; they
+    /// take precedence over weights fitted on the corpus.
+    pub fn with_idf_file(mut self, path: &str) -> Result<Self> {
+        let raw = std::fs::read_to_string(path).map_err(|e| anyhow!("reading {path}: {e}"))?;
+        self.fixed_idf = serde_json::from_str(&raw).map_err(|e| anyhow!("parsing {path}: {e}"))?;
+        Ok(self)
+    }
+
+    /// Fit the corpus IDF weights on every stored chunk, a page at a time.
+    /// Returns the number of chunks seen.
+    pub async fn fit_from_chunks(&self, pool: &MySqlPool) -> Result<usize> {
+        let mut idf = Idf::default();
+        let mut after = String::new();
+        loop {
+            let rows = sqlx::query("SELECT id, heading, text FROM chunks WHERE id > ? ORDER BY id LIMIT ?")
+                .bind(&after)
+                .bind(Self::FIT_PAGE)
+                .fetch_all(pool)
+                .await?;
+            for row in &rows {
+                let heading: Option<String> = row.get("heading");
+                let text: String = row.get("text");
+                idf.add(&format!("{}\n{text}", heading.unwrap_or_default()));
+            }
+            match rows.last() {
+                Some(last) if rows.len() as i64 == Self::FIT_PAGE => after = last.get("id"),
+                _ => break,
+            }
+        }
+        let docs = idf.docs;
+        *self.corpus_idf.write().unwrap() = idf;
+        Ok(docs)
+    }
+
+    /// The vector of a stored document, or with `query` of a search query,
+    /// which alone is weighted by the corpus IDF.
+    fn vector_for(&self, text: &str, query: bool) -> Vec<f32> {
+        let tokens = lexical_tokens(text);
+        let mut tf: HashMap<String, f32> = HashMap::new();
+        for t in &tokens {
+            *tf.entry(t.clone()).or_default() += 1.0;
+        }
+        for pair in tokens.windows(2) {
+            *tf.entry(format!("{} {}", pair[0], pair[1])).or_default() += 1.0;
+        }
+
+        let corpus_idf = self.corpus_idf.read().unwrap();
+        let mut v = vec![0f32; self.dim];
+        for (feature, count) in tf {
+            let base = if feature.contains(' ') { Self::BIGRAM_WEIGHT } else { 1.0 };
+            let idf = match self.fixed_idf.get(&feature) {
+                Some(idf) => *idf,
+                None if query => corpus_idf.weight(&feature),
+                None => 1.0,
+            };
+            let weight = base * (1.0 + count.ln()) * idf;
+            let h = fnv1a(feature.as_bytes());
+            let bucket = (h % self.dim as u64) as usize;
+            // The sign bit keeps colliding features from always adding up
+            if h >> 63 == 0 {
+                v[bucket] += weight;
+            } else {
+                v[bucket] -= weight;
+            }
+        }
+        l2_normalize(&mut v);
+        v
+    }
+}
+
+#[async_trait]
+impl Embedder for LexicalEmbedder {
+    fn name(&self) -> &str {
+        "lexical"
+    }
+
+    fn dim(&self) -> usize {
+        self.dim
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        Ok(texts.iter().map(|t| self.vector_for(t, false)).collect())
+    }
+
+    async fn embed_query(&self, text: &str) -> Result<Vec<f32>> {
+        Ok(self.vector_for(text, true))
+    }
+}
+
+pub const STOPWORDS: &[&str] = &[
+    "a", "about", "above", "after", "again", "all", "also", "am", "an", "and", "any", "are", "as", "at", "be",
+    "because", "been", "before", "being", "below", "between", "both", "but", "by", "can", "could", "did", "do",
+    "does", "doing", "down", "during", "each", "etc", "few", "for", "from", "further", "had", "has", "have",
+    "having", "he", "her", "here", "hers", "him", "his", "how", "i", "if", "in", "into", "is", "it", "its",
+    "itself", "just", "may", "me", "more", "most", "must", "my", "no", "nor", "not", "now", "of", "off", "on",
+    "once", "only", "or", "other", "our", "ours", "out", "over", "own", "per", "same", "shall", "she", "should",
+    "so", "some", "such", "than", "that", "the", "their", "theirs", "them", "then", "there", "these", "they",
+    "this", "those", "through", "thus", "to", "too", "under", "until", "up", "upon", "us", "very", "via", "was",
+    "we", "were", "what", "when", "where", "which", "while", "who", "whom", "why", "will", "with", "within",
+    "would", "you", "your", "yours",
+];
+
+/// Lowercased, stopword-free, lightly stemmed word tokens. Hyphens inside a
+/// token are kept so part numbers like "683-17105" stay intact.
+fn lexical_tokens(text: &str) -> Vec<String> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '-'))
+        .map(|t| t.trim_matches('-').to_lowercase())
+        .filter(|t| t.chars().count() > 1 && !STOPWORDS.contains(&t.as_str()))
+        .map(|t| stem(&t))
+        .collect()
+}
+
+/// Minimal suffix stripping so "generation", "generator" and "generating"
+/// share a feature. Numbers and short words are left alone.
+pub fn stem(token: &str) -> String {
+    const SUFFIXES: &[&str] = &[
+        "ational", "ations", "ation", "ators", "ator", "ating", "ated", "ates", "ings", "ing", "ies", "ied", "es",
+        "ed", "s",
+    ];
+    if token.chars().any(|c| c.is_ascii_digit()) {
+        return token.to_string();
+    }
+    for suffix in SUFFIXES {
+        if let Some(stem) = token.strip_suffix(suffix) {
+            if stem.chars().count() < 3 || (*suffix == "s" && stem.ends_with('s')) {
+                continue;
+            }
+            return stem.to_string();
+        }
+    }
+    token.to_string()
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h
+}
+
+pub fn l2_normalize(v: &mut [f32]) {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        for x in v.iter_mut() {
+            *x /= norm;
+        }
+    }
+}
+
+/// Keep the `HttpFailure` downcastable (the file worker requeues transient
+/// ones) while logging the full message.
+fn failure(what: &str, e: HttpFailure) -> anyhow::Error {
+    let message = format!("{what}: {e}");
+    anyhow::Error::new(e).context(message)
+}
+
+fn check_dims(name: &str, dim: usize, expected: usize, out: &[Vec<f32>]) -> Result<()> {
+    if out.len() != expected {
+        return Err(anyhow!("{name} returned {} embeddings for {expected} inputs", out.len()));
+    }
+    if let Some(v) = out.iter().find(|v| v.len() != dim) {
+        return Err(anyhow!("{name} returned a {}-dim embedding, expected {dim}", v.len()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cosine(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(x, y)| x * y).sum()
+    }
+
+    fn fitted(docs: &[&str]) -> LexicalEmbedder {
+        let e = LexicalEmbedder::new(LexicalEmbedder::DEFAULT_DIM);
+        let mut idf = Idf::default();
+        for d in docs {
+            idf.add(d);
+        }
+        *e.corpus_idf.write().unwrap() = idf;
+        e
+    }
+
+    #[test]
+    fn tokens_drop_stopwords_and_stem() {
+        assert_eq!(lexical_tokens("The Oxygen Generation of the ISS"), vec!["oxygen", "gener", "iss"]);
+        assert_eq!(lexical_tokens("part 683-17105, rev-B"), vec!["part", "683-17105", "rev-b"]);
+        assert_eq!(stem("generators"), "gener");
+        assert_eq!(stem("bus"), "bus");
+        assert_eq!(stem("class"), "class");
+        assert_eq!(stem("r2d2s"), "r2d2s");
+    }
+
+    #[test]
+    fn idf_favors_rare_terms() {
+        let e = fitted(&["system power", "system thermal", "system oxygen"]);
+        let idf = e.corpus_idf.read().unwrap();
+        assert!(idf.weight("oxygen") > idf.weight("system"));
+        assert!(idf.weight("unseen") > idf.weight("oxygen"));
+        assert_eq!(Idf::default().weight("anything"), 1.0);
+    }
+
+    #[test]
+    fn vectors_are_normalized_and_deterministic() {
+        let e = LexicalEmbedder::new(64);
+        let a = e.vector_for("cabin air revitalization", false);
+        assert_eq!(a.len(), 64);
+        assert!((cosine(&a, &a) - 1.0).abs() < 1e-4);
+        assert_eq!(a, e.vector_for("cabin air revitalization", false));
+        assert!(e.vector_for("the of and", false).iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn refitting_the_idf_leaves_stored_vectors_valid() {
+        let e = LexicalEmbedder::new(LexicalEmbedder::DEFAULT_DIM);
+        let doc = "oxygen generation assembly";
+        let stored = e.vector_for(doc, false);
+        *e.corpus_idf.write().unwrap() = fitted(&["oxygen generation", "power generation", "thermal"]).corpus_idf.into_inner().unwrap();
+        assert_eq!(e.vector_for(doc, false), stored);
+        // Only the query side follows the new weights
+        assert_ne!(e.vector_for("oxygen generation", true), e.vector_for("oxygen generation", false));
+    }
+
+    /// Acceptance example: "oxygen generation" must rank the ECLS document of
+    /// demo-data (132-1-Final.pdf) above the unrelated files. Chunks are
+    /// embedded before the IDF is fitted, as on a fresh install, and the
+    /// query after.
+    #[tokio::test]
+    async fn oxygen_generation_ranks_the_eclss_document_first() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("demo-data");
+        let mut files: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        files.sort();
+        let e = LexicalEmbedder::new(LexicalEmbedder::DEFAULT_DIM);
+        let cfg = crate::chunking::ChunkConfig { size: 1200, overlap: 200 };
+        let mut idf = Idf::default();
+        let mut indexed = Vec::new();
+        for path in &files {
+            let pages = crate::extract::extract_pages(std::fs::read(path).unwrap(), "application/pdf").await.unwrap();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            for chunk in crate::chunking::chunk_pages(&pages, cfg) {
+                let text = format!("{}\n{}", chunk.heading.unwrap_or_default(), chunk.text);
+                indexed.push((name.clone(), e.embed_batch(&[text.clone()]).await.unwrap().remove(0)));
+                idf.add(&text);
+            }
+        }
+        assert!(files.len() >= 10 && indexed.len() > 100, "demo-data is missing");
+        *e.corpus_idf.write().unwrap() = idf;
+
+        // Files ranked by their best chunk, as the query worker groups hits
+        let q = e.embed_query("oxygen generation").await.unwrap();
+        let mut best: HashMap<&str, f32> = HashMap::new();
+        for (name, v) in &indexed {
+            let score = best.entry(name.as_str()).or_insert(f32::MIN);
+            *score = score.max(cosine(&q, v));
+        }
+        let mut ranked: Vec<(&str, f32)> = best.into_iter().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        assert_eq!(ranked[0].0, "132-1-Final.pdf", "{ranked:?}");
+        assert!(ranked[0].1 > 1.1 * ranked[1].1, "{ranked:?}");
+    }
+
+    #[test]
+    fn fixed_idf_overrides_the_corpus() {
+        let mut e = fitted(&["oxygen", "oxygen power"]);
+        e.fixed_idf.insert("power".to_string(), 0.0);
+        assert!(e.vector_for("power", false).iter().all(|x| *x == 0.0));
+        assert!(e.vector_for("power", true).iter().all(|x| *x == 0.0));
+    }
+
+    #[tokio::test]
+    async fn deterministic_embedder_is_stable() {
+        let e = DeterministicEmbedder::new(32);
+        let a = e.embed("hello").await.unwrap();
+        assert_eq!(a.len(), 32);
+        assert_eq!(a, e.embed("hello").await.unwrap());
+        assert_ne!(a, e.embed("world").await.unwrap());
+    }
+}
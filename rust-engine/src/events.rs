@@ -0,0 +1,181 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// Events buffered per subscriber before it starts missing them.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Progress of a query, pushed to `/api/query/stream` subscribers.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum QueryEvent {
+    /// The worker entered a stage: rewriting, embedding, searching, relationships,
+    /// answering, or waiting (requeued after a transient dependency failure)
+    Stage { stage: String },
+    /// Files retrieved for the query, in the shape of `related_files` in the result
+    Files { files: Vec<serde_json::Value> },
+    /// Next piece of the answer as the model generates it (before citation validation)
+    Token { text: String },
+    /// The stored result, including the validated answer and citations
+    Result { result: serde_json::Value },
+    Error { message: String },
+    /// Last event of a query: Completed, Failed or Cancelled
+    Done { status: String },
+}
+
+impl QueryEvent {
+    pub fn stage(stage: &str) -> Self {
+        Self::Stage { stage: stage.to_string() }
+    }
+
+    /// SSE event name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Stage { .. } => "stage",
+            Self::Files { .. } => "files",
+            Self::Token { .. } => "token",
+            Self::Result { .. } => "result",
+            Self::Error { .. } => "error",
+            Self::Done { .. } => "done",
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self, Self::Done { .. })
+    }
+}
+
+/// An event with its number within the query
+pub type Numbered = (usize, QueryEvent);
+
+struct Channel {
+    sender: broadcast::Sender<Numbered>,
+    /// Every event of the current attempt with its number, so late
+    /// subscribers and reconnects can catch up
+    history: Vec<Numbered>,
+    /// Number of the next event; keeps counting across attempts
+    next_seq: usize,
+}
+
+/// In-process fan-out of query events from the worker to stream subscribers.
+/// Events are numbered per query so clients can resume with `Last-Event-ID`.
+/// A channel lives until its query finishes; later subscribers are served
+/// from the stored result instead. Subscribers on a replica other than the
+/// worker's see no events here and fall back to polling the query row.
+#[derive(Default)]
+pub struct QueryEvents {
+    channels: Mutex<HashMap<String, Channel>>,
+}
+
+impl QueryEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn publish(&self, query_id: &str, event: QueryEvent) {
+        let mut channels = self.channels.lock().unwrap();
+        let channel = channels.entry(query_id.to_string()).or_insert_with(new_channel);
+        let seq = channel.next_seq;
+        channel.next_seq += 1;
+        channel.history.push((seq, event.clone()));
+        // No receivers is fine: nobody is watching this query
+        let _ = channel.sender.send((seq, event));
+    }
+
+    /// Forget the events of an earlier attempt (after a transient failure the
+    /// query starts over), so late subscribers don't see two answers' tokens
+    /// run together. Numbering continues where it left off.
+    pub fn start_attempt(&self, query_id: &str) {
+        if let Some(channel) = self.channels.lock().unwrap().get_mut(query_id) {
+            channel.history.clear();
+        }
+    }
+
+    /// Publish the terminal event and drop the channel.
+    pub fn finish(&self, query_id: &str, status: &str) {
+        self.publish(query_id, QueryEvent::Done { status: status.to_string() });
+        self.channels.lock().unwrap().remove(query_id);
+    }
+
+    /// Events published so far plus a receiver for the rest. Both are taken
+    /// under one lock so no event is missed or seen twice.
+    pub fn subscribe(&self, query_id: &str) -> (Vec<Numbered>, broadcast::Receiver<Numbered>) {
+        let mut channels = self.channels.lock().unwrap();
+        let channel = channels.entry(query_id.to_string()).or_insert_with(new_channel);
+        (channel.history.clone(), channel.sender.subscribe())
+    }
+
+    /// Drop a channel nobody published to once its last subscriber is gone
+    /// (e.g. a stream opened for a query that was never picked up).
+    pub fn release(&self, query_id: &str) {
+        let mut channels = self.channels.lock().unwrap();
+        if channels
+            .get(query_id)
+            .map(|c| c.next_seq == 0 && c.sender.receiver_count() == 0)
+            .unwrap_or(false)
+        {
+            channels.remove(query_id);
+        }
+    }
+}
+
+fn new_channel() -> Channel {
+    Channel { sender: broadcast::channel(CHANNEL_CAPACITY).0, history: Vec::new(), next_seq: 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seqs(history: &[Numbered]) -> Vec<usize> {
+        history.iter().map(|(seq, _)| *seq).collect()
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_history_then_live_events() {
+        let events = QueryEvents::new();
+        events.publish("q", QueryEvent::stage("embedding"));
+        events.publish("q", QueryEvent::stage("searching"));
+        let (history, mut rx) = events.subscribe("q");
+        assert_eq!(seqs(&history), vec![0, 1]);
+        events.publish("q", QueryEvent::Token { text: "Oxygen".into() });
+        let (seq, event) = rx.recv().await.unwrap();
+        assert_eq!((seq, event.name()), (2, "token"));
+
+        events.finish("q", "Completed");
+        let (seq, event) = rx.recv().await.unwrap();
+        assert_eq!(seq, 3);
+        assert!(event.is_done());
+        // The channel is gone; a new subscriber starts from scratch
+        assert!(events.subscribe("q").0.is_empty());
+    }
+
+    #[test]
+    fn a_new_attempt_drops_old_events_but_keeps_numbering() {
+        let events = QueryEvents::new();
+        events.publish("q", QueryEvent::Token { text: "half an ans".into() });
+        events.publish("q", QueryEvent::stage("waiting"));
+        events.start_attempt("q");
+        assert!(events.subscribe("q").0.is_empty());
+        events.publish("q", QueryEvent::stage("embedding"));
+        assert_eq!(seqs(&events.subscribe("q").0), vec![2]);
+        // No channel yet: nothing to reset
+        events.start_attempt("other");
+        assert!(events.channels.lock().unwrap().get("other").is_none());
+    }
+
+    #[test]
+    fn release_drops_only_unused_channels() {
+        let events = QueryEvents::new();
+        let (_, rx) = events.subscribe("idle");
+        drop(rx);
+        events.release("idle");
+        assert!(events.channels.lock().unwrap().get("idle").is_none());
+
+        events.publish("busy", QueryEvent::stage("searching"));
+        events.start_attempt("busy");
+        events.release("busy");
+        assert!(events.channels.lock().unwrap().get("busy").is_some());
+    }
+}
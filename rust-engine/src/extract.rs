@@ -0,0 +1,166 @@
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+use tracing::info;
+
+/// Text extracted from one page of a stored document. Page numbers are 1-based
+/// so they can be shown to users as-is.
+#[derive(Debug, Clone)]
+pub struct PageText {
+    pub page: u32,
+    pub text: String,
+}
+
+/// `PDF_EXTRACT_TIMEOUT_SECS`: longest a single PDF may take to parse (default 120).
+fn pdf_extract_timeout() -> Duration {
+    let secs = std::env::var("PDF_EXTRACT_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(120);
+    Duration::from_secs(secs)
+}
+
+/// Whether a MIME type is plain text that can be read as UTF-8.
+pub fn is_text_type(mime_type: &str) -> bool {
+    let mime = mime_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    mime.starts_with("text/")
+        || matches!(
+            mime.as_str(),
+            "application/json" | "application/xml" | "application/x-ndjson" | "application/yaml" | "application/x-yaml"
+        )
+}
+
+/// Extract per-page text from a stored file's bytes. PDFs (by MIME type or
+/// signature) are parsed in-process with `pdf-extract`; text types are read as
+/// UTF-8 and treated as a single page. Anything else (images, Office
+/// documents, archives) yields no pages, so the file ends up `NoText` instead
+/// of feeding binary noise to the models and indexes.
+pub async fn extract_pages(data: Vec<u8>, mime_type: &str) -> Result<Vec<PageText>> {
+    let is_pdf = mime_type.eq_ignore_ascii_case("application/pdf") || data.starts_with(b"%PDF-");
+    let raw_pages = if is_pdf {
+        // PDF parsing is CPU-bound and pdf-extract can panic or spin on
+        // malformed input, so keep it off the async runtime, turn panics into
+        // errors and stop waiting after the deadline. The blocking thread
+        // cannot be interrupted and finishes on its own.
+        let timeout = pdf_extract_timeout();
+        let parse = tokio::task::spawn_blocking(move || {
+            pdf_extract::extract_text_from_mem_by_pages(&data).map_err(|e| anyhow!("pdf extraction failed: {e}"))
+        });
+        tokio::time::timeout(timeout, parse)
+            .await
+            .map_err(|_| anyhow!("pdf extraction timed out after {}s", timeout.as_secs()))?
+            .map_err(|e| anyhow!("text extraction panicked: {e}"))??
+    } else if is_text_type(mime_type) {
+        vec![String::from_utf8_lossy(&data).into_owned()]
+    } else {
+        info!("No text extraction for MIME type {}", mime_type);
+        Vec::new()
+    };
+
+    Ok(raw_pages
+        .into_iter()
+        .enumerate()
+        .map(|(i, raw)| PageText {
+            page: i as u32 + 1,
+            text: normalize_whitespace(&raw),
+        })
+        .collect())
+}
+
+/// True when none of the pages carry any readable characters (e.g. scanned PDFs
+/// without an OCR layer).
+pub fn is_empty(pages: &[PageText]) -> bool {
+    pages.iter().all(|p| p.text.chars().all(|c| !c.is_alphanumeric()))
+}
+
+/// Concatenate page text up to `max_chars` characters, tagging each page so the
+/// model can tell where content came from.
+pub fn excerpt(pages: &[PageText], max_chars: usize) -> String {
+    let mut out = String::new();
+    let mut used = 0usize;
+    for p in pages.iter().filter(|p| !p.text.is_empty()) {
+        if used >= max_chars {
+            break;
+        }
+        let header = format!("[page {}]\n", p.page);
+        out.push_str(&header);
+        let remaining = max_chars - used;
+        let taken: String = p.text.chars().take(remaining).collect();
+        used += taken.chars().count();
+        out.push_str(&taken);
+        out.push('\n');
+    }
+    out
+}
+
+/// Collapse runs of spaces/tabs and blank lines left behind by PDF layout.
+fn normalize_whitespace(raw: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut blank_run = 0;
+    for line in raw.lines() {
+        let collapsed = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if collapsed.is_empty() {
+            blank_run += 1;
+            if blank_run == 1 && !lines.is_empty() {
+                lines.push(String::new());
+            }
+            continue;
+        }
+        blank_run = 0;
+        lines.push(collapsed);
+    }
+    while lines.last().map(|l| l.is_empty()).unwrap_or(false) {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_types() {
+        assert!(is_text_type("text/plain; charset=utf-8"));
+        assert!(is_text_type("text/markdown"));
+        assert!(is_text_type("application/json"));
+        assert!(!is_text_type("image/png"));
+        assert!(!is_text_type("application/vnd.openxmlformats-officedocument.wordprocessingml.document"));
+        assert!(!is_text_type("application/octet-stream"));
+    }
+
+    #[tokio::test]
+    async fn binary_types_yield_no_pages() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        let pages = extract_pages(png, "image/png").await.unwrap();
+        assert!(pages.is_empty());
+        assert!(is_empty(&pages));
+    }
+
+    #[tokio::test]
+    async fn text_is_one_normalized_page() {
+        let pages = extract_pages(b"Main  bus\t voltage\n\n\n\n160 V\n\n".to_vec(), "text/plain").await.unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].page, 1);
+        assert_eq!(pages[0].text, "Main bus voltage\n\n160 V");
+    }
+
+    #[tokio::test]
+    async fn malformed_pdf_is_an_error() {
+        assert!(extract_pages(b"%PDF-1.7 not really".to_vec(), "application/pdf").await.is_err());
+    }
+
+    #[test]
+    fn is_empty_ignores_punctuation() {
+        let pages = vec![PageText { page: 1, text: " .. -- ".into() }];
+        assert!(is_empty(&pages));
+    }
+
+    #[test]
+    fn excerpt_tags_pages_and_respects_budget() {
+        let pages = vec![
+            PageText { page: 1, text: "abcdef".into() },
+            PageText { page: 2, text: String::new() },
+            PageText { page: 3, text: "ghij".into() },
+        ];
+        assert_eq!(excerpt(&pages, 100), "[page 1]\nabcdef\n[page 3]\nghij\n");
+        assert_eq!(excerpt(&pages, 8), "[page 1]\nabcdef\n[page 3]\ngh\n");
+        assert_eq!(excerpt(&pages, 6), "[page 1]\nabcdef\n");
+    }
+}
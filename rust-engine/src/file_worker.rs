@@ -1,133 +1,584 @@
-use crate::gemini_client::{demo_text_embedding, generate_text_with_model, DEMO_EMBED_DIM};
-use crate::vector;
-use crate::vector_db::QdrantClient;
-use sqlx::MySqlPool;
-use anyhow::Result;
-use tracing::{info, error};
+use crate::blob_store::{self, BlobStore};
+use crate::chunking::{self, ChunkConfig};
+use crate::embedding::Embedder;
+use crate::extract;
+use crate::keyword::{KeywordDoc, KeywordIndex};
+use crate::jobs::{self, Job, JobHandler};
+use crate::lease::Lease;
+use crate::llm::{LlmError, LlmStage, Llms};
+use crate::storage;
+use crate::vector_store::{file_payload, VectorFilter, VectorPoint, VectorStore};
+use sqlx::{MySqlPool, Row};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::{error, info};
+
+/// Upper bound on extracted characters sent to the model per prompt.
+const PROMPT_TEXT_BUDGET: usize = 30_000;
+/// Points sent to the vector store per upsert request.
+pub const UPSERT_BATCH: usize = 64;
+
+/// `job_type` of file analysis jobs; the subject is the file id.
+pub const ANALYZE_FILE: &str = "analyze_file";
+
+/// Per-stage caps on files in a stage at once, shared by all analysis tasks so
+/// a slow stage (Gemini Pro graph data) holds a bounded number of slots.
+/// A task slot is only taken once a file is let into a stage (see `enter`), so
+/// files waiting for a busy stage leave the slots to files in other stages.
+struct StageLimits {
+    tasks: Semaphore,
+    extract: Semaphore,
+    describe: Semaphore,
+    graph: Semaphore,
+    embed: Semaphore,
+    /// Files claimed at once: enough to fill every stage.
+    in_flight: usize,
+}
+
+impl StageLimits {
+    /// `concurrency` task slots and `ANALYSIS_<STAGE>_CONCURRENCY` per stage;
+    /// stages default to the task count, and half of it for the graph stage.
+    fn from_env(concurrency: usize) -> Self {
+        let concurrency = concurrency.max(1);
+        let limit = |stage: &str, default: usize| env_usize(&format!("ANALYSIS_{stage}_CONCURRENCY")).unwrap_or(default).max(1);
+        let extract = limit("EXTRACT", concurrency);
+        let describe = limit("DESCRIBE", concurrency);
+        let graph = limit("GRAPH", concurrency.div_ceil(2));
+        let embed = limit("EMBED", concurrency);
+        Self {
+            tasks: Semaphore::new(concurrency),
+            extract: Semaphore::new(extract),
+            describe: Semaphore::new(describe),
+            graph: Semaphore::new(graph),
+            embed: Semaphore::new(embed),
+            in_flight: extract + describe + graph + embed,
+        }
+    }
+
+    /// Wait for room in `stage`, then for a task slot; both are held until
+    /// the permits are dropped at the end of the stage.
+    async fn enter<'a>(&'a self, stage: &'a Semaphore) -> Result<(SemaphorePermit<'a>, SemaphorePermit<'a>)> {
+        let stage = stage.acquire().await?;
+        let task = self.tasks.acquire().await?;
+        Ok((stage, task))
+    }
+}
 
 pub struct FileWorker {
     pool: MySqlPool,
-    qdrant: QdrantClient,
+    store: Arc<dyn VectorStore>,
+    embedder: Arc<dyn Embedder>,
+    keywords: Arc<KeywordIndex>,
+    llms: Arc<Llms>,
+    blob_store: Arc<dyn BlobStore>,
+    chunk_cfg: ChunkConfig,
+    limits: StageLimits,
 }
 
 impl FileWorker {
-    pub fn new(pool: MySqlPool) -> Self {
-        let qdrant_url = std::env::var("QDRANT_URL").unwrap_or_else(|_| "http://qdrant:6333".to_string());
-        let qdrant = QdrantClient::new(&qdrant_url);
-        Self { pool, qdrant }
+    pub fn new(
+        pool: MySqlPool,
+        store: Arc<dyn VectorStore>,
+        embedder: Arc<dyn Embedder>,
+        keywords: Arc<KeywordIndex>,
+        llms: Arc<Llms>,
+        blob_store: Arc<dyn BlobStore>,
+    ) -> Self {
+        // FILE_WORKER_CONCURRENCY: files being worked on at once by this process
+        let concurrency = env_usize("FILE_WORKER_CONCURRENCY").unwrap_or(4);
+        Self {
+            pool,
+            store,
+            embedder,
+            keywords,
+            llms,
+            blob_store,
+            chunk_cfg: ChunkConfig::from_env(),
+            limits: StageLimits::from_env(concurrency),
+        }
+    }
+}
+
+#[async_trait]
+impl JobHandler for FileWorker {
+    fn job_type(&self) -> &'static str {
+        ANALYZE_FILE
+    }
+
+    /// Files waiting for a stage hold no task slot, so more are claimed than
+    /// `FILE_WORKER_CONCURRENCY`; `StageLimits` bounds the work actually running.
+    fn concurrency(&self) -> usize {
+        self.limits.in_flight
+    }
+
+    /// Files left queued or running without an active analysis job.
+    async fn recover(&self) -> Result<u64> {
+        let done = sqlx::query(
+            "INSERT INTO jobs (id, job_type, subject_id, payload, max_attempts) \
+             SELECT UUID(), ?, f.id, JSON_OBJECT(), ? FROM files f \
+             WHERE f.pending_analysis = TRUE AND f.analysis_status IN ('Queued', 'InProgress') \
+             AND NOT EXISTS (SELECT 1 FROM jobs j WHERE j.job_type = ? AND j.subject_id = f.id AND j.status IN ('Queued', 'InProgress'))",
+        )
+        .bind(ANALYZE_FILE)
+        .bind(jobs::default_max_attempts())
+        .bind(ANALYZE_FILE)
+        .execute(&self.pool)
+        .await?;
+        Ok(done.rows_affected())
     }
 
-    pub async fn run(&self) {
-        info!("FileWorker starting");
-        if let Err(e) = self.qdrant.ensure_files_collection(DEMO_EMBED_DIM).await {
-            error!("Failed to ensure Qdrant collection: {}", e);
+    async fn run(&self, job: &Job, lease: &Lease) -> Result<()> {
+        let file_id = job.subject()?;
+        // A file deleted or already analyzed since the job was queued needs nothing
+        let started = sqlx::query("UPDATE files SET analysis_status = 'InProgress', analysis_started_at = NOW(), analysis_attempts = analysis_attempts + 1 WHERE id = ? AND pending_analysis = TRUE")
+            .bind(file_id)
+            .execute(&self.pool)
+            .await?;
+        if started.rows_affected() == 0 {
+            info!("Skipping analysis of file {}: deleted or already analyzed", file_id);
+            return Ok(());
         }
-        loop {
-            match self.fetch_and_claim().await {
-                Ok(Some(fid)) => {
-                    info!("Processing file {}", fid);
-                    if let Err(e) = self.process_file(&fid).await {
-                        error!("Error processing file {}: {}", fid, e);
-                        if let Err(mark_err) = self.mark_failed(&fid, &format!("{}", e)).await {
-                            error!("Failed to mark file {} as failed: {}", fid, mark_err);
-                        }
-                    }
-                }
-                Ok(None) => {
-                    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-                }
-                Err(e) => {
-                    error!("FileWorker fetch error: {}", e);
-                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-                }
+        // Uploaded as a duplicate: reuse the analysis of the same content when it is available
+        if let Some(source) = job.payload.get("copy_from").and_then(|v| v.as_str()) {
+            if self.copy_analysis(file_id, source, lease).await? {
+                info!("Copied analysis of file {} to its duplicate {}", source, file_id);
+                return Ok(());
             }
+            info!("No completed analysis of file {} to copy; analyzing {}", source, file_id);
         }
+        info!("Processing file {}", file_id);
+        self.process_file(file_id, lease).await
     }
 
-    async fn fetch_and_claim(&self) -> Result<Option<String>> {
-        // Claim files that are queued or stuck in progress for >10min
-        if let Some(row) = sqlx::query(
-            "SELECT id FROM files WHERE (analysis_status = 'Queued' OR (analysis_status = 'InProgress' AND created_at < (NOW() - INTERVAL 10 MINUTE))) AND pending_analysis = TRUE LIMIT 1"
-        )
-        .fetch_optional(&self.pool)
-        .await? {
-            use sqlx::Row;
-            let id: String = row.get("id");
-            // Mark as in-progress
-            let _ = sqlx::query("UPDATE files SET analysis_status = 'InProgress' WHERE id = ?")
-                .bind(&id)
-                .execute(&self.pool)
-                .await?;
-            Ok(Some(id))
-        } else {
-            Ok(None)
-        }
+    async fn on_retry(&self, job: &Job, err: &anyhow::Error) -> Result<()> {
+        sqlx::query("UPDATE files SET analysis_status = 'Queued', analysis_error = ? WHERE id = ? AND pending_analysis = TRUE")
+            .bind(failure_reason(err))
+            .bind(job.subject()?)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
     }
 
-    async fn process_file(&self, file_id: &str) -> Result<()> {
-        use sqlx::Row;
-        let row = sqlx::query("SELECT filename, path FROM files WHERE id = ?")
-            .bind(file_id)
-            .fetch_one(&self.pool)
+    /// Terminal until someone asks for a reanalysis (see `queue_reanalysis`).
+    async fn on_dead_letter(&self, job: &Job, err: &anyhow::Error) -> Result<()> {
+        sqlx::query("UPDATE files SET analysis_status = 'DeadLettered', analysis_error = ? WHERE id = ? AND pending_analysis = TRUE")
+            .bind(failure_reason(err))
+            .bind(job.subject()?)
+            .execute(&self.pool)
             .await?;
-    let filename: String = row.get("filename");
-    let _path: String = row.get("path");
-
-        // Stage 1: Gemini 2.5 Flash for description
-        let desc = generate_text_with_model(
-            "gemini-2.5-flash",
-            &format!(
-                "Describe the file '{filename}' and extract all key components, keywords, and details for later vectorization. Be comprehensive and factual."
-            ),
+        Ok(())
+    }
+}
+
+impl FileWorker {
+    /// Filename, blob store key, MIME type and the file-level vector payload of a file.
+    async fn load_file(&self, file_id: &str) -> Result<(String, String, String, serde_json::Value)> {
+        let row = sqlx::query(
+            "SELECT filename, path, content_hash, mime_type, tags, CAST(UNIX_TIMESTAMP(created_at) AS SIGNED) AS uploaded_at FROM files WHERE id = ?",
         )
-        .await
-        .unwrap_or_else(|e| format!("[desc error: {}]", e));
-        sqlx::query("UPDATE files SET description = ?, analysis_status = 'InProgress' WHERE id = ?")
+        .bind(file_id)
+        .fetch_one(&self.pool)
+        .await?;
+        let filename: String = row.get("filename");
+        let mime_type: Option<String> = row.get("mime_type");
+        let mime_type = mime_type.unwrap_or_else(|| storage::guess_mime(&filename).to_string());
+        let meta = file_payload(file_id, &mime_type, row.get("tags"), row.get("uploaded_at"));
+        let content_hash: Option<String> = row.get("content_hash");
+        let key = storage::object_key(content_hash.as_deref(), row.get("path"))?;
+        Ok((filename, key, mime_type, meta))
+    }
+
+    async fn process_file(&self, file_id: &str, lease: &Lease) -> Result<()> {
+        let (filename, key, mime_type, meta) = self.load_file(file_id).await?;
+
+        // Stage 1: extract per-page text from the stored file and persist it
+        let pages = {
+            let _permits = self.limits.enter(&self.limits.extract).await?;
+            if !self.blob_store.exists(&key).await? {
+                return Err(anyhow!("stored content of file {file_id} is missing from the {} blob store ({key})", self.blob_store.name()));
+            }
+            let data = blob_store::read_all(self.blob_store.as_ref(), &key).await?;
+            extract::extract_pages(data, &mime_type).await?
+        };
+        lease.ensure_held()?;
+        if !self.save_pages(file_id, &pages).await? {
+            return self.deleted_during_analysis(file_id).await;
+        }
+        if extract::is_empty(&pages) {
+            info!("No extractable text in file {} ({} pages)", file_id, pages.len());
+            self.finish(file_id, lease, "analysis_status = 'NoText', pending_analysis = TRUE").await?;
+            return Ok(());
+        }
+        let content = extract::excerpt(&pages, PROMPT_TEXT_BUDGET);
+
+        // Stage 2: description (Gemini 2.5 Flash by default)
+        let desc = {
+            let _permits = self.limits.enter(&self.limits.describe).await?;
+            self.llms.get(LlmStage::Describe).generate(
+                &format!(
+                    "Describe the file '{filename}' and extract all key components, keywords, and details for later vectorization. Be comprehensive and factual. Use only the extracted content below.\nExtracted content:\n{content}"
+                ),
+            )
+            .await?
+        };
+        lease.ensure_held()?;
+        sqlx::query("UPDATE files SET description = ? WHERE id = ?")
             .bind(&desc)
             .bind(file_id)
             .execute(&self.pool)
             .await?;
 
-        // Stage 2: Gemini 2.5 Pro for deep vector graph data
-        let vector_graph = generate_text_with_model(
-            "gemini-2.5-pro",
-            &format!(
-                "Given the file '{filename}' and its description: {desc}\nGenerate a set of vector graph data (keywords, use cases, relationships) that can be used for broad and precise search. Only include what is directly supported by the file."
-            ),
-        )
-        .await
-        .unwrap_or_else(|e| format!("[vector error: {}]", e));
-
-        // Stage 3: Embed and upsert to Qdrant
-        let emb = demo_text_embedding(&vector_graph).await?;
-        match self.qdrant.upsert_point(file_id, emb.clone()).await {
-            Ok(_) => {
-                let _ = vector::store_embedding(file_id, emb.clone());
+        // Stage 3: deep vector graph data (Gemini 2.5 Pro by default)
+        let vector_graph = {
+            let _permits = self.limits.enter(&self.limits.graph).await?;
+            self.llms.get(LlmStage::Graph).generate(
+                &format!(
+                    "Given the file '{filename}', its description: {desc}\nand its extracted content:\n{content}\nGenerate a set of vector graph data (keywords, use cases, relationships) that can be used for broad and precise search. Only include what is directly supported by the file."
+                ),
+            )
+            .await?
+        };
+
+        // Stage 4: chunk the extracted text and persist the passages
+        lease.ensure_held()?;
+        let chunks = chunking::chunk_pages(&pages, self.chunk_cfg);
+        let Some(chunk_ids) = self.save_chunks(file_id, &chunks).await? else {
+            return self.deleted_during_analysis(file_id).await;
+        };
+
+        // Stage 5: embed the file summary and every chunk, then upsert to the vector store
+        let mut texts = Vec::with_capacity(chunks.len() + 1);
+        texts.push(format!("{filename}\n{desc}\n{vector_graph}"));
+        texts.extend(chunks.iter().map(|c| match &c.heading {
+            Some(h) => format!("{h}\n{}", c.text),
+            None => c.text.clone(),
+        }));
+        let mut embeddings = {
+            let _permits = self.limits.enter(&self.limits.embed).await?;
+            self.embedder.embed_batch(&texts).await?.into_iter()
+        };
+        let summary_emb = embeddings.next().unwrap_or_default();
+        let mut points = Vec::with_capacity(texts.len());
+        let mut summary_payload = meta.clone();
+        summary_payload["type"] = json!("file");
+        points.push(VectorPoint { id: file_id.to_string(), vector: summary_emb, payload: summary_payload });
+        for ((chunk, chunk_id), emb) in chunks.iter().zip(chunk_ids.iter().cloned()).zip(embeddings) {
+            let mut payload = meta.clone();
+            payload["type"] = json!("chunk");
+            payload["page"] = json!(chunk.page);
+            payload["chunk_index"] = json!(chunk.index);
+            points.push(VectorPoint { id: chunk_id, vector: emb, payload });
+        }
+        lease.ensure_held()?;
+        self.store.delete(&VectorFilter::file(file_id)).await?;
+        for batch in points.chunks(UPSERT_BATCH) {
+            self.store.upsert(batch.to_vec()).await?;
+        }
+
+        // Stage 6: make the chunks searchable by keyword (same text as embedded)
+        let docs = chunks
+            .iter()
+            .zip(chunk_ids)
+            .zip(texts.into_iter().skip(1))
+            .map(|((c, chunk_id), text)| KeywordDoc { chunk_id, page: c.page, chunk_index: c.index, text })
+            .collect();
+        self.keywords.index_file(file_id, meta, docs).await?;
+
+        // Mark file as ready
+        self.finish(file_id, lease, "pending_analysis = FALSE, analysis_status = 'Completed', analysis_error = NULL").await
+    }
+
+    /// Give `file_id` the analysis of `source`, a file with the same content:
+    /// its description, pages, chunks, vectors and keyword entries, carrying
+    /// `file_id`'s own tags and upload time. False, with nothing written, when
+    /// `source` has no completed analysis.
+    async fn copy_analysis(&self, file_id: &str, source: &str, lease: &Lease) -> Result<bool> {
+        let Some(src) = sqlx::query("SELECT description FROM files WHERE id = ? AND analysis_status = 'Completed'")
+            .bind(source)
+            .fetch_optional(&self.pool)
+            .await?
+        else {
+            return Ok(false);
+        };
+        let desc: Option<String> = src.get("description");
+        let (_, _, _, meta) = self.load_file(file_id).await?;
+
+        // Chunk and point ids are per file, so every copied chunk gets a new id
+        let chunks = sqlx::query("SELECT id, chunk_index, page_number, heading, text FROM chunks WHERE file_id = ? ORDER BY chunk_index")
+            .bind(source)
+            .fetch_all(&self.pool)
+            .await?;
+        let new_ids: HashMap<String, String> = chunks
+            .iter()
+            .map(|c| (c.get("id"), uuid::Uuid::new_v4().to_string()))
+            .collect();
+        let mut points = Vec::new();
+        let mut offset = None;
+        loop {
+            let (page, next) = self.store.scroll(&VectorFilter::file(source), offset, UPSERT_BATCH).await?;
+            for p in page {
+                let id = match p.payload.get("type").and_then(|t| t.as_str()) {
+                    Some("file") => file_id.to_string(),
+                    _ => match new_ids.get(&p.id) {
+                        Some(id) => id.clone(),
+                        None => continue,
+                    },
+                };
+                let mut payload = meta.clone();
+                for key in ["type", "page", "chunk_index"] {
+                    if let Some(v) = p.payload.get(key) {
+                        payload[key] = v.clone();
+                    }
+                }
+                points.push(VectorPoint { id, vector: p.vector, payload });
             }
-            Err(err) => {
-                error!("Qdrant upsert failed for {}: {}", file_id, err);
-                let _ = vector::store_embedding(file_id, emb);
+            match next {
+                Some(o) => offset = Some(o),
+                None => break,
             }
         }
+        if points.is_empty() {
+            return Ok(false);
+        }
 
-        // Mark file as ready
-        sqlx::query("UPDATE files SET pending_analysis = FALSE, analysis_status = 'Completed' WHERE id = ?")
+        lease.ensure_held()?;
+        let mut tx = self.pool.begin().await?;
+        if !Self::lock_file(&mut tx, file_id).await? {
+            self.deleted_during_analysis(file_id).await?;
+            return Ok(true);
+        }
+        sqlx::query("DELETE FROM file_pages WHERE file_id = ?").bind(file_id).execute(&mut *tx).await?;
+        sqlx::query("INSERT INTO file_pages (file_id, page_number, text) SELECT ?, page_number, text FROM file_pages WHERE file_id = ?")
             .bind(file_id)
-            .execute(&self.pool)
+            .bind(source)
+            .execute(&mut *tx)
             .await?;
-        Ok(())
+        sqlx::query("DELETE FROM chunks WHERE file_id = ?").bind(file_id).execute(&mut *tx).await?;
+        let mut docs = Vec::with_capacity(chunks.len());
+        for c in &chunks {
+            let id = &new_ids[&c.get::<String, _>("id")];
+            let heading: Option<String> = c.get("heading");
+            let text: String = c.get("text");
+            sqlx::query("INSERT INTO chunks (id, file_id, chunk_index, page_number, heading, text) VALUES (?, ?, ?, ?, ?, ?)")
+                .bind(id)
+                .bind(file_id)
+                .bind(c.get::<i32, _>("chunk_index"))
+                .bind(c.get::<i32, _>("page_number"))
+                .bind(&heading)
+                .bind(&text)
+                .execute(&mut *tx)
+                .await?;
+            docs.push(KeywordDoc {
+                chunk_id: id.clone(),
+                page: c.get::<i32, _>("page_number").max(0) as u32,
+                chunk_index: c.get::<i32, _>("chunk_index").max(0) as u32,
+                text: match heading {
+                    Some(h) => format!("{h}\n{text}"),
+                    None => text,
+                },
+            });
+        }
+        sqlx::query("UPDATE files SET description = ? WHERE id = ?")
+            .bind(&desc)
+            .bind(file_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        lease.ensure_held()?;
+        self.store.delete(&VectorFilter::file(file_id)).await?;
+        for batch in points.chunks(UPSERT_BATCH) {
+            self.store.upsert(batch.to_vec()).await?;
+        }
+        self.keywords.index_file(file_id, meta, docs).await?;
+        self.finish(file_id, lease, "pending_analysis = FALSE, analysis_status = 'Completed', analysis_error = NULL").await?;
+        Ok(true)
     }
 
-    async fn mark_failed(&self, file_id: &str, reason: &str) -> Result<()> {
-        sqlx::query("UPDATE files SET analysis_status = 'Failed', pending_analysis = TRUE WHERE id = ?")
+    /// Lock the `files` row in `tx`, so the file cannot be deleted until it
+    /// commits. False when it is gone already.
+    async fn lock_file(tx: &mut sqlx::MySqlConnection, file_id: &str) -> Result<bool> {
+        Ok(sqlx::query("SELECT id FROM files WHERE id = ? FOR UPDATE")
             .bind(file_id)
-            .execute(&self.pool)
+            .fetch_optional(tx)
+            .await?
+            .is_some())
+    }
+
+    /// False, with nothing written, when the file was deleted.
+    async fn save_pages(&self, file_id: &str, pages: &[extract::PageText]) -> Result<bool> {
+        // Replace any pages left over from a previous (interrupted) run
+        let mut tx = self.pool.begin().await?;
+        if !Self::lock_file(&mut tx, file_id).await? {
+            return Ok(false);
+        }
+        sqlx::query("DELETE FROM file_pages WHERE file_id = ?")
+            .bind(file_id)
+            .execute(&mut *tx)
             .await?;
-        sqlx::query("UPDATE files SET description = COALESCE(description, ?) WHERE id = ?")
-            .bind(format!("[analysis failed: {}]", reason))
+        for p in pages {
+            sqlx::query("INSERT INTO file_pages (file_id, page_number, text) VALUES (?, ?, ?)")
+                .bind(file_id)
+                .bind(p.page)
+                .bind(&p.text)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// The ids of the saved chunks; `None`, with nothing written, when the
+    /// file was deleted.
+    async fn save_chunks(&self, file_id: &str, chunks: &[chunking::Chunk]) -> Result<Option<Vec<String>>> {
+        let mut tx = self.pool.begin().await?;
+        if !Self::lock_file(&mut tx, file_id).await? {
+            return Ok(None);
+        }
+        sqlx::query("DELETE FROM chunks WHERE file_id = ?")
+            .bind(file_id)
+            .execute(&mut *tx)
+            .await?;
+        let mut ids = Vec::with_capacity(chunks.len());
+        for c in chunks {
+            let id = uuid::Uuid::new_v4().to_string();
+            sqlx::query("INSERT INTO chunks (id, file_id, chunk_index, page_number, heading, text) VALUES (?, ?, ?, ?, ?, ?)")
+                .bind(&id)
+                .bind(file_id)
+                .bind(c.index)
+                .bind(c.page)
+                .bind(&c.heading)
+                .bind(&c.text)
+                .execute(&mut *tx)
+                .await?;
+            ids.push(id);
+        }
+        tx.commit().await?;
+        Ok(Some(ids))
+    }
+
+    /// Record how the analysis ended, unless the job was meanwhile reset or
+    /// taken over (`LeaseLost`). A file deleted while it was analyzed may
+    /// have been purged before its vectors and keyword entries were written,
+    /// so they are removed again.
+    async fn finish(&self, file_id: &str, lease: &Lease, set: &str) -> Result<()> {
+        lease.ensure_held()?;
+        let done = sqlx::query(&format!("UPDATE files SET {set} WHERE id = ? AND analysis_status = 'InProgress'"))
             .bind(file_id)
             .execute(&self.pool)
             .await?;
+        if done.rows_affected() == 0 {
+            let exists = sqlx::query("SELECT id FROM files WHERE id = ?").bind(file_id).fetch_optional(&self.pool).await?.is_some();
+            if !exists {
+                return self.deleted_during_analysis(file_id).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn deleted_during_analysis(&self, file_id: &str) -> Result<()> {
+        info!("File {} was deleted during analysis; removing what was written for it", file_id);
+        purge_file_index(&self.pool, self.store.as_ref(), &self.keywords, file_id).await;
         Ok(())
     }
 }
+
+/// Remove everything derived from a file: vector store points, keyword index
+/// entries, chunks and extracted pages. The `files` row itself is left to the caller.
+pub async fn purge_file_index(pool: &MySqlPool, store: &dyn VectorStore, keywords: &KeywordIndex, file_id: &str) {
+    if let Err(e) = store.delete(&VectorFilter::file(file_id)).await {
+        error!("Vector delete failed for {}: {}", file_id, e);
+    }
+    if let Err(e) = keywords.remove_file(file_id).await {
+        error!("Keyword index delete failed for {}: {}", file_id, e);
+    }
+    let _ = sqlx::query("DELETE FROM chunks WHERE file_id = ?").bind(file_id).execute(pool).await;
+    let _ = sqlx::query("DELETE FROM file_pages WHERE file_id = ?").bind(file_id).execute(pool).await;
+}
+
+fn env_usize(key: &str) -> Option<usize> {
+    std::env::var(key).ok().and_then(|v| v.parse().ok())
+}
+
+/// Stored reason for a failed file: the LLM error kind when there is one,
+/// then the full error chain.
+fn failure_reason(e: &anyhow::Error) -> String {
+    match e.downcast_ref::<LlmError>() {
+        Some(llm_err) => format!("{}: {e:#}", llm_err.kind()),
+        None => format!("{e:#}"),
+    }
+}
+
+/// Reset a file for a fresh analysis (attempt count and error cleared) and
+/// enqueue its job. Returns `None` for an unknown file and `Some(false)` when
+/// an analysis job is already queued or running, which is left as it is.
+pub async fn queue_reanalysis(pool: &MySqlPool, file_id: &str) -> Result<Option<bool>> {
+    let mut tx = pool.begin().await?;
+    // Locks the file row, so concurrent requests for the same file enqueue one job
+    let exists = sqlx::query("SELECT id FROM files WHERE id = ? FOR UPDATE")
+        .bind(file_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .is_some();
+    if !exists {
+        return Ok(None);
+    }
+    let active = sqlx::query("SELECT id FROM jobs WHERE job_type = ? AND subject_id = ? AND status IN ('Queued', 'InProgress') LIMIT 1")
+        .bind(ANALYZE_FILE)
+        .bind(file_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .is_some();
+    if active {
+        return Ok(Some(false));
+    }
+    sqlx::query("UPDATE files SET pending_analysis = TRUE, analysis_status = 'Queued', analysis_error = NULL, analysis_attempts = 0 WHERE id = ?")
+        .bind(file_id)
+        .execute(&mut *tx)
+        .await?;
+    jobs::enqueue(&mut *tx, ANALYZE_FILE, Some(file_id), json!({}), 0).await?;
+    tx.commit().await?;
+    Ok(Some(true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn graph_stage_gets_half_the_slots() {
+        let permits = |l: &StageLimits| {
+            [&l.extract, &l.describe, &l.graph, &l.embed].map(|s| s.available_permits())
+        };
+        assert_eq!(permits(&StageLimits::from_env(4)), [4, 4, 2, 4]);
+        assert_eq!(permits(&StageLimits::from_env(5)), [5, 5, 3, 5]);
+        assert_eq!(permits(&StageLimits::from_env(1)), [1, 1, 1, 1]);
+        assert_eq!(permits(&StageLimits::from_env(0)), [1, 1, 1, 1]);
+        let limits = StageLimits::from_env(4);
+        assert_eq!((limits.tasks.available_permits(), limits.in_flight), (4, 14));
+    }
+
+    #[tokio::test]
+    async fn files_waiting_for_a_stage_hold_no_task_slot() {
+        let limits = StageLimits::from_env(2);
+        let wait = std::time::Duration::from_millis(50);
+        let _in_graph = limits.enter(&limits.graph).await.unwrap();
+        // The graph stage is full: the next file waits without a task slot...
+        assert!(tokio::time::timeout(wait, limits.enter(&limits.graph)).await.is_err());
+        assert_eq!(limits.tasks.available_permits(), 1);
+        // ...which is left to a file in another stage
+        let _extracting = tokio::time::timeout(wait, limits.enter(&limits.extract)).await.unwrap().unwrap();
+        assert_eq!(limits.tasks.available_permits(), 0);
+    }
+
+    #[test]
+    fn failure_reason_leads_with_the_llm_error_kind() {
+        let err = anyhow::Error::new(LlmError::Malformed("no candidates".to_string())).context("describe");
+        assert_eq!(failure_reason(&err), "malformed: describe: LLM returned an unusable response: no candidates");
+        assert_eq!(failure_reason(&anyhow!("disk full").context("extract")), "extract: disk full");
+    }
+}
@@ -1,89 +1,116 @@
-use anyhow::Result;
+use crate::llm::{LlmError, LlmProvider, LlmResult, SseLines};
+use async_trait::async_trait;
 use reqwest::Client;
 use serde::Deserialize;
 use serde_json::json;
 
-// NOTE: This file provides lightweight helpers around the Gemini API. For the
-// hackathon demo we fall back to deterministic strings when the API key is not
-// configured so the flows still work end-to-end.
+// NOTE: This file provides lightweight helpers around the Gemini API. Failures
+// surface as `LlmError`; running without a key is the job of the demo provider.
 
-pub const DEMO_EMBED_DIM: usize = 64;
+/// Gemini `generateContent` API.
+pub struct GeminiProvider {
+    client: Client,
+    api_key: String,
+    model: String,
+}
 
-/// Demo text embedding (replace with real Gemini text embedding API)
-pub async fn demo_text_embedding(text: &str) -> Result<Vec<f32>> {
-    let mut v = vec![0f32; DEMO_EMBED_DIM];
-    for (i, b) in text.as_bytes().iter().enumerate() {
-        let idx = i % v.len();
-        v[idx] += (*b as f32) / 255.0;
+impl GeminiProvider {
+    pub fn new(api_key: String, model: String) -> Self {
+        Self { client: Client::new(), api_key, model }
     }
-    Ok(v)
-}
 
-/// Generate text using the default model (GEMINI_MODEL or gemini-2.5-pro).
-#[allow(dead_code)]
-pub async fn generate_text(prompt: &str) -> Result<String> {
-    let model = std::env::var("GEMINI_MODEL").unwrap_or_else(|_| "gemini-2.5-pro".to_string());
-    generate_text_with_model(&model, prompt).await
+    async fn send(&self, method: &str, prompt: &str) -> LlmResult<reqwest::Response> {
+        let url = format!(
+            "https://generativelanguage.googleapis.com/v1beta/models/{}:{method}{}key={}",
+            self.model,
+            if method.contains('?') { "&" } else { "?" },
+            self.api_key
+        );
+        let body = json!({
+            "contents": [ { "parts": [ { "text": prompt } ] } ]
+        });
+        let resp = self.client.post(&url).json(&body).send().await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let headers = resp.headers().clone();
+            let txt = resp.text().await.unwrap_or_default();
+            return Err(LlmError::from_response(status, &headers, &txt));
+        }
+        Ok(resp)
+    }
 }
 
-/// Generate text with an explicit Gemini model. Falls back to a deterministic
-/// response when the API key is not set so the demo still runs.
-pub async fn generate_text_with_model(model: &str, prompt: &str) -> Result<String> {
-    let api_key = match std::env::var("GEMINI_API_KEY") {
-        Ok(k) if !k.is_empty() => k,
-        _ => {
-            return Ok(format!(
-                "[demo] Gemini ({}) not configured. Prompt preview: {}",
-                model,
-                truncate(prompt, 240)
-            ));
-        }
-    };
+#[async_trait]
+impl LlmProvider for GeminiProvider {
+    fn name(&self) -> &str {
+        "gemini"
+    }
 
-    let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
-        model, api_key
-    );
+    fn model(&self) -> &str {
+        &self.model
+    }
 
-    let body = json!({
-        "contents": [ { "parts": [ { "text": prompt } ] } ]
-    });
+    async fn generate(&self, prompt: &str) -> LlmResult<String> {
+        let txt = self.send("generateContent", prompt).await?.text().await?;
+        let data: Response = serde_json::from_str(&txt).map_err(|e| LlmError::Malformed(e.to_string()))?;
+        match data.into_text()? {
+            Some(text) if !text.trim().is_empty() => Ok(text),
+            _ => Err(LlmError::Malformed("empty response".to_string())),
+        }
+    }
 
-    let client = Client::new();
-    let resp = client.post(&url).json(&body).send().await?;
-    let status = resp.status();
-    let txt = resp.text().await?;
-    if !status.is_success() {
-        return Ok(format!(
-            "[demo] Gemini ({}) error {}: {}",
-            model,
-            status,
-            truncate(&txt, 240)
-        ));
+    /// Uses `streamGenerateContent`, which sends one `data: {json}` line per
+    /// partial response.
+    async fn stream(&self, prompt: &str, on_text: &mut (dyn for<'t> FnMut(&'t str) + Send)) -> LlmResult<String> {
+        let mut resp = self.send("streamGenerateContent?alt=sse", prompt).await?;
+        let mut out = String::new();
+        let mut lines = SseLines::default();
+        while let Some(chunk) = resp.chunk().await? {
+            for data in lines.push(&chunk) {
+                let Ok(partial) = serde_json::from_str::<Response>(&data) else { continue };
+                if let Some(text) = partial.into_text()? {
+                    out.push_str(&text);
+                    on_text(&text);
+                }
+            }
+        }
+        if out.trim().is_empty() {
+            return Err(LlmError::Malformed("empty response".to_string()));
+        }
+        Ok(out)
     }
+}
 
-    #[derive(Deserialize)]
-    struct Part { text: Option<String> }
-    #[derive(Deserialize)]
-    struct Content { parts: Vec<Part> }
-    #[derive(Deserialize)]
-    struct Candidate { content: Content }
-    #[derive(Deserialize)]
-    struct Response { candidates: Option<Vec<Candidate>> }
+/// Finish reasons that mean the output was withheld rather than completed.
+const BLOCKED_FINISH_REASONS: &[&str] = &["SAFETY", "PROHIBITED_CONTENT", "BLOCKLIST", "SPII", "RECITATION"];
 
-    let data: Response = serde_json::from_str(&txt).unwrap_or(Response { candidates: None });
-    let out = data
-        .candidates
-        .and_then(|mut v| v.pop())
-        .and_then(|c| c.content.parts.into_iter().find_map(|p| p.text))
-        .unwrap_or_else(|| "[demo] Gemini returned empty response".to_string());
-    Ok(out)
-}
+#[derive(Deserialize)]
+struct Part { text: Option<String> }
+#[derive(Deserialize)]
+struct Content { parts: Vec<Part> }
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Candidate { content: Option<Content>, finish_reason: Option<String> }
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PromptFeedback { block_reason: Option<String> }
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Response { candidates: Option<Vec<Candidate>>, prompt_feedback: Option<PromptFeedback> }
 
-fn truncate(s: &str, max: usize) -> String {
-    if s.len() <= max {
-        s.to_string()
-    } else {
-        format!("{}…", &s[..max])
+impl Response {
+    /// Text of the last candidate, if any, or a safety error when the prompt
+    /// or the candidate was blocked.
+    fn into_text(self) -> LlmResult<Option<String>> {
+        if let Some(reason) = self.prompt_feedback.and_then(|f| f.block_reason) {
+            return Err(LlmError::Safety(format!("prompt blocked: {reason}")));
+        }
+        let Some(candidate) = self.candidates.and_then(|mut v| v.pop()) else {
+            return Ok(None);
+        };
+        if let Some(reason) = candidate.finish_reason.as_deref().filter(|r| BLOCKED_FINISH_REASONS.contains(r)) {
+            return Err(LlmError::Safety(format!("response blocked: {reason}")));
+        }
+        Ok(candidate.content.and_then(|c| c.parts.into_iter().find_map(|p| p.text)))
     }
 }
@@ -0,0 +1,551 @@
+use anyhow::{anyhow, Result};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+/// Read access to node vectors for the graph. Vectors are unit length so the
+/// dot product is the cosine similarity.
+pub trait VectorSource {
+    fn vector(&self, node: u32) -> &[f32];
+}
+
+/// Hierarchical navigable small world graph over nodes `0..len()`.
+///
+/// Nodes are only ever appended; deleted nodes stay in the graph as waypoints
+/// and are filtered out by the caller until `compact` drops them. Levels are
+/// derived from the node number so rebuilding from the same data gives the
+/// same layer assignment.
+#[derive(Debug, Clone)]
+pub struct Hnsw {
+    m: usize,
+    ef_construction: usize,
+    /// neighbors[node][layer] -> adjacent nodes on that layer
+    neighbors: Vec<Vec<Vec<u32>>>,
+    entry: Option<u32>,
+    max_level: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Scored(f32, u32);
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.partial_cmp(&other.0).unwrap_or(Ordering::Equal).then(self.1.cmp(&other.1))
+    }
+}
+
+const GRAPH_MAGIC: &[u8; 8] = b"ASTRAHNS";
+const MAX_LEVEL: usize = 16;
+
+/// Dot product with eight independent accumulators so the compiler can
+/// vectorize it (a single running sum forces strict ordering).
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let mut acc = [0f32; 8];
+    let (ca, cb) = (a.chunks_exact(8), b.chunks_exact(8));
+    let tail: f32 = ca.remainder().iter().zip(cb.remainder()).map(|(x, y)| x * y).sum();
+    for (x, y) in ca.zip(cb) {
+        for (s, (x, y)) in acc.iter_mut().zip(x.iter().zip(y)) {
+            *s += x * y;
+        }
+    }
+    acc.iter().sum::<f32>() + tail
+}
+
+impl Hnsw {
+    pub fn new(m: usize, ef_construction: usize) -> Self {
+        Self { m: m.max(4), ef_construction: ef_construction.max(m), neighbors: Vec::new(), entry: None, max_level: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.neighbors.len()
+    }
+
+    fn max_degree(&self, layer: usize) -> usize {
+        if layer == 0 { self.m * 2 } else { self.m }
+    }
+
+    /// Geometric level distribution (mL = 1/ln M) from a hash of the node number.
+    fn level_for(&self, node: u32) -> usize {
+        let mut x = (node as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        x ^= x >> 31;
+        let u = ((x >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let level = (-u.ln() / (self.m as f64).ln()).floor() as usize;
+        level.min(MAX_LEVEL)
+    }
+
+    /// Add the next node (its number must equal `len()`).
+    pub fn insert<V: VectorSource>(&mut self, node: u32, vectors: &V) {
+        debug_assert_eq!(node as usize, self.neighbors.len());
+        let level = self.level_for(node);
+        self.neighbors.push(vec![Vec::new(); level + 1]);
+        let Some(mut ep) = self.entry else {
+            self.entry = Some(node);
+            self.max_level = level;
+            return;
+        };
+        let q = vectors.vector(node);
+        let mut ep_score = dot(q, vectors.vector(ep));
+        for layer in (level + 1..=self.max_level).rev() {
+            (ep, ep_score) = self.greedy(q, ep, ep_score, layer, vectors);
+        }
+        let mut entry_points = vec![Scored(ep_score, ep)];
+        for layer in (0..=level.min(self.max_level)).rev() {
+            let candidates = self.search_layer(q, &entry_points, self.ef_construction, layer, vectors);
+            let selected = self.select_neighbors(&candidates, self.max_degree(layer), vectors);
+            self.neighbors[node as usize][layer] = selected.iter().map(|s| s.1).collect();
+            for s in &selected {
+                self.link(s.1, node, layer, vectors);
+            }
+            entry_points = candidates;
+        }
+        if level > self.max_level {
+            self.max_level = level;
+            self.entry = Some(node);
+        }
+    }
+
+    /// Add `node` to `target`'s adjacency on `layer`, pruning if it overflows.
+    fn link<V: VectorSource>(&mut self, target: u32, node: u32, layer: usize, vectors: &V) {
+        let max = self.max_degree(layer);
+        let list = &mut self.neighbors[target as usize][layer];
+        if list.contains(&node) {
+            return;
+        }
+        list.push(node);
+        if list.len() <= max {
+            return;
+        }
+        let tv = vectors.vector(target);
+        let mut candidates: Vec<Scored> = list.iter().map(|&n| Scored(dot(tv, vectors.vector(n)), n)).collect();
+        candidates.sort_by(|a, b| b.cmp(a));
+        let kept = self.select_neighbors(&candidates, max, vectors);
+        self.neighbors[target as usize][layer] = kept.iter().map(|s| s.1).collect();
+    }
+
+    /// Diversity heuristic: keep a candidate only if it is closer to the base
+    /// than to any already selected neighbor, then top up with the best rest.
+    /// `candidates` must be sorted best first.
+    fn select_neighbors<V: VectorSource>(&self, candidates: &[Scored], max: usize, vectors: &V) -> Vec<Scored> {
+        let mut selected: Vec<Scored> = Vec::with_capacity(max);
+        let mut skipped: Vec<Scored> = Vec::new();
+        for &c in candidates {
+            if selected.len() >= max {
+                break;
+            }
+            let cv = vectors.vector(c.1);
+            if selected.iter().all(|s| dot(cv, vectors.vector(s.1)) < c.0) {
+                selected.push(c);
+            } else {
+                skipped.push(c);
+            }
+        }
+        for c in skipped {
+            if selected.len() >= max {
+                break;
+            }
+            selected.push(c);
+        }
+        selected
+    }
+
+    fn greedy<V: VectorSource>(&self, q: &[f32], mut ep: u32, mut ep_score: f32, layer: usize, vectors: &V) -> (u32, f32) {
+        loop {
+            let mut changed = false;
+            for &n in &self.neighbors[ep as usize][layer] {
+                let s = dot(q, vectors.vector(n));
+                if s > ep_score {
+                    ep = n;
+                    ep_score = s;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return (ep, ep_score);
+            }
+        }
+    }
+
+    /// Best-first search on one layer; returns up to `ef` nodes, best first.
+    fn search_layer<V: VectorSource>(&self, q: &[f32], entry_points: &[Scored], ef: usize, layer: usize, vectors: &V) -> Vec<Scored> {
+        let mut visited = vec![0u64; self.neighbors.len().div_ceil(64)];
+        let mut mark = |n: u32| {
+            let (w, b) = (n as usize / 64, n as usize % 64);
+            let seen = visited[w] & (1 << b) != 0;
+            visited[w] |= 1 << b;
+            seen
+        };
+        let mut candidates: BinaryHeap<Scored> = BinaryHeap::new();
+        let mut results: BinaryHeap<Reverse<Scored>> = BinaryHeap::new();
+        for &e in entry_points {
+            if !mark(e.1) {
+                candidates.push(e);
+                results.push(Reverse(e));
+            }
+        }
+        while results.len() > ef {
+            results.pop();
+        }
+        while let Some(c) = candidates.pop() {
+            let worst = results.peek().map(|r| r.0 .0).unwrap_or(f32::MIN);
+            if c.0 < worst && results.len() >= ef {
+                break;
+            }
+            for &n in &self.neighbors[c.1 as usize][layer] {
+                if mark(n) {
+                    continue;
+                }
+                let s = dot(q, vectors.vector(n));
+                let worst = results.peek().map(|r| r.0 .0).unwrap_or(f32::MIN);
+                if results.len() < ef || s > worst {
+                    candidates.push(Scored(s, n));
+                    results.push(Reverse(Scored(s, n)));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+        let mut out: Vec<Scored> = results.into_iter().map(|r| r.0).collect();
+        out.sort_by(|a, b| b.cmp(a));
+        out
+    }
+
+    /// Approximate nearest neighbors of `q` as (node, similarity), best first.
+    pub fn search<V: VectorSource>(&self, q: &[f32], ef: usize, vectors: &V) -> Vec<(u32, f32)> {
+        let Some(mut ep) = self.entry else {
+            return Vec::new();
+        };
+        let mut ep_score = dot(q, vectors.vector(ep));
+        for layer in (1..=self.max_level).rev() {
+            (ep, ep_score) = self.greedy(q, ep, ep_score, layer, vectors);
+        }
+        self.search_layer(q, &[Scored(ep_score, ep)], ef, 0, vectors)
+            .into_iter()
+            .map(|s| (s.1, s.0))
+            .collect()
+    }
+
+    /// Drop removed nodes and renumber the rest. `remap[old]` is the new number
+    /// (or `None` if removed) and must preserve order. Neighbors that pointed at
+    /// a removed node are reconnected through that node's own neighbors.
+    pub fn compact<V: VectorSource>(&self, remap: &[Option<u32>], new_vectors: &V) -> Hnsw {
+        let mut out = Hnsw::new(self.m, self.ef_construction);
+        let live = remap.iter().filter(|r| r.is_some()).count();
+        out.neighbors = Vec::with_capacity(live);
+        for (old, layers) in self.neighbors.iter().enumerate() {
+            let Some(new_id) = remap[old] else { continue };
+            let base = new_vectors.vector(new_id);
+            let mut new_layers = Vec::with_capacity(layers.len());
+            for (layer, adj) in layers.iter().enumerate() {
+                let mut candidates: Vec<u32> = Vec::new();
+                for &n in adj {
+                    match remap[n as usize] {
+                        Some(m) => candidates.push(m),
+                        None => {
+                            // Bridge over the removed neighbor
+                            if let Some(bridge) = self.neighbors[n as usize].get(layer) {
+                                candidates.extend(bridge.iter().filter_map(|&b| remap[b as usize]).filter(|&b| b != new_id));
+                            }
+                        }
+                    }
+                }
+                candidates.sort_unstable();
+                candidates.dedup();
+                let mut scored: Vec<Scored> = candidates.into_iter().map(|c| Scored(dot(base, new_vectors.vector(c)), c)).collect();
+                scored.sort_by(|a, b| b.cmp(a));
+                let kept = out.select_neighbors(&scored, self.max_degree(layer), new_vectors);
+                new_layers.push(kept.into_iter().map(|s| s.1).collect());
+            }
+            out.neighbors.push(new_layers);
+        }
+        // Highest surviving node becomes the entry point
+        let mut best: Option<(usize, u32)> = None;
+        for (i, layers) in out.neighbors.iter().enumerate() {
+            let level = layers.len() - 1;
+            if best.map(|(l, _)| level > l).unwrap_or(true) {
+                best = Some((level, i as u32));
+            }
+        }
+        out.entry = best.map(|(_, n)| n);
+        out.max_level = best.map(|(l, _)| l).unwrap_or(0);
+        out
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(GRAPH_MAGIC);
+        buf.extend_from_slice(&(self.m as u32).to_le_bytes());
+        buf.extend_from_slice(&(self.ef_construction as u32).to_le_bytes());
+        buf.extend_from_slice(&(self.neighbors.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.entry.map(|e| e as i64).unwrap_or(-1).to_le_bytes());
+        buf.extend_from_slice(&(self.max_level as u32).to_le_bytes());
+        for layers in &self.neighbors {
+            buf.push(layers.len() as u8);
+            for adj in layers {
+                buf.extend_from_slice(&(adj.len() as u16).to_le_bytes());
+                for n in adj {
+                    buf.extend_from_slice(&n.to_le_bytes());
+                }
+            }
+        }
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 36 || &bytes[..8] != GRAPH_MAGIC {
+            return Err(anyhow!("not an HNSW graph file"));
+        }
+        let (body, crc) = bytes.split_at(bytes.len() - 4);
+        if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into()?) {
+            return Err(anyhow!("HNSW graph checksum mismatch"));
+        }
+        let mut r = Reader { buf: body, pos: 8 };
+        let m = r.u32()? as usize;
+        let ef_construction = r.u32()? as usize;
+        let count = r.u32()? as usize;
+        let entry = r.i64()?;
+        let max_level = r.u32()? as usize;
+        // Every node takes at least one byte, so a larger count is corrupt
+        if count > body.len() - r.pos {
+            return Err(anyhow!("HNSW graph file is truncated"));
+        }
+        let mut neighbors = Vec::with_capacity(count);
+        for node in 0..count {
+            let levels = r.u8()? as usize;
+            if levels == 0 || levels > MAX_LEVEL + 1 {
+                return Err(anyhow!("HNSW graph node {node} has {levels} layers"));
+            }
+            let mut layers = Vec::with_capacity(levels);
+            for _ in 0..levels {
+                let n = r.u16()? as usize;
+                let mut adj = Vec::with_capacity(n);
+                for _ in 0..n {
+                    let v = r.u32()?;
+                    if v as usize >= count {
+                        return Err(anyhow!("HNSW graph references unknown node {v}"));
+                    }
+                    adj.push(v);
+                }
+                layers.push(adj);
+            }
+            neighbors.push(layers);
+        }
+        if r.pos != body.len() {
+            return Err(anyhow!("HNSW graph file has trailing data"));
+        }
+        // Searches index into these without bounds checks of their own
+        let entry = match (entry, count) {
+            (-1, 0) => None,
+            (e, _) if e >= 0 && (e as usize) < count && neighbors[e as usize].len() == max_level + 1 => Some(e as u32),
+            _ => return Err(anyhow!("HNSW graph entry point {entry} is invalid for {count} nodes at level {max_level}")),
+        };
+        for (node, layers) in neighbors.iter().enumerate() {
+            for (layer, adj) in layers.iter().enumerate() {
+                if let Some(&n) = adj.iter().find(|&&n| neighbors[n as usize].len() <= layer) {
+                    return Err(anyhow!("HNSW graph node {node} links to node {n} on layer {layer} it is not part of"));
+                }
+            }
+        }
+        Ok(Self { m, ef_construction, neighbors, entry, max_level })
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        let end = self.pos + n;
+        if end > self.buf.len() {
+            return Err(anyhow!("HNSW graph file is truncated"));
+        }
+        let out = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Flat {
+        dim: usize,
+        data: Vec<f32>,
+    }
+
+    impl VectorSource for Flat {
+        fn vector(&self, node: u32) -> &[f32] {
+            &self.data[node as usize * self.dim..(node as usize + 1) * self.dim]
+        }
+    }
+
+    /// Deterministic unit vectors (xorshift), so failures reproduce.
+    fn random_unit(seed: u64, count: usize, dim: usize) -> Flat {
+        let mut x = seed.max(1);
+        let mut data = Vec::with_capacity(count * dim);
+        for _ in 0..count {
+            let v: Vec<f32> = (0..dim)
+                .map(|_| {
+                    x ^= x << 13;
+                    x ^= x >> 7;
+                    x ^= x << 17;
+                    (x >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+                })
+                .collect();
+            let n = dot(&v, &v).sqrt();
+            data.extend(v.iter().map(|a| a / n));
+        }
+        Flat { dim, data }
+    }
+
+    fn build(vectors: &Flat) -> Hnsw {
+        let mut g = Hnsw::new(8, 48);
+        for node in 0..(vectors.data.len() / vectors.dim) as u32 {
+            g.insert(node, vectors);
+        }
+        g
+    }
+
+    fn brute_force(q: &[f32], vectors: &Flat, k: usize, keep: impl Fn(u32) -> bool) -> Vec<u32> {
+        let mut scored: Vec<(u32, f32)> = (0..(vectors.data.len() / vectors.dim) as u32)
+            .filter(|n| keep(*n))
+            .map(|n| (n, dot(q, vectors.vector(n))))
+            .collect();
+        scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        scored.into_iter().take(k).map(|(n, _)| n).collect()
+    }
+
+    /// Mean fraction of the exact top 10 found in the graph's top 10.
+    fn recall(g: &Hnsw, vectors: &Flat, queries: &Flat, keep: impl Fn(u32) -> bool + Copy) -> f64 {
+        let count = queries.data.len() / queries.dim;
+        let mut found = 0;
+        for i in 0..count as u32 {
+            let q = queries.vector(i);
+            let exact = brute_force(q, vectors, 10, keep);
+            let approx: Vec<u32> = g.search(q, 64, vectors).into_iter().map(|(n, _)| n).take(10).collect();
+            found += exact.iter().filter(|n| approx.contains(n)).count();
+        }
+        found as f64 / (count * 10) as f64
+    }
+
+    #[test]
+    fn dot_matches_naive_sum() {
+        let a: Vec<f32> = (0..19).map(|i| i as f32 * 0.5).collect();
+        let b: Vec<f32> = (0..19).map(|i| 1.0 - i as f32 * 0.1).collect();
+        let naive: f32 = a.iter().zip(&b).map(|(x, y)| x * y).sum();
+        assert!((dot(&a, &b) - naive).abs() < 1e-3);
+    }
+
+    #[test]
+    fn search_recall_against_brute_force() {
+        let vectors = random_unit(7, 800, 24);
+        let queries = random_unit(99, 30, 24);
+        let g = build(&vectors);
+        let r = recall(&g, &vectors, &queries, |_| true);
+        assert!(r >= 0.9, "recall@10 was {r}");
+    }
+
+    #[test]
+    fn compact_keeps_recall_over_survivors() {
+        let vectors = random_unit(11, 600, 16);
+        let g = build(&vectors);
+        // Drop every third node
+        let mut remap = Vec::new();
+        let mut kept = Flat { dim: 16, data: Vec::new() };
+        for n in 0..600u32 {
+            if n % 3 == 0 {
+                remap.push(None);
+            } else {
+                remap.push(Some((kept.data.len() / 16) as u32));
+                kept.data.extend_from_slice(vectors.vector(n));
+            }
+        }
+        let compacted = g.compact(&remap, &kept);
+        assert_eq!(compacted.len(), kept.data.len() / 16);
+        assert!(compacted.neighbors.iter().flatten().flatten().all(|&n| (n as usize) < compacted.len()));
+        let r = recall(&compacted, &kept, &random_unit(5, 30, 16), |_| true);
+        assert!(r >= 0.85, "recall@10 after compaction was {r}");
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let vectors = random_unit(3, 300, 8);
+        let g = build(&vectors);
+        let restored = Hnsw::from_bytes(&g.to_bytes()).unwrap();
+        assert_eq!(restored.len(), g.len());
+        assert_eq!(restored.entry, g.entry);
+        assert_eq!(restored.neighbors, g.neighbors);
+        let q = vectors.vector(42);
+        assert_eq!(restored.search(q, 32, &vectors)[0].0, 42);
+        assert!(Hnsw::from_bytes(&Hnsw::new(16, 100).to_bytes()).unwrap().entry.is_none());
+    }
+
+    /// Re-seal a modified graph file so only the structural checks can reject it.
+    fn reseal(mut bytes: Vec<u8>) -> Vec<u8> {
+        let body = bytes.len() - 4;
+        let crc = crc32fast::hash(&bytes[..body]);
+        bytes[body..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn from_bytes_rejects_corrupt_graphs() {
+        let vectors = random_unit(3, 50, 8);
+        let bytes = build(&vectors).to_bytes();
+
+        let mut flipped = bytes.clone();
+        flipped[40] ^= 0xff;
+        assert!(Hnsw::from_bytes(&flipped).is_err());
+        assert!(Hnsw::from_bytes(&bytes[..bytes.len() - 9]).is_err());
+
+        // Header: magic(8) m(4) ef(4) count(4) entry(8) max_level(4)
+        let mut bad_entry = bytes.clone();
+        bad_entry[20..28].copy_from_slice(&50i64.to_le_bytes());
+        let err = Hnsw::from_bytes(&reseal(bad_entry)).unwrap_err();
+        assert!(err.to_string().contains("entry point"), "{err}");
+
+        let mut bad_level = bytes.clone();
+        bad_level[28..32].copy_from_slice(&9u32.to_le_bytes());
+        assert!(Hnsw::from_bytes(&reseal(bad_level)).is_err());
+
+        // First neighbor of node 0 (levels u8, then u16 count, then ids)
+        let mut bad_neighbor = bytes.clone();
+        bad_neighbor[35..39].copy_from_slice(&50u32.to_le_bytes());
+        let err = Hnsw::from_bytes(&reseal(bad_neighbor)).unwrap_err();
+        assert!(err.to_string().contains("unknown node"), "{err}");
+
+        let mut huge_count = bytes.clone();
+        huge_count[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Hnsw::from_bytes(&reseal(huge_count)).is_err());
+    }
+}
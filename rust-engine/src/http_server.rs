@@ -0,0 +1,121 @@
+//! Serves the warp routes on hyper so that replies can stream their body.
+//! warp 0.4 only builds bodies from bytes it already holds, so a handler that
+//! streams (stored file contents) attaches its stream to the reply with
+//! `stream_body` and this server sends it as the body in place of the empty one.
+
+use crate::blob_store::ByteStream;
+use bytes::Bytes;
+use futures_util::TryStreamExt;
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, StreamBody};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tower_service::Service;
+use tracing::{debug, error};
+use warp::filters::BoxedFilter;
+use warp::hyper::body::Frame;
+use warp::reply::Response;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A body waiting in the extensions of a reply to be sent by the server.
+#[derive(Clone)]
+struct StreamedBody(Arc<Mutex<Option<ByteStream>>>);
+
+/// Send `stream` as the body of `resp`. The caller sets Content-Length; an
+/// error from the stream aborts the response.
+pub fn stream_body(resp: &mut Response, stream: ByteStream) {
+    resp.extensions_mut().insert(StreamedBody(Arc::new(Mutex::new(Some(stream)))));
+}
+
+/// The reply as hyper sends it: its streamed body if it has one.
+fn into_hyper(resp: Response) -> warp::http::Response<UnsyncBoxBody<Bytes, BoxError>> {
+    let (mut parts, body) = resp.into_parts();
+    let streamed = parts.extensions.remove::<StreamedBody>().and_then(|s| s.0.lock().unwrap().take());
+    let body = match streamed {
+        Some(stream) => StreamBody::new(stream.map_ok(Frame::data).map_err(BoxError::from)).boxed_unsync(),
+        None => body.map_err(BoxError::from).boxed_unsync(),
+    };
+    warp::http::Response::from_parts(parts, body)
+}
+
+/// Accept connections on `addr` and answer them with `routes`, like
+/// `warp::serve(routes).run(addr)`.
+pub async fn serve(routes: BoxedFilter<(Response,)>, addr: SocketAddr) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    loop {
+        let (io, _) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Out of file descriptors and the like; don't spin on it
+                error!("Accept error: {}", e);
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let routes = warp::service(routes.clone());
+        let svc = warp::hyper::service::service_fn(move |req| {
+            let mut routes = routes.clone();
+            async move {
+                let resp = routes.call(req).await?;
+                Ok::<_, Infallible>(into_hyper(resp))
+            }
+        });
+        tokio::spawn(async move {
+            if let Err(e) = auto::Builder::new(TokioExecutor::new()).serve_connection(TokioIo::new(io), svc).await {
+                debug!("Connection error: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body_of(resp: Response) -> Bytes {
+        into_hyper(resp).into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn streamed_bodies_replace_the_empty_one() {
+        let chunks: Vec<anyhow::Result<Bytes>> = vec![Ok(Bytes::from_static(b"%PDF-")), Ok(Bytes::from_static(b"1.7"))];
+        let mut resp = Response::default();
+        stream_body(&mut resp, Box::pin(futures_util::stream::iter(chunks)));
+        assert_eq!(body_of(resp).await, "%PDF-1.7");
+
+        assert_eq!(body_of(Response::new("buffered".into())).await, "buffered");
+    }
+
+    #[tokio::test]
+    async fn stream_errors_end_the_body_with_an_error() {
+        let chunks: Vec<anyhow::Result<Bytes>> = vec![Ok(Bytes::from_static(b"part")), Err(anyhow::anyhow!("connection reset"))];
+        let mut resp = Response::default();
+        stream_body(&mut resp, Box::pin(futures_util::stream::iter(chunks)));
+        assert!(into_hyper(resp).into_body().collect().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_routes_with_streamed_bodies() {
+        use warp::Filter;
+        let routes = warp::path("blob")
+            .map(|| {
+                let chunks: Vec<anyhow::Result<Bytes>> = (0..64).map(|_| Ok(Bytes::from(vec![b'x'; 1024]))).collect();
+                let mut resp = Response::default();
+                resp.headers_mut().insert(warp::http::header::CONTENT_LENGTH, (64 * 1024).into());
+                stream_body(&mut resp, Box::pin(futures_util::stream::iter(chunks)));
+                resp
+            })
+            .boxed();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        tokio::spawn(serve(routes, addr));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let resp = reqwest::get(format!("http://{addr}/blob")).await.unwrap();
+        assert_eq!(resp.content_length(), Some(64 * 1024));
+        assert!(resp.bytes().await.unwrap().iter().all(|b| *b == b'x'));
+        assert_eq!(reqwest::get(format!("http://{addr}/other")).await.unwrap().status(), 404);
+    }
+}
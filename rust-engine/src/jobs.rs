@@ -0,0 +1,371 @@
+//! Durable job queue. Work that runs in the background (file analysis, query
+//! answering, ...) is a row in `jobs`; a `JobHandler` per `job_type` does the
+//! work and the `JobRunner` owns everything around it: claiming by priority
+//! once `next_run_at` is due, leases, concurrency, retries with backoff and
+//! dead-lettering.
+//!
+//! Statuses: `Queued` -> `InProgress` -> `Completed`, or back to `Queued` with
+//! a later `next_run_at` after a transient error, or `DeadLettered` after a
+//! permanent error or once `max_attempts` runs have failed.
+
+use crate::lease::{self, Lease, LeaseLost};
+use crate::outbound;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde_json::Value;
+use sqlx::{Acquire, MySql, MySqlPool, Row};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tracing::{error, info, warn};
+
+/// Wait before polling again when no job of a type is due.
+const IDLE_POLL: Duration = Duration::from_millis(500);
+/// Upper bound on the delay before a retry.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// A claimed job.
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: String,
+    pub job_type: String,
+    /// The file, query, ... the job works on.
+    pub subject_id: Option<String>,
+    /// Handler options, e.g. `copy_from` for the analysis of a duplicate file.
+    pub payload: Value,
+    pub priority: i32,
+    /// Runs so far, including this one.
+    pub attempts: u32,
+    pub max_attempts: u32,
+}
+
+impl Job {
+    pub fn subject(&self) -> Result<&str> {
+        self.subject_id.as_deref().ok_or_else(|| anyhow!("{} job {} has no subject", self.job_type, self.id))
+    }
+}
+
+/// Runs the jobs of one `job_type`.
+#[async_trait]
+pub trait JobHandler: Send + Sync {
+    fn job_type(&self) -> &'static str;
+
+    /// Jobs of this type run at once per process.
+    fn concurrency(&self) -> usize {
+        1
+    }
+
+    /// Enqueue jobs for work that has none, e.g. rows queued before the jobs
+    /// table existed. Called once at startup; returns the jobs enqueued.
+    async fn recover(&self) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Do the work. Transient errors (see `outbound::transient`) are retried;
+    /// any other error dead-letters the job.
+    async fn run(&self, job: &Job, lease: &Lease) -> Result<()>;
+
+    /// The job failed transiently and is scheduled to run again.
+    async fn on_retry(&self, _job: &Job, _err: &anyhow::Error) -> Result<()> {
+        Ok(())
+    }
+
+    /// The job failed for good.
+    async fn on_dead_letter(&self, _job: &Job, _err: &anyhow::Error) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// `JOB_MAX_ATTEMPTS`: runs before a transiently failing job is dead-lettered (default 5).
+pub fn default_max_attempts() -> u32 {
+    std::env::var("JOB_MAX_ATTEMPTS").ok().and_then(|v| v.parse().ok()).unwrap_or(5u32).max(1)
+}
+
+/// Enqueue a job that is due now; returns its id.
+pub async fn enqueue<'e, E>(exec: E, job_type: &str, subject_id: Option<&str>, payload: Value, priority: i32) -> Result<String>
+where
+    E: sqlx::Executor<'e, Database = MySql>,
+{
+    enqueue_after(exec, job_type, subject_id, payload, priority, Duration::ZERO).await
+}
+
+/// Enqueue a job that becomes due after `delay`; returns its id.
+pub async fn enqueue_after<'e, E>(
+    exec: E,
+    job_type: &str,
+    subject_id: Option<&str>,
+    payload: Value,
+    priority: i32,
+    delay: Duration,
+) -> Result<String>
+where
+    E: sqlx::Executor<'e, Database = MySql>,
+{
+    let id = uuid::Uuid::new_v4().to_string();
+    sqlx::query(
+        "INSERT INTO jobs (id, job_type, subject_id, payload, priority, max_attempts, next_run_at) VALUES (?, ?, ?, ?, ?, ?, NOW() + INTERVAL ? SECOND)",
+    )
+    .bind(&id)
+    .bind(job_type)
+    .bind(subject_id)
+    .bind(payload)
+    .bind(priority)
+    .bind(default_max_attempts())
+    .bind(delay.as_secs())
+    .execute(exec)
+    .await?;
+    Ok(id)
+}
+
+/// Claims and runs jobs for the registered handlers.
+pub struct JobRunner {
+    pool: MySqlPool,
+    handlers: Vec<Arc<dyn JobHandler>>,
+}
+
+impl JobRunner {
+    pub fn new(pool: MySqlPool) -> Self {
+        Self { pool, handlers: Vec::new() }
+    }
+
+    pub fn register(&mut self, handler: Arc<dyn JobHandler>) {
+        self.handlers.push(handler);
+    }
+
+    /// Spawn one claim loop per handler.
+    pub fn start(self) {
+        for handler in self.handlers {
+            let pool = self.pool.clone();
+            tokio::spawn(async move { run_handler(pool, handler).await });
+        }
+    }
+}
+
+async fn run_handler(pool: MySqlPool, handler: Arc<dyn JobHandler>) {
+    let job_type = handler.job_type();
+    match handler.recover().await {
+        Ok(0) => {}
+        Ok(n) => info!("Enqueued {} {} jobs for work left without one", n, job_type),
+        Err(e) => error!("Failed to recover {} jobs: {}", job_type, e),
+    }
+    let concurrency = handler.concurrency().max(1);
+    info!("Running {} jobs with {} concurrent tasks", job_type, concurrency);
+    let slots = Arc::new(Semaphore::new(concurrency));
+    loop {
+        // Leave jobs queued while a model or embedding service is down
+        if let Some((dependency, wait)) = outbound::pause_remaining() {
+            warn!("{} jobs paused for {}s: {} unavailable", job_type, wait.as_secs(), dependency);
+            tokio::time::sleep(wait).await;
+            continue;
+        }
+        // Only claim a job once a task slot is free, so claimed jobs never wait on us
+        let Ok(slot) = slots.clone().acquire_owned().await else { return };
+        match claim(&pool, job_type).await {
+            Ok(Some((job, lease))) => {
+                let pool = pool.clone();
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    execute(&pool, handler.as_ref(), &job, &lease).await;
+                    drop(slot);
+                });
+            }
+            Ok(None) => {
+                drop(slot);
+                tokio::time::sleep(IDLE_POLL).await;
+            }
+            Err(e) => {
+                drop(slot);
+                error!("Failed to claim {} job: {}", job_type, e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// Claim the due job of `job_type` with the highest priority: a queued job
+/// whose `next_run_at` has passed, or a running one whose lease expired.
+/// `SKIP LOCKED` lets concurrent claimants (tasks or replicas) pass over a
+/// row another one is taking instead of waiting for it or taking it too.
+async fn claim(pool: &MySqlPool, job_type: &str) -> Result<Option<(Job, Lease)>> {
+    let token = lease::new_token();
+    let mut conn = pool.acquire().await?;
+    // Under the default REPEATABLE READ, InnoDB would keep every scanned row
+    // locked until commit and other claimants would skip rows they could take
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL READ COMMITTED").execute(&mut *conn).await?;
+    let mut tx = conn.begin().await?;
+    let row = sqlx::query(
+        "SELECT id, subject_id, payload, priority, attempts, max_attempts FROM jobs \
+         WHERE job_type = ? AND ((status = 'Queued' AND next_run_at <= NOW()) OR (status = 'InProgress' AND lease_expires_at < NOW())) \
+         ORDER BY priority DESC, next_run_at LIMIT 1 FOR UPDATE SKIP LOCKED",
+    )
+    .bind(job_type)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(row) = row else { return Ok(None) };
+    let id: String = row.get("id");
+    sqlx::query(
+        "UPDATE jobs SET status = 'InProgress', attempts = attempts + 1, claimed_by = ?, lease_expires_at = NOW() + INTERVAL ? SECOND WHERE id = ?",
+    )
+    .bind(&token)
+    .bind(lease::lease_duration().as_secs())
+    .bind(&id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    let job = Job {
+        id: id.clone(),
+        job_type: job_type.to_string(),
+        subject_id: row.get("subject_id"),
+        payload: row.get::<Option<Value>, _>("payload").unwrap_or(Value::Null),
+        priority: row.get("priority"),
+        attempts: row.get::<i32, _>("attempts").max(0) as u32 + 1,
+        max_attempts: row.get::<i32, _>("max_attempts").max(1) as u32,
+    };
+    Ok(Some((job, Lease::hold(pool, "jobs", id, token))))
+}
+
+/// Run a claimed job and record the outcome.
+async fn execute(pool: &MySqlPool, handler: &dyn JobHandler, job: &Job, lease: &Lease) {
+    let outcome = if job.attempts > job.max_attempts {
+        // Its worker died or hung on every run
+        Err(anyhow!("abandoned after {} runs without finishing", job.max_attempts))
+    } else {
+        info!("Running {} job {} (priority {}, attempt {}/{})", job.job_type, job.id, job.priority, job.attempts, job.max_attempts);
+        handler.run(job, lease).await
+    };
+    let e = match outcome {
+        Ok(()) => {
+            if let Err(e) = settle(pool, lease, "status = 'Completed', last_error = NULL", None, None).await {
+                error!("Failed to complete {} job {}: {}", job.job_type, job.id, e);
+            }
+            return;
+        }
+        Err(e) => e,
+    };
+    if e.is::<LeaseLost>() {
+        // Reset or taken over by another worker; its outcome is not ours to record
+        warn!("Stopped {} job {}: {}", job.job_type, job.id, e);
+        return;
+    }
+    let reason = format!("{e:#}");
+    let retry = outbound::transient(&e).filter(|_| job.attempts < job.max_attempts);
+    let settled = match retry {
+        Some(retry_after) => {
+            let delay = retry_delay(job.attempts, retry_after);
+            warn!("{} job {} failed transiently, retrying in {}s: {}", job.job_type, job.id, delay.as_secs(), reason);
+            let set = "status = 'Queued', next_run_at = NOW() + INTERVAL ? SECOND, last_error = ?, claimed_by = NULL";
+            match settle(pool, lease, set, Some(delay.as_secs()), Some(&reason)).await {
+                Ok(true) => handler.on_retry(job, &e).await,
+                other => other.map(|_| ()),
+            }
+        }
+        None => {
+            error!("{} job {} dead-lettered: {}", job.job_type, job.id, reason);
+            match settle(pool, lease, "status = 'DeadLettered', last_error = ?", None, Some(&reason)).await {
+                Ok(true) => handler.on_dead_letter(job, &e).await,
+                other => other.map(|_| ()),
+            }
+        }
+    };
+    if let Err(settle_err) = settled {
+        error!("Failed to record failure of {} job {}: {}", job.job_type, job.id, settle_err);
+    }
+}
+
+/// Apply `set` to the job while the lease still holds it and end the lease;
+/// false when the job was meanwhile reset or taken over.
+async fn settle(pool: &MySqlPool, lease: &Lease, set: &str, delay_secs: Option<u64>, reason: Option<&str>) -> Result<bool> {
+    let sql = format!("UPDATE jobs SET {set}, lease_expires_at = NULL WHERE id = ? AND claimed_by = ? AND status = 'InProgress'");
+    let mut query = sqlx::query(&sql);
+    if let Some(secs) = delay_secs {
+        query = query.bind(secs);
+    }
+    if let Some(reason) = reason {
+        query = query.bind(reason);
+    }
+    let done = query.bind(lease.id()).bind(lease.token()).execute(pool).await?;
+    Ok(done.rows_affected() == 1)
+}
+
+/// `JOB_RETRY_BASE_SECS` (default 30) doubled per attempt with equal jitter,
+/// at least the service's Retry-After and at most an hour.
+fn retry_delay(attempt: u32, retry_after: Option<Duration>) -> Duration {
+    let base = std::env::var("JOB_RETRY_BASE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(30u64);
+    let exp = Duration::from_secs(base).saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1))).min(MAX_RETRY_DELAY);
+    let jittered = exp.mul_f64(0.5 + 0.5 * outbound::random_unit());
+    retry_after.map(|d| d.max(jittered)).unwrap_or(jittered).min(MAX_RETRY_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// A job type of its own per test, so tests sharing the database do not claim each other's jobs.
+    fn test_type() -> String {
+        format!("test_{}", uuid::Uuid::new_v4().simple())
+    }
+
+    async fn cleanup(pool: &MySqlPool, job_type: &str) {
+        sqlx::query("DELETE FROM jobs WHERE job_type = ?").bind(job_type).execute(pool).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn claims_due_jobs_by_priority() {
+        let Some(pool) = crate::db::test_pool().await else { return };
+        let job_type = test_type();
+        let low = enqueue(&pool, &job_type, Some("low"), Value::Null, 0).await.unwrap();
+        let high = enqueue(&pool, &job_type, Some("high"), serde_json::json!({"copy_from": "x"}), 10).await.unwrap();
+        enqueue_after(&pool, &job_type, Some("later"), Value::Null, 100, Duration::from_secs(3600)).await.unwrap();
+
+        let (job, _lease) = claim(&pool, &job_type).await.unwrap().expect("high");
+        assert_eq!((job.id.as_str(), job.subject().unwrap(), job.attempts), (high.as_str(), "high", 1));
+        assert_eq!(job.payload["copy_from"], "x");
+        let (job, _lease) = claim(&pool, &job_type).await.unwrap().expect("low");
+        assert_eq!(job.id, low);
+        assert!(claim(&pool, &job_type).await.unwrap().is_none(), "the delayed job is not due");
+        cleanup(&pool, &job_type).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_claims_take_distinct_jobs() {
+        let Some(pool) = crate::db::test_pool().await else { return };
+        let job_type = test_type();
+        for _ in 0..8 {
+            enqueue(&pool, &job_type, None, Value::Null, 0).await.unwrap();
+        }
+        let claims: Vec<_> = (0..10)
+            .map(|_| {
+                let (pool, job_type) = (pool.clone(), job_type.clone());
+                tokio::spawn(async move { claim(&pool, &job_type).await.unwrap().map(|(job, _)| job.id) })
+            })
+            .collect();
+        let mut claimed = Vec::new();
+        for c in claims {
+            claimed.extend(c.await.unwrap());
+        }
+        assert_eq!(claimed.len(), 8);
+        assert_eq!(claimed.iter().collect::<HashSet<_>>().len(), 8);
+        cleanup(&pool, &job_type).await;
+    }
+
+    #[tokio::test]
+    async fn expired_leases_are_reclaimed_and_the_old_claimant_cannot_settle() {
+        let Some(pool) = crate::db::test_pool().await else { return };
+        let job_type = test_type();
+        let id = enqueue(&pool, &job_type, None, Value::Null, 0).await.unwrap();
+        let (_, first) = claim(&pool, &job_type).await.unwrap().unwrap();
+        assert!(claim(&pool, &job_type).await.unwrap().is_none(), "leased");
+
+        // Its worker hung: the lease runs out
+        sqlx::query("UPDATE jobs SET lease_expires_at = NOW() - INTERVAL 1 SECOND WHERE id = ?").bind(&id).execute(&pool).await.unwrap();
+        let (job, second) = claim(&pool, &job_type).await.unwrap().expect("reclaimed");
+        assert_eq!((job.id.as_str(), job.attempts), (id.as_str(), 2));
+        assert_ne!(first.token(), second.token());
+
+        assert!(!settle(&pool, &first, "status = 'Completed'", None, None).await.unwrap());
+        assert!(settle(&pool, &second, "status = 'Completed'", None, None).await.unwrap());
+        cleanup(&pool, &job_type).await;
+    }
+}
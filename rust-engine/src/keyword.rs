@@ -0,0 +1,463 @@
+use crate::embedding::{stem, STOPWORDS};
+use crate::vector_store::{file_payload, ScoredPoint, VectorFilter};
+use anyhow::Result;
+use sqlx::{MySqlPool, Row};
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+/// BM25 term-frequency saturation and length normalization.
+const BM25_K1: f32 = 1.2;
+const BM25_B: f32 = 0.75;
+/// Sequence numbers re-read behind the cursor on every refresh, in case a
+/// change row committed after one with a higher number.
+const CHANGE_LOOKBACK: i64 = 64;
+/// Change rows older than this are pruned when the index is rebuilt.
+const CHANGE_RETENTION_DAYS: i64 = 7;
+
+/// A chunk to index, as stored in the `chunks` table.
+pub struct KeywordDoc {
+    pub chunk_id: String,
+    pub page: u32,
+    pub chunk_index: u32,
+    pub text: String,
+}
+
+struct Doc {
+    chunk_id: String,
+    file_id: String,
+    page: u32,
+    chunk_index: u32,
+    len: u32,
+    terms: Vec<String>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Slots are reused after a file is removed
+    docs: Vec<Option<Doc>>,
+    free: Vec<u32>,
+    by_file: HashMap<String, Vec<u32>>,
+    /// File-level payload (tags, MIME type, upload time) used for filtering
+    file_meta: HashMap<String, serde_json::Value>,
+    /// term -> (doc slot, term frequency)
+    postings: HashMap<String, Vec<(u32, u32)>>,
+    total_len: u64,
+    live: usize,
+}
+
+/// Position in the `keyword_changes` feed.
+#[derive(Default)]
+struct ChangeCursor {
+    seq: i64,
+    /// Sequence numbers within the lookback window that are already applied
+    applied: HashSet<i64>,
+}
+
+/// In-memory BM25 inverted index over chunk text. Complements vector search
+/// for exact identifiers (part numbers, acronyms, procedure IDs) that
+/// embeddings blur. Rebuilt from the `chunks` table at startup. Every change
+/// a replica makes is also recorded in `keyword_changes`, and `refresh`
+/// reloads the files other replicas changed before a search.
+pub struct KeywordIndex {
+    inner: RwLock<Inner>,
+    pool: MySqlPool,
+    /// Held for a whole refresh, so only one runs at a time
+    cursor: tokio::sync::Mutex<ChangeCursor>,
+}
+
+impl KeywordIndex {
+    pub fn new(pool: MySqlPool) -> Self {
+        Self { inner: RwLock::new(Inner::default()), pool, cursor: tokio::sync::Mutex::new(ChangeCursor::default()) }
+    }
+
+    /// Load every stored chunk into a fresh index.
+    pub async fn rebuild(&self) -> Result<usize> {
+        let mut cursor = self.cursor.lock().await;
+        self.rebuild_locked(&mut cursor).await
+    }
+
+    async fn rebuild_locked(&self, cursor: &mut ChangeCursor) -> Result<usize> {
+        sqlx::query("DELETE FROM keyword_changes WHERE changed_at < NOW() - INTERVAL ? DAY")
+            .bind(CHANGE_RETENTION_DAYS)
+            .execute(&self.pool)
+            .await?;
+        // Read the feed position first: whatever commits during the load is
+        // replayed by the next refresh
+        let seq: Option<i64> = sqlx::query_scalar("SELECT MAX(seq) FROM keyword_changes").fetch_one(&self.pool).await?;
+        let seq = seq.unwrap_or(0);
+        let applied: Vec<i64> = sqlx::query_scalar("SELECT seq FROM keyword_changes WHERE seq > ? AND seq <= ?")
+            .bind(seq - CHANGE_LOOKBACK)
+            .bind(seq)
+            .fetch_all(&self.pool)
+            .await?;
+        let files = load_files(&self.pool, None).await?;
+        *self.inner.write().unwrap() = Inner::default();
+        for (file_id, (meta, docs)) in files {
+            self.apply_file(&file_id, meta, docs);
+        }
+        *cursor = ChangeCursor { seq, applied: applied.into_iter().collect() };
+        Ok(self.len())
+    }
+
+    /// Reload the files changed by other replicas since the last refresh.
+    pub async fn refresh(&self) -> Result<()> {
+        let mut cursor = self.cursor.lock().await;
+        // Rows past the cursor were pruned: too far behind to catch up file by file
+        let oldest: Option<i64> = sqlx::query_scalar("SELECT MIN(seq) FROM keyword_changes").fetch_one(&self.pool).await?;
+        if oldest.is_some_and(|o| o > cursor.seq + 1) && cursor.seq > 0 {
+            let n = self.rebuild_locked(&mut cursor).await?;
+            tracing::info!("Keyword index fell behind the change feed; rebuilt with {} chunks", n);
+            return Ok(());
+        }
+        let rows: Vec<(i64, String)> = sqlx::query_as("SELECT seq, file_id FROM keyword_changes WHERE seq > ? ORDER BY seq")
+            .bind(cursor.seq - CHANGE_LOOKBACK)
+            .fetch_all(&self.pool)
+            .await?;
+        let pending: Vec<&(i64, String)> = rows.iter().filter(|(seq, _)| !cursor.applied.contains(seq)).collect();
+        let mut changed: Vec<&str> = pending.iter().map(|(_, f)| f.as_str()).collect();
+        changed.sort_unstable();
+        changed.dedup();
+        for file_id in changed {
+            match load_files(&self.pool, Some(file_id)).await?.remove(file_id) {
+                Some((meta, docs)) => self.apply_file(file_id, meta, docs),
+                None => self.inner.write().unwrap().remove_file(file_id),
+            }
+        }
+        cursor.applied.extend(pending.iter().map(|(seq, _)| *seq));
+        if let Some((last, _)) = rows.last() {
+            cursor.seq = cursor.seq.max(*last);
+        }
+        let floor = cursor.seq - CHANGE_LOOKBACK;
+        cursor.applied.retain(|seq| *seq > floor);
+        Ok(())
+    }
+
+    /// Record a change to a file's entries for the other replicas. This
+    /// replica has applied it already, so its own refresh skips the row.
+    async fn publish(&self, file_id: &str) -> Result<()> {
+        let seq = sqlx::query("INSERT INTO keyword_changes (file_id) VALUES (?)")
+            .bind(file_id)
+            .execute(&self.pool)
+            .await?
+            .last_insert_id() as i64;
+        self.cursor.lock().await.applied.insert(seq);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().live
+    }
+
+    /// Replace the indexed chunks of a file. `meta` is the file-level payload
+    /// (see `vector_store::file_payload`) that filters are evaluated against.
+    pub async fn index_file(&self, file_id: &str, meta: serde_json::Value, docs: Vec<KeywordDoc>) -> Result<()> {
+        self.apply_file(file_id, meta, docs);
+        self.publish(file_id).await
+    }
+
+    /// Change the tags filters see for an indexed file.
+    pub async fn set_file_tags(&self, file_id: &str, tags: serde_json::Value) -> Result<()> {
+        if let Some(meta) = self.inner.write().unwrap().file_meta.get_mut(file_id) {
+            meta["tags"] = tags;
+        }
+        self.publish(file_id).await
+    }
+
+    pub async fn remove_file(&self, file_id: &str) -> Result<()> {
+        self.inner.write().unwrap().remove_file(file_id);
+        self.publish(file_id).await
+    }
+    fn apply_file(&self, file_id: &str, meta: serde_json::Value, docs: Vec<KeywordDoc>) {
+        let mut inner = self.inner.write().unwrap();
+        inner.remove_file(file_id);
+        inner.file_meta.insert(file_id.to_string(), meta);
+        for d in docs {
+            let terms = keyword_tokens(&d.text);
+            let mut tf: HashMap<&str, u32> = HashMap::new();
+            for t in &terms {
+                *tf.entry(t.as_str()).or_default() += 1;
+            }
+            let slot = match inner.free.pop() {
+                Some(s) => s,
+                None => {
+                    inner.docs.push(None);
+                    inner.docs.len() as u32 - 1
+                }
+            };
+            for (term, count) in &tf {
+                inner.postings.entry(term.to_string()).or_default().push((slot, *count));
+            }
+            let unique: Vec<String> = tf.keys().map(|t| t.to_string()).collect();
+            inner.total_len += terms.len() as u64;
+            inner.live += 1;
+            inner.by_file.entry(file_id.to_string()).or_default().push(slot);
+            inner.docs[slot as usize] = Some(Doc {
+                chunk_id: d.chunk_id,
+                file_id: file_id.to_string(),
+                page: d.page,
+                chunk_index: d.chunk_index,
+                len: terms.len() as u32,
+                terms: unique,
+            });
+        }
+    }
+
+    /// Top `k` chunks by BM25 score among files matching the filter. Hits carry
+    /// the same payload as chunk points in the vector store so both result
+    /// lists can be fused.
+    pub fn search(&self, query: &str, k: usize, filter: &VectorFilter) -> Vec<ScoredPoint> {
+        let inner = self.inner.read().unwrap();
+        if inner.live == 0 || k == 0 {
+            return Vec::new();
+        }
+        let mut terms = keyword_tokens(query);
+        terms.sort();
+        terms.dedup();
+        let n = inner.live as f32;
+        let avg_len = (inner.total_len as f32 / n).max(1.0);
+        let mut scores: HashMap<u32, f32> = HashMap::new();
+        for term in &terms {
+            let Some(postings) = inner.postings.get(term) else { continue };
+            let df = postings.len() as f32;
+            let idf = ((n - df + 0.5) / (df + 0.5) + 1.0).ln();
+            for &(slot, tf) in postings {
+                let len = inner.docs[slot as usize].as_ref().map(|d| d.len).unwrap_or(0) as f32;
+                let tf = tf as f32;
+                let norm = tf * (BM25_K1 + 1.0) / (tf + BM25_K1 * (1.0 - BM25_B + BM25_B * len / avg_len));
+                *scores.entry(slot).or_default() += idf * norm;
+            }
+        }
+        let mut ranked: Vec<(u32, f32)> = scores.into_iter().collect();
+        ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        ranked
+            .into_iter()
+            .filter_map(|(slot, score)| {
+                let d = inner.docs[slot as usize].as_ref()?;
+                let meta = inner.file_meta.get(&d.file_id)?;
+                if !filter.is_empty() && !filter.matches(meta) {
+                    return None;
+                }
+                let mut payload = meta.clone();
+                payload["type"] = serde_json::json!("chunk");
+                payload["page"] = serde_json::json!(d.page);
+                payload["chunk_index"] = serde_json::json!(d.chunk_index);
+                Some(ScoredPoint { id: d.chunk_id.clone(), score, payload })
+            })
+            .take(k)
+            .collect()
+    }
+}
+
+impl Inner {
+    fn remove_file(&mut self, file_id: &str) {
+        self.file_meta.remove(file_id);
+        let Some(slots) = self.by_file.remove(file_id) else { return };
+        for slot in slots {
+            let Some(doc) = self.docs[slot as usize].take() else { continue };
+            for term in &doc.terms {
+                if let Some(list) = self.postings.get_mut(term) {
+                    list.retain(|(s, _)| *s != slot);
+                    if list.is_empty() {
+                        self.postings.remove(term);
+                    }
+                }
+            }
+            self.total_len -= doc.len as u64;
+            self.live -= 1;
+            self.free.push(slot);
+        }
+    }
+}
+
+/// Stored chunks with their file-level payloads, grouped by file; all files,
+/// or just one.
+async fn load_files(pool: &MySqlPool, file_id: Option<&str>) -> Result<HashMap<String, (serde_json::Value, Vec<KeywordDoc>)>> {
+    let sql = format!(
+        "SELECT c.id, c.file_id, c.page_number, c.chunk_index, c.heading, c.text, f.filename, f.mime_type, f.tags, \
+         CAST(UNIX_TIMESTAMP(f.created_at) AS SIGNED) AS uploaded_at \
+         FROM chunks c JOIN files f ON f.id = c.file_id {} ORDER BY c.file_id, c.chunk_index",
+        if file_id.is_some() { "WHERE c.file_id = ?" } else { "" }
+    );
+    let mut query = sqlx::query(&sql);
+    if let Some(id) = file_id {
+        query = query.bind(id);
+    }
+    let rows = query.fetch_all(pool).await?;
+    let mut files: HashMap<String, (serde_json::Value, Vec<KeywordDoc>)> = HashMap::new();
+    for row in rows {
+        let file_id: String = row.get("file_id");
+        let heading: Option<String> = row.get("heading");
+        let text: String = row.get("text");
+        let entry = files.entry(file_id.clone()).or_insert_with(|| {
+            let filename: String = row.get("filename");
+            let mime_type: Option<String> = row.get("mime_type");
+            let mime_type = mime_type.unwrap_or_else(|| crate::storage::guess_mime(&filename).to_string());
+            (file_payload(&file_id, &mime_type, row.get("tags"), row.get("uploaded_at")), Vec::new())
+        });
+        entry.1.push(KeywordDoc {
+            chunk_id: row.get("id"),
+            page: row.get::<i32, _>("page_number").max(0) as u32,
+            chunk_index: row.get::<i32, _>("chunk_index").max(0) as u32,
+            text: match heading {
+                Some(h) => format!("{h}\n{text}"),
+                None => text,
+            },
+        });
+    }
+    Ok(files)
+}
+
+/// Lowercased search terms. Identifiers joined by `-`, `/` or `.` are kept
+/// whole ("683-17105", "p/n") and also split into their parts; acronyms and
+/// anything containing digits are not stemmed.
+pub fn keyword_tokens(text: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    for raw in text.split(|c: char| !(c.is_alphanumeric() || matches!(c, '-' | '/' | '.'))) {
+        let raw = raw.trim_matches(|c: char| matches!(c, '-' | '/' | '.'));
+        if raw.is_empty() {
+            continue;
+        }
+        let parts: Vec<&str> = raw.split(['-', '/', '.']).filter(|p| !p.is_empty()).collect();
+        if parts.len() > 1 {
+            out.push(raw.to_lowercase());
+        }
+        for part in parts {
+            let lower = part.to_lowercase();
+            if STOPWORDS.contains(&lower.as_str()) || (lower.chars().count() < 2 && !lower.chars().all(|c| c.is_ascii_digit())) {
+                continue;
+            }
+            let acronym = part.chars().count() > 1 && part.chars().all(|c| !c.is_lowercase());
+            out.push(if acronym { lower } else { stem(&lower) });
+        }
+    }
+    out
+}
+
+/// Reciprocal rank fusion: each list contributes `1 / (k + rank)` per hit, so
+/// lists with incomparable score scales (cosine vs BM25) can be merged.
+pub fn reciprocal_rank_fusion(lists: &[Vec<ScoredPoint>], k: f32, limit: usize) -> Vec<ScoredPoint> {
+    let mut fused: Vec<ScoredPoint> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for list in lists {
+        for (rank, hit) in list.iter().enumerate() {
+            let score = 1.0 / (k + rank as f32 + 1.0);
+            match index.get(&hit.id) {
+                Some(&i) => fused[i].score += score,
+                None => {
+                    index.insert(hit.id.clone(), fused.len());
+                    fused.push(ScoredPoint { id: hit.id.clone(), score, payload: hit.payload.clone() });
+                }
+            }
+        }
+    }
+    fused.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    fused.truncate(limit);
+    fused
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Only the in-memory side is exercised; the pool never connects.
+    fn index() -> KeywordIndex {
+        KeywordIndex::new(sqlx::mysql::MySqlPoolOptions::new().connect_lazy("mysql://localhost/unused").unwrap())
+    }
+
+    fn doc(chunk_id: &str, page: u32, text: &str) -> KeywordDoc {
+        KeywordDoc { chunk_id: chunk_id.to_string(), page, chunk_index: page, text: text.to_string() }
+    }
+
+    fn meta(file_id: &str, tags: &[&str]) -> serde_json::Value {
+        file_payload(file_id, "application/pdf", Some(json!(tags)), Some(1_700_000_000))
+    }
+
+    fn ids(hits: &[ScoredPoint]) -> Vec<&str> {
+        hits.iter().map(|h| h.id.as_str()).collect()
+    }
+
+    #[test]
+    fn tokens_keep_identifiers_whole() {
+        let tokens = keyword_tokens("Replace P/N 683-17105 on the ECLSS pumps.");
+        for t in ["p/n", "683-17105", "683", "17105", "eclss", "pump", "replace"] {
+            assert!(tokens.contains(&t.to_string()), "missing {t} in {tokens:?}");
+        }
+        assert!(!tokens.contains(&"the".to_string()));
+        assert!(!tokens.contains(&"p".to_string()));
+    }
+
+    #[tokio::test]
+    async fn bm25_prefers_rare_terms_and_short_documents() {
+        let index = index();
+        index.apply_file(
+            "f1",
+            meta("f1", &[]),
+            vec![
+                doc("c1", 1, "coolant pump coolant loop maintenance procedure for the external radiator panels and valves"),
+                doc("c2", 2, "coolant pump"),
+                doc("c3", 3, "oxygen generation assembly"),
+            ],
+        );
+        let hits = index.search("coolant pump", 10, &VectorFilter::default());
+        assert_eq!(ids(&hits), vec!["c2", "c1"]);
+        assert!(hits[0].score > hits[1].score);
+        let hits = index.search("oxygen coolant", 10, &VectorFilter::default());
+        assert_eq!(hits[0].id, "c3", "the rarer term should dominate");
+        assert!(index.search("nothing matches", 10, &VectorFilter::default()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn hits_carry_chunk_payload_and_respect_filters() {
+        let index = index();
+        index.apply_file("f1", meta("f1", &["eclss"]), vec![doc("c1", 4, "urine processor assembly")]);
+        index.apply_file("f2", meta("f2", &["power"]), vec![doc("c2", 9, "urine processor distillation")]);
+        let hits = index.search("urine processor", 10, &VectorFilter::default());
+        assert_eq!(hits.len(), 2);
+        let filter = VectorFilter { tags: Some(vec!["power".into()]), ..Default::default() };
+        let hits = index.search("urine processor", 10, &filter);
+        assert_eq!(ids(&hits), vec!["c2"]);
+        assert_eq!(hits[0].payload["type"], "chunk");
+        assert_eq!(hits[0].payload["page"], 9);
+        assert_eq!(hits[0].payload["file_id"], "f2");
+        assert_eq!(index.search("urine", 1, &VectorFilter::default()).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reindexing_and_removal_keep_counts_consistent() {
+        let index = index();
+        index.apply_file("f1", meta("f1", &[]), vec![doc("c1", 1, "alpha beta"), doc("c2", 2, "gamma")]);
+        index.apply_file("f2", meta("f2", &[]), vec![doc("c3", 1, "alpha delta")]);
+        assert_eq!(index.len(), 3);
+
+        // Re-indexing replaces the old chunks and reuses their slots
+        index.apply_file("f1", meta("f1", &[]), vec![doc("c4", 1, "epsilon")]);
+        assert_eq!(index.len(), 2);
+        assert_eq!(ids(&index.search("alpha", 10, &VectorFilter::default())), vec!["c3"]);
+        assert!(index.search("gamma", 10, &VectorFilter::default()).is_empty());
+
+        index.inner.write().unwrap().remove_file("f2");
+        let inner = index.inner.read().unwrap();
+        assert_eq!(inner.live, 1);
+        assert_eq!(inner.total_len, 1);
+        assert!(!inner.postings.contains_key("alpha"));
+        assert_eq!(inner.docs.iter().filter(|d| d.is_some()).count(), 1);
+        assert_eq!(inner.docs.len(), 3);
+    }
+
+    fn hit(id: &str) -> ScoredPoint {
+        ScoredPoint { id: id.to_string(), score: 0.0, payload: json!({ "id": id }) }
+    }
+
+    #[test]
+    fn rank_fusion_rewards_agreement() {
+        let vector = vec![hit("a"), hit("b"), hit("c")];
+        let keyword = vec![hit("c"), hit("d")];
+        let fused = reciprocal_rank_fusion(&[vector, keyword], 60.0, 3);
+        // "b" and "d" tie at 1/62; the first list seen wins
+        assert_eq!(ids(&fused), vec!["c", "a", "b"]);
+        assert!((fused[0].score - (1.0 / 63.0 + 1.0 / 61.0)).abs() < 1e-6);
+        assert!((fused[1].score - 1.0 / 61.0).abs() < 1e-6);
+        assert!(reciprocal_rank_fusion(&[], 60.0, 5).is_empty());
+    }
+}
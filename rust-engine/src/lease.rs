@@ -0,0 +1,175 @@
+//! Leases on claimed job rows. A claimant stamps the row with a claim token
+//! in `claimed_by` and an expiry in `lease_expires_at`; a heartbeat keeps
+//! renewing the expiry while the job runs, so another worker may only reclaim
+//! the row once its worker died or hung.
+
+use sqlx::MySqlPool;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+lazy_static::lazy_static! {
+    /// Identifies this process in `claimed_by`: `WORKER_ID`, else the host name and pid.
+    static ref WORKER_ID: String = std::env::var("WORKER_ID").unwrap_or_else(|_| {
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "engine".to_string());
+        format!("{host}-{}", std::process::id())
+    });
+}
+
+/// `LEASE_SECS`: how long a claim stays valid without a heartbeat (default 60).
+pub fn lease_duration() -> Duration {
+    let secs = std::env::var("LEASE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(60u64);
+    Duration::from_secs(secs.max(3))
+}
+
+/// New value for `claimed_by`: this worker's id and a unique claim token.
+pub fn new_token() -> String {
+    format!("{}/{}", *WORKER_ID, uuid::Uuid::new_v4().simple())
+}
+
+/// A claimed row of `table` (with `id`, `status`, `claimed_by` and
+/// `lease_expires_at` columns). The heartbeat stops when the lease is dropped.
+pub struct Lease {
+    id: String,
+    token: String,
+    table: &'static str,
+    lost: Arc<AtomicBool>,
+    heartbeat: JoinHandle<()>,
+}
+
+impl Lease {
+    /// Start renewing a lease the caller just took by setting `claimed_by = token`.
+    pub fn hold(pool: &MySqlPool, table: &'static str, id: String, token: String) -> Lease {
+        Self::with_duration(pool, table, id, token, lease_duration())
+    }
+
+    /// Like `hold`, renewing the lease for `ttl` at a time instead of `LEASE_SECS`.
+    pub fn with_duration(pool: &MySqlPool, table: &'static str, id: String, token: String, ttl: Duration) -> Lease {
+        let lost = Arc::new(AtomicBool::new(false));
+        let heartbeat = tokio::spawn(heartbeat(pool.clone(), table, id.clone(), token.clone(), ttl, lost.clone()));
+        Lease { id, token, table, lost, heartbeat }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Value of `claimed_by` while this lease holds the row; guard writes with it.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Error unless the lease is still held. A lost lease means the row was
+    /// reset, deleted or reclaimed by another worker after it expired.
+    pub fn ensure_held(&self) -> Result<(), LeaseLost> {
+        if self.lost.load(Ordering::Relaxed) {
+            return Err(self.lost_error());
+        }
+        Ok(())
+    }
+
+    pub fn lost_error(&self) -> LeaseLost {
+        LeaseLost { table: self.table, id: self.id.clone() }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+    }
+}
+
+/// Renew the lease every third of its duration until the row is no longer ours.
+async fn heartbeat(pool: MySqlPool, table: &'static str, id: String, token: String, ttl: Duration, lost: Arc<AtomicBool>) {
+    loop {
+        tokio::time::sleep(ttl / 3).await;
+        let renewed = sqlx::query(&format!(
+            "UPDATE {table} SET lease_expires_at = NOW() + INTERVAL ? SECOND WHERE id = ? AND claimed_by = ? AND status = 'InProgress'"
+        ))
+        .bind(ttl.as_secs())
+        .bind(&id)
+        .bind(&token)
+        .execute(&pool)
+        .await;
+        match renewed {
+            Ok(done) if done.rows_affected() == 0 => {
+                lost.store(true, Ordering::Relaxed);
+                return;
+            }
+            Ok(_) => {}
+            // Try again next beat; the lease only lapses if renewals keep failing
+            Err(e) => warn!("Failed to renew lease on {} {}: {}", table, id, e),
+        }
+    }
+}
+
+/// The row is no longer claimed by this worker.
+#[derive(Debug)]
+pub struct LeaseLost {
+    table: &'static str,
+    id: String,
+}
+
+impl fmt::Display for LeaseLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "lease on {} {} lost", self.table, self.id)
+    }
+}
+
+impl std::error::Error for LeaseLost {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_name_the_worker_and_are_unique() {
+        let (a, b) = (new_token(), new_token());
+        assert_ne!(a, b);
+        assert!(a.starts_with(&format!("{}/", *WORKER_ID)));
+        assert!(a.len() <= 128, "fits claimed_by");
+    }
+
+    #[tokio::test]
+    async fn lost_leases_fail_ensure_held() {
+        let pool = sqlx::mysql::MySqlPoolOptions::new().connect_lazy("mysql://localhost/unused").unwrap();
+        let lease = Lease::hold(&pool, "jobs", "job-1".to_string(), new_token());
+        assert!(lease.ensure_held().is_ok());
+        lease.lost.store(true, Ordering::Relaxed);
+        assert_eq!(lease.ensure_held().unwrap_err().to_string(), "lease on jobs job-1 lost");
+    }
+
+    #[tokio::test]
+    async fn heartbeat_renews_until_the_row_is_taken() {
+        let Some(pool) = crate::db::test_pool().await else { return };
+        let token = new_token();
+        let id = crate::jobs::enqueue(&pool, "test_lease", None, serde_json::json!({}), 0).await.unwrap();
+        sqlx::query("UPDATE jobs SET status = 'InProgress', claimed_by = ?, lease_expires_at = NOW() WHERE id = ?")
+            .bind(&token)
+            .bind(&id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let expires = || async {
+            sqlx::query_scalar::<_, i64>("SELECT TIMESTAMPDIFF(SECOND, NOW(), lease_expires_at) FROM jobs WHERE id = ?")
+                .bind(&id)
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        };
+
+        // A 3 second lease, so the heartbeat runs every second
+        let lease = Lease::with_duration(&pool, "jobs", id.clone(), token, Duration::from_secs(3));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(expires().await >= 1, "renewed");
+        assert!(lease.ensure_held().is_ok());
+
+        sqlx::query("UPDATE jobs SET claimed_by = 'someone else' WHERE id = ?").bind(&id).execute(&pool).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert!(lease.ensure_held().is_err());
+        sqlx::query("DELETE FROM jobs WHERE id = ?").bind(&id).execute(&pool).await.unwrap();
+    }
+}
@@ -0,0 +1,718 @@
+use crate::gemini_client::GeminiProvider;
+use crate::outbound::{self, estimate_tokens, Outbound, OutboundError};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::header::HeaderMap;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+
+/// Why a provider call failed. Transient failures are retried; the rest fail
+/// the file or query with the error as its reason.
+#[derive(Debug, Clone)]
+pub enum LlmError {
+    /// Missing or rejected credentials (401/403)
+    Auth(String),
+    /// Too many requests; the server may say when to come back
+    RateLimited { retry_after: Option<Duration>, message: String },
+    /// Daily or billing quota exhausted; retrying soon will not help
+    Quota(String),
+    /// The prompt or the answer was blocked by a safety filter
+    Safety(String),
+    /// A success status with a body we could not use
+    Malformed(String),
+    Timeout,
+    /// Network failure or 5xx
+    Unavailable(String),
+    /// Any other 4xx, e.g. a prompt over the context limit
+    Rejected(String),
+    /// A stream failed after part of the answer was delivered, so it cannot
+    /// be retried without repeating text
+    Interrupted(String),
+}
+
+pub type LlmResult<T> = std::result::Result<T, LlmError>;
+
+impl LlmError {
+    /// Stable identifier stored with failed jobs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Auth(_) => "auth",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::Quota(_) => "quota",
+            Self::Safety(_) => "safety",
+            Self::Malformed(_) => "malformed",
+            Self::Timeout => "timeout",
+            Self::Unavailable(_) => "unavailable",
+            Self::Rejected(_) => "rejected",
+            Self::Interrupted(_) => "interrupted",
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::RateLimited { .. } | Self::Timeout | Self::Unavailable(_))
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Build an error from its `kind()` name, for scripted failures.
+    pub fn from_kind(kind: &str, message: String) -> Option<Self> {
+        Some(match kind {
+            "auth" => Self::Auth(message),
+            "rate_limited" => Self::RateLimited { retry_after: None, message },
+            "quota" => Self::Quota(message),
+            "safety" => Self::Safety(message),
+            "malformed" => Self::Malformed(message),
+            "timeout" => Self::Timeout,
+            "unavailable" => Self::Unavailable(message),
+            "rejected" => Self::Rejected(message),
+            "interrupted" => Self::Interrupted(message),
+            _ => return None,
+        })
+    }
+
+    /// Classify a non-success HTTP response from a provider.
+    pub fn from_response(status: StatusCode, headers: &HeaderMap, body: &str) -> Self {
+        let message = format!("{status}: {}", truncate(body, 300));
+        match status.as_u16() {
+            401 | 403 => Self::Auth(message),
+            408 | 504 => Self::Timeout,
+            429 => {
+                // Per-day and billing limits come back as 429 too, but will not
+                // clear within any sensible retry window
+                let lower = body.to_ascii_lowercase();
+                if lower.contains("perday") || lower.contains("insufficient_quota") || lower.contains("billing") {
+                    Self::Quota(message)
+                } else {
+                    Self::RateLimited { retry_after: outbound::retry_after_header(headers).or_else(|| retry_delay(body)), message }
+                }
+            }
+            500..=599 => Self::Unavailable(message),
+            _ => Self::Rejected(message),
+        }
+    }
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auth(m) => write!(f, "LLM authentication failed: {m}"),
+            Self::RateLimited { retry_after: Some(d), message } => {
+                write!(f, "LLM rate limited (retry after {}s): {message}", d.as_secs())
+            }
+            Self::RateLimited { retry_after: None, message } => write!(f, "LLM rate limited: {message}"),
+            Self::Quota(m) => write!(f, "LLM quota exhausted: {m}"),
+            Self::Safety(m) => write!(f, "LLM response blocked by safety filter: {m}"),
+            Self::Malformed(m) => write!(f, "LLM returned an unusable response: {m}"),
+            Self::Timeout => write!(f, "LLM request timed out"),
+            Self::Unavailable(m) => write!(f, "LLM unavailable: {m}"),
+            Self::Rejected(m) => write!(f, "LLM rejected the request: {m}"),
+            Self::Interrupted(m) => write!(f, "LLM stream interrupted: {m}"),
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+impl From<reqwest::Error> for LlmError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Self::Timeout
+        } else if e.is_decode() {
+            Self::Malformed(e.to_string())
+        } else {
+            Self::Unavailable(e.to_string())
+        }
+    }
+}
+
+impl OutboundError for LlmError {
+    fn is_transient(&self) -> bool {
+        LlmError::is_transient(self)
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        LlmError::retry_after(self)
+    }
+
+    fn timed_out() -> Self {
+        Self::Timeout
+    }
+
+    fn circuit_open(dependency: &str, retry_in: Duration) -> Self {
+        Self::Unavailable(format!("{dependency} circuit open, retry in {}s", retry_in.as_secs()))
+    }
+}
+
+/// Gemini's `"retryDelay": "12s"` in a 429 body.
+fn retry_delay(body: &str) -> Option<Duration> {
+    let rest = &body[body.find("\"retryDelay\"")? + "\"retryDelay\"".len()..];
+    let value = rest.trim_start_matches([':', ' ', '"']);
+    let end = value.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+    value[..end].parse::<f64>().ok().map(|s| Duration::from_secs(s.ceil() as u64))
+}
+
+/// A text generation backend. The workers only talk to this trait, so any
+/// stage can run against Gemini, a local OpenAI-compatible server or a script.
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    /// Short identifier used in logs.
+    fn name(&self) -> &str;
+
+    fn model(&self) -> &str;
+
+    async fn generate(&self, prompt: &str) -> LlmResult<String>;
+
+    /// Like `generate`, but calls `on_text` with each piece of text as it
+    /// arrives. Returns the full text. Providers without streaming deliver the
+    /// whole answer as one piece.
+    async fn stream(&self, prompt: &str, on_text: &mut (dyn for<'t> FnMut(&'t str) + Send)) -> LlmResult<String> {
+        let out = self.generate(prompt).await?;
+        on_text(&out);
+        Ok(out)
+    }
+}
+
+/// Pipeline steps that call a model, each configurable on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LlmStage {
+    /// File description (file worker)
+    Describe,
+    /// Keywords and relationships for search (file worker)
+    Graph,
+    /// Standalone rewrite of a session follow-up (query worker)
+    Rewrite,
+    /// Relationships between retrieved files (query worker)
+    Relationships,
+    /// Final cited answer, streamed (query worker)
+    Answer,
+}
+
+impl LlmStage {
+    pub const ALL: [LlmStage; 5] = [Self::Describe, Self::Graph, Self::Rewrite, Self::Relationships, Self::Answer];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Describe => "describe",
+            Self::Graph => "graph",
+            Self::Rewrite => "rewrite",
+            Self::Relationships => "relationships",
+            Self::Answer => "answer",
+        }
+    }
+
+    /// Quick steps get the small Gemini model, the rest the large one.
+    fn default_gemini_model(self) -> &'static str {
+        match self {
+            Self::Describe | Self::Rewrite => "gemini-2.5-flash",
+            Self::Graph | Self::Relationships | Self::Answer => "gemini-2.5-pro",
+        }
+    }
+}
+
+/// The provider used for every stage, shared by both workers.
+pub struct Llms {
+    stages: HashMap<LlmStage, Arc<dyn LlmProvider>>,
+}
+
+impl Llms {
+    pub fn get(&self, stage: LlmStage) -> &dyn LlmProvider {
+        self.stages[&stage].as_ref()
+    }
+}
+
+/// Build the providers from LLM_PROVIDER (`gemini`, `openai`, `scripted` or
+/// `demo`) and LLM_MODEL, each overridable per stage with LLM_<STAGE>_PROVIDER
+/// and LLM_<STAGE>_MODEL (e.g. LLM_ANSWER_MODEL). Without LLM_PROVIDER a
+/// GEMINI_API_KEY selects gemini; with neither, startup fails, since the demo
+/// provider must be chosen explicitly. Every provider except the demo one goes
+/// through the outbound layer of its service (see `outbound`).
+pub fn llms_from_env() -> Result<Arc<Llms>> {
+    let env = |key: &str| std::env::var(key).ok().filter(|v| !v.is_empty());
+    let default_kind = default_provider(env("LLM_PROVIDER"), env("GEMINI_API_KEY").is_some())?;
+    let default_model = env("LLM_MODEL");
+
+    let mut stages = HashMap::new();
+    for stage in LlmStage::ALL {
+        let prefix = format!("LLM_{}", stage.as_str().to_uppercase());
+        let kind = env(&format!("{prefix}_PROVIDER")).map(|k| k.to_lowercase()).unwrap_or_else(|| default_kind.clone());
+        let model = env(&format!("{prefix}_MODEL")).or_else(|| default_model.clone());
+        let provider: Arc<dyn LlmProvider> = match kind.as_str() {
+            "gemini" => {
+                let api_key = env("GEMINI_API_KEY").ok_or_else(|| {
+                    anyhow!("{prefix} uses gemini, which requires GEMINI_API_KEY (set LLM_PROVIDER=demo to run without a model)")
+                })?;
+                let model = model.unwrap_or_else(|| stage.default_gemini_model().to_string());
+                Arc::new(Guarded::new(GeminiProvider::new(api_key, model), outbound::dependency("gemini", true)))
+            }
+            "openai" => Arc::new(Guarded::new(
+                OpenAiChatProvider::new(
+                    &env("OPENAI_CHAT_URL").unwrap_or_else(|| "http://localhost:11434/v1".to_string()),
+                    model.or_else(|| env("OPENAI_CHAT_MODEL")).unwrap_or_else(|| "llama3.1".to_string()),
+                    env("OPENAI_API_KEY"),
+                ),
+                outbound::dependency("openai", true),
+            )),
+            "scripted" => {
+                let path = env("LLM_SCRIPT_PATH").ok_or_else(|| anyhow!("{prefix} uses scripted, which requires LLM_SCRIPT_PATH"))?;
+                Arc::new(Guarded::new(ScriptedProvider::from_file(&path, stage)?, outbound::dependency("scripted", true)))
+            }
+            "demo" => Arc::new(DemoProvider),
+            other => return Err(anyhow!("unknown {prefix}_PROVIDER '{other}'")),
+        };
+        info!("LLM stage {}: {} ({})", stage.as_str(), provider.name(), provider.model());
+        stages.insert(stage, provider);
+    }
+    Ok(Arc::new(Llms { stages }))
+}
+
+/// The provider of stages without their own: LLM_PROVIDER, else gemini when
+/// there is a key for it. Never demo by default, since its prompt previews
+/// would be stored as file descriptions and answers.
+fn default_provider(llm_provider: Option<String>, has_gemini_key: bool) -> Result<String> {
+    match llm_provider {
+        Some(kind) => Ok(kind.to_lowercase()),
+        None if has_gemini_key => Ok("gemini".to_string()),
+        None => Err(anyhow!(
+            "no LLM provider configured: set GEMINI_API_KEY, or LLM_PROVIDER to openai, scripted or demo (no model, labelled prompt previews)"
+        )),
+    }
+}
+
+/// Sends a provider's calls through the outbound layer of its service: rate
+/// limits, timeouts, the circuit breaker and any in-call retries. A stream is
+/// only retried if nothing has been passed on yet, so subscribers never see
+/// text twice.
+pub struct Guarded<P> {
+    inner: P,
+    outbound: Arc<Outbound>,
+}
+
+impl<P: LlmProvider> Guarded<P> {
+    pub fn new(inner: P, outbound: Arc<Outbound>) -> Self {
+        Self { inner, outbound }
+    }
+}
+
+#[async_trait]
+impl<P: LlmProvider> LlmProvider for Guarded<P> {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn model(&self) -> &str {
+        self.inner.model()
+    }
+
+    async fn generate(&self, prompt: &str) -> LlmResult<String> {
+        self.outbound.call(estimate_tokens(prompt), || self.inner.generate(prompt)).await
+    }
+
+    async fn stream(&self, prompt: &str, on_text: &mut (dyn for<'t> FnMut(&'t str) + Send)) -> LlmResult<String> {
+        let mut attempt = 0;
+        loop {
+            let mut emitted = false;
+            let result = match self.outbound.admit::<LlmError>(estimate_tokens(prompt)).await {
+                Err(e) => Err(e),
+                Ok(()) => {
+                    let mut forward = |text: &str| {
+                        emitted = true;
+                        on_text(text);
+                    };
+                    let call = tokio::time::timeout(self.outbound.timeout(), self.inner.stream(prompt, &mut forward));
+                    self.outbound.finish(call.await.unwrap_or(Err(LlmError::Timeout)))
+                }
+            };
+            match result {
+                Ok(out) => return Ok(out),
+                Err(e) if emitted && e.is_transient() => return Err(LlmError::Interrupted(e.to_string())),
+                Err(e) => match self.outbound.backoff(&e, attempt) {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    None => return Err(e),
+                },
+            }
+            attempt += 1;
+        }
+    }
+}
+
+/// Any server implementing the OpenAI `/chat/completions` endpoint (OpenAI,
+/// Ollama, llama.cpp, vLLM, ...).
+pub struct OpenAiChatProvider {
+    client: Client,
+    base: String,
+    model: String,
+    api_key: Option<String>,
+}
+
+impl OpenAiChatProvider {
+    pub fn new(base: &str, model: String, api_key: Option<String>) -> Self {
+        Self {
+            client: Client::new(),
+            base: base.trim_end_matches('/').to_string(),
+            model,
+            api_key,
+        }
+    }
+
+    async fn send(&self, prompt: &str, stream: bool) -> LlmResult<reqwest::Response> {
+        let mut req = self.client.post(format!("{}/chat/completions", self.base)).json(&json!({
+            "model": self.model,
+            "messages": [ { "role": "user", "content": prompt } ],
+            "stream": stream,
+        }));
+        if let Some(key) = &self.api_key {
+            req = req.bearer_auth(key);
+        }
+        let resp = req.send().await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let headers = resp.headers().clone();
+            let t = resp.text().await.unwrap_or_default();
+            return Err(LlmError::from_response(status, &headers, &t));
+        }
+        Ok(resp)
+    }
+}
+
+/// `finish_reason` of an OpenAI-style choice that means the output was filtered.
+fn openai_filtered(reason: Option<&str>) -> bool {
+    reason == Some("content_filter")
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiChatProvider {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn generate(&self, prompt: &str) -> LlmResult<String> {
+        #[derive(Deserialize)]
+        struct Message { content: Option<String> }
+        #[derive(Deserialize)]
+        struct Choice { message: Message, finish_reason: Option<String> }
+        #[derive(Deserialize)]
+        struct Response { choices: Vec<Choice> }
+
+        let data: Response = self.send(prompt, false).await?.json().await?;
+        let Some(choice) = data.choices.into_iter().next() else {
+            return Err(LlmError::Malformed("no choices".to_string()));
+        };
+        if openai_filtered(choice.finish_reason.as_deref()) {
+            return Err(LlmError::Safety("content_filter".to_string()));
+        }
+        match choice.message.content {
+            Some(text) if !text.trim().is_empty() => Ok(text),
+            _ => Err(LlmError::Malformed("empty message".to_string())),
+        }
+    }
+
+    async fn stream(&self, prompt: &str, on_text: &mut (dyn for<'t> FnMut(&'t str) + Send)) -> LlmResult<String> {
+        #[derive(Deserialize)]
+        struct Delta { content: Option<String> }
+        #[derive(Deserialize)]
+        struct Choice { delta: Delta, finish_reason: Option<String> }
+        #[derive(Deserialize)]
+        struct Chunk { choices: Vec<Choice> }
+
+        let mut resp = self.send(prompt, true).await?;
+        let mut lines = SseLines::default();
+        let mut out = String::new();
+        while let Some(chunk) = resp.chunk().await? {
+            for data in lines.push(&chunk) {
+                if data == "[DONE]" {
+                    continue;
+                }
+                let Ok(chunk) = serde_json::from_str::<Chunk>(&data) else { continue };
+                for choice in chunk.choices {
+                    if openai_filtered(choice.finish_reason.as_deref()) {
+                        return Err(LlmError::Safety("content_filter".to_string()));
+                    }
+                    if let Some(text) = choice.delta.content {
+                        out.push_str(&text);
+                        on_text(&text);
+                    }
+                }
+            }
+        }
+        if out.trim().is_empty() {
+            return Err(LlmError::Malformed("empty stream".to_string()));
+        }
+        Ok(out)
+    }
+}
+
+/// One step of a script: a response, or `{"error": "<kind>"}` to fail with
+/// that `LlmError` kind.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum ScriptStep {
+    Text(String),
+    Error { error: String, message: Option<String> },
+}
+
+/// Replays canned responses in order and repeats the last one, for running
+/// the pipeline and its failure paths without any model.
+pub struct ScriptedProvider {
+    steps: Mutex<VecDeque<ScriptStep>>,
+}
+
+impl ScriptedProvider {
+    /// Load a JSON script: either an array of steps used by every stage, or an
+    /// object of arrays keyed by stage name (`describe`, `answer`, ...).
+    pub fn from_file(path: &str, stage: LlmStage) -> Result<Self> {
+        let raw = std::fs::read_to_string(path).map_err(|e| anyhow!("reading LLM script {path}: {e}"))?;
+        let script: serde_json::Value = serde_json::from_str(&raw).map_err(|e| anyhow!("parsing LLM script {path}: {e}"))?;
+        let list = match &script {
+            serde_json::Value::Object(map) => map.get(stage.as_str()).cloned().unwrap_or_default(),
+            other => other.clone(),
+        };
+        let steps: Vec<ScriptStep> = match list {
+            serde_json::Value::Null => Vec::new(),
+            v => serde_json::from_value(v).map_err(|e| anyhow!("LLM script {path}: {e}"))?,
+        };
+        if steps.is_empty() {
+            return Err(anyhow!("LLM script {path} has no steps for stage {}", stage.as_str()));
+        }
+        for step in &steps {
+            if let ScriptStep::Error { error, .. } = step {
+                LlmError::from_kind(error, String::new()).ok_or_else(|| anyhow!("LLM script {path}: unknown error kind '{error}'"))?;
+            }
+        }
+        Ok(Self { steps: Mutex::new(steps.into()) })
+    }
+}
+
+#[async_trait]
+impl LlmProvider for ScriptedProvider {
+    fn name(&self) -> &str {
+        "scripted"
+    }
+
+    fn model(&self) -> &str {
+        "script"
+    }
+
+    async fn generate(&self, _prompt: &str) -> LlmResult<String> {
+        let step = {
+            let mut steps = self.steps.lock().unwrap();
+            if steps.len() > 1 { steps.pop_front() } else { steps.front().cloned() }
+        };
+        match step {
+            Some(ScriptStep::Text(text)) => Ok(text),
+            Some(ScriptStep::Error { error, message }) => {
+                let message = message.unwrap_or_else(|| "scripted failure".to_string());
+                Err(LlmError::from_kind(&error, message).unwrap_or(LlmError::Rejected(error)))
+            }
+            None => Err(LlmError::Malformed("script is empty".to_string())),
+        }
+    }
+}
+
+/// Explicit stand-in when no model is available (LLM_PROVIDER=demo): every
+/// answer is a labelled preview of the prompt, so the flows run end-to-end.
+pub struct DemoProvider;
+
+impl DemoProvider {
+    pub const NAME: &'static str = "demo";
+}
+
+#[async_trait]
+impl LlmProvider for DemoProvider {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn model(&self) -> &str {
+        "none"
+    }
+
+    async fn generate(&self, prompt: &str) -> LlmResult<String> {
+        Ok(format!("[demo] No model configured. Prompt preview: {}", truncate(prompt, 240)))
+    }
+}
+
+/// Splits a Server-Sent Events body into the payloads of its `data:` lines.
+/// Works on raw bytes so multi-byte characters split across network chunks
+/// stay intact.
+#[derive(Default)]
+pub struct SseLines {
+    pending: Vec<u8>,
+}
+
+impl SseLines {
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(chunk);
+        let mut out = Vec::new();
+        while let Some(pos) = self.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Some(data) = line.trim_end().strip_prefix("data:") {
+                out.push(data.trim().to_string());
+            }
+        }
+        out
+    }
+}
+
+/// First `max` characters of `s`, marked when cut.
+pub fn truncate(s: &str, max: usize) -> String {
+    match s.char_indices().nth(max) {
+        Some((i, _)) => format!("{}…", &s[..i]),
+        None => s.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demo_is_never_the_default_provider() {
+        assert_eq!(default_provider(Some("Demo".to_string()), false).unwrap(), "demo");
+        assert_eq!(default_provider(Some("openai".to_string()), true).unwrap(), "openai");
+        assert_eq!(default_provider(None, true).unwrap(), "gemini");
+        assert!(default_provider(None, false).unwrap_err().to_string().starts_with("no LLM provider configured"));
+    }
+
+    #[test]
+    fn responses_are_classified_by_status_and_body() {
+        let none = HeaderMap::new();
+        let kind = |status: u16, body: &str| LlmError::from_response(StatusCode::from_u16(status).unwrap(), &none, body).kind();
+        assert_eq!(kind(401, ""), "auth");
+        assert_eq!(kind(403, "API key not valid"), "auth");
+        assert_eq!(kind(408, ""), "timeout");
+        assert_eq!(kind(504, "upstream timed out"), "timeout");
+        assert_eq!(kind(500, ""), "unavailable");
+        assert_eq!(kind(503, "overloaded"), "unavailable");
+        assert_eq!(kind(400, "prompt is too long"), "rejected");
+        assert_eq!(kind(429, "Resource has been exhausted (e.g. check quota)."), "rate_limited");
+        // Daily and billing limits do not clear by waiting a few seconds
+        assert_eq!(kind(429, r#"{"quotaId": "GenerateRequestsPerDayPerProjectPerModel-FreeTier"}"#), "quota");
+        assert_eq!(kind(429, r#"{"error": {"code": "insufficient_quota"}}"#), "quota");
+        assert_eq!(kind(429, "check your plan and billing details"), "quota");
+        assert!(LlmError::from_response(StatusCode::TOO_MANY_REQUESTS, &none, "").is_transient());
+        assert!(!LlmError::from_response(StatusCode::UNAUTHORIZED, &none, "").is_transient());
+    }
+
+    #[test]
+    fn rate_limits_say_when_to_retry() {
+        let body = r#"{"error": {"code": 429, "details": [{"@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "12.5s"}]}}"#;
+        assert_eq!(retry_delay(body), Some(Duration::from_secs(13)));
+        assert_eq!(retry_delay(r#""retryDelay":"7s""#), Some(Duration::from_secs(7)));
+        assert_eq!(retry_delay(r#""retryDelay": "soon""#), None);
+        assert_eq!(retry_delay("rate limited"), None);
+
+        let err = LlmError::from_response(StatusCode::TOO_MANY_REQUESTS, &HeaderMap::new(), body);
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(13)));
+        // Retry-After wins over the body
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::RETRY_AFTER, "30".parse().unwrap());
+        let err = LlmError::from_response(StatusCode::TOO_MANY_REQUESTS, &headers, body);
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn sse_lines_are_framed_across_chunks() {
+        let mut lines = SseLines::default();
+        assert!(lines.push(b"data: {\"a\"").is_empty());
+        assert_eq!(lines.push(b": 1}\n\ndata: [DONE]\n"), vec!["{\"a\": 1}", "[DONE]"]);
+        // Comments, event names and CRLF endings
+        assert_eq!(lines.push(b": keep-alive\nevent: message\r\ndata:x\r\n"), vec!["x"]);
+        // A multi-byte character split between two chunks
+        let text = "data: \u{00e9}t\u{00e9}\n".as_bytes();
+        assert!(lines.push(&text[..7]).is_empty());
+        assert_eq!(lines.push(&text[7..]), vec!["\u{00e9}t\u{00e9}"]);
+    }
+
+    fn script(name: &str, json: &str) -> String {
+        let path = std::env::temp_dir().join(format!("llm-script-{name}-{}.json", std::process::id()));
+        std::fs::write(&path, json).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[tokio::test]
+    async fn scripts_replay_in_order_and_repeat_the_last_step() {
+        let path = script("order", r#"{"describe": ["first", {"error": "rate_limited", "message": "slow down"}, "last"], "answer": ["an answer"]}"#);
+        let describe = ScriptedProvider::from_file(&path, LlmStage::Describe).unwrap();
+        assert_eq!(describe.generate("").await.unwrap(), "first");
+        let err = describe.generate("").await.unwrap_err();
+        assert_eq!((err.kind(), err.to_string().as_str()), ("rate_limited", "LLM rate limited: slow down"));
+        assert_eq!(describe.generate("").await.unwrap(), "last");
+        assert_eq!(describe.generate("").await.unwrap(), "last");
+        let answer = ScriptedProvider::from_file(&path, LlmStage::Answer).unwrap();
+        assert_eq!(answer.generate("").await.unwrap(), "an answer");
+
+        assert!(ScriptedProvider::from_file(&path, LlmStage::Graph).is_err(), "no steps for the stage");
+        let bad = script("bad", r#"["ok", {"error": "gremlins"}]"#);
+        let Err(err) = ScriptedProvider::from_file(&bad, LlmStage::Graph) else { panic!("accepted an unknown error kind") };
+        assert!(err.to_string().contains("unknown error kind 'gremlins'"));
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(bad);
+    }
+
+    /// Streams `text`, then fails with `error`.
+    struct Failing {
+        text: &'static str,
+        error: LlmError,
+    }
+
+    #[async_trait]
+    impl LlmProvider for Failing {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        fn model(&self) -> &str {
+            "test"
+        }
+
+        async fn generate(&self, _prompt: &str) -> LlmResult<String> {
+            Err(self.error.clone())
+        }
+
+        async fn stream(&self, _prompt: &str, on_text: &mut (dyn for<'t> FnMut(&'t str) + Send)) -> LlmResult<String> {
+            if !self.text.is_empty() {
+                on_text(self.text);
+            }
+            Err(self.error.clone())
+        }
+    }
+
+    async fn guarded_stream(text: &'static str, error: LlmError) -> (LlmError, String) {
+        let guarded = Guarded::new(Failing { text, error }, outbound::dependency("llm-guarded-test", false));
+        let mut seen = String::new();
+        let err = guarded.stream("prompt", &mut |t: &str| seen.push_str(t)).await.unwrap_err();
+        (err, seen)
+    }
+
+    #[tokio::test]
+    async fn streams_failing_after_text_are_interrupted() {
+        let (err, seen) = guarded_stream("The ECLSS ", LlmError::Unavailable("connection reset".to_string())).await;
+        assert_eq!(err.kind(), "interrupted");
+        assert!(!err.is_transient());
+        assert_eq!(seen, "The ECLSS ");
+
+        // Nothing delivered yet: the error stays retryable
+        let (err, seen) = guarded_stream("", LlmError::Unavailable("connection reset".to_string())).await;
+        assert_eq!(err.kind(), "unavailable");
+        assert!(seen.is_empty());
+        // Permanent errors are passed on as they are
+        let (err, _) = guarded_stream("partial", LlmError::Safety("blocked".to_string())).await;
+        assert_eq!(err.kind(), "safety");
+    }
+}
@@ -1,15 +1,34 @@
 mod file_worker;
 mod api;
+mod blob_store;
+mod blobs;
+mod chunking;
+mod citations;
 mod db;
+mod embedded_store;
+mod embedding;
+mod events;
+mod extract;
 mod gemini_client;
+mod hnsw;
+mod http_server;
+mod jobs;
+mod keyword;
+mod lease;
+mod llm;
 mod models;
+mod outbound;
+mod s3_store;
+mod sessions;
 mod storage;
 mod vector;
 mod worker;
 mod vector_db;
+mod vector_store;
 
 use std::env;
 use std::error::Error;
+use std::sync::Arc;
 use tracing::info;
 use warp::Filter;
 
@@ -32,27 +51,48 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize DB
     let pool = db::init_db(&database_url).await.map_err(|e| -> Box<dyn Error> { Box::new(e) })?;
 
-    // Spawn query worker
-    let worker = worker::Worker::new(pool.clone());
-    tokio::spawn(async move { worker.run().await });
+    // Embedding provider shared by both workers
+    let embedder = embedding::embedder_from_env(&pool).await.map_err(|e| -> Box<dyn Error> { e.into() })?;
 
-    // Spawn file analysis worker
-    let file_worker = file_worker::FileWorker::new(pool.clone());
-    tokio::spawn(async move { file_worker.run().await });
+    // Text generation providers, configurable per pipeline stage
+    let llms = llm::llms_from_env().map_err(|e| -> Box<dyn Error> { e.into() })?;
+
+    // Where file contents live: local directory or S3-compatible bucket
+    let blob_store = blob_store::blob_store_from_env().map_err(|e| -> Box<dyn Error> { e.into() })?;
+
+    // Vector store shared by the workers and the API
+    let store = vector_store::vector_store_from_env(pool.clone()).map_err(|e| -> Box<dyn Error> { e.into() })?;
+    if let Err(e) = store.ensure_ready(embedder.dim()).await {
+        tracing::error!("Failed to prepare {} vector store: {}", store.name(), e);
+    }
+
+    // BM25 keyword index over chunk text, rebuilt from MySQL on every start
+    let keywords = Arc::new(keyword::KeywordIndex::new(pool.clone()));
+    match keywords.rebuild().await {
+        Ok(n) => info!("Loaded {} chunks into keyword index", n),
+        Err(e) => tracing::error!("Failed to build keyword index: {}", e),
+    }
+
+    // Query progress events, published by the worker and streamed by the API
+    let events = Arc::new(events::QueryEvents::new());
+
+    // Job runner with the query and file analysis workers as handlers
+    let mut runner = jobs::JobRunner::new(pool.clone());
+    runner.register(Arc::new(worker::Worker::new(pool.clone(), store.clone(), embedder.clone(), keywords.clone(), events.clone(), llms.clone())));
+    runner.register(Arc::new(file_worker::FileWorker::new(pool.clone(), store.clone(), embedder.clone(), keywords.clone(), llms.clone(), blob_store.clone())));
+    runner.start();
 
     // API routes
-    let api_routes = api::routes(pool.clone())
+    let api_routes = api::routes(pool.clone(), store.clone(), keywords.clone(), events.clone(), blob_store.clone())
         .with(warp::cors()
             .allow_any_origin()
             .allow_headers(vec!["content-type", "authorization"])
-            .allow_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"]))
+            .allow_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"]))
         .with(warp::log("rust_engine"));
 
     info!("Rust Engine started on http://0.0.0.0:8000");
 
-    warp::serve(api_routes)
-        .run(([0, 0, 0, 0], 8000))
-        .await;
+    http_server::serve(api_routes.map(warp::Reply::into_response).boxed(), ([0, 0, 0, 0], 8000).into()).await?;
 
     Ok(())
 }
\ No newline at end of file
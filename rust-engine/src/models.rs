@@ -10,7 +10,7 @@ pub struct FileRecord {
     pub description: Option<String>,
     pub created_at: Option<DateTime<Utc>>,
     pub pending_analysis: bool, // true if file is not yet ready for search
-    pub analysis_status: String, // 'Queued', 'InProgress', 'Completed', 'Failed'
+    pub analysis_status: String, // 'Queued', 'InProgress', 'Completed', 'NoText', 'DeadLettered' ('Failed' before retries existed)
 }
 
 impl FileRecord {
@@ -43,6 +43,7 @@ pub struct QueryRecord {
     pub status: QueryStatus,
     pub payload: serde_json::Value,
     pub result: Option<serde_json::Value>,
+    pub session_id: Option<String>,
     pub created_at: Option<DateTime<Utc>>,
     pub updated_at: Option<DateTime<Utc>>,
 }
@@ -54,6 +55,7 @@ impl QueryRecord {
             status: QueryStatus::Queued,
             payload,
             result: None,
+            session_id: None,
             created_at: None,
             updated_at: None,
         }
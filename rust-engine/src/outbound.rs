@@ -0,0 +1,604 @@
+use lazy_static::lazy_static;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// Every call to an external service (Gemini, OpenAI-compatible servers,
+// Qdrant) goes through the `Outbound` of that dependency: rate limiting, a
+// timeout per attempt and a circuit breaker. They are registered globally so
+// the workers can pause while a dependency is down. Retrying transient
+// failures is left to the job runner, which reschedules the job with backoff;
+// in-call retries are off unless `<NAME>_MAX_RETRIES` asks for them.
+lazy_static! {
+    static ref DEPENDENCIES: Mutex<HashMap<String, Arc<Outbound>>> = Mutex::new(HashMap::new());
+}
+
+/// The shared outbound layer of a dependency, created from the environment on
+/// first use. Settings are read from `<NAME>_RPM`, `<NAME>_TPM`,
+/// `<NAME>_TIMEOUT_SECS`, `<NAME>_MAX_RETRIES`, `<NAME>_BREAKER_THRESHOLD` and
+/// `<NAME>_BREAKER_COOLDOWN_SECS` (e.g. GEMINI_RPM). `critical` dependencies
+/// pause the workers while their breaker is open; others (like Qdrant, which
+/// has a local fallback) only fail fast.
+pub fn dependency(name: &str, critical: bool) -> Arc<Outbound> {
+    DEPENDENCIES
+        .lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_insert_with(|| {
+            let outbound = Arc::new(Outbound::from_env(name, critical));
+            info!("Outbound {}: {}", name, outbound.config);
+            outbound
+        })
+        .clone()
+}
+
+/// How long until every critical dependency accepts calls again, if any is
+/// down. The workers sleep this long instead of claiming more work.
+pub fn pause_remaining() -> Option<(String, Duration)> {
+    let deps = DEPENDENCIES.lock().unwrap();
+    deps.values()
+        .filter(|d| d.critical)
+        .filter_map(|d| d.breaker.lock().unwrap().open_for().map(|wait| (d.name.clone(), wait)))
+        .max_by_key(|(_, wait)| *wait)
+}
+
+/// Errors the outbound layer knows how to retry.
+pub trait OutboundError: Sized + fmt::Display {
+    /// Worth trying again later: rate limits, timeouts, 5xx, network errors
+    fn is_transient(&self) -> bool;
+
+    /// How long the server asked us to wait
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
+
+    fn timed_out() -> Self;
+
+    fn circuit_open(dependency: &str, retry_in: Duration) -> Self;
+}
+
+/// Failure of a plain HTTP dependency (Qdrant, embedding APIs).
+#[derive(Debug, Clone)]
+pub struct HttpFailure {
+    pub status: Option<StatusCode>,
+    pub retry_after: Option<Duration>,
+    pub message: String,
+    /// Failed without a status for a reason a retry will not fix: the
+    /// response could not be decoded or the request could not be built
+    pub permanent: bool,
+}
+
+impl HttpFailure {
+    pub fn from_response(status: StatusCode, headers: &HeaderMap, body: &str) -> Self {
+        Self { status: Some(status), retry_after: retry_after_header(headers), message: body.to_string(), permanent: false }
+    }
+}
+
+impl fmt::Display for HttpFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "{status} - {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for HttpFailure {}
+
+impl From<reqwest::Error> for HttpFailure {
+    fn from(e: reqwest::Error) -> Self {
+        let permanent = e.is_decode() || e.is_builder();
+        Self { status: e.status(), retry_after: None, message: e.to_string(), permanent }
+    }
+}
+
+impl OutboundError for HttpFailure {
+    fn is_transient(&self) -> bool {
+        match self.status {
+            // No status: connection refused, reset, DNS, ... unless the
+            // server answered with something we could not read
+            None => !self.permanent,
+            Some(s) => s == StatusCode::TOO_MANY_REQUESTS || s == StatusCode::REQUEST_TIMEOUT || s.is_server_error(),
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        self.retry_after
+    }
+
+    fn timed_out() -> Self {
+        Self { status: None, retry_after: None, message: "request timed out".to_string(), permanent: false }
+    }
+
+    fn circuit_open(dependency: &str, retry_in: Duration) -> Self {
+        Self {
+            status: None,
+            retry_after: Some(retry_in),
+            message: format!("{dependency} is unavailable (circuit open, retry in {}s)", retry_in.as_secs()),
+            permanent: false,
+        }
+    }
+}
+
+/// Whether an error from a worker stage is worth retrying later, and how long
+/// to wait if the dependency said so.
+pub fn transient(e: &anyhow::Error) -> Option<Option<Duration>> {
+    if let Some(err) = e.downcast_ref::<crate::llm::LlmError>() {
+        return err.is_transient().then(|| err.retry_after());
+    }
+    if let Some(err) = e.downcast_ref::<HttpFailure>() {
+        return err.is_transient().then(|| err.retry_after());
+    }
+    // Database and storage blips, also when wrapped by another error
+    e.chain()
+        .any(|cause| match (cause.downcast_ref::<sqlx::Error>(), cause.downcast_ref::<std::io::Error>()) {
+            (Some(err), _) => sqlx_transient(err),
+            (_, Some(err)) => io_transient(err),
+            _ => false,
+        })
+        .then_some(None)
+}
+
+/// Lost or unavailable connections, and MySQL deadlocks and lock wait
+/// timeouts, which roll back the transaction for us to run again.
+fn sqlx_transient(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_) | sqlx::Error::Tls(_) => true,
+        sqlx::Error::Database(db) => db
+            .try_downcast_ref::<sqlx::mysql::MySqlDatabaseError>()
+            .is_some_and(|db| matches!(db.number(), 1205 | 1213)),
+        _ => false,
+    }
+}
+
+fn io_transient(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(
+        e.kind(),
+        TimedOut | ConnectionReset | ConnectionAborted | ConnectionRefused | NotConnected | BrokenPipe | Interrupted | WouldBlock | UnexpectedEof
+    )
+}
+
+/// Retry-After in seconds (the HTTP-date form is not used by our providers).
+pub fn retry_after_header(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    requests_per_min: Option<f64>,
+    tokens_per_min: Option<f64>,
+    timeout: Duration,
+    max_retries: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    breaker_threshold: u32,
+    breaker_cooldown: Duration,
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limit = |v: Option<f64>| v.map(|v| v.to_string()).unwrap_or_else(|| "unlimited".to_string());
+        write!(
+            f,
+            "{} req/min, {} tokens/min, timeout {}s, {} retries, breaker after {} failures for {}s",
+            limit(self.requests_per_min),
+            limit(self.tokens_per_min),
+            self.timeout.as_secs(),
+            self.max_retries,
+            self.breaker_threshold,
+            self.breaker_cooldown.as_secs()
+        )
+    }
+}
+
+pub struct Outbound {
+    name: String,
+    critical: bool,
+    config: Config,
+    limiter: tokio::sync::Mutex<Buckets>,
+    breaker: Mutex<Breaker>,
+}
+
+impl Outbound {
+    fn from_env(name: &str, critical: bool) -> Self {
+        let prefix = name.to_uppercase();
+        let env = |key: &str| std::env::var(format!("{prefix}_{key}")).ok().and_then(|v| v.parse::<f64>().ok());
+        let rate = |key: &str| env(key).filter(|v| *v > 0.0);
+        // Qdrant is local and fast; model calls can take minutes
+        let default_timeout = if name == "qdrant" { 10.0 } else { 300.0 };
+        let config = Config {
+            requests_per_min: rate("RPM"),
+            tokens_per_min: rate("TPM"),
+            timeout: Duration::from_secs_f64(env("TIMEOUT_SECS").unwrap_or(default_timeout)),
+            // The job runner owns retries; see the comment on `DEPENDENCIES`
+            max_retries: env("MAX_RETRIES").map(|v| v as u32).unwrap_or(0),
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            breaker_threshold: env("BREAKER_THRESHOLD").map(|v| v as u32).unwrap_or(5).max(1),
+            breaker_cooldown: Duration::from_secs_f64(env("BREAKER_COOLDOWN_SECS").unwrap_or(30.0)),
+        };
+        Self::new(name, critical, config)
+    }
+
+    fn new(name: &str, critical: bool, config: Config) -> Self {
+        Self {
+            name: name.to_string(),
+            critical,
+            limiter: tokio::sync::Mutex::new(Buckets::new(config.requests_per_min, config.tokens_per_min)),
+            breaker: Mutex::new(Breaker::default()),
+            config,
+        }
+    }
+
+    /// Run `attempt`, retrying up to `<NAME>_MAX_RETRIES` times. `tokens` is the estimated size of the
+    /// request for the tokens/min budget (use `estimate_tokens`).
+    pub async fn call<T, E, F, Fut>(&self, tokens: u64, mut attempt: F) -> Result<T, E>
+    where
+        E: OutboundError,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut n = 0;
+        loop {
+            let result = match self.admit(tokens).await {
+                Ok(()) => self.finish(tokio::time::timeout(self.config.timeout, attempt()).await.unwrap_or_else(|_| Err(E::timed_out()))),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(v) => return Ok(v),
+                Err(e) => match self.backoff(&e, n) {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    None => return Err(e),
+                },
+            }
+            n += 1;
+        }
+    }
+
+    /// Wait for the rate limits and check the breaker before an attempt.
+    pub async fn admit<E: OutboundError>(&self, tokens: u64) -> Result<(), E> {
+        if let Some(wait) = self.breaker.lock().unwrap().try_acquire() {
+            return Err(E::circuit_open(&self.name, wait));
+        }
+        let mut buckets = self.limiter.lock().await;
+        loop {
+            match buckets.take(tokens) {
+                None => return Ok(()),
+                Some(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+
+    /// Record the outcome of an attempt with the breaker and pass it on.
+    pub fn finish<T, E: OutboundError>(&self, result: Result<T, E>) -> Result<T, E> {
+        let mut breaker = self.breaker.lock().unwrap();
+        match &result {
+            Ok(_) => breaker.success(),
+            // Permanent errors (bad request, safety) say nothing about availability
+            Err(e) if !e.is_transient() => breaker.success(),
+            Err(_) => {
+                if breaker.failure(self.config.breaker_threshold, self.config.breaker_cooldown) {
+                    warn!("{} is failing; pausing calls for {}s", self.name, self.config.breaker_cooldown.as_secs());
+                }
+            }
+        }
+        result
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.config.timeout
+    }
+
+    /// Delay before retry `attempt + 1`, or None to give up. Exponential with
+    /// jitter so parallel callers spread out, never shorter than Retry-After.
+    pub fn backoff<E: OutboundError>(&self, err: &E, attempt: u32) -> Option<Duration> {
+        if !err.is_transient() || attempt >= self.config.max_retries {
+            return None;
+        }
+        // The breaker tripped: waiting out the cooldown here would hold the job
+        if self.breaker.lock().unwrap().open_for().is_some() {
+            return None;
+        }
+        let exp = self.config.base_delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.config.max_delay);
+        let jittered = exp.mul_f64(0.5 + 0.5 * random_unit());
+        let delay = err.retry_after().map(|d| d.max(jittered)).unwrap_or(jittered).min(self.config.max_delay);
+        warn!("{} attempt {} failed: {}; retrying in {:.1}s", self.name, attempt + 1, err, delay.as_secs_f64());
+        Some(delay)
+    }
+}
+
+/// Rough token count of a text for the tokens/min budget.
+pub fn estimate_tokens(text: &str) -> u64 {
+    (text.len() as u64 / 4).max(1)
+}
+
+/// Uniform in [0, 1); good enough for jitter without a rand dependency.
+pub fn random_unit() -> f64 {
+    let mut h = RandomState::new().build_hasher();
+    h.write_u128(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos());
+    (h.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Token buckets for requests and tokens per minute, refilled continuously
+/// and starting full so a burst up to the limit goes through at once.
+struct Buckets {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+}
+
+struct Bucket {
+    capacity: f64,
+    per_sec: f64,
+    level: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(per_min: f64) -> Self {
+        Self { capacity: per_min, per_sec: per_min / 60.0, level: per_min, updated: Instant::now() }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        self.level = (self.level + now.duration_since(self.updated).as_secs_f64() * self.per_sec).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Time until `amount` is available (zero if it is now).
+    fn wait_for(&self, amount: f64) -> Duration {
+        // A single request larger than the whole budget waits for a full bucket
+        let amount = amount.min(self.capacity);
+        Duration::from_secs_f64(((amount - self.level) / self.per_sec).max(0.0))
+    }
+}
+
+impl Buckets {
+    fn new(requests_per_min: Option<f64>, tokens_per_min: Option<f64>) -> Self {
+        Self { requests: requests_per_min.map(Bucket::new), tokens: tokens_per_min.map(Bucket::new) }
+    }
+
+    /// Take one request and `tokens` if both are available, else how long to wait.
+    fn take(&mut self, tokens: u64) -> Option<Duration> {
+        let now = Instant::now();
+        let needs = [(self.requests.as_mut(), 1.0), (self.tokens.as_mut(), tokens as f64)];
+        let mut wait = Duration::ZERO;
+        let mut ready = Vec::new();
+        for (bucket, amount) in needs {
+            if let Some(b) = bucket {
+                b.refill(now);
+                wait = wait.max(b.wait_for(amount));
+                ready.push((b, amount));
+            }
+        }
+        if !wait.is_zero() {
+            return Some(wait);
+        }
+        for (b, amount) in ready {
+            b.level -= amount.min(b.capacity);
+        }
+        None
+    }
+}
+
+/// Closed until `threshold` transient failures in a row, then open for the
+/// cooldown; after that one trial call is let through (half-open) and its
+/// outcome closes or re-opens the breaker.
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+    trial_in_flight: bool,
+}
+
+impl Breaker {
+    fn open_for(&self) -> Option<Duration> {
+        let until = self.open_until?;
+        let now = Instant::now();
+        (until > now).then(|| until - now)
+    }
+
+    /// None if a call may proceed, else how long until it might.
+    fn try_acquire(&mut self) -> Option<Duration> {
+        match (self.open_until, self.open_for()) {
+            (None, _) => None,
+            (Some(_), Some(wait)) => Some(wait),
+            // Cooldown over: one trial at a time
+            (Some(_), None) if self.trial_in_flight => Some(Duration::from_secs(1)),
+            (Some(_), None) => {
+                self.trial_in_flight = true;
+                None
+            }
+        }
+    }
+
+    fn success(&mut self) {
+        if self.open_until.is_some() {
+            info!("Dependency recovered; closing circuit");
+        }
+        *self = Self::default();
+    }
+
+    /// Count a failure; true when this opened the breaker.
+    fn failure(&mut self, threshold: u32, cooldown: Duration) -> bool {
+        self.failures += 1;
+        let trial_failed = self.trial_in_flight;
+        self.trial_in_flight = false;
+        if trial_failed || (self.open_until.is_none() && self.failures >= threshold) {
+            self.open_until = Some(Instant::now() + cooldown);
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn config(max_retries: u32, breaker_threshold: u32) -> Config {
+        Config {
+            requests_per_min: None,
+            tokens_per_min: None,
+            timeout: Duration::from_secs(5),
+            max_retries,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+            breaker_threshold,
+            breaker_cooldown: Duration::from_millis(50),
+        }
+    }
+
+    fn unavailable() -> HttpFailure {
+        HttpFailure { status: Some(StatusCode::SERVICE_UNAVAILABLE), retry_after: None, message: "down".to_string(), permanent: false }
+    }
+
+    /// Call `outbound` with an attempt that fails `failures` times, then succeeds; returns the result and the attempts made.
+    async fn flaky(outbound: &Outbound, failures: u32) -> (Result<u32, HttpFailure>, u32) {
+        let calls = AtomicU32::new(0);
+        let result = outbound
+            .call(1, || async {
+                let n = calls.fetch_add(1, Ordering::SeqCst);
+                if n < failures {
+                    Err(unavailable())
+                } else {
+                    Ok(n)
+                }
+            })
+            .await;
+        (result, calls.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_left_to_the_caller_by_default() {
+        let outbound = Outbound::new("test", false, config(0, 5));
+        let (result, calls) = flaky(&outbound, 1).await;
+        assert!(result.unwrap_err().is_transient());
+        assert_eq!(calls, 1);
+
+        let retrying = Outbound::new("test", false, config(2, 5));
+        let (result, calls) = flaky(&retrying, 2).await;
+        assert_eq!((result.unwrap(), calls), (2, 3));
+    }
+
+    #[tokio::test]
+    async fn undecodable_responses_are_permanent() {
+        let resp = reqwest::Response::from(warp::http::Response::new("not json"));
+        let err: HttpFailure = resp.json::<serde_json::Value>().await.unwrap_err().into();
+        assert!(err.status.is_none());
+        assert!(!err.is_transient());
+
+        let err: HttpFailure = reqwest::Client::new().get("not a url").build().unwrap_err().into();
+        assert!(!err.is_transient());
+
+        assert!(HttpFailure::timed_out().is_transient());
+        assert!(!HttpFailure { status: Some(StatusCode::BAD_REQUEST), ..unavailable() }.is_transient());
+    }
+
+    #[test]
+    fn database_and_io_blips_are_transient() {
+        use std::io::{Error, ErrorKind};
+        assert_eq!(transient(&sqlx::Error::PoolTimedOut.into()), Some(None));
+        assert_eq!(transient(&sqlx::Error::PoolClosed.into()), Some(None));
+        assert!(transient(&sqlx::Error::Io(Error::from(ErrorKind::ConnectionReset)).into()).is_some());
+        assert!(transient(&sqlx::Error::Tls("handshake eof".into()).into()).is_some());
+        assert!(transient(&sqlx::Error::RowNotFound.into()).is_none());
+        assert!(transient(&sqlx::Error::ColumnNotFound("id".to_string()).into()).is_none());
+        // Through the context added on the way up
+        let wrapped = anyhow::Error::from(sqlx::Error::PoolTimedOut).context("failed to claim a job");
+        assert!(transient(&wrapped).is_some());
+    }
+
+    #[test]
+    fn io_errors_are_transient_by_kind() {
+        use std::io::{Error, ErrorKind};
+        for kind in [ErrorKind::TimedOut, ErrorKind::ConnectionReset, ErrorKind::Interrupted, ErrorKind::BrokenPipe, ErrorKind::UnexpectedEof] {
+            assert!(transient(&Error::from(kind).into()).is_some(), "{kind:?}");
+        }
+        for kind in [ErrorKind::NotFound, ErrorKind::PermissionDenied, ErrorKind::InvalidData] {
+            assert!(transient(&Error::from(kind).into()).is_none(), "{kind:?}");
+        }
+        let wrapped = anyhow::Error::from(Error::from(ErrorKind::TimedOut)).context("failed to read blob");
+        assert!(transient(&wrapped).is_some());
+        assert!(transient(&anyhow::anyhow!("invalid blob key")).is_none());
+    }
+
+    #[test]
+    fn breaker_opens_after_threshold_and_lets_one_trial_through() {
+        let cooldown = Duration::from_millis(30);
+        let mut breaker = Breaker::default();
+        assert!(!breaker.failure(2, cooldown));
+        assert!(breaker.try_acquire().is_none());
+        assert!(breaker.failure(2, cooldown));
+        assert!(breaker.try_acquire().is_some());
+
+        std::thread::sleep(cooldown);
+        assert!(breaker.try_acquire().is_none(), "the trial call");
+        assert!(breaker.try_acquire().is_some(), "a second call while the trial runs");
+        // A failed trial re-opens at once, whatever the count
+        assert!(breaker.failure(10, cooldown));
+        assert!(breaker.open_for().is_some());
+
+        std::thread::sleep(cooldown);
+        assert!(breaker.try_acquire().is_none());
+        breaker.success();
+        assert!(breaker.try_acquire().is_none());
+        assert!(breaker.try_acquire().is_none());
+        assert_eq!(breaker.failures, 0);
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_do_not_count_towards_the_breaker() {
+        let outbound = Outbound::new("test", false, config(0, 2));
+        let bad_request = || HttpFailure { status: Some(StatusCode::BAD_REQUEST), ..unavailable() };
+        for _ in 0..3 {
+            let _ = outbound.finish::<(), _>(Err(bad_request()));
+        }
+        assert!(outbound.admit::<HttpFailure>(1).await.is_ok());
+
+        let _ = flaky(&outbound, 5).await;
+        let _ = flaky(&outbound, 5).await;
+        let err = outbound.admit::<HttpFailure>(1).await.unwrap_err();
+        assert!(err.message.contains("circuit open"));
+        assert!(err.is_transient());
+        assert!(outbound.backoff(&unavailable(), 0).is_none());
+    }
+
+    #[test]
+    fn buckets_start_full_and_refill_over_time() {
+        // 60 requests and 600 tokens per minute: 1 request and 10 tokens per second
+        let mut buckets = Buckets::new(Some(60.0), Some(600.0));
+        assert!(buckets.take(500).is_none());
+        let wait = buckets.take(200).expect("only 100 tokens left");
+        assert!(wait > Duration::from_millis(9_900) && wait <= Duration::from_secs(10), "{wait:?}");
+        // Nothing was taken by the refused call
+        assert!(buckets.take(100).is_none());
+
+        // Larger than the budget: waits for a full bucket instead of forever
+        let mut tokens = Bucket::new(600.0);
+        assert_eq!(tokens.wait_for(10_000.0), Duration::ZERO);
+        tokens.level = 0.0;
+        assert!(tokens.wait_for(10_000.0) <= Duration::from_secs(60));
+
+        let mut requests = Buckets::new(Some(60.0), None);
+        for _ in 0..60 {
+            assert!(requests.take(1_000_000).is_none());
+        }
+        let wait = requests.take(1).expect("out of requests");
+        assert!(wait <= Duration::from_secs(1));
+        std::thread::sleep(Duration::from_millis(20));
+        requests.requests.as_mut().unwrap().refill(Instant::now());
+        assert!(requests.requests.as_ref().unwrap().level > 0.0);
+    }
+}
@@ -0,0 +1,106 @@
+use crate::llm::{DemoProvider, LlmProvider};
+use anyhow::Result;
+use sqlx::{MySqlPool, Row};
+
+/// Characters of each earlier answer kept in prompts.
+const ANSWER_CHARS: usize = 800;
+
+/// One completed question/answer exchange of a session.
+#[derive(Debug, Clone)]
+pub struct Turn {
+    pub question: String,
+    pub answer: String,
+}
+
+/// Prior turns included in prompts, from SESSION_HISTORY_TURNS (default 6).
+pub fn history_turns() -> usize {
+    std::env::var("SESSION_HISTORY_TURNS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(6)
+}
+
+/// The most recent completed turns of a session before `query_id`, oldest first.
+pub async fn load_history(pool: &MySqlPool, session_id: &str, query_id: &str, max_turns: usize) -> Result<Vec<Turn>> {
+    if max_turns == 0 {
+        return Ok(Vec::new());
+    }
+    let rows = sqlx::query(
+        "SELECT payload, result FROM queries \
+         WHERE session_id = ? AND id <> ? AND status = 'Completed' \
+         AND created_at <= (SELECT created_at FROM queries WHERE id = ?) \
+         ORDER BY created_at DESC LIMIT ?",
+    )
+    .bind(session_id)
+    .bind(query_id)
+    .bind(query_id)
+    .bind(max_turns as u64)
+    .fetch_all(pool)
+    .await?;
+    let mut turns: Vec<Turn> = rows
+        .into_iter()
+        .filter_map(|row| {
+            let payload: serde_json::Value = row.get("payload");
+            let result: Option<serde_json::Value> = row.get("result");
+            let question = payload.get("q").and_then(|v| v.as_str())?.to_string();
+            let answer = result.as_ref()?.get("final_answer").and_then(|v| v.as_str())?;
+            Some(Turn { question, answer: clip(answer, ANSWER_CHARS) })
+        })
+        .collect();
+    turns.reverse();
+    Ok(turns)
+}
+
+/// Render turns for a prompt.
+pub fn format_history(turns: &[Turn]) -> String {
+    turns
+        .iter()
+        .map(|t| format!("User: {}\nAssistant: {}", t.question, t.answer))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Rewrite a follow-up ("what about its backup pump?") into a question that
+/// can be searched without the conversation. Falls back to prefixing the
+/// previous question when the model fails or is the demo stand-in, which
+/// still gives retrieval the missing subject.
+pub async fn rewrite_follow_up(llm: &dyn LlmProvider, history: &[Turn], question: &str) -> String {
+    let Some(last) = history.last() else {
+        return question.to_string();
+    };
+    let fallback = || format!("{} {}", last.question, question);
+    if llm.name() == DemoProvider::NAME {
+        return fallback();
+    }
+    let prompt = format!(
+        "Rewrite the user's latest question as a standalone question that can be understood without the conversation.\n\
+        Resolve pronouns and references (it, its, that system, the second one) using the conversation.\n\
+        If it is already standalone, return it unchanged. Output only the question.\n\n\
+        Conversation:\n{history}\n\n\
+        Latest question: {question}",
+        history = format_history(history),
+    );
+    match llm.generate(&prompt).await {
+        Ok(text) => {
+            let line = text.lines().map(str::trim).find(|l| !l.is_empty()).unwrap_or("");
+            let line = line.trim_matches('"').trim();
+            if line.is_empty() {
+                fallback()
+            } else {
+                line.to_string()
+            }
+        }
+        Err(e) => {
+            tracing::warn!("Follow-up rewrite failed, searching with the previous question as context: {}", e);
+            fallback()
+        }
+    }
+}
+
+fn clip(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(max_chars).collect();
+    format!("{cut}…")
+}
@@ -1,7 +1,16 @@
-use anyhow::Result;
+use crate::blob_store::ByteStream;
+use anyhow::{anyhow, bail, Result};
+use futures_util::TryStreamExt;
+use sha2::{Digest, Sha256};
+use std::fmt;
 use std::fs;
-use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use unicode_normalization::UnicodeNormalization;
+
+/// Partial uploads older than this are left over from a crash and removed at startup.
+const STALE_UPLOAD_AGE: Duration = Duration::from_secs(24 * 3600);
 
 pub fn storage_dir() -> PathBuf {
     std::env::var("ASTRA_STORAGE")
@@ -9,26 +18,292 @@ pub fn storage_dir() -> PathBuf {
         .unwrap_or_else(|_| PathBuf::from("/app/storage"))
 }
 
+/// Local scratch space for files being received, whichever blob store keeps them.
+fn uploads_dir() -> PathBuf {
+    storage_dir().join(".uploads")
+}
+
+/// Blob store key of the content with this SHA-256: `blobs/ab/cd/abcd…`,
+/// sharded so no directory grows too large on the filesystem backend. Only
+/// lowercase hex, as hashes are stored, so each content has exactly one key.
+pub fn blob_key(sha256: &str) -> Result<String> {
+    if sha256.len() != 64 || !sha256.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        bail!("invalid content hash '{sha256}'");
+    }
+    Ok(format!("blobs/{}/{}/{sha256}", &sha256[..2], &sha256[2..4]))
+}
+
+/// Blob store key of a `files` row. Rows store their key in `path`; rows from
+/// before content addressing hold an absolute path under the storage
+/// directory instead, which maps to the key relative to it (see `legacy_key`).
+pub fn object_key(content_hash: Option<&str>, path: &str) -> Result<String> {
+    if let Some(hash) = content_hash {
+        return blob_key(hash);
+    }
+    legacy_key(path).ok_or_else(|| {
+        anyhow!("{path} is outside the storage directory {}; move the file under it", storage_dir().display())
+    })
+}
+
+/// Key of a path from before content addressing. None for an absolute path
+/// outside the storage directory (ASTRA_STORAGE moved since it was stored),
+/// or one climbing out of it with `..`, which the blob store cannot reach.
+pub fn legacy_key(path: &str) -> Option<String> {
+    let path = Path::new(path);
+    let key = if path.is_absolute() { path.strip_prefix(storage_dir()).ok()? } else { path };
+    let key = key.to_string_lossy().into_owned();
+    crate::blob_store::check_key(&key).ok().map(|_| key)
+}
+
 pub fn ensure_storage_dir() -> Result<()> {
     let dir = storage_dir();
     if !dir.exists() {
         fs::create_dir_all(&dir)?;
     }
+    fs::create_dir_all(uploads_dir())?;
+    // Other replicas may be writing here right now; only old leftovers go
+    for entry in fs::read_dir(uploads_dir())?.flatten() {
+        let age = entry.metadata().and_then(|m| m.modified()).ok().and_then(|t| SystemTime::now().duration_since(t).ok());
+        if age.is_some_and(|a| a > STALE_UPLOAD_AGE) {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
     Ok(())
 }
 
-pub fn save_file(filename: &str, contents: &[u8]) -> Result<PathBuf> {
-    ensure_storage_dir()?;
-    let mut path = storage_dir();
-    path.push(filename);
-    let mut f = fs::File::create(&path)?;
-    f.write_all(contents)?;
-    Ok(path)
+/// `MAX_UPLOAD_BYTES`: largest accepted uploaded file (default 50 MB).
+pub fn max_upload_bytes() -> u64 {
+    std::env::var("MAX_UPLOAD_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(50_000_000)
+}
+
+/// A file being received. Bytes go to a local temp file while the SHA-256 and
+/// size are computed; dropping an unfinished upload (e.g. the
+/// client went away) removes the temp file.
+pub struct Upload {
+    tmp: PathBuf,
+    file: tokio::fs::File,
+    hasher: Sha256,
+    size: u64,
+    limit: Option<u64>,
 }
 
-pub fn delete_file(path: &Path) -> Result<()> {
-    if path.exists() {
-        fs::remove_file(path)?;
+impl Upload {
+    pub async fn create(limit: Option<u64>) -> Result<Self> {
+        let tmp = uploads_dir().join(format!("{}.part", uuid::Uuid::new_v4()));
+        let file = tokio::fs::File::create(&tmp).await?;
+        Ok(Self { tmp, file, hasher: Sha256::new(), size: 0, limit })
+    }
+
+    /// Append bytes; fails with `TooLarge` once the upload passes its limit.
+    pub async fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.size += bytes.len() as u64;
+        if let Some(limit) = self.limit.filter(|l| self.size > *l) {
+            return Err(TooLarge { limit }.into());
+        }
+        self.hasher.update(bytes);
+        self.file.write_all(bytes).await?;
+        Ok(())
+    }
+
+    /// Flush the temp file to disk. It stays in the uploads directory until
+    /// the staged file is dropped.
+    pub async fn finish(mut self) -> Result<StagedFile> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        let sha256 = hex::encode(std::mem::take(&mut self.hasher).finalize());
+        let tmp = std::mem::take(&mut self.tmp);
+        Ok(StagedFile { tmp, size: self.size, sha256 })
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        // Empty once `finish` handed the temp file over
+        if !self.tmp.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+/// A complete upload whose hash is known, not yet in the blob store. Dropping
+/// it removes the temp file.
+pub struct StagedFile {
+    tmp: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl StagedFile {
+    /// The bytes, for `BlobStore::put`.
+    pub async fn stream(&self) -> Result<ByteStream> {
+        let file = tokio::fs::File::open(&self.tmp).await?;
+        Ok(Box::pin(tokio_util::io::ReaderStream::new(file).map_err(anyhow::Error::from)))
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.tmp.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+/// Stage a local file for blob storage, streaming like an upload.
+pub async fn import_file(src: &Path) -> Result<StagedFile> {
+    let mut input = tokio::fs::File::open(src).await?;
+    let mut upload = Upload::create(None).await?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = input.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        upload.write(&buf[..n]).await?;
+    }
+    upload.finish().await
+}
+
+/// An upload went past its size limit.
+#[derive(Debug)]
+pub struct TooLarge {
+    pub limit: u64,
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "file exceeds the upload limit of {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// Longest accepted filename, in UTF-8 bytes (the common filesystem limit).
+const MAX_FILENAME_BYTES: usize = 255;
+
+/// A client-supplied filename that cannot be used as a display name.
+#[derive(Debug)]
+pub struct BadFilename {
+    pub reason: &'static str,
+}
+
+impl fmt::Display for BadFilename {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid filename: {}", self.reason)
+    }
+}
+
+impl std::error::Error for BadFilename {}
+
+/// The display name for a client-supplied filename: NFC-normalized and
+/// trimmed. Names are only ever stored as metadata, never used to build a
+/// path, but anything that looks like a path or could spoof what is shown
+/// (separators, `.`/`..`, control or bidi override characters) is refused
+/// rather than quietly rewritten.
+pub fn display_name(raw: &str) -> Result<String, BadFilename> {
+    let name: String = raw.nfc().collect();
+    let name = name.trim();
+    let bad = |reason| Err(BadFilename { reason });
+    if name.is_empty() {
+        return bad("must not be empty");
+    }
+    if name.contains(['/', '\\']) {
+        return bad("must not contain path separators");
+    }
+    if name.chars().any(|c| c.is_control() || matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')) {
+        return bad("must not contain control characters");
+    }
+    if name.chars().all(|c| c == '.') {
+        return bad("must not be a relative path component");
+    }
+    if name.len() > MAX_FILENAME_BYTES {
+        return bad("must be at most 255 bytes");
+    }
+    Ok(name.to_string())
+}
+
+/// MIME type from a filename's extension, for files uploaded without a usable
+/// Content-Type.
+pub fn guess_mime(filename: &str) -> &'static str {
+    let ext = Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "pdf" => "application/pdf",
+        "txt" | "log" => "text/plain",
+        "md" => "text/markdown",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "html" | "htm" => "text/html",
+        "xml" => "application/xml",
+        "doc" => "application/msword",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "ab12cd34ef56ab12cd34ef56ab12cd34ef56ab12cd34ef56ab12cd34ef56ab12";
+
+    #[test]
+    fn object_keys_of_new_and_legacy_rows() {
+        assert_eq!(object_key(Some(HASH), "ignored").unwrap(), format!("blobs/ab/12/{HASH}"));
+        assert!(object_key(Some("not-a-hash"), "blobs/x").is_err());
+        let legacy = storage_dir().join("1234_manual.pdf");
+        assert_eq!(object_key(None, &legacy.to_string_lossy()).unwrap(), "1234_manual.pdf");
+        assert_eq!(object_key(None, "blobs/ab/12/x").unwrap(), "blobs/ab/12/x");
+    }
+
+    #[test]
+    fn legacy_paths_outside_the_storage_dir_have_no_key() {
+        let elsewhere = std::env::temp_dir().join("old-storage").join("manual.pdf");
+        let elsewhere = elsewhere.to_string_lossy();
+        assert_eq!(legacy_key(&elsewhere), None);
+        let climbing = storage_dir().join("../../etc/passwd");
+        assert_eq!(legacy_key(&climbing.to_string_lossy()), None);
+        assert_eq!(legacy_key("../x"), None);
+        assert!(object_key(None, &elsewhere).unwrap_err().to_string().contains("outside the storage directory"));
+    }
+
+    #[test]
+    fn blob_keys_need_a_sha256() {
+        for bad in ["", "a", "ab1", &HASH[..63], &HASH.replace('a', "g"), &format!("é{}", &HASH[2..])] {
+            assert!(blob_key(bad).is_err(), "{bad:?}");
+        }
+        // One key per content: the uppercase spelling of a hash is refused
+        assert!(blob_key(&HASH.to_uppercase()).is_err());
+        assert!(blob_key(&format!("AB{}", &HASH[2..])).is_err());
+    }
+
+    #[test]
+    fn display_names_are_normalized_and_trimmed() {
+        assert_eq!(display_name("  ECLSS manual v2.pdf \t").unwrap(), "ECLSS manual v2.pdf");
+        // "e" + combining acute becomes the precomposed "é"
+        assert_eq!(display_name("re\u{301}sume\u{301}.pdf").unwrap(), "r\u{e9}sum\u{e9}.pdf");
+        assert_eq!(display_name("..hidden.pdf").unwrap(), "..hidden.pdf");
+        assert_eq!(display_name(&"a".repeat(255)).unwrap().len(), 255);
+    }
+
+    #[test]
+    fn hostile_display_names_are_refused() {
+        let reason = |raw: &str| display_name(raw).unwrap_err().reason;
+        assert_eq!(reason("   "), "must not be empty");
+        assert_eq!(reason("../../etc/cron.d/x"), "must not contain path separators");
+        assert_eq!(reason("/etc/passwd"), "must not contain path separators");
+        assert_eq!(reason("..\\windows\\system.ini"), "must not contain path separators");
+        assert_eq!(reason(".."), "must not be a relative path component");
+        assert_eq!(reason(" . "), "must not be a relative path component");
+        assert_eq!(reason("report\u{0}.pdf"), "must not contain control characters");
+        assert_eq!(reason("line\nbreak.pdf"), "must not contain control characters");
+        // Right-to-left override: "invoice\u{202E}fdp.exe" shows as "invoiceexe.pdf"
+        assert_eq!(reason("invoice\u{202E}fdp.exe"), "must not contain control characters");
+        assert_eq!(reason(&"é".repeat(128)), "must be at most 255 bytes");
     }
-    Ok(())
 }
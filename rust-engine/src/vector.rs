@@ -1,24 +1,381 @@
+use crate::vector_store::{ScoredPoint, VectorFilter, VectorPoint, VectorStore};
 use anyhow::Result;
+use async_trait::async_trait;
 use lazy_static::lazy_static;
+use sqlx::{MySqlPool, Row};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Mutex;
+use tracing::{error, info, warn};
 
+// In-memory mirror of the vector index, used when Qdrant is unavailable. Every
+// point is persisted to the `vectors` table, which the mirror is loaded from
+// the first time Qdrant fails.
 lazy_static! {
-    static ref VECTOR_STORE: Mutex<HashMap<String, Vec<f32>>> = Mutex::new(HashMap::new());
+    static ref VECTOR_STORE: Mutex<HashMap<String, (Vec<f32>, serde_json::Value)>> = Mutex::new(HashMap::new());
 }
 
-pub fn store_embedding(id: &str, emb: Vec<f32>) -> Result<()> {
+pub fn store_embedding(id: &str, emb: Vec<f32>, payload: serde_json::Value) -> Result<()> {
     let mut s = VECTOR_STORE.lock().unwrap();
-    s.insert(id.to_string(), emb);
+    s.insert(id.to_string(), (emb, payload));
     Ok(())
 }
 
-pub fn query_top_k(_query_emb: &[f32], k: usize) -> Result<Vec<String>> {
-    // Very naive: return up to k ids from the store.
+/// Drop every point whose payload matches the filter.
+pub fn remove_matching(filter: &VectorFilter) {
+    let mut s = VECTOR_STORE.lock().unwrap();
+    s.retain(|_, (_, payload)| !filter.matches(payload));
+}
+
+/// Rank stored points by cosine similarity to `query_emb`, best first.
+pub fn query_top_k(query_emb: &[f32], k: usize, filter: &VectorFilter) -> Result<Vec<ScoredPoint>> {
+    let query_norm = norm(query_emb);
+    if query_norm == 0.0 || k == 0 {
+        return Ok(Vec::new());
+    }
     let s = VECTOR_STORE.lock().unwrap();
-    let mut out = Vec::new();
-    for key in s.keys().take(k) {
-        out.push(key.clone());
+    let mut scored: Vec<(f32, &String)> = s
+        .iter()
+        .filter(|(_, (emb, payload))| emb.len() == query_emb.len() && filter.matches(payload))
+        .map(|(id, (emb, _))| (cosine(query_emb, query_norm, emb), id))
+        .collect();
+    scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+    Ok(scored
+        .into_iter()
+        .take(k)
+        .map(|(score, id)| ScoredPoint { id: id.clone(), score, payload: s[id].1.clone() })
+        .collect())
+}
+
+fn count_matching(filter: &VectorFilter) -> usize {
+    let s = VECTOR_STORE.lock().unwrap();
+    s.values().filter(|(_, payload)| filter.matches(payload)).count()
+}
+
+/// Page through the mirror in id order; the offset is the last id returned.
+fn scroll_matching(filter: &VectorFilter, offset: Option<String>, limit: usize) -> (Vec<VectorPoint>, Option<String>) {
+    let s = VECTOR_STORE.lock().unwrap();
+    let mut ids: Vec<&String> = s
+        .iter()
+        .filter(|(id, (_, payload))| offset.as_ref().map(|o| *id > o).unwrap_or(true) && filter.matches(payload))
+        .map(|(id, _)| id)
+        .collect();
+    ids.sort();
+    let page: Vec<VectorPoint> = ids
+        .iter()
+        .take(limit)
+        .map(|id| VectorPoint { id: (*id).clone(), vector: s[*id].0.clone(), payload: s[*id].1.clone() })
+        .collect();
+    let next = if ids.len() > limit { page.last().map(|p| p.id.clone()) } else { None };
+    (page, next)
+}
+
+/// Persist points, replacing any stored under the same ids.
+async fn persist_points(pool: &MySqlPool, points: &[VectorPoint]) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    for p in points {
+        let file_id = p.payload.get("file_id").and_then(|v| v.as_str()).unwrap_or(&p.id);
+        sqlx::query("REPLACE INTO vectors (id, file_id, payload, embedding) VALUES (?, ?, ?, ?)")
+            .bind(&p.id)
+            .bind(file_id)
+            .bind(&p.payload)
+            .bind(encode(&p.vector))
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Load persisted points into memory, a page at a time. Vectors of a different
+/// dimension (left over from a previous embedder) are skipped.
+pub async fn rebuild_from_db(pool: &MySqlPool, dim: usize) -> Result<usize> {
+    VECTOR_STORE.lock().unwrap().clear();
+    let mut after = String::new();
+    loop {
+        let rows = sqlx::query("SELECT id, payload, embedding FROM vectors WHERE id > ? ORDER BY id LIMIT ?")
+            .bind(&after)
+            .bind(LOAD_PAGE)
+            .fetch_all(pool)
+            .await?;
+        let Some(last) = rows.last() else { break };
+        after = last.get("id");
+        let mut s = VECTOR_STORE.lock().unwrap();
+        for row in &rows {
+            let emb = decode(&row.get::<Vec<u8>, _>("embedding"));
+            if emb.len() == dim {
+                s.insert(row.get("id"), (emb, row.get("payload")));
+            }
+        }
+    }
+    Ok(VECTOR_STORE.lock().unwrap().len())
+}
+
+/// Rows read per query when loading the mirror.
+const LOAD_PAGE: i64 = 1000;
+
+/// Wraps a remote store (Qdrant) with the in-memory mirror. Writes always go
+/// to the `vectors` table; the mirror itself is only loaded from it once the
+/// remote fails, so a healthy deployment never holds the corpus in memory.
+/// Deletes the remote rejects are queued in `vector_deletes` and replayed
+/// before the next remote write or search.
+pub struct FallbackStore<S: VectorStore> {
+    primary: S,
+    pool: MySqlPool,
+    dim: AtomicUsize,
+    mirror_loaded: AtomicBool,
+    /// Held while loading the mirror, and while writing to it, so a write
+    /// racing the load is neither lost nor applied twice
+    mirror_lock: tokio::sync::Mutex<()>,
+    /// Set when `vector_deletes` may have rows; starts set so queued deletes
+    /// survive a restart
+    deletes_pending: AtomicBool,
+}
+
+impl<S: VectorStore> FallbackStore<S> {
+    pub fn new(primary: S, pool: MySqlPool) -> Self {
+        Self {
+            primary,
+            pool,
+            dim: AtomicUsize::new(0),
+            mirror_loaded: AtomicBool::new(false),
+            mirror_lock: tokio::sync::Mutex::new(()),
+            deletes_pending: AtomicBool::new(true),
+        }
+    }
+
+    fn mirror_loaded(&self) -> bool {
+        self.mirror_loaded.load(Ordering::Acquire)
+    }
+
+    /// Load the mirror from the database if that hasn't happened yet.
+    async fn load_mirror(&self) -> Result<()> {
+        let _guard = self.mirror_lock.lock().await;
+        if self.mirror_loaded() {
+            return Ok(());
+        }
+        let n = rebuild_from_db(&self.pool, self.dim.load(Ordering::Acquire)).await?;
+        info!("{} unavailable; loaded {} vectors into the in-memory fallback", self.primary.name(), n);
+        self.mirror_loaded.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Replay deletes queued while the remote was failing. Until this
+    /// succeeds, nothing else may be written to the remote: a replayed delete
+    /// would otherwise remove points written after it was queued.
+    async fn drain_deletes(&self) -> Result<()> {
+        if !self.deletes_pending.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let result: Result<()> = async {
+            let ids: Vec<String> = sqlx::query_scalar("SELECT file_id FROM vector_deletes ORDER BY queued_at")
+                .fetch_all(&self.pool)
+                .await?;
+            for fid in ids {
+                self.primary.delete(&VectorFilter::file(&fid)).await?;
+                sqlx::query("DELETE FROM vector_deletes WHERE file_id = ?").bind(&fid).execute(&self.pool).await?;
+                info!("Replayed queued {} delete for file {}", self.primary.name(), fid);
+            }
+            Ok(())
+        }
+        .await;
+        if result.is_err() {
+            self.deletes_pending.store(true, Ordering::Release);
+        }
+        result
+    }
+
+    /// Record a delete the remote rejected so `drain_deletes` can replay it.
+    async fn queue_delete(&self, file_ids: &[String]) -> Result<()> {
+        for fid in file_ids {
+            sqlx::query("INSERT IGNORE INTO vector_deletes (file_id) VALUES (?)")
+                .bind(fid)
+                .execute(&self.pool)
+                .await?;
+        }
+        self.deletes_pending.store(true, Ordering::Release);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S: VectorStore> VectorStore for FallbackStore<S> {
+    fn name(&self) -> &str {
+        self.primary.name()
+    }
+
+    async fn ensure_ready(&self, dim: usize) -> Result<()> {
+        self.dim.store(dim, Ordering::Release);
+        self.primary.ensure_ready(dim).await
+    }
+
+    async fn upsert(&self, points: Vec<VectorPoint>) -> Result<()> {
+        persist_points(&self.pool, &points).await?;
+        {
+            let _guard = self.mirror_lock.lock().await;
+            if self.mirror_loaded() {
+                for p in &points {
+                    let _ = store_embedding(&p.id, p.vector.clone(), p.payload.clone());
+                }
+            }
+        }
+        let result = match self.drain_deletes().await {
+            Ok(()) => self.primary.upsert(points).await,
+            Err(e) => Err(e),
+        };
+        if let Err(err) = result {
+            error!("{} upsert failed, kept in fallback index: {}", self.primary.name(), err);
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, filter: &VectorFilter) -> Result<()> {
+        if let Some(ids) = &filter.file_ids {
+            for fid in ids {
+                sqlx::query("DELETE FROM vectors WHERE file_id = ?").bind(fid).execute(&self.pool).await?;
+            }
+        }
+        {
+            let _guard = self.mirror_lock.lock().await;
+            if self.mirror_loaded() {
+                remove_matching(filter);
+            }
+        }
+        let result = match self.drain_deletes().await {
+            Ok(()) => self.primary.delete(filter).await,
+            Err(e) => Err(e),
+        };
+        match (result, &filter.file_ids) {
+            (Ok(()), _) => Ok(()),
+            (Err(err), Some(ids)) => {
+                warn!("{} delete failed, queued for retry: {}", self.primary.name(), err);
+                self.queue_delete(ids).await
+            }
+            // Only per-file deletes can be replayed
+            (Err(err), None) => Err(err),
+        }
+    }
+
+    async fn search(&self, vector: &[f32], k: usize, filter: &VectorFilter) -> Result<Vec<ScoredPoint>> {
+        let result = match self.drain_deletes().await {
+            Ok(()) => self.primary.search(vector, k, filter).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            // Empty results only fall through once the mirror is in use, since
+            // it may hold points the remote missed while it was down
+            Ok(hits) if !hits.is_empty() || !self.mirror_loaded() => return Ok(hits),
+            Ok(_) => {}
+            Err(err) => {
+                error!("{} search failed: {}", self.primary.name(), err);
+                self.load_mirror().await?;
+            }
+        }
+        let hits = query_top_k(vector, k, filter)?;
+        if !hits.is_empty() {
+            info!("Using in-memory fallback for search");
+        }
+        Ok(hits)
+    }
+
+    async fn count(&self, filter: &VectorFilter) -> Result<usize> {
+        match self.primary.count(filter).await {
+            Ok(n) => Ok(n),
+            Err(_) => {
+                self.load_mirror().await?;
+                Ok(count_matching(filter))
+            }
+        }
+    }
+
+    async fn scroll(&self, filter: &VectorFilter, offset: Option<String>, limit: usize) -> Result<(Vec<VectorPoint>, Option<String>)> {
+        match self.primary.scroll(filter, offset.clone(), limit).await {
+            Ok(page) => Ok(page),
+            Err(_) => {
+                self.load_mirror().await?;
+                Ok(scroll_matching(filter, offset, limit))
+            }
+        }
+    }
+}
+
+fn encode(v: &[f32]) -> Vec<u8> {
+    v.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn decode(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+fn norm(v: &[f32]) -> f32 {
+    v.iter().map(|x| x * x).sum::<f32>().sqrt()
+}
+
+fn cosine(q: &[f32], q_norm: f32, v: &[f32]) -> f32 {
+    let v_norm = norm(v);
+    if v_norm == 0.0 {
+        return 0.0;
+    }
+    q.iter().zip(v).map(|(a, b)| a * b).sum::<f32>() / (q_norm * v_norm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // The mirror is process-wide, so each test works on its own file id
+    fn put(file_id: &str, id: &str, emb: Vec<f32>) {
+        store_embedding(id, emb, json!({ "file_id": file_id, "type": "chunk" })).unwrap();
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        let v = vec![0.5, -1.25, 3.0e-7, f32::MAX];
+        assert_eq!(decode(&encode(&v)), v);
+        // A trailing partial float is ignored
+        let mut bytes = encode(&v);
+        bytes.push(7);
+        assert_eq!(decode(&bytes), v);
+    }
+
+    #[test]
+    fn cosine_handles_scale_and_zero() {
+        let q = [1.0, 0.0];
+        assert!((cosine(&q, norm(&q), &[5.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine(&q, norm(&q), &[0.0, 2.0]).abs() < 1e-6);
+        assert_eq!(cosine(&q, norm(&q), &[0.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn top_k_ranks_by_similarity_within_filter() {
+        put("rank-file", "rank-a", vec![1.0, 0.0, 0.0]);
+        put("rank-file", "rank-b", vec![0.7, 0.7, 0.0]);
+        put("rank-file", "rank-c", vec![0.0, 0.0, 1.0]);
+        put("rank-file", "rank-short", vec![1.0, 0.0]);
+        put("rank-other", "rank-x", vec![1.0, 0.0, 0.0]);
+        let hits = query_top_k(&[1.0, 0.1, 0.0], 2, &VectorFilter::file("rank-file")).unwrap();
+        let ids: Vec<&str> = hits.iter().map(|h| h.id.as_str()).collect();
+        assert_eq!(ids, vec!["rank-a", "rank-b"]);
+        assert!(query_top_k(&[0.0, 0.0, 0.0], 2, &VectorFilter::file("rank-file")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn scroll_pages_in_id_order_and_remove_drops_matches() {
+        for i in 0..5 {
+            put("scroll-file", &format!("scroll-{i}"), vec![1.0]);
+        }
+        let filter = VectorFilter::file("scroll-file");
+        let (first, next) = scroll_matching(&filter, None, 3);
+        assert_eq!(first.iter().map(|p| p.id.as_str()).collect::<Vec<_>>(), vec!["scroll-0", "scroll-1", "scroll-2"]);
+        let (rest, next) = scroll_matching(&filter, next, 3);
+        assert_eq!(rest.len(), 2);
+        assert!(next.is_none());
+        assert_eq!(count_matching(&filter), 5);
+        remove_matching(&filter);
+        assert_eq!(count_matching(&filter), 0);
     }
-    Ok(out)
 }
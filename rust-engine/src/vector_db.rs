@@ -1,5 +1,9 @@
+use crate::outbound::{self, HttpFailure, Outbound};
+use crate::vector_store::{ScoredPoint, VectorFilter, VectorPoint, VectorStore};
 use anyhow::Result;
-use reqwest::Client;
+use async_trait::async_trait;
+use reqwest::{Client, Method};
+use std::sync::Arc;
 use serde_json::json;
 use serde::Deserialize;
 
@@ -7,97 +11,198 @@ use serde::Deserialize;
 pub struct QdrantClient {
     base: String,
     client: Client,
+    outbound: Arc<Outbound>,
 }
 
 impl QdrantClient {
-
-    /// Delete a point from collection 'files' by id
-    pub async fn delete_point(&self, id: &str) -> Result<()> {
-        let url = format!("{}/collections/files/points/delete", self.base);
-        let body = json!({
-            "points": [id]
-        });
-        let resp = self.client.post(&url).json(&body).send().await?;
-        let status = resp.status();
-        if status.is_success() {
-            Ok(())
-        } else {
-            let t = resp.text().await.unwrap_or_default();
-            Err(anyhow::anyhow!("qdrant delete failed: {} - {}", status, t))
-        }
-    }
     pub fn new(base: &str) -> Self {
         Self {
             base: base.trim_end_matches('/').to_string(),
             client: Client::new(),
+            // Not critical: the fallback index keeps the workers going while Qdrant is down
+            outbound: outbound::dependency("qdrant", false),
         }
     }
 
-    /// Upsert a point into collection `files` with id and vector
-    pub async fn upsert_point(&self, id: &str, vector: Vec<f32>) -> Result<()> {
-        let url = format!("{}/collections/files/points", self.base);
-        let body = json!({
-            "points": [{
-                "id": id,
-                "vector": vector,
-                "payload": {"type": "file"}
-            }]
-        });
-
-        let resp = self.client.post(&url).json(&body).send().await?;
-        let status = resp.status();
-        if status.is_success() {
-            Ok(())
-        } else {
-            let t = resp.text().await.unwrap_or_default();
-            Err(anyhow::anyhow!("qdrant upsert failed: {} - {}", status, t))
-        }
+    /// Send a request through the outbound layer. Statuses in `accept` count
+    /// as success alongside 2xx; everything else is an `HttpFailure`.
+    async fn send(&self, method: Method, path: &str, body: Option<&serde_json::Value>, accept: &[u16]) -> Result<serde_json::Value, HttpFailure> {
+        let url = format!("{}/{path}", self.base);
+        self.outbound
+            .call(1, || async {
+                let mut req = self.client.request(method.clone(), &url);
+                if let Some(body) = body {
+                    req = req.json(body);
+                }
+                let resp = req.send().await?;
+                let status = resp.status();
+                if !status.is_success() && !accept.contains(&status.as_u16()) {
+                    let headers = resp.headers().clone();
+                    let t = resp.text().await.unwrap_or_default();
+                    return Err(HttpFailure::from_response(status, &headers, &t));
+                }
+                Ok(resp.json().await.unwrap_or(serde_json::Value::Null))
+            })
+            .await
     }
 
     /// Ensure the 'files' collection exists with the given dimension and distance metric
     pub async fn ensure_files_collection(&self, dim: usize) -> Result<()> {
-        let url = format!("{}/collections/files", self.base);
         let body = json!({
             "vectors": {"size": dim, "distance": "Cosine"}
         });
-        let resp = self.client.put(&url).json(&body).send().await?;
         // 200 OK or 201 Created means ready; 409 Conflict means already exists
-        if resp.status().is_success() || resp.status().as_u16() == 409 {
-            Ok(())
-        } else {
-            let status = resp.status();
-            let t = resp.text().await.unwrap_or_default();
-            Err(anyhow::anyhow!("qdrant ensure collection failed: {} - {}", status, t))
+        self.send(Method::PUT, "collections/files", Some(&body), &[409])
+            .await
+            .map_err(|e| anyhow::anyhow!("qdrant ensure collection failed: {e}"))?;
+        // Index the payload fields that searches and deletes filter on
+        for (field, schema) in [("file_id", "keyword"), ("tags", "keyword"), ("mime_type", "keyword"), ("uploaded_at", "integer")] {
+            let index_body = json!({"field_name": field, "field_schema": schema});
+            let _ = self.send(Method::PUT, "collections/files/index", Some(&index_body), &[]).await;
+        }
+        // An existing collection keeps its original size; refuse to mix dimensions
+        match self.collection_dim().await? {
+            Some(existing) if existing != dim => Err(anyhow::anyhow!(
+                "qdrant collection 'files' has dimension {existing} but the embedder produces {dim}; drop the collection and reindex"
+            )),
+            _ => Ok(()),
         }
     }
 
-    /// Search top-k nearest points from 'files', return (id, score)
-    pub async fn search_top_k(&self, vector: Vec<f32>, k: usize) -> Result<Vec<(String, f32)>> {
-        let url = format!("{}/collections/files/points/search", self.base);
-        let body = json!({
+    /// Vector size of the existing 'files' collection, if it exists
+    async fn collection_dim(&self) -> Result<Option<usize>> {
+        let info = match self.send(Method::GET, "collections/files", None, &[]).await {
+            Ok(info) => info,
+            Err(e) if e.status.is_some() => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(info
+            .pointer("/result/config/params/vectors/size")
+            .and_then(|v| v.as_u64())
+            .map(|v| v as usize))
+    }
+
+    /// POST a body to a 'files' collection endpoint and return the `result` field
+    async fn post_files(&self, endpoint: &str, body: serde_json::Value, what: &str) -> Result<serde_json::Value> {
+        let data = self
+            .send(Method::POST, &format!("collections/files/{endpoint}"), Some(&body), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("qdrant {what} failed: {e}"))?;
+        Ok(data.get("result").cloned().unwrap_or(serde_json::Value::Null))
+    }
+}
+
+/// Translate a payload filter into Qdrant's filter syntax
+fn qdrant_filter(filter: &VectorFilter) -> Option<serde_json::Value> {
+    let mut must = Vec::new();
+    if let Some(ids) = &filter.file_ids {
+        must.push(json!({"key": "file_id", "match": {"any": ids}}));
+    }
+    if let Some(tags) = &filter.tags {
+        must.push(json!({"key": "tags", "match": {"any": tags}}));
+    }
+    if let Some(types) = &filter.mime_types {
+        must.push(json!({"key": "mime_type", "match": {"any": types}}));
+    }
+    if filter.uploaded_from.is_some() || filter.uploaded_to.is_some() {
+        must.push(json!({"key": "uploaded_at", "range": {"gte": filter.uploaded_from, "lte": filter.uploaded_to}}));
+    }
+    if must.is_empty() {
+        None
+    } else {
+        Some(json!({ "must": must }))
+    }
+}
+
+/// Qdrant ids can be strings (UUIDs) or integers
+fn point_id(id: &serde_json::Value) -> String {
+    match id.as_str() {
+        Some(s) => s.to_string(),
+        None => id.to_string(),
+    }
+}
+
+#[async_trait]
+impl VectorStore for QdrantClient {
+    fn name(&self) -> &str {
+        "qdrant"
+    }
+
+    async fn ensure_ready(&self, dim: usize) -> Result<()> {
+        self.ensure_files_collection(dim).await
+    }
+
+    /// Upsert a batch of points into collection `files`
+    async fn upsert(&self, points: Vec<VectorPoint>) -> Result<()> {
+        let points: Vec<serde_json::Value> = points
+            .into_iter()
+            .map(|p| json!({"id": p.id, "vector": p.vector, "payload": p.payload}))
+            .collect();
+        let body = json!({ "points": points });
+
+        self.send(Method::PUT, "collections/files/points", Some(&body), &[])
+            .await
+            .map_err(|e| anyhow::anyhow!("qdrant upsert failed: {e}"))?;
+        Ok(())
+    }
+
+    /// Delete every point matching the filter (e.g. file summary and chunks of a file)
+    async fn delete(&self, filter: &VectorFilter) -> Result<()> {
+        let filter = qdrant_filter(filter).ok_or_else(|| anyhow::anyhow!("refusing to delete without a filter"))?;
+        self.post_files("points/delete", json!({ "filter": filter }), "delete").await?;
+        Ok(())
+    }
+
+    /// Search top-k nearest points from 'files', returning ids, scores and payloads
+    async fn search(&self, vector: &[f32], k: usize, filter: &VectorFilter) -> Result<Vec<ScoredPoint>> {
+        let mut body = json!({
             "vector": vector,
-            "limit": k
+            "limit": k,
+            "with_payload": true
         });
-        let resp = self.client.post(&url).json(&body).send().await?;
-        let status = resp.status();
-        if !status.is_success() {
-            let t = resp.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!("qdrant search failed: {} - {}", status, t));
+        if let Some(f) = qdrant_filter(filter) {
+            body["filter"] = f;
         }
         #[derive(Deserialize)]
-        struct Hit { id: serde_json::Value, score: f32 }
-        #[derive(Deserialize)]
-        struct Data { result: Vec<Hit> }
-        let data: Data = resp.json().await?;
-        let mut out = Vec::new();
-        for h in data.result {
-            // id can be string or number; handle string
-            if let Some(s) = h.id.as_str() {
-                out.push((s.to_string(), h.score));
-            } else {
-                out.push((h.id.to_string(), h.score));
-            }
+        struct Hit { id: serde_json::Value, score: f32, payload: Option<serde_json::Value> }
+        let hits: Vec<Hit> = serde_json::from_value(self.post_files("points/search", body, "search").await?)?;
+        Ok(hits
+            .into_iter()
+            .map(|h| ScoredPoint { id: point_id(&h.id), score: h.score, payload: h.payload.unwrap_or(serde_json::Value::Null) })
+            .collect())
+    }
+
+    async fn count(&self, filter: &VectorFilter) -> Result<usize> {
+        let mut body = json!({ "exact": true });
+        if let Some(f) = qdrant_filter(filter) {
+            body["filter"] = f;
         }
-        Ok(out)
+        let result = self.post_files("points/count", body, "count").await?;
+        Ok(result.get("count").and_then(|v| v.as_u64()).unwrap_or(0) as usize)
+    }
+
+    async fn scroll(&self, filter: &VectorFilter, offset: Option<String>, limit: usize) -> Result<(Vec<VectorPoint>, Option<String>)> {
+        let mut body = json!({ "limit": limit, "with_payload": true, "with_vector": true });
+        if let Some(f) = qdrant_filter(filter) {
+            body["filter"] = f;
+        }
+        if let Some(o) = offset {
+            body["offset"] = json!(o);
+        }
+        #[derive(Deserialize)]
+        struct Record { id: serde_json::Value, payload: Option<serde_json::Value>, vector: Option<Vec<f32>> }
+        #[derive(Deserialize)]
+        struct Page { points: Vec<Record>, next_page_offset: Option<serde_json::Value> }
+        let page: Page = serde_json::from_value(self.post_files("points/scroll", body, "scroll").await?)?;
+        let points = page
+            .points
+            .into_iter()
+            .map(|r| VectorPoint {
+                id: point_id(&r.id),
+                vector: r.vector.unwrap_or_default(),
+                payload: r.payload.unwrap_or(serde_json::Value::Null),
+            })
+            .collect();
+        Ok((points, page.next_page_offset.filter(|v| !v.is_null()).map(|v| point_id(&v))))
     }
 }
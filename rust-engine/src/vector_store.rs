@@ -0,0 +1,146 @@
+use crate::embedded_store::EmbeddedStore;
+use crate::vector::FallbackStore;
+use crate::vector_db::QdrantClient;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::MySqlPool;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::info;
+
+/// A vector with the payload stored alongside it (`type`, `file_id`, `page`, ...).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VectorPoint {
+    pub id: String,
+    pub vector: Vec<f32>,
+    pub payload: serde_json::Value,
+}
+
+/// A search hit with the payload stored alongside the vector.
+#[derive(Debug, Clone)]
+pub struct ScoredPoint {
+    pub id: String,
+    pub score: f32,
+    pub payload: serde_json::Value,
+}
+
+/// Payload conditions shared by every backend. Empty means "match everything".
+/// List conditions match a payload that has any of the listed values.
+#[derive(Debug, Clone, Default)]
+pub struct VectorFilter {
+    pub file_ids: Option<Vec<String>>,
+    pub tags: Option<Vec<String>>,
+    pub mime_types: Option<Vec<String>>,
+    /// Inclusive bounds on the file's upload time (`uploaded_at`, Unix seconds)
+    pub uploaded_from: Option<i64>,
+    pub uploaded_to: Option<i64>,
+}
+
+impl VectorFilter {
+    pub fn file(file_id: &str) -> Self {
+        Self { file_ids: Some(vec![file_id.to_string()]), ..Default::default() }
+    }
+
+    /// True when the filter places no conditions on payloads.
+    pub fn is_empty(&self) -> bool {
+        self.file_ids.is_none()
+            && self.tags.is_none()
+            && self.mime_types.is_none()
+            && self.uploaded_from.is_none()
+            && self.uploaded_to.is_none()
+    }
+
+    /// Evaluate the filter against a payload, for backends without native filtering.
+    pub fn matches(&self, payload: &serde_json::Value) -> bool {
+        let str_field = |key: &str| payload.get(key).and_then(|v| v.as_str()).unwrap_or("");
+        if let Some(ids) = &self.file_ids {
+            let fid = str_field("file_id");
+            if !ids.iter().any(|id| id == fid) {
+                return false;
+            }
+        }
+        if let Some(tags) = &self.tags {
+            let has_tag = payload
+                .get("tags")
+                .and_then(|v| v.as_array())
+                .map(|list| list.iter().filter_map(|t| t.as_str()).any(|t| tags.iter().any(|w| w == t)))
+                .unwrap_or(false);
+            if !has_tag {
+                return false;
+            }
+        }
+        if let Some(types) = &self.mime_types {
+            let mime = str_field("mime_type");
+            if !types.iter().any(|t| t == mime) {
+                return false;
+            }
+        }
+        if self.uploaded_from.is_some() || self.uploaded_to.is_some() {
+            let Some(at) = payload.get("uploaded_at").and_then(|v| v.as_i64()) else {
+                return false;
+            };
+            if self.uploaded_from.map(|from| at < from).unwrap_or(false) || self.uploaded_to.map(|to| at > to).unwrap_or(false) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// File-level payload fields stored on every point of a file so searches can
+/// filter on them. `tags` is the JSON array from the `files` table.
+pub fn file_payload(file_id: &str, mime_type: &str, tags: Option<serde_json::Value>, uploaded_at: Option<i64>) -> serde_json::Value {
+    serde_json::json!({
+        "file_id": file_id,
+        "mime_type": mime_type,
+        "tags": tags.filter(|t| t.is_array()).unwrap_or_else(|| serde_json::json!([])),
+        "uploaded_at": uploaded_at,
+    })
+}
+
+/// Storage and similarity search for file and chunk vectors. Implemented by
+/// Qdrant (with an in-memory fallback) and by an embedded on-disk index.
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    /// Short identifier used in logs and stats.
+    fn name(&self) -> &str;
+
+    /// Create/open the index for vectors of `dim` and load any state it needs.
+    async fn ensure_ready(&self, dim: usize) -> Result<()>;
+
+    async fn upsert(&self, points: Vec<VectorPoint>) -> Result<()>;
+
+    /// Delete every point matching the filter.
+    async fn delete(&self, filter: &VectorFilter) -> Result<()>;
+
+    async fn search(&self, vector: &[f32], k: usize, filter: &VectorFilter) -> Result<Vec<ScoredPoint>>;
+
+    async fn count(&self, filter: &VectorFilter) -> Result<usize>;
+
+    /// Page through stored points. Pass the returned offset to get the next page;
+    /// `None` means there are no more points.
+    async fn scroll(&self, filter: &VectorFilter, offset: Option<String>, limit: usize) -> Result<(Vec<VectorPoint>, Option<String>)>;
+}
+
+/// Build the store selected by VECTOR_BACKEND: `qdrant` (default, at QDRANT_URL,
+/// mirrored into memory and MySQL for fallback) or `embedded` (on-disk under
+/// VECTOR_STORE_PATH, no Qdrant needed).
+pub fn vector_store_from_env(pool: MySqlPool) -> Result<Arc<dyn VectorStore>> {
+    let backend = std::env::var("VECTOR_BACKEND").unwrap_or_else(|_| "qdrant".to_string()).to_lowercase();
+    let store: Arc<dyn VectorStore> = match backend.as_str() {
+        "qdrant" => {
+            let qdrant_url = std::env::var("QDRANT_URL").unwrap_or_else(|_| "http://qdrant:6333".to_string());
+            Arc::new(FallbackStore::new(QdrantClient::new(&qdrant_url), pool))
+        }
+        "embedded" => {
+            let dir = std::env::var("VECTOR_STORE_PATH")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| crate::storage::storage_dir().join("vectors"));
+            Arc::new(EmbeddedStore::new(dir))
+        }
+        other => return Err(anyhow!("unknown VECTOR_BACKEND '{other}'")),
+    };
+    info!("Using {} vector store", store.name());
+    Ok(store)
+}
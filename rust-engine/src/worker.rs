@@ -1,210 +1,470 @@
-use crate::gemini_client::{demo_text_embedding, generate_text_with_model, DEMO_EMBED_DIM};
+use crate::citations::{self, AnswerStream, Passage};
+use crate::embedding::Embedder;
+use crate::events::{QueryEvent, QueryEvents};
+use crate::jobs::{self, Job, JobHandler};
+use crate::keyword::{self, KeywordIndex};
+use crate::lease::Lease;
+use crate::llm::{LlmError, LlmStage, Llms};
 use crate::models::{QueryRecord, QueryStatus};
-use crate::vector;
-use crate::vector_db::QdrantClient;
-use anyhow::Result;
+use crate::sessions::{self, Turn};
+use crate::vector_store::{ScoredPoint, VectorFilter, VectorStore};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate};
+use serde::Deserialize;
 use sqlx::MySqlPool;
-use std::time::Duration;
-use tracing::{error, info};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tracing::{error, info, warn};
 
-pub struct Worker {
-    pool: MySqlPool,
-    qdrant: QdrantClient,
+/// Chunk hits requested per wanted file before grouping.
+const CHUNKS_PER_FILE: usize = 4;
+/// Upper bound on chunk hits fetched for a single query.
+const MAX_CHUNK_HITS: usize = 100;
+/// Passages per file, and in total, quoted to the model and citable.
+const PASSAGES_PER_FILE: usize = 3;
+const MAX_PROMPT_PASSAGES: usize = 24;
+/// Rank offset for reciprocal rank fusion; 60 is the usual choice and keeps
+/// either list from dominating on its top hit alone.
+const RRF_K: f32 = 60.0;
+/// `job_type` of query jobs; the subject is the query id.
+pub const ANSWER_QUERY: &str = "answer_query";
+/// Queries are interactive, so their jobs go ahead of file analysis.
+pub const QUERY_PRIORITY: i32 = 10;
+
+/// How chunks are retrieved, chosen per query with the `mode` payload field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RetrievalMode {
+    Vector,
+    Keyword,
+    Hybrid,
 }
 
-impl Worker {
-    pub fn new(pool: MySqlPool) -> Self {
-        let qdrant_url = std::env::var("QDRANT_URL").unwrap_or_else(|_| "http://qdrant:6333".to_string());
-        let qdrant = QdrantClient::new(&qdrant_url);
-        Self { pool, qdrant }
+impl RetrievalMode {
+    fn from_payload(payload: &serde_json::Value) -> Result<Self> {
+        let mode = match payload.get("mode") {
+            None | Some(serde_json::Value::Null) => "hybrid",
+            Some(v) => v.as_str().ok_or_else(|| anyhow!("mode must be a string"))?,
+        };
+        match mode {
+            "vector" => Ok(Self::Vector),
+            "keyword" => Ok(Self::Keyword),
+            "hybrid" => Ok(Self::Hybrid),
+            other => Err(anyhow!("unknown retrieval mode '{other}' (expected vector, keyword or hybrid)")),
+        }
     }
 
-    pub async fn run(&self) {
-        info!("Worker starting");
-
-        // Ensure qdrant collection exists
-        if let Err(e) = self.qdrant.ensure_files_collection(DEMO_EMBED_DIM).await {
-            error!("Failed to ensure Qdrant collection: {}", e);
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Vector => "vector",
+            Self::Keyword => "keyword",
+            Self::Hybrid => "hybrid",
         }
+    }
+}
 
-        // Requeue stale InProgress jobs older than cutoff (e.g., 10 minutes)
-        if let Err(e) = self.requeue_stale_inprogress(10 * 60).await {
-            error!("Failed to requeue stale jobs: {}", e);
-        }
+/// The `filter` object of a query payload. Every condition is optional and all
+/// given conditions must hold; list conditions match any of their values.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QueryFilter {
+    file_ids: Option<Vec<String>>,
+    tags: Option<Vec<String>>,
+    mime_types: Option<Vec<String>>,
+    uploaded_from: Option<DateBound>,
+    uploaded_to: Option<DateBound>,
+    /// Checked against MySQL rather than the vector payload, since the status
+    /// changes after a file's points are written
+    analysis_status: Option<Vec<String>>,
+}
 
-        loop {
-            // Claim next queued query
-            match self.fetch_and_claim().await {
-                Ok(Some(mut q)) => {
-                    info!("Processing query {}", q.id);
-                    if let Err(e) = self.process_query(&mut q).await {
-                        error!("Error processing {}: {}", q.id, e);
-                        let _ = self.mark_failed(&q.id, &format!("{}", e)).await;
-                    }
-                }
-                Ok(None) => {
-                    tokio::time::sleep(Duration::from_secs(2)).await;
-                }
-                Err(e) => {
-                    error!("Worker fetch error: {}", e);
-                    tokio::time::sleep(Duration::from_secs(5)).await;
+/// Unix seconds, an RFC 3339 timestamp or a `YYYY-MM-DD` date.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum DateBound {
+    Unix(i64),
+    Text(String),
+}
+
+impl DateBound {
+    /// A bare date covers the whole day: start of day as a lower bound, end of
+    /// day as an upper bound (UTC).
+    fn to_unix(&self, upper: bool) -> Result<i64> {
+        match self {
+            Self::Unix(t) => Ok(*t),
+            Self::Text(s) => {
+                if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+                    return Ok(t.timestamp());
                 }
+                let day = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                    .map_err(|_| anyhow!("invalid date '{s}' (expected YYYY-MM-DD, RFC 3339 or Unix seconds)"))?;
+                let t = if upper { day.and_hms_opt(23, 59, 59) } else { day.and_hms_opt(0, 0, 0) };
+                Ok(t.map(|t| t.and_utc().timestamp()).unwrap_or_default())
             }
         }
     }
+}
 
-    async fn fetch_and_claim(&self) -> Result<Option<QueryRecord>> {
-        // Note: MySQL transactional SELECT FOR UPDATE handling is more complex; for this hackathon scaffold
-        // we do a simple two-step: select one queued id, then update it to InProgress if it is still queued.
-        if let Some(row) = sqlx::query("SELECT id, payload FROM queries WHERE status = 'Queued' ORDER BY created_at LIMIT 1")
-            .fetch_optional(&self.pool)
-            .await?
-        {
-            use sqlx::Row;
-            let id: String = row.get("id");
-            let payload: serde_json::Value = row.get("payload");
+impl QueryFilter {
+    fn from_payload(payload: &serde_json::Value) -> Result<Self> {
+        match payload.get("filter") {
+            None | Some(serde_json::Value::Null) => Ok(Self::default()),
+            Some(f) => serde_json::from_value(f.clone()).map_err(|e| anyhow!("invalid query filter: {e}")),
+        }
+    }
 
-            let updated = sqlx::query("UPDATE queries SET status = 'InProgress' WHERE id = ? AND status = 'Queued'")
-                .bind(&id)
-                .execute(&self.pool)
-                .await?;
+    /// The payload conditions every retrieval backend evaluates. Tags are
+    /// compared lowercased, as they are stored.
+    fn vector_filter(&self) -> Result<VectorFilter> {
+        Ok(VectorFilter {
+            file_ids: self.file_ids.clone(),
+            tags: self.tags.as_ref().map(|t| t.iter().map(|t| t.trim().to_lowercase()).collect()),
+            mime_types: self.mime_types.as_ref().map(|t| t.iter().map(|t| t.trim().to_lowercase()).collect()),
+            uploaded_from: self.uploaded_from.as_ref().map(|d| d.to_unix(false)).transpose()?,
+            uploaded_to: self.uploaded_to.as_ref().map(|d| d.to_unix(true)).transpose()?,
+        })
+    }
+}
 
-            if updated.rows_affected() == 1 {
-                let mut q = QueryRecord::new(payload);
-                q.id = id;
-                q.status = QueryStatus::InProgress;
-                return Ok(Some(q));
-            }
+/// Check the `mode` and `filter` of a new query, so the API answers a bad one
+/// with a 400 instead of queuing a query that can only fail.
+pub fn validate_options(payload: &serde_json::Value) -> Result<()> {
+    RetrievalMode::from_payload(payload)?;
+    QueryFilter::from_payload(payload)?.vector_filter()?;
+    Ok(())
+}
+
+pub struct Worker {
+    pool: MySqlPool,
+    store: Arc<dyn VectorStore>,
+    embedder: Arc<dyn Embedder>,
+    keywords: Arc<KeywordIndex>,
+    events: Arc<QueryEvents>,
+    llms: Arc<Llms>,
+    concurrency: usize,
+}
+
+impl Worker {
+    pub fn new(
+        pool: MySqlPool,
+        store: Arc<dyn VectorStore>,
+        embedder: Arc<dyn Embedder>,
+        keywords: Arc<KeywordIndex>,
+        events: Arc<QueryEvents>,
+        llms: Arc<Llms>,
+    ) -> Self {
+        // QUERY_WORKER_CONCURRENCY: queries answered at once by this process
+        let concurrency = std::env::var("QUERY_WORKER_CONCURRENCY").ok().and_then(|v| v.parse().ok()).unwrap_or(2);
+        Self { pool, store, embedder, keywords, events, llms, concurrency }
+    }
+}
+
+#[async_trait]
+impl JobHandler for Worker {
+    fn job_type(&self) -> &'static str {
+        ANSWER_QUERY
+    }
+
+    fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    /// Queries left queued or running without an active job.
+    async fn recover(&self) -> Result<u64> {
+        let done = sqlx::query(
+            "INSERT INTO jobs (id, job_type, subject_id, payload, priority, max_attempts) \
+             SELECT UUID(), ?, q.id, JSON_OBJECT(), ?, ? FROM queries q \
+             WHERE q.status IN ('Queued', 'InProgress') \
+             AND NOT EXISTS (SELECT 1 FROM jobs j WHERE j.job_type = ? AND j.subject_id = q.id AND j.status IN ('Queued', 'InProgress'))",
+        )
+        .bind(ANSWER_QUERY)
+        .bind(QUERY_PRIORITY)
+        .bind(jobs::default_max_attempts())
+        .bind(ANSWER_QUERY)
+        .execute(&self.pool)
+        .await?;
+        Ok(done.rows_affected())
+    }
+
+    async fn run(&self, job: &Job, lease: &Lease) -> Result<()> {
+        use sqlx::Row;
+        let id = job.subject()?;
+        // Cancelled, answered or deleted since the job was queued: nothing to do
+        let started = sqlx::query("UPDATE queries SET status = 'InProgress' WHERE id = ? AND status NOT IN ('Cancelled', 'Completed')")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        if started.rows_affected() == 0 {
+            info!("Skipping query {}: cancelled, answered or deleted", id);
+            return Ok(());
         }
-        Ok(None)
+        // Subscribers joining from here on see this attempt only
+        self.events.start_attempt(id);
+        let row = sqlx::query("SELECT payload, session_id FROM queries WHERE id = ?")
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await?;
+        let mut q = QueryRecord::new(row.get("payload"));
+        q.id = id.to_string();
+        q.session_id = row.get("session_id");
+        q.status = QueryStatus::InProgress;
+
+        info!("Processing query {}", q.id);
+        let status = self.process_query(&mut q, lease).await?;
+        self.events.finish(&q.id, status_str(&status));
+        Ok(())
+    }
+
+    /// Picked up again once the dependency recovers; the stream stays open.
+    async fn on_retry(&self, job: &Job, _err: &anyhow::Error) -> Result<()> {
+        let id = job.subject()?;
+        // A cancel that arrived meanwhile wins
+        sqlx::query("UPDATE queries SET status = 'Queued' WHERE id = ? AND status = 'InProgress'")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        self.events.publish(id, QueryEvent::stage("waiting"));
+        Ok(())
+    }
+
+    async fn on_dead_letter(&self, job: &Job, err: &anyhow::Error) -> Result<()> {
+        let id = job.subject()?;
+        let kind = err.downcast_ref::<LlmError>().map(|e| e.kind());
+        let result = serde_json::json!({"error": format!("{err}"), "error_kind": kind});
+        sqlx::query("UPDATE queries SET status = 'Failed', result = ? WHERE id = ? AND status IN ('Queued', 'InProgress')")
+            .bind(result)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        self.events.publish(id, QueryEvent::Error { message: format!("{err}") });
+        self.events.finish(id, status_str(&QueryStatus::Failed));
+        Ok(())
     }
+}
 
-    async fn process_query(&self, q: &mut QueryRecord) -> Result<()> {
-        // Stage 1: set InProgress (idempotent)
-        self.update_status(&q.id, QueryStatus::InProgress).await?;
+impl Worker {
+    /// Run the query pipeline; returns Completed, or Cancelled when the query
+    /// was cancelled between stages.
+    async fn process_query(&self, q: &mut QueryRecord, lease: &Lease) -> Result<QueryStatus> {
+        // Stage 1: its job was claimed under a lease (see `jobs::claim`) and the query set InProgress in `run`
 
-        // Stage 2: embed query text
+        // Stage 2: read query options
         let text = q.payload.get("q").and_then(|v| v.as_str()).unwrap_or("");
-        let emb = demo_text_embedding(text).await?;
+        let mode = RetrievalMode::from_payload(&q.payload)?;
+        let query_filter = QueryFilter::from_payload(&q.payload)?;
+        let mut filter = query_filter.vector_filter()?;
+        if let Some(statuses) = &query_filter.analysis_status {
+            // Narrow retrieval itself, so files in other states don't take up top_k slots
+            let matching = files_with_status(&self.pool, statuses).await?;
+            filter.file_ids = Some(match filter.file_ids.take() {
+                Some(wanted) => wanted.into_iter().filter(|id| matching.contains(id)).collect(),
+                None => matching.into_iter().collect(),
+            });
+        }
         let top_k = q.payload.get("top_k").and_then(|v| v.as_u64()).unwrap_or(5) as usize;
-        let top_k = top_k.max(1).min(20);
+        let top_k = top_k.clamp(1, 20);
 
-        // Check cancellation
-        if self.is_cancelled(&q.id).await? { return Ok(()); }
-
-        // Stage 3: search top-K in Qdrant
-        let hits = match self.qdrant.search_top_k(emb.clone(), top_k).await {
-            Ok(list) if !list.is_empty() => list,
-            Ok(_) => Vec::new(),
-            Err(err) => {
-                error!("Qdrant search failed for query {}: {}", q.id, err);
-                Vec::new()
-            }
+        // Stage 2b: in a session, turn a follow-up into a standalone question for retrieval
+        let history: Vec<Turn> = match &q.session_id {
+            Some(sid) => sessions::load_history(&self.pool, sid, &q.id, sessions::history_turns()).await?,
+            None => Vec::new(),
         };
-
-        let hits = if hits.is_empty() {
-            match vector::query_top_k(&emb, top_k) {
-                Ok(fallback_ids) if !fallback_ids.is_empty() => {
-                    info!("Using in-memory fallback for query {}", q.id);
-                    fallback_ids.into_iter().map(|id| (id, 0.0)).collect()
-                }
-                _ => Vec::new(),
-            }
+        let standalone = if history.is_empty() {
+            text.to_string()
         } else {
-            hits
+            self.events.publish(&q.id, QueryEvent::stage("rewriting"));
+            sessions::rewrite_follow_up(self.llms.get(LlmStage::Rewrite), &history, text).await
         };
 
         // Check cancellation
-        if self.is_cancelled(&q.id).await? { return Ok(()); }
+        if self.should_stop(&q.id, lease).await? { return Ok(QueryStatus::Cancelled); }
+
+        // Stage 3: search chunks; fetch more than top_k so that several passages of the
+        // same file still leave room for other files
+        let chunk_limit = (top_k * CHUNKS_PER_FILE).min(MAX_CHUNK_HITS);
+        let hits = self.retrieve(&q.id, &standalone, mode, &filter, chunk_limit).await?;
+        let grouped = group_hits_by_file(hits);
 
-        // Stage 4: fetch file metadata for IDs
+        // Check cancellation
+        if self.should_stop(&q.id, lease).await? { return Ok(QueryStatus::Cancelled); }
+
+        // Stage 4: fetch file metadata and passage text for the best-scoring files
         let mut files_json = Vec::new();
-        for (fid, score) in hits {
-            if let Some(row) = sqlx::query("SELECT id, filename, path, description FROM files WHERE id = ? AND pending_analysis = FALSE")
-                .bind(&fid)
-                .fetch_optional(&self.pool)
-                .await? {
+        let mut passages: Vec<Passage> = Vec::new();
+        for file in grouped {
+            if files_json.len() >= top_k {
+                break;
+            }
+            // Without a status filter only analyzed files qualify; with one, the
+            // caller chose which states to see
+            let sql = if query_filter.analysis_status.is_some() {
+                "SELECT id, filename, description, analysis_status FROM files WHERE id = ?"
+            } else {
+                "SELECT id, filename, description, analysis_status FROM files WHERE id = ? AND pending_analysis = FALSE"
+            };
+            let row = sqlx::query(sql).bind(&file.file_id).fetch_optional(&self.pool).await?;
+            let status_ok = |row: &sqlx::mysql::MySqlRow| {
+                use sqlx::Row;
+                let status: Option<String> = row.get("analysis_status");
+                query_filter.analysis_status.as_ref().map(|want| want.iter().any(|w| Some(w) == status.as_ref())).unwrap_or(true)
+            };
+            if let Some(row) = row.filter(status_ok) {
                 use sqlx::Row;
                 let id: String = row.get("id");
                 let filename: String = row.get("filename");
-                let path: String = row.get("path");
                 let description: Option<String> = row.get("description");
+                let mut passages_json = Vec::new();
+                for (i, hit) in file.passages.iter().enumerate() {
+                    let mut label = None;
+                    if i < PASSAGES_PER_FILE && passages.len() < MAX_PROMPT_PASSAGES {
+                        if let Some(chunk) = sqlx::query("SELECT page_number, text FROM chunks WHERE id = ? AND file_id = ?")
+                            .bind(&hit.id)
+                            .bind(&id)
+                            .fetch_optional(&self.pool)
+                            .await? {
+                            let l = format!("S{}", passages.len() + 1);
+                            passages.push(Passage {
+                                label: l.clone(),
+                                chunk_id: hit.id.clone(),
+                                file_id: id.clone(),
+                                filename: filename.clone(),
+                                page: chunk.get::<i32, _>("page_number").max(0) as u32,
+                                text: chunk.get("text"),
+                            });
+                            label = Some(l);
+                        }
+                    }
+                    passages_json.push(serde_json::json!({
+                        "label": label,
+                        "chunk_id": hit.id,
+                        "page": hit.payload.get("page"),
+                        "chunk_index": hit.payload.get("chunk_index"),
+                        "score": hit.score,
+                    }));
+                }
                 files_json.push(serde_json::json!({
-                    "id": id, "filename": filename, "path": path, "description": description, "score": score
+                    "id": id, "filename": filename, "description": description,
+                    "score": file.score, "passages": passages_json
                 }));
             }
         }
 
+        self.events.publish(&q.id, QueryEvent::Files { files: files_json.clone() });
+
         // Stage 5: call Gemini to analyze relationships and propose follow-up details strictly from provided files
-        let relationships_prompt = build_relationships_prompt(text, &files_json);
-        let (relationships, final_answer) = if files_json.is_empty() {
+        let relationships_prompt = build_relationships_prompt(&standalone, &files_json, &passages);
+        let (relationships, final_answer, citations) = if files_json.is_empty() {
+            let answer = "I could not find any relevant documents yet. Once files finish analysis I will be able to answer.";
+            self.events.publish(&q.id, QueryEvent::Token { text: answer.to_string() });
             (
                 "No analyzed files are ready yet. Try seeding demo data or wait for processing to finish.".to_string(),
-                "I could not find any relevant documents yet. Once files finish analysis I will be able to answer.".to_string(),
+                answer.to_string(),
+                Vec::new(),
             )
         } else {
-            let relationships = generate_text_with_model("gemini-2.5-pro", &relationships_prompt)
-                .await
-                .unwrap_or_else(|e| format!("[demo] relationships error: {}", e));
-
-            // Stage 6: final answer synthesis with strict constraints (no speculation; say unknown when insufficient)
-            let final_prompt = build_final_answer_prompt(text, &files_json, &relationships);
-            let final_answer = generate_text_with_model("gemini-2.5-pro", &final_prompt)
-                .await
-                .unwrap_or_else(|e| format!("[demo] final answer error: {}", e));
-            (relationships, final_answer)
+            self.events.publish(&q.id, QueryEvent::stage("relationships"));
+            let relationships = self.llms.get(LlmStage::Relationships)
+                .generate(&relationships_prompt)
+                .await?;
+
+            if self.should_stop(&q.id, lease).await? { return Ok(QueryStatus::Cancelled); }
+
+            // Stage 6: final answer synthesis with strict constraints (no speculation; say unknown when insufficient),
+            // streamed to subscribers up to the citation list
+            self.events.publish(&q.id, QueryEvent::stage("answering"));
+            let final_prompt = build_final_answer_prompt(text, &standalone, &history, &files_json, &passages, &relationships);
+            let mut visible = AnswerStream::default();
+            let publish_token = |text: String| {
+                if !text.is_empty() {
+                    self.events.publish(&q.id, QueryEvent::Token { text });
+                }
+            };
+            let raw_answer = self.llms.get(LlmStage::Answer)
+                .stream(&final_prompt, &mut |piece| publish_token(visible.push(piece)))
+                .await?;
+            publish_token(visible.finish());
+
+            // Stage 7: keep only citations that point at passages we actually retrieved
+            let (final_answer, citations) = citations::parse_answer(&raw_answer, &passages);
+            (relationships, final_answer, citations)
         };
 
-        // Stage 7: persist results
+        if self.should_stop(&q.id, lease).await? { return Ok(QueryStatus::Cancelled); }
+
+        // Stage 8: persist results
         let result = serde_json::json!({
             "summary": format!("Found {} related files", files_json.len()),
+            "mode": mode.as_str(),
+            "session_id": q.session_id,
+            "standalone_question": standalone,
             "related_files": files_json,
             "relationships": relationships,
             "final_answer": final_answer,
+            "citations": citations,
         });
-        sqlx::query("UPDATE queries SET status = 'Completed', result = ? WHERE id = ?")
-            .bind(result)
+        lease.ensure_held()?;
+        let saved = sqlx::query("UPDATE queries SET status = 'Completed', result = ? WHERE id = ? AND status = 'InProgress'")
+            .bind(&result)
             .bind(&q.id)
             .execute(&self.pool)
             .await?;
-        Ok(())
+        if saved.rows_affected() == 0 {
+            // Cancelled during the answer stage, or the lease lapsed
+            if self.is_cancelled(&q.id).await? { return Ok(QueryStatus::Cancelled); }
+            return Err(lease.lost_error().into());
+        }
+        self.events.publish(&q.id, QueryEvent::Result { result });
+        Ok(QueryStatus::Completed)
     }
 
-    async fn update_status(&self, id: &str, status: QueryStatus) -> Result<()> {
-        let s = match status {
-            QueryStatus::Queued => "Queued",
-            QueryStatus::InProgress => "InProgress",
-            QueryStatus::Completed => "Completed",
-            QueryStatus::Cancelled => "Cancelled",
-            QueryStatus::Failed => "Failed",
+    /// Ranked chunk (and file summary) hits for the query. Hybrid mode fuses the
+    /// vector and BM25 lists by rank, so scores are RRF scores rather than
+    /// similarities.
+    async fn retrieve(&self, query_id: &str, text: &str, mode: RetrievalMode, filter: &VectorFilter, limit: usize) -> Result<Vec<ScoredPoint>> {
+        if filter.file_ids.as_ref().is_some_and(|ids| ids.is_empty()) {
+            return Ok(Vec::new());
+        }
+        let vector_hits = if mode == RetrievalMode::Keyword {
+            Vec::new()
+        } else {
+            self.events.publish(query_id, QueryEvent::stage("embedding"));
+            let emb = self.embedder.embed_query(text).await?;
+            self.events.publish(query_id, QueryEvent::stage("searching"));
+            match self.store.search(&emb, limit, filter).await {
+                Ok(list) => list,
+                Err(err) => {
+                    error!("Vector search failed for query {}: {}", query_id, err);
+                    Vec::new()
+                }
+            }
         };
-        sqlx::query("UPDATE queries SET status = ? WHERE id = ?")
-            .bind(s)
-            .bind(id)
-            .execute(&self.pool)
-            .await?;
-        Ok(())
-    }
-
-    async fn mark_failed(&self, id: &str, message: &str) -> Result<()> {
-        let result = serde_json::json!({"error": message});
-        sqlx::query("UPDATE queries SET status = 'Failed', result = ? WHERE id = ?")
-            .bind(result)
-            .bind(id)
-            .execute(&self.pool)
-            .await?;
-        Ok(())
+        if mode == RetrievalMode::Keyword {
+            self.events.publish(query_id, QueryEvent::stage("searching"));
+        }
+        let keyword_hits = if mode == RetrievalMode::Vector {
+            Vec::new()
+        } else {
+            // Pick up files other replicas indexed or deleted
+            if let Err(err) = self.keywords.refresh().await {
+                warn!("Keyword index refresh failed for query {}: {}", query_id, err);
+            }
+            self.keywords.search(text, limit, filter)
+        };
+        Ok(match mode {
+            RetrievalMode::Vector => vector_hits,
+            RetrievalMode::Keyword => keyword_hits,
+            RetrievalMode::Hybrid => keyword::reciprocal_rank_fusion(&[vector_hits, keyword_hits], RRF_K, limit),
+        })
     }
 
-    async fn requeue_stale_inprogress(&self, age_secs: i64) -> Result<()> {
-        // MySQL: requeue items updated_at < now()-age and status = InProgress
-        sqlx::query(
-            "UPDATE queries SET status = 'Queued' WHERE status = 'InProgress' AND updated_at < (NOW() - INTERVAL ? SECOND)"
-        )
-        .bind(age_secs)
-        .execute(&self.pool)
-        .await?;
-        Ok(())
+    /// True when the query was cancelled; fails with `LeaseLost` when another
+    /// worker has taken it over.
+    async fn should_stop(&self, id: &str, lease: &Lease) -> Result<bool> {
+        if self.is_cancelled(id).await? {
+            return Ok(true);
+        }
+        lease.ensure_held()?;
+        Ok(false)
     }
 
     async fn is_cancelled(&self, id: &str) -> Result<bool> {
@@ -221,46 +481,192 @@ impl Worker {
     }
 }
 
-fn build_relationships_prompt(query: &str, files: &Vec<serde_json::Value>) -> String {
+fn status_str(status: &QueryStatus) -> &'static str {
+    match status {
+        QueryStatus::Queued => "Queued",
+        QueryStatus::InProgress => "InProgress",
+        QueryStatus::Completed => "Completed",
+        QueryStatus::Cancelled => "Cancelled",
+        QueryStatus::Failed => "Failed",
+    }
+}
+
+/// Ids of the files whose analysis status is one of `statuses`.
+async fn files_with_status(pool: &MySqlPool, statuses: &[String]) -> Result<HashSet<String>> {
+    if statuses.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let sql = format!("SELECT id FROM files WHERE analysis_status IN ({})", vec!["?"; statuses.len()].join(", "));
+    let mut query = sqlx::query_scalar(&sql);
+    for status in statuses {
+        query = query.bind(status);
+    }
+    Ok(query.fetch_all(pool).await?.into_iter().collect())
+}
+
+/// Retrieved passages of one file; `score` is the best passage score.
+struct FileHits {
+    file_id: String,
+    score: f32,
+    passages: Vec<ScoredPoint>,
+}
+
+/// Group point hits back to their files, best file first. The file summary
+/// point contributes to the score but is not listed as a passage.
+fn group_hits_by_file(hits: Vec<ScoredPoint>) -> Vec<FileHits> {
+    let mut files: Vec<FileHits> = Vec::new();
+    for hit in hits {
+        let file_id = hit
+            .payload
+            .get("file_id")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| hit.id.clone());
+        let is_chunk = hit.payload.get("type").and_then(|v| v.as_str()) == Some("chunk");
+        let idx = match files.iter().position(|f| f.file_id == file_id) {
+            Some(i) => i,
+            None => {
+                files.push(FileHits { file_id, score: hit.score, passages: Vec::new() });
+                files.len() - 1
+            }
+        };
+        let entry = &mut files[idx];
+        entry.score = entry.score.max(hit.score);
+        if is_chunk {
+            entry.passages.push(hit);
+        }
+    }
+    files.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    files
+}
+
+fn build_relationships_prompt(query: &str, files: &[serde_json::Value], passages: &[Passage]) -> String {
     let files_snippets: Vec<String> = files.iter().map(|f| format!(
-        "- id: {id}, filename: {name}, path: {path}, desc: {desc}",
+        "- id: {id}, filename: {name}, desc: {desc}",
         id=f.get("id").and_then(|v| v.as_str()).unwrap_or(""),
         name=f.get("filename").and_then(|v| v.as_str()).unwrap_or(""),
-        path=f.get("path").and_then(|v| v.as_str()).unwrap_or(""),
         desc=f.get("description").and_then(|v| v.as_str()).unwrap_or("")
     )).collect();
     format!(
         "You are an assistant analyzing relationships STRICTLY within the provided files.\n\
         Query: {query}\n\
         Files:\n{files}\n\
+        Retrieved passages:\n{passages}\n\
         Tasks:\n\
         1) Summarize key details from the files relevant to the query.\n\
         2) Describe relationships and linkages strictly supported by these files.\n\
         3) List important follow-up questions that could be answered only using the provided files.\n\
         Rules: Do NOT guess or invent. If information is insufficient in the files, explicitly state that.",
         query=query,
-        files=files_snippets.join("\n")
+        files=files_snippets.join("\n"),
+        passages=citations::format_passages(passages)
     )
 }
 
-fn build_final_answer_prompt(query: &str, files: &Vec<serde_json::Value>, relationships: &str) -> String {
+fn build_final_answer_prompt(
+    query: &str,
+    standalone: &str,
+    history: &[Turn],
+    files: &[serde_json::Value],
+    passages: &[Passage],
+    relationships: &str,
+) -> String {
     let files_short: Vec<String> = files.iter().map(|f| format!(
         "- {name} ({id})",
         id=f.get("id").and_then(|v| v.as_str()).unwrap_or(""),
         name=f.get("filename").and_then(|v| v.as_str()).unwrap_or("")
     )).collect();
+    // Earlier turns give context for follow-ups; they are not a source of facts
+    let conversation = if history.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "Conversation so far (context only; earlier answers are not sources):\n{}\n\nInterpreted as: {standalone}\n",
+            sessions::format_history(history)
+        )
+    };
     format!(
         "You are to compose a final answer to the user query using only the information from the files.\n\
+        {conversation}\
         Query: {query}\n\
         Files considered:\n{files}\n\
+        Retrieved passages:\n{passages}\n\
         Relationship analysis:\n{rels}\n\
         Requirements:\n\
         - Use only information present in the files and analysis above.\n\
         - If the answer is uncertain or cannot be determined from the files, clearly state that limitation.\n\
         - Avoid speculation or assumptions.\n\
+        - {cite}\n\
         Provide a concise, structured answer.",
         query=query,
         files=files_short.join("\n"),
-        rels=relationships
+        passages=citations::format_passages(passages),
+        rels=relationships,
+        cite=citations::citation_instructions()
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn hit(id: &str, file_id: &str, kind: &str, score: f32) -> ScoredPoint {
+        ScoredPoint { id: id.to_string(), score, payload: json!({ "file_id": file_id, "type": kind }) }
+    }
+
+    #[test]
+    fn hits_group_by_file_best_first() {
+        let grouped = group_hits_by_file(vec![
+            hit("c1", "a", "chunk", 0.5),
+            hit("b", "b", "file", 0.9),
+            hit("c2", "a", "chunk", 0.7),
+            hit("c3", "b", "chunk", 0.2),
+        ]);
+        assert_eq!(grouped.iter().map(|f| f.file_id.as_str()).collect::<Vec<_>>(), vec!["b", "a"]);
+        assert_eq!(grouped[0].score, 0.9);
+        // The summary point scores the file but is not a passage
+        assert_eq!(grouped[0].passages.iter().map(|p| p.id.as_str()).collect::<Vec<_>>(), vec!["c3"]);
+        assert_eq!(grouped[1].passages.len(), 2);
+    }
+
+    #[test]
+    fn bad_options_are_caught_before_queuing() {
+        assert!(validate_options(&json!({ "q": "oxygen generation" })).is_ok());
+        assert!(validate_options(&json!({ "q": "x", "mode": "keyword", "filter": { "tags": ["eclss"], "uploaded_from": "2024-01-01" } })).is_ok());
+        let reason = |payload: serde_json::Value| validate_options(&payload).unwrap_err().to_string();
+        assert!(reason(json!({ "mode": "semantic" })).starts_with("unknown retrieval mode 'semantic'"));
+        assert_eq!(reason(json!({ "mode": 3 })), "mode must be a string");
+        assert!(reason(json!({ "filter": { "colour": "red" } })).starts_with("invalid query filter"));
+        assert!(reason(json!({ "filter": "eclss" })).starts_with("invalid query filter"));
+        assert!(reason(json!({ "filter": { "uploaded_to": "last tuesday" } })).starts_with("invalid date 'last tuesday'"));
+    }
+
+    #[test]
+    fn filter_parses_dates_and_normalizes_lists() {
+        let payload = json!({ "filter": {
+            "tags": [" ECLSS "],
+            "mime_types": ["Application/PDF"],
+            "uploaded_from": "2024-01-01",
+            "uploaded_to": "2024-01-01",
+            "analysis_status": ["Completed"]
+        }});
+        let filter = QueryFilter::from_payload(&payload).unwrap();
+        let vf = filter.vector_filter().unwrap();
+        assert_eq!(vf.tags, Some(vec!["eclss".to_string()]));
+        assert_eq!(vf.mime_types, Some(vec!["application/pdf".to_string()]));
+        assert_eq!(vf.uploaded_from, Some(1_704_067_200));
+        assert_eq!(vf.uploaded_to, Some(1_704_067_200 + 86_399));
+        // The status is resolved against MySQL, not matched on payloads
+        assert!(vf.file_ids.is_none());
+
+        let rfc = json!({ "filter": { "uploaded_from": "2024-01-01T12:00:00+02:00", "uploaded_to": 5 } });
+        let vf = QueryFilter::from_payload(&rfc).unwrap().vector_filter().unwrap();
+        assert_eq!((vf.uploaded_from, vf.uploaded_to), (Some(1_704_103_200), Some(5)));
+
+        assert!(QueryFilter::from_payload(&json!({ "filter": { "colour": "red" } })).is_err());
+        let bad_date = json!({ "filter": { "uploaded_to": "last tuesday" } });
+        assert!(QueryFilter::from_payload(&bad_date).unwrap().vector_filter().is_err());
+        assert!(QueryFilter::from_payload(&json!({})).unwrap().vector_filter().unwrap().is_empty());
+    }
+}
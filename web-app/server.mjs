@@ -29,7 +29,7 @@ app.get('/api/healthz', (_req, res) => {
 app.post('/api/files/import-demo', async (req, res) => {
   try {
     const qs = req.url.includes('?') ? req.url.substring(req.url.indexOf('?')) : '';
-    const url = `${RUST_ENGINE_BASE}/api/files/import-demo${qs}`;
+    const url = `${RUST_ENGINE_BASE}/api/v1/files/import-demo${qs}`;
     const upstream = await fetch(url, { method: 'POST', headers: { 'content-type': 'application/json' }, body: req.body ? JSON.stringify(req.body) : undefined });
     const text = await upstream.text();
     res.status(upstream.status).type(upstream.headers.get('content-type') || 'application/json').send(text);